@@ -0,0 +1,318 @@
+//! Super image workflow without the UI, for scripts and build servers.
+//!
+//! ```text
+//! oplus_super_cli validate <config.json> [--variant NAME] [--json]
+//! oplus_super_cli build <config.json> -o <super.img> [--sparse] [--slot single|ab] [--active a|b] [--metadata-slots N] [--ignore-free-space] [--layout-json] [--variant NAME] [--json]
+//! oplus_super_cli unpack <super.img> -d <outdir> [--json]
+//! oplus_super_cli inspect <super.img> [--layout] [--json]
+//! ```
+//!
+//! With `--json` the result goes to stdout as the same report the app's commands
+//! return (`ValidationReport`, `BuildReport`, `PartitionConfig`), and a failure as
+//! `{"error": ..., "exit_code": ...}`. Build progress goes to stderr as a percentage.
+//! `build --layout-json` also writes `<super.img>.layout.json`, and `inspect --layout`
+//! prints the same `SuperLayout` for any super image.
+
+use edl_toolkit_lib::logger;
+use edl_toolkit_lib::super_image_creater::{
+    BuildError, JsonParseError, ManifestError, OutputFormat, PartitionConfig, Slot, SlotMode, SplitError,
+    SuperImageBuilder, UnpackError, config_from_super_dump, inspect_super, read_partition_config, read_super_metadata,
+    resolve_variant, unpack_super_image,
+};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+
+/// The config failed validation, or its partitions do not fit
+const EXIT_INVALID: u8 = 1;
+/// A file could not be read or written
+const EXIT_IO: u8 = 2;
+/// Anything else: a corrupt or unsupported image, a cancelled build
+const EXIT_FAILED: u8 = 3;
+/// Unknown subcommand or option, as `EX_USAGE` of sysexits.h
+const EXIT_USAGE: u8 = 64;
+
+const USAGE: &str = "\
+Usage:
+  oplus_super_cli validate <config.json> [--variant NAME] [--json]
+  oplus_super_cli build <config.json> -o <super.img> [--sparse] [--slot single|ab] [--active a|b] [--metadata-slots N] [--ignore-free-space] [--layout-json] [--variant NAME] [--json]
+  oplus_super_cli unpack <super.img> -d <outdir> [--json]
+  oplus_super_cli inspect <super.img> [--layout] [--json]
+
+Exit codes: 0 success, 1 invalid config, 2 I/O error, 3 other failure, 64 bad usage";
+
+/// Why a subcommand failed, with the exit code it maps to. An empty `message` means
+/// the subcommand has reported the failure itself.
+struct Failure {
+    code: u8,
+    message: String,
+}
+
+impl Failure {
+    fn usage(message: impl Into<String>) -> Self {
+        Failure { code: EXIT_USAGE, message: message.into() }
+    }
+}
+
+impl From<JsonParseError> for Failure {
+    fn from(e: JsonParseError) -> Self {
+        let code = match e {
+            JsonParseError::FileError(_) | JsonParseError::FileErrorAt { .. } => EXIT_IO,
+            _ => EXIT_INVALID,
+        };
+        Failure { code, message: e.to_string() }
+    }
+}
+
+impl From<BuildError> for Failure {
+    fn from(e: BuildError) -> Self {
+        let code = match e {
+            BuildError::Io(_)
+            | BuildError::InsufficientSpace { .. }
+            | BuildError::Manifest(ManifestError::Io(_))
+            | BuildError::Split(SplitError::Io(_)) => EXIT_IO,
+            BuildError::Cancelled => EXIT_FAILED,
+            _ => EXIT_INVALID,
+        };
+        Failure { code, message: e.to_string() }
+    }
+}
+
+impl From<UnpackError> for Failure {
+    fn from(e: UnpackError) -> Self {
+        let code = match e {
+            UnpackError::Io(_) => EXIT_IO,
+            _ => EXIT_FAILED,
+        };
+        Failure { code, message: e.to_string() }
+    }
+}
+
+/// Command line after the subcommand: positional arguments and options
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+    json: bool,
+}
+
+impl Args {
+    /// Splits `args`, where the options in `with_value` take the argument after them
+    fn parse(mut args: impl Iterator<Item = String>, with_value: &[&str], flags: &[&str]) -> Result<Self, Failure> {
+        let mut parsed = Args { positional: Vec::new(), options: Vec::new(), json: false };
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                parsed.json = true;
+            } else if with_value.contains(&arg.as_str()) {
+                let value = args.next().ok_or_else(|| Failure::usage(format!("{} needs a value", arg)))?;
+                parsed.options.push((arg, Some(value)));
+            } else if flags.contains(&arg.as_str()) {
+                parsed.options.push((arg, None));
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                return Err(Failure::usage(format!("Unknown option {}", arg)));
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn value(&self, names: &[&str]) -> Option<&str> {
+        self.options.iter().rev().find(|(name, _)| names.contains(&name.as_str())).and_then(|(_, value)| value.as_deref())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    /// The single positional argument, `what` names it in the error
+    fn input(&self, what: &str) -> Result<&str, Failure> {
+        match self.positional.as_slice() {
+            [input] => Ok(input),
+            [] => Err(Failure::usage(format!("Missing {}", what))),
+            [_, extra, ..] => Err(Failure::usage(format!("Unexpected argument {}", extra))),
+        }
+    }
+}
+
+/// Prints `value` as JSON, or `text` of it
+fn output<T: Serialize>(json: bool, value: &T, text: impl FnOnce(&mut dyn Write, &T) -> io::Result<()>) -> Result<(), Failure> {
+    if json {
+        let out = serde_json::to_string_pretty(value).map_err(|e| Failure { code: EXIT_FAILED, message: e.to_string() })?;
+        write_stdout(|stdout| writeln!(stdout, "{}", out))
+    } else {
+        write_stdout(|stdout| text(stdout, value))
+    }
+}
+
+/// Runs `write` on the locked stdout. A closed stdout (`| head`) ends the output
+/// early but is not a failure.
+fn write_stdout(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> Result<(), Failure> {
+    let mut stdout = io::stdout().lock();
+    match write(&mut stdout).and_then(|()| stdout.flush()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(Failure { code: EXIT_IO, message: format!("Cannot write to stdout: {}", e) }),
+        _ => Ok(()),
+    }
+}
+
+/// The config at `path` with its image paths resolved against its directory, and
+/// `variant` selected
+fn load_config(path: &str, variant: Option<&str>) -> Result<PartitionConfig, Failure> {
+    let mut config = read_partition_config(path)?;
+    if let Some(name) = variant {
+        config = resolve_variant(&config, &config.variants, name)
+            .map_err(|e| Failure { code: EXIT_INVALID, message: e.to_string() })?;
+    }
+    config.resolve_paths(Path::new(path).parent().unwrap_or(Path::new("")));
+    Ok(config)
+}
+
+fn validate(args: Args) -> Result<(), Failure> {
+    let path = args.input("config file")?;
+    let config = load_config(path, args.value(&["--variant"]))?;
+    let report = config.validate().err().unwrap_or_default();
+    output(args.json, &report, |stdout, report| {
+        for entry in &report.entries {
+            writeln!(stdout, "{:?}: {}", entry.severity, entry.message)?;
+        }
+        writeln!(stdout, "{}: {} error(s), {} warning(s)", path, report.errors().count(), report.warnings().count())
+    })?;
+    match report.has_errors() {
+        true => Err(Failure { code: EXIT_INVALID, message: String::new() }),
+        false => Ok(()),
+    }
+}
+
+fn build(args: Args) -> Result<(), Failure> {
+    let path = args.input("config file")?;
+    let output_path = args.value(&["-o", "--output"]).ok_or_else(|| Failure::usage("Missing -o <super.img>"))?;
+    let active = match args.value(&["--active"]).unwrap_or("a") {
+        "a" => Slot::A,
+        "b" => Slot::B,
+        other => return Err(Failure::usage(format!("Unknown slot {}, expected a or b", other))),
+    };
+    let slot_mode = match args.value(&["--slot"]).unwrap_or("single") {
+        "single" => SlotMode::Single,
+        "ab" => SlotMode::AB { active },
+        other => return Err(Failure::usage(format!("Unknown slot mode {}, expected single or ab", other))),
+    };
+    let format = if args.flag("--sparse") { OutputFormat::Sparse { max_blob_size: None } } else { OutputFormat::Raw };
+    let metadata_slots = match args.value(&["--metadata-slots"]) {
+        Some(count) => Some(count.parse::<u32>().map_err(|_| Failure::usage(format!("Invalid metadata slot count {}", count)))?),
+        None => None,
+    };
+    let config = load_config(path, args.value(&["--variant"]))?;
+
+    let mut builder = SuperImageBuilder::new(config)
+        .output(PathBuf::from(output_path))
+        .format(format)
+        .slot_mode(slot_mode)
+        .check_free_space(!args.flag("--ignore-free-space"))
+        .layout_file(args.flag("--layout-json"));
+    if let Some(count) = metadata_slots {
+        builder = builder.metadata_slot_count(count);
+    }
+    let mut last_percent = None;
+    let result = builder.build_with_cancel(&AtomicBool::new(false), |progress| {
+        if last_percent != Some(progress.overall_percent) {
+            last_percent = Some(progress.overall_percent);
+            eprint!("\rBuilding {}: {:>3}%", output_path, progress.overall_percent);
+            let _ = std::io::stderr().flush();
+        }
+    });
+    if last_percent.is_some() {
+        eprintln!();
+    }
+    let report = result?;
+    output(args.json, &report, |stdout, report| {
+        for partition in report.partitions.iter().filter(|p| p.size > 0) {
+            writeln!(stdout, "{:<24} {:<32} offset {:>12} size {:>12}", partition.name, partition.group_name, partition.offset, partition.size)?;
+        }
+        writeln!(stdout, "Wrote {}: {} bytes", output_path, report.image_size)
+    })
+}
+
+fn unpack(args: Args) -> Result<(), Failure> {
+    let image = args.input("super image")?;
+    let out_dir = args.value(&["-d", "--dir"]).ok_or_else(|| Failure::usage("Missing -d <outdir>"))?;
+    let config = unpack_super_image(Path::new(image), Path::new(out_dir))?;
+    output(args.json, &config, |stdout, config| {
+        for partition in config.data_partitions() {
+            writeln!(stdout, "{}", partition.path)?;
+        }
+        writeln!(stdout, "Unpacked {} partition(s) to {}", config.data_partitions().count(), out_dir)
+    })
+}
+
+fn inspect(args: Args) -> Result<(), Failure> {
+    let image = Path::new(args.input("super image")?);
+    if args.flag("--layout") {
+        return output(true, &inspect_super(image)?, |_, _| Ok(()));
+    }
+    if args.json {
+        return output(true, &config_from_super_dump(image)?, |_, _| Ok(()));
+    }
+    let (geometry, metadata) = read_super_metadata(image)?;
+    write_stdout(|stdout| {
+        writeln!(
+            stdout,
+            "Metadata: {} slot(s) of {} bytes, version 10.{}",
+            geometry.metadata_slot_count, geometry.metadata_max_size, metadata.minor_version
+        )?;
+        for device in &metadata.block_devices {
+            writeln!(stdout, "Block device {}: {} bytes, alignment {}", device.partition_name, device.size, device.alignment)?;
+        }
+        writeln!(stdout, "\n{:<32} {:>14}", "Group", "Maximum size")?;
+        for group in &metadata.groups {
+            let maximum = match group.maximum_size {
+                0 => "unlimited".to_string(),
+                size => size.to_string(),
+            };
+            writeln!(stdout, "{:<32} {:>14}", group.name, maximum)?;
+        }
+        writeln!(stdout, "\n{:<24} {:<32} {:>14} {:>14} {:>8}", "Partition", "Group", "Offset", "Size", "Extents")?;
+        for partition in &metadata.partitions {
+            let start = partition.first_extent_index as usize;
+            let extents = metadata.extents.get(start..start + partition.num_extents as usize).unwrap_or_default();
+            let size: u64 = extents.iter().map(|e| e.num_sectors * 512).sum();
+            let offset = extents.first().map(|e| (e.target_data * 512).to_string()).unwrap_or_else(|| "-".to_string());
+            let group = metadata.groups.get(partition.group_index as usize).map_or("?", |g| g.name.as_str());
+            writeln!(stdout, "{:<24} {:<32} {:>14} {:>14} {:>8}", partition.name, group, offset, size, extents.len())?;
+        }
+        Ok(())
+    })
+}
+
+fn main() -> ExitCode {
+    logger::init();
+    let mut args = std::env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    let json = std::env::args().any(|a| a == "--json");
+    let result = match command.as_str() {
+        "validate" => Args::parse(args, &["--variant"], &[]).and_then(validate),
+        "build" => Args::parse(args, &["-o", "--output", "--slot", "--active", "--metadata-slots", "--variant"], &["--sparse", "--ignore-free-space", "--layout-json"]).and_then(build),
+        "unpack" => Args::parse(args, &["-d", "--dir"], &[]).and_then(unpack),
+        "inspect" => Args::parse(args, &[], &["--layout"]).and_then(inspect),
+        "-h" | "--help" | "help" => {
+            let _ = writeln!(io::stdout().lock(), "{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        "" => Err(Failure::usage("Missing subcommand")),
+        other => Err(Failure::usage(format!("Unknown subcommand {}", other))),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) if failure.message.is_empty() => ExitCode::from(failure.code),
+        Err(failure) => {
+            if json {
+                let _ = writeln!(io::stdout().lock(), "{}", serde_json::json!({ "error": failure.message, "exit_code": failure.code }));
+            }
+            eprintln!("Error: {}", failure.message);
+            if failure.code == EXIT_USAGE {
+                eprintln!("{}", USAGE);
+            }
+            ExitCode::from(failure.code)
+        }
+    }
+}
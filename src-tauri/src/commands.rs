@@ -0,0 +1,503 @@
+//! Tauri commands for the super image config and build, with errors as `ErrorDto`
+//! so the frontend can tell a config problem from a missing file.
+use crate::super_image_creater::{
+    self, AutoSizeError, BuildError, GroupUsage, JsonParseError, LpmakeParseError, NvError, UnpackError, ValidationEntry,
+    ValidationError, ValidationReport, VariantError,
+};
+use crate::{SuperBuildState, config_dir};
+use notify::Watcher;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, State};
+
+/// Error of a command, serialized with a `code` tag that tells the frontend where
+/// to point the user, e.g.
+/// `{"code": "config", "message": "...", "field": "partitions[3].size", ...}`.
+///
+/// `config`, `validation`, `report` and `partition` are fixed in the config editor, `file` in
+/// the file picker; `cancelled` and `failed` only need the message shown.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum ErrorDto {
+    /// The config is wrong at `field` (like `partitions[3].size`) or at `line` and
+    /// `column` of `file`, where known
+    Config {
+        message: String,
+        field: Option<String>,
+        file: Option<PathBuf>,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+    /// The config does not build; every issue names its partition, group or device
+    Validation { message: String, issues: Vec<ValidationError> },
+    /// `PartitionConfig::validate` found errors; `report` has them, and the warnings
+    Report { message: String, report: ValidationReport },
+    /// One partition is the problem, `file` is its image if that is
+    Partition { message: String, partition: String, file: Option<PathBuf> },
+    /// `file` (an image, the config or the output) could not be opened, read or written
+    File { message: String, file: Option<PathBuf> },
+    Cancelled { message: String },
+    Failed { message: String },
+}
+
+impl ErrorDto {
+    pub fn message(&self) -> &str {
+        match self {
+            ErrorDto::Config { message, .. }
+            | ErrorDto::Validation { message, .. }
+            | ErrorDto::Report { message, .. }
+            | ErrorDto::Partition { message, .. }
+            | ErrorDto::File { message, .. }
+            | ErrorDto::Cancelled { message }
+            | ErrorDto::Failed { message } => message,
+        }
+    }
+
+    /// Names `path` as the file of a `File` error that has none yet
+    pub fn with_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        if let ErrorDto::File { file: file @ None, .. } = &mut self {
+            *file = Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    fn config(message: String, field: Option<&str>) -> Self {
+        ErrorDto::Config { message, field: field.map(str::to_string), file: None, line: None, column: None }
+    }
+
+    fn file(message: String, file: Option<PathBuf>) -> Self {
+        ErrorDto::File { message, file }
+    }
+}
+
+impl fmt::Display for ErrorDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+// For the commands in `lib.rs` that still return their errors as strings
+impl From<ErrorDto> for String {
+    fn from(e: ErrorDto) -> Self {
+        e.to_string()
+    }
+}
+
+impl From<io::Error> for ErrorDto {
+    fn from(e: io::Error) -> Self {
+        ErrorDto::file(e.to_string(), None)
+    }
+}
+
+impl From<tokio::task::JoinError> for ErrorDto {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ErrorDto::Failed { message: e.to_string() }
+    }
+}
+
+impl From<JsonParseError> for ErrorDto {
+    fn from(e: JsonParseError) -> Self {
+        let message = e.to_string();
+        match e {
+            JsonParseError::FileError(_) => ErrorDto::file(message, None),
+            JsonParseError::FileErrorAt { path, .. } => ErrorDto::file(message, Some(path)),
+            JsonParseError::JsonError { line, column, path, field_path, .. } => ErrorDto::Config {
+                message,
+                field: field_path,
+                file: path,
+                // 0 is unknown
+                line: (line > 0).then_some(line),
+                column: (column > 0).then_some(column),
+            },
+            JsonParseError::InvalidSize { field, path, .. } => {
+                ErrorDto::Config { message, field: Some(field), file: path, line: None, column: None }
+            }
+            JsonParseError::UnsupportedVersion { .. } => ErrorDto::config(message, Some("config_version")),
+        }
+    }
+}
+
+impl From<VariantError> for ErrorDto {
+    fn from(e: VariantError) -> Self {
+        ErrorDto::config(e.to_string(), Some("variants"))
+    }
+}
+
+impl From<NvError> for ErrorDto {
+    fn from(e: NvError) -> Self {
+        ErrorDto::config(e.to_string(), Some("nv_id"))
+    }
+}
+
+impl From<AutoSizeError> for ErrorDto {
+    fn from(e: AutoSizeError) -> Self {
+        let message = e.to_string();
+        match e {
+            AutoSizeError::MissingPath(partition) => ErrorDto::Partition { message, partition, file: None },
+            AutoSizeError::Image { partition, path, .. } => ErrorDto::Partition { message, partition, file: Some(path.into()) },
+        }
+    }
+}
+
+impl From<BuildError> for ErrorDto {
+    fn from(e: BuildError) -> Self {
+        let message = e.to_string();
+        match e {
+            BuildError::Io(_) => ErrorDto::file(message, None),
+            BuildError::Invalid(issues) => ErrorDto::Validation { message, issues },
+            BuildError::ImageTooLarge { partition, .. } => ErrorDto::Partition { message, partition, file: None },
+            BuildError::AutoSize(e) => e.into(),
+            BuildError::Nv(e) => e.into(),
+            // Another output directory is the fix
+            BuildError::InsufficientSpace { dir, .. } => ErrorDto::file(message, Some(dir)),
+            BuildError::MetadataTooLarge { .. } | BuildError::TooManyMetadataSlots { .. } => {
+                ErrorDto::config(message, Some("super_meta.size"))
+            }
+            BuildError::BlockDevice(_) | BuildError::MultipleBlockDevices(_) | BuildError::SparseUnaligned { .. } => {
+                ErrorDto::config(message, Some("block_devices"))
+            }
+            BuildError::NameTooLong(_)
+            | BuildError::NoMetadataSlots
+            | BuildError::OutOfSpace(_)
+            | BuildError::AlreadySlotted(_)
+            | BuildError::Link(_) => ErrorDto::config(message, None),
+            BuildError::Cancelled => ErrorDto::Cancelled { message },
+            BuildError::Manifest(_) | BuildError::Split(_) | BuildError::LayoutChanged(_) => ErrorDto::Failed { message },
+        }
+    }
+}
+
+impl From<ValidationReport> for ErrorDto {
+    fn from(report: ValidationReport) -> Self {
+        ErrorDto::Report { message: report.to_string(), report }
+    }
+}
+
+impl From<LpmakeParseError> for ErrorDto {
+    fn from(e: LpmakeParseError) -> Self {
+        ErrorDto::config(e.to_string(), None)
+    }
+}
+
+impl From<notify::Error> for ErrorDto {
+    fn from(e: notify::Error) -> Self {
+        let file = e.paths.first().cloned();
+        ErrorDto::file(e.to_string(), file)
+    }
+}
+
+impl From<UnpackError> for ErrorDto {
+    fn from(e: UnpackError) -> Self {
+        let message = e.to_string();
+        match e {
+            UnpackError::ExternalBlockDevice { partition, .. } | UnpackError::UnsupportedExtent { partition, .. } => {
+                ErrorDto::Partition { message, partition, file: None }
+            }
+            UnpackError::Cancelled => ErrorDto::Cancelled { message },
+            // Not an image this can read, another one has to be picked
+            UnpackError::Io(_) | UnpackError::Geometry(_) | UnpackError::Metadata(_) | UnpackError::SparseImage => {
+                ErrorDto::file(message, None)
+            }
+        }
+    }
+}
+
+/// The config of storage variant `variant` (see `resolve_variant`), the config as
+/// written when `None`
+pub(crate) fn select_variant(
+    config: super_image_creater::PartitionConfig,
+    variant: Option<&str>,
+) -> Result<super_image_creater::PartitionConfig, ErrorDto> {
+    match variant {
+        Some(name) => Ok(super_image_creater::resolve_variant(&config, &config.variants, name)?),
+        None => Ok(config),
+    }
+}
+
+/// The builder of `create_super_image` and `start_super_build`: the config at `path`
+/// with its image paths resolved, `variant` selected and `nv_mapping` read
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn super_builder(
+    path: &str,
+    output: &str,
+    sparse: bool,
+    zstd_level: Option<i32>,
+    chunk_size: Option<u64>,
+    metadata_slots: Option<u32>,
+    variant: Option<&str>,
+    nv_mapping: Option<&str>,
+) -> Result<super_image_creater::SuperImageBuilder, ErrorDto> {
+    let config = super_image_creater::read_partition_config_async(path).await?;
+    let mut config = select_variant(config, variant)?;
+    config.resolve_paths(&config_dir(path));
+    let format = if let Some(level) = zstd_level {
+        super_image_creater::OutputFormat::Zstd { level }
+    } else if sparse {
+        super_image_creater::OutputFormat::Sparse { max_blob_size: None }
+    } else {
+        super_image_creater::OutputFormat::Raw
+    };
+    let mut builder = super_image_creater::SuperImageBuilder::new(config).output(output).format(format).split(chunk_size);
+    if let Some(mapping) = nv_mapping {
+        builder = builder.nv_mapping(super_image_creater::read_nv_mapping(mapping)?);
+    }
+    if let Some(count) = metadata_slots {
+        builder = builder.metadata_slot_count(count);
+    }
+    Ok(builder)
+}
+
+/// Opens a partition config for editing, with the migrations applied if it had an
+/// older layout. A config that does not parse is rejected with a `config` error
+/// naming the field, so the editor can point at it.
+#[tauri::command]
+pub fn open_super_config(path: String) -> Result<super_image_creater::MigratedConfig, ErrorDto> {
+    Ok(super_image_creater::read_partition_config_migrated(&path)?)
+}
+
+/// `open_super_config` for JSON the frontend already holds, e.g. a dropped file
+#[tauri::command]
+pub fn parse_super_config(content: String) -> Result<super_image_creater::MigratedConfig, ErrorDto> {
+    Ok(super_image_creater::read_partition_config_migrated_from_str(&content)?)
+}
+
+/// Turns a pasted lpmake command line into a partition config, for the editor to
+/// show and save, see `config_from_lpmake_args`
+#[tauri::command]
+pub fn import_lpmake_command(command_line: String) -> Result<super_image_creater::PartitionConfig, ErrorDto> {
+    let args = super_image_creater::split_command_line(&command_line)?;
+    Ok(super_image_creater::config_from_lpmake_args(&args)?)
+}
+
+/// Runs every config check and returns the consolidated report (empty when clean).
+/// With a `variant`, the config of that variant is checked; with `nv_mapping`, the
+/// config as built with the images of its `nv_id`.
+#[tauri::command]
+pub fn validate_super_config(path: String, variant: Option<String>, nv_mapping: Option<String>) -> Result<super_image_creater::ValidationReport, ErrorDto> {
+    let config = super_image_creater::read_partition_config(&path)?;
+    let mut config = select_variant(config, variant.as_deref())?;
+    let result = match nv_mapping {
+        Some(mapping_path) => {
+            let mapping = super_image_creater::read_nv_mapping(&mapping_path)?;
+            config.resolve_paths(&config_dir(&path));
+            config.validate_with_nv(&mapping)
+        }
+        None => config.validate(),
+    };
+    Ok(result.err().unwrap_or_default())
+}
+
+/// Lays out a super image from a partition config without writing it, for a map of
+/// where each partition will land and how full each group and block device gets
+#[tauri::command]
+pub async fn plan_super_image(path: String, variant: Option<String>, metadata_slots: Option<u32>) -> Result<super_image_creater::SuperLayout, ErrorDto> {
+    let config = super_image_creater::read_partition_config_async(&path).await?;
+    let mut config = select_variant(config, variant.as_deref())?;
+    config.resolve_paths(&config_dir(&path));
+    let options = super_image_creater::PlanOptions { metadata_slot_count: metadata_slots, ..Default::default() };
+    Ok(tokio::task::spawn_blocking(move || super_image_creater::plan_super_image(&config, &options)).await??)
+}
+
+/// Builds a super image natively from a partition config.
+///
+/// Relative image paths in the config are relative to the config file. `variant`
+/// selects a storage variant of the config, `nv_mapping` is the firmware's NV
+/// mapping file, whose images for the config's `nv_id` replace the partition paths.
+/// `sparse` writes an Android sparse image; with `zstd_level` the image is zstd
+/// compressed instead of raw or sparse, conventionally as `super.img.zst`; the report then has
+/// both sizes and the SHA-256 of the raw image.
+/// With `chunk_size` the output is written as `<output>.0`, `<output>.1`, ... of that
+/// size plus a `<output>.chunks.json` index, e.g. 4 GiB - 1 MiB for a FAT32 card;
+/// `join_super_image` joins them again.
+/// `metadata_slots` overrides the number of LP metadata slots, two for stock configs.
+/// The build refuses to start when the output directory clearly lacks the space for
+/// it (see `estimate_super_size`), unless `ignore_free_space` is set.
+/// With `layout_file` the layout of the image is written to `<output>.layout.json`,
+/// in the format of `inspect_super_layout`.
+///
+/// Progress is emitted as `super-build-progress` (at most ~10 per second), followed by
+/// exactly one `super-build-done` (with the build report) or `super-build-error` event
+/// (with the `ErrorDto`).
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_super_image(app: AppHandle, path: String, output: String, sparse: bool, zstd_level: Option<i32>, chunk_size: Option<u64>, metadata_slots: Option<u32>, variant: Option<String>, nv_mapping: Option<String>, ignore_free_space: Option<bool>, layout_file: Option<bool>, build_state: State<'_, SuperBuildState>) -> Result<super_image_creater::BuildReport, ErrorDto> {
+    let _ = app.emit("log_event", &format!("Creating Super image {}...", output));
+    build_state.cancel.store(false, Ordering::SeqCst);
+    let cancel = build_state.cancel.clone();
+    let result = match super_builder(&path, &output, sparse, zstd_level, chunk_size, metadata_slots, variant.as_deref(), nv_mapping.as_deref()).await {
+        Ok(builder) => {
+            let builder = builder.check_free_space(!ignore_free_space.unwrap_or(false)).layout_file(layout_file.unwrap_or(false));
+            let progress_app = app.clone();
+            let mut last_emit: Option<Instant> = None;
+            builder
+                .build_async_with_cancel(cancel, move |progress| {
+                    if last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100)) {
+                        last_emit = Some(Instant::now());
+                        let _ = progress_app.emit("super-build-progress", &progress);
+                    }
+                })
+                .await
+                .map_err(ErrorDto::from)
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(report) => {
+            let _ = app.emit("log_event", &format!("Create Super image...OK ({} bytes)", report.image_size));
+            let _ = app.emit("super-build-done", &report);
+            Ok(report)
+        }
+        Err(e) => {
+            let _ = app.emit("log_event", &format!("Failed to create Super image: {}", e));
+            let _ = app.emit("super-build-error", &e);
+            Err(e)
+        }
+    }
+}
+
+/// Extracts every partition of a raw super image into `out_dir` as `<name>.img` and
+/// returns the config describing them. Placeholder partitions get no image.
+#[tauri::command]
+pub async fn unpack_super_image(app: AppHandle, path: String, out_dir: String) -> Result<super_image_creater::PartitionConfig, ErrorDto> {
+    let _ = app.emit("log_event", &format!("Unpacking Super image {}...", path));
+    let image = path.clone();
+    let result = tokio::task::spawn_blocking(move || super_image_creater::unpack_super(&image, &out_dir))
+        .await?
+        .map_err(|e| match e {
+            // May as well come from writing to out_dir
+            UnpackError::Io(_) => ErrorDto::from(e),
+            e => ErrorDto::from(e).with_file(&path),
+        });
+    match &result {
+        Ok(config) => {
+            let _ = app.emit("log_event", &format!("Unpack Super image...OK ({} partitions)", config.partitions.len()));
+        }
+        Err(e) => {
+            let _ = app.emit("log_event", &format!("Failed to unpack Super image: {}", e));
+        }
+    }
+    result
+}
+
+/// Payload of `config-validated` for a config that builds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigSummary {
+    pub partition_count: usize,
+    /// Sum of the partition extents
+    pub total_size: u64,
+    pub groups: Vec<GroupUsage>,
+    /// What `PartitionConfig::validate` found that does not stop a build
+    pub warnings: Vec<ValidationEntry>,
+}
+
+/// Reads and validates the config at `path` and lays it out, as `watch_config` does
+/// on every change
+pub(crate) fn check_config(path: &str) -> Result<ConfigSummary, ErrorDto> {
+    let mut config = super_image_creater::read_partition_config(path).map_err(|e| ErrorDto::from(e).with_file(path))?;
+    config.resolve_paths(&config_dir(path));
+    let warnings = match config.validate() {
+        Ok(()) => Vec::new(),
+        Err(report) if report.has_errors() => return Err(report.into()),
+        Err(report) => report.entries,
+    };
+    let layout = super_image_creater::plan_super_image(&config, &super_image_creater::PlanOptions::default())?;
+    Ok(ConfigSummary {
+        partition_count: layout.partitions.len(),
+        total_size: layout.partitions.iter().map(|p| p.size).sum(),
+        groups: layout.groups,
+        warnings,
+    })
+}
+
+/// Quiet time after a change before `watch_config` checks the config; editors
+/// often save in several writes, or write a temporary file and rename it
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// A config watched by `watch_config_file`, the watch ends when this is dropped
+pub struct ConfigWatch {
+    watcher: Option<notify::RecommendedWatcher>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for ConfigWatch {
+    fn drop(&mut self) {
+        // The thread ends once the watcher, and with it the sender, is gone
+        self.watcher.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Calls `on_change` with `check_config` of `path` now, and again whenever the file
+/// changed and then stayed unchanged for `debounce`.
+///
+/// The directory of `path` is watched rather than the file, so a save that replaces
+/// the file is seen too.
+pub(crate) fn watch_config_file(
+    path: String,
+    debounce: Duration,
+    mut on_change: impl FnMut(Result<ConfigSummary, ErrorDto>) + Send + 'static,
+) -> Result<ConfigWatch, ErrorDto> {
+    let file_name = Path::new(&path).file_name().map(|n| n.to_os_string());
+    let dir = match config_dir(&path) {
+        dir if dir.as_os_str().is_empty() => PathBuf::from("."),
+        dir => dir,
+    };
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading the config is an access too
+        if let Ok(event) = event
+            && !matches!(event.kind, notify::EventKind::Access(_))
+            && event.paths.iter().any(|p| p.file_name().map(|n| n.to_os_string()) == file_name)
+        {
+            let _ = sender.send(());
+        }
+    })?;
+    watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+    let thread = thread::spawn(move || {
+        on_change(check_config(&path));
+        while receiver.recv().is_ok() {
+            loop {
+                match receiver.recv_timeout(debounce) {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            on_change(check_config(&path));
+        }
+    });
+    Ok(ConfigWatch { watcher: Some(watcher), thread: Some(thread) })
+}
+
+/// The config watch of `watch_config`, if any
+#[derive(Default)]
+pub struct ConfigWatchState {
+    watch: Mutex<Option<ConfigWatch>>,
+}
+
+/// Emits `config-validated` with the `ConfigSummary` of the partition config at
+/// `path` (or the `ErrorDto` of why it does not build) now and after every save,
+/// until `unwatch_config`. Watching another config ends the watch of the previous one.
+#[tauri::command]
+pub fn watch_config(app: AppHandle, path: String, watch_state: State<'_, ConfigWatchState>) -> Result<(), ErrorDto> {
+    let mut watch = watch_state.watch.lock().unwrap();
+    watch.take();
+    *watch = Some(watch_config_file(path, CONFIG_WATCH_DEBOUNCE, move |result| {
+        let _ = app.emit("config-validated", &result);
+    })?);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn unwatch_config(watch_state: State<'_, ConfigWatchState>) {
+    watch_state.watch.lock().unwrap().take();
+}
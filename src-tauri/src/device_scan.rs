@@ -0,0 +1,154 @@
+use log::warn;
+use serde::Serialize;
+use serialport::{SerialPortType, available_ports};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::qdl::usb::{self, QUALCOMM_VID};
+
+/// Emergency download, Sahara then Firehose
+const EDL_PID: u16 = 0x9008;
+/// Sahara memory dump after a crash
+const MEMORY_DUMP_PID: u16 = 0x900e;
+/// Composite diagnostic interfaces OPlus and Qualcomm reference builds enumerate with
+const DIAG_PIDS: [u16; 5] = [0x901d, 0x9025, 0x9091, 0x90b8, 0x90db];
+
+#[derive(Error, Debug)]
+pub enum ScanError {
+    #[error("Cannot list serial ports: {0}")]
+    SerialPort(#[from] serialport::Error),
+    #[error("No permission to open {0}. Add your user to the 'dialout' (or 'uucp') group, or install a udev rule for 05c6:9008, then reconnect the device.")]
+    PermissionDenied(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceMode {
+    /// 9008, ready for a loader
+    Edl,
+    /// 900E, the device crashed and waits for a memory dump
+    MemoryDump,
+    /// Diagnostic port of a booted device
+    Diag,
+}
+
+impl DeviceMode {
+    fn from_pid(pid: u16) -> Option<DeviceMode> {
+        match pid {
+            EDL_PID => Some(DeviceMode::Edl),
+            MEMORY_DUMP_PID => Some(DeviceMode::MemoryDump),
+            pid if DIAG_PIDS.contains(&pid) => Some(DeviceMode::Diag),
+            _ => None,
+        }
+    }
+}
+
+/// A Qualcomm device for the device picker
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScannedDevice {
+    /// Serial port (`COM5`, `/dev/ttyUSB0`), or `usb:<bus>:<address>` for a device
+    /// only reachable through libusb
+    pub port_name: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub mode: DeviceMode,
+    pub serial_number: Option<String>,
+    /// Product string or port description, e.g. `Qualcomm HS-USB QDLoader 9008`
+    pub friendly_name: String,
+}
+
+/// Finds Qualcomm devices in EDL, memory dump and diag mode.
+///
+/// Serial ports come first: on Windows the Qualcomm port driver exposes the device
+/// as a COM port, on Linux `qcserial` as `/dev/ttyUSB*`. EDL devices without a port
+/// (WinUSB or no kernel driver) are then added from libusb, unless already listed.
+/// Without a working libusb only serial ports are listed.
+///
+/// On Linux, when no EDL device can be opened because of missing permissions, the
+/// scan fails with `ScanError::PermissionDenied` and a hint on how to fix it.
+pub fn scan_devices() -> Result<Vec<ScannedDevice>, ScanError> {
+    let mut devices = Vec::new();
+    let mut denied = None;
+    for port in available_ports()? {
+        let SerialPortType::UsbPort(info) = port.port_type else {
+            continue;
+        };
+        let Some(mode) = DeviceMode::from_pid(info.pid).filter(|_| info.vid == QUALCOMM_VID) else {
+            continue;
+        };
+        if mode != DeviceMode::Diag && !can_open(&port.port_name) {
+            denied.get_or_insert(port.port_name);
+            continue;
+        }
+        let serial_number = info.serial_number.or_else(|| info.product.as_deref().and_then(usb::serial_from_product));
+        let friendly_name = info.product.unwrap_or_else(|| format!("Qualcomm {:04X}", info.pid));
+        devices.push(ScannedDevice { port_name: port.port_name, vid: info.vid, pid: info.pid, mode, serial_number, friendly_name });
+    }
+
+    let scan = usb::scan_edl_devices().unwrap_or_else(|e| {
+        warn!("Cannot list USB devices: {}", e);
+        usb::EdlScan::default()
+    });
+    for device in scan.devices {
+        let Some(mode) = DeviceMode::from_pid(device.product_id) else {
+            continue;
+        };
+        let listed = devices.iter().any(|d| d.pid == device.product_id && d.serial_number == device.serial_number);
+        if listed {
+            continue;
+        }
+        devices.push(ScannedDevice {
+            port_name: format!("usb:{}:{}", device.bus, device.address),
+            vid: device.vendor_id,
+            pid: device.product_id,
+            mode,
+            serial_number: device.serial_number,
+            friendly_name: device.product.unwrap_or_else(|| format!("Qualcomm {:04X}", device.product_id)),
+        });
+    }
+    if cfg!(target_os = "linux") && denied.is_none() {
+        denied = scan.skipped.iter().find(|s| s.reason.contains("Access")).map(|s| format!("USB device {}:{}", s.bus, s.address));
+    }
+    match denied {
+        Some(port) if devices.iter().all(|d| d.mode == DeviceMode::Diag) => Err(ScanError::PermissionDenied(port)),
+        _ => Ok(devices),
+    }
+}
+
+/// Whether the port can be opened for reading and writing. Only Linux reports
+/// permission problems this way, other platforms are assumed to be fine.
+fn can_open(port_name: &str) -> bool {
+    if !cfg!(target_os = "linux") {
+        return true;
+    }
+    match serialport::new(port_name, 115200).open() {
+        Err(e) => e.kind() != serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied),
+        Ok(_) => true,
+    }
+}
+
+/// Calls `on_change` with the devices found, first right away, then every
+/// `interval` whenever a device appeared or disappeared, until `stop` is set. Scan
+/// errors are passed on as well, once until the next successful scan.
+pub fn watch_devices(
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    mut on_change: impl FnMut(Result<&[ScannedDevice], &ScanError>) + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last: Option<Result<Vec<ScannedDevice>, String>> = None;
+        while !stop.load(Ordering::SeqCst) {
+            let scan = scan_devices();
+            let current = scan.as_ref().map(|d| d.clone()).map_err(|e| e.to_string());
+            if last.as_ref() != Some(&current) {
+                on_change(scan.as_deref());
+                last = Some(current);
+            }
+            thread::sleep(interval);
+        }
+    })
+}
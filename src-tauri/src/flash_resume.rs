@@ -0,0 +1,154 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::qdl::firehose::{FirehoseError, PROGRAM_SEGMENT_BYTES, firehose_flash_partition};
+use crate::qdl::types::QdlChan;
+use crate::super_image_creater::open_raw_or_sparse;
+
+/// Bytes hashed at each end of the image for its fingerprint
+const FINGERPRINT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Where an image is flashed to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FlashTarget {
+    pub lun: u8,
+    pub start_sector: u64,
+    pub num_sectors: u64,
+    pub sector_size: u64,
+}
+
+/// Progress of an unfinished flash, kept in `<image>.flash-state.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlashState {
+    /// `image_fingerprint` of the image being flashed
+    pub image_hash: String,
+    pub target: FlashTarget,
+    /// Sectors from `target.start_sector` on that the Device acknowledged
+    pub sectors_done: u64,
+}
+
+/// State file of `image`
+pub fn state_path(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".flash-state.json");
+    image.with_file_name(name)
+}
+
+/// SHA-256 of the image size and its first and last 4 MiB, hex encoded.
+///
+/// Hashing a multi-GB image would take longer than many resumed transfers save,
+/// and a rebuilt image differs in its metadata at the start anyway.
+pub fn image_fingerprint(image: &Path) -> io::Result<String> {
+    let mut file = File::open(image)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    let mut buf = Vec::new();
+    (&mut file).take(FINGERPRINT_BYTES).read_to_end(&mut buf)?;
+    if size > FINGERPRINT_BYTES {
+        file.seek(SeekFrom::Start(size.saturating_sub(FINGERPRINT_BYTES).max(FINGERPRINT_BYTES)))?;
+        file.read_to_end(&mut buf)?;
+    }
+    hasher.update(&buf);
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The saved state of flashing `image` to `target`, if an earlier attempt with the
+/// same image and target stopped part way. Unreadable or stale state files count as
+/// no state.
+pub fn resumable_state(image: &Path, target: &FlashTarget) -> io::Result<Option<FlashState>> {
+    let Ok(content) = fs::read_to_string(state_path(image)) else {
+        return Ok(None);
+    };
+    let Ok(state) = serde_json::from_str::<FlashState>(&content) else {
+        return Ok(None);
+    };
+    if state.target != *target || state.sectors_done == 0 || state.sectors_done >= target.num_sectors {
+        return Ok(None);
+    }
+    if state.image_hash != image_fingerprint(image)? {
+        return Ok(None);
+    }
+    Ok(Some(state))
+}
+
+/// Forgets the progress of `image`
+pub fn reset(image: &Path) -> io::Result<()> {
+    match fs::remove_file(state_path(image)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Flashes `image` (raw or sparse) to `target` in segments, saving the acknowledged
+/// progress after each one.
+///
+/// With `resume`, a matching state file (see `resumable_state`) makes the first
+/// `<program>` start at the first unacknowledged sector, with the image read from
+/// the same offset; without one, or without `resume`, the whole image is sent. The
+/// state file is removed once the last segment is acknowledged. `progress` gets the
+/// bytes acknowledged or sent so far and the total, counting resumed bytes as done.
+///
+/// `cancel` is checked before every segment. A cancelled flash fails with
+/// `FirehoseError::Cancelled` once the segment in flight is acknowledged, so the
+/// Device is back in command mode and the state file matches what it wrote: the
+/// flash can be resumed later.
+pub fn flash_resumable<T: QdlChan>(
+    channel: &mut T,
+    image: &Path,
+    target: FlashTarget,
+    resume: bool,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    let image_hash = image_fingerprint(image)?;
+    let mut sectors_done = if resume { resumable_state(image, &target)?.map(|s| s.sectors_done).unwrap_or(0) } else { 0 };
+    if sectors_done == 0 {
+        reset(image)?;
+    }
+    info!(
+        "Flashing {} to LUN {} sector {}, {} of {} sectors already written",
+        image.display(),
+        target.lun,
+        target.start_sector,
+        sectors_done,
+        target.num_sectors
+    );
+    let _span = tracing::debug_span!("flash", image = %image.display()).entered();
+
+    let (mut reader, _) = open_raw_or_sparse(image)?;
+    let sector_size = target.sector_size;
+    let skip = sectors_done * sector_size;
+    if io::copy(&mut (&mut reader).take(skip), &mut io::sink())? < skip {
+        anyhow::bail!("{} is shorter than the saved progress", image.display());
+    }
+
+    let total = target.num_sectors * sector_size;
+    let segment_sectors = (PROGRAM_SEGMENT_BYTES / sector_size).max(1);
+    while sectors_done < target.num_sectors {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(FirehoseError::Cancelled.into());
+        }
+        let sectors = segment_sectors.min(target.num_sectors - sectors_done);
+        let offset = sectors_done * sector_size;
+        firehose_flash_partition(
+            channel,
+            target.lun,
+            target.start_sector + sectors_done,
+            sector_size as usize,
+            &mut reader,
+            sectors,
+            |sent, _| progress(offset + sent, total),
+        )?;
+        sectors_done += sectors;
+        let state = FlashState { image_hash: image_hash.clone(), target, sectors_done };
+        fs::write(state_path(image), serde_json::to_string_pretty(&state)?)?;
+    }
+    reset(image)?;
+    Ok(())
+}
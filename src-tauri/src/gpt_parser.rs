@@ -2,7 +2,9 @@ use std::io::{Read, Seek, SeekFrom, Cursor};
 use byteorder::{LittleEndian, ReadBytesExt};
 use encoding::{Encoding, DecoderTrap};
 use encoding::all::UTF_16LE;
+use serde::Serialize;
 use thiserror::Error;
+use crate::super_image_creater::{BlockDevice, ByteSize};
 
 #[derive(Debug, Error)]
 pub enum GptError {
@@ -16,12 +18,26 @@ pub enum GptError {
     IoError(#[from] std::io::Error),
     #[error("UTF-16 decoding error")]
     Utf16DecodeError,
+    #[error("No GPT has been parsed")]
+    NotParsed,
+    #[error("Invalid protective MBR")]
+    InvalidProtectiveMbr,
+    #[error("Invalid GPT header size {0}")]
+    InvalidHeaderSize(u32),
+    #[error("GPT header CRC mismatch (stored {stored:#010x}, computed {computed:#010x})")]
+    HeaderCrcMismatch { stored: u32, computed: u32 },
+    #[error("Partition entry array CRC mismatch (stored {stored:#010x}, computed {computed:#010x})")]
+    EntryArrayCrcMismatch { stored: u32, computed: u32 },
+    #[error("Reading the GPT from the device failed: {0}")]
+    DeviceRead(String),
 }
 
 pub type Result<T> = std::result::Result<T, GptError>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct PartitionEntry {
+    /// Position in the partition entry array
+    pub index: u32,
     pub name: String,
     pub first_lba: u64,
     pub last_lba: u64,
@@ -37,9 +53,26 @@ impl PartitionEntry {
     }
 }
 
+/// Fields of the GPT header describing the partition entry array
+#[derive(Debug, Clone, PartialEq)]
+pub struct GptHeader {
+    pub header_size: u32,
+    pub part_entry_start_lba: u64,
+    pub num_part_entries: u32,
+    pub part_entry_size: u32,
+}
+
+impl GptHeader {
+    /// Size of the whole partition entry array in bytes
+    pub fn entry_array_size(&self) -> u64 {
+        self.num_part_entries as u64 * self.part_entry_size as u64
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GptParser {
     partitions: Vec<PartitionEntry>,
+    header: Option<GptHeader>,
     sector_size: u32,
 }
 
@@ -47,6 +80,7 @@ impl GptParser {
     pub fn new() -> Self {
         Self {
             partitions: Vec::new(),
+            header: None,
             sector_size: 512,
         }
     }
@@ -54,9 +88,19 @@ impl GptParser {
     pub fn partitions(&self) -> &[PartitionEntry] {
         &self.partitions
     }
+
+    /// Header of the last parsed GPT
+    pub fn header(&self) -> Option<&GptHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
     
     pub fn parse(&mut self, data: &[u8], sector_size: u32) -> Result<()> {
         self.partitions.clear();
+        self.header = None;
         self.sector_size = sector_size;
         
         // Check data length
@@ -78,6 +122,10 @@ impl GptParser {
         }
         
         // 3. Read key information from GPT Header
+        // Seek to offset 12 (0xC): Header Size
+        cursor.seek(SeekFrom::Start(sector_size as u64 + 12))?;
+        let header_size = cursor.read_u32::<LittleEndian>()?;
+
         // Seek to offset 72 (0x48): Partition Entry Start LBA
         cursor.seek(SeekFrom::Start(sector_size as u64 + 72))?;
         
@@ -86,6 +134,7 @@ impl GptParser {
         let part_entry_size = cursor.read_u32::<LittleEndian>()?;
         
         println!("{},{}", part_entry_start_lba, part_entry_size);
+        self.header = Some(GptHeader { header_size, part_entry_start_lba, num_part_entries, part_entry_size });
         
         
         // 4. Calculate actual offset of partition table entries
@@ -97,7 +146,7 @@ impl GptParser {
         cursor.seek(SeekFrom::Start(entry_offset))?;
         
         // 5. Iterate through all partition table entries
-        for _i in 0..num_part_entries {
+        for i in 0..num_part_entries {
             // Check if there is enough data
             let entry_start_pos = cursor.position();
             if entry_start_pos + part_entry_size as u64 > data.len() as u64 {
@@ -128,6 +177,7 @@ impl GptParser {
                 let name = Self::decode_utf16le(&name_bytes)?;
                 
                 self.partitions.push(PartitionEntry {
+                    index: i,
                     name,
                     first_lba,
                     last_lba,
@@ -207,4 +257,170 @@ impl std::fmt::Display for PartitionEntry {
             self.name, self.first_lba, self.last_lba, 
             self.last_lba - self.first_lba + 1)
     }
-}
\ No newline at end of file
+}
+/// LP block size and alignment written into a `BlockDevice` found in the GPT, the
+/// defaults lpmake uses
+const SUPER_BLOCK_SIZE: u64 = 4096;
+const SUPER_ALIGNMENT: u64 = 1024 * 1024;
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// One used entry of the GPT partition entry array
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GptPartition {
+    /// Position in the partition entry array
+    pub index: u32,
+    pub name: String,
+    /// GUIDs in their usual text form, e.g. `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`
+    pub type_guid: String,
+    pub unique_guid: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+}
+
+impl GptPartition {
+    pub fn size_in_sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+}
+
+/// A validated primary GPT of one LUN
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GptTable {
+    pub sector_size: u32,
+    pub disk_guid: String,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// LBA of the backup header, i.e. the last sector of the LUN
+    pub backup_lba: u64,
+    pub partitions: Vec<GptPartition>,
+}
+
+impl GptTable {
+    /// Partition by name, ignoring case like `GptParser::find_partition_by_name`
+    pub fn find(&self, name: &str) -> Option<&GptPartition> {
+        self.partitions.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// `BlockDevice` entry for the `super` partition of this table, with the LP
+    /// default block size and alignment, for use in a `PartitionConfig`
+    pub fn super_block_device(&self) -> Option<BlockDevice> {
+        let partition = self.find("super")?;
+        Some(BlockDevice {
+            block_size: ByteSize::new(SUPER_BLOCK_SIZE),
+            name: "super".to_string(),
+            alignment: ByteSize::new(SUPER_ALIGNMENT),
+            size: ByteSize::new(partition.size_in_sectors() * self.sector_size as u64),
+        })
+    }
+}
+
+/// Parses and validates a primary GPT, e.g. a `gpt_main0.bin` dumped in EDL mode:
+/// the protective MBR in LBA 0, the header in LBA 1 with its CRC, and the
+/// partition entry array with its CRC. Unused entries are skipped.
+pub fn parse_gpt(data: &[u8], sector_size: u32) -> Result<GptTable> {
+    let sector = sector_size as usize;
+    if data.len() < sector * 2 {
+        return Err(GptError::InsufficientData);
+    }
+
+    // Protective MBR: boot signature and a partition of type 0xEE
+    if data[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != [0x55, 0xAA]
+        || !(0..4).any(|i| data[MBR_PARTITION_TABLE_OFFSET + i * 16 + 4] == MBR_PROTECTIVE_TYPE)
+    {
+        return Err(GptError::InvalidProtectiveMbr);
+    }
+
+    let header = &data[sector..sector * 2];
+    if &header[0..8] != b"EFI PART" {
+        return Err(GptError::InvalidSignature);
+    }
+    let header_size = read_u32(header, 12);
+    if header_size < 92 || header_size as usize > sector {
+        return Err(GptError::InvalidHeaderSize(header_size));
+    }
+    let mut zeroed = header[..header_size as usize].to_vec();
+    zeroed[16..20].fill(0);
+    let (stored, computed) = (read_u32(header, 16), crc32(&zeroed));
+    if stored != computed {
+        return Err(GptError::HeaderCrcMismatch { stored, computed });
+    }
+
+    let entry_lba = read_u64(header, 72);
+    let num_entries = read_u32(header, 80) as usize;
+    let entry_size = read_u32(header, 84) as usize;
+    if entry_size < 128 {
+        return Err(GptError::EntryOutOfBounds);
+    }
+    let array_start = entry_lba as usize * sector;
+    let array_end = array_start + num_entries * entry_size;
+    if array_end > data.len() {
+        return Err(GptError::EntryOutOfBounds);
+    }
+    let array = &data[array_start..array_end];
+    let (stored, computed) = (read_u32(header, 88), crc32(array));
+    if stored != computed {
+        return Err(GptError::EntryArrayCrcMismatch { stored, computed });
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in array.chunks_exact(entry_size).enumerate() {
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        partitions.push(GptPartition {
+            index: index as u32,
+            name: GptParser::decode_utf16le(&entry[56..128])?,
+            type_guid: format_guid(&entry[0..16]),
+            unique_guid: format_guid(&entry[16..32]),
+            first_lba: read_u64(entry, 32),
+            last_lba: read_u64(entry, 40),
+            attributes: read_u64(entry, 48),
+        });
+    }
+
+    Ok(GptTable {
+        sector_size,
+        disk_guid: format_guid(&header[56..72]),
+        first_usable_lba: read_u64(header, 40),
+        last_usable_lba: read_u64(header, 48),
+        backup_lba: read_u64(header, 32),
+        partitions,
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUIDs store their first three groups little endian
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        read_u32(bytes, 0),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10..16].iter().map(|b| format!("{:02X}", b)).collect::<String>()
+    )
+}
+
+/// CRC-32 (IEEE) as used by GPT
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,174 @@
+use crate::gpt_parser::{GptError, GptParser, Result};
+use crate::xml_file_util::{self, Patch};
+
+/// fh_loader applies patches with this filename to the device instead of a file
+const DISK: &str = "DISK";
+
+// Offsets of the patched GPT header fields
+const HEADER_CRC_OFFSET: u64 = 16;
+const CURRENT_LBA_OFFSET: u64 = 24;
+const BACKUP_LBA_OFFSET: u64 = 32;
+const LAST_USABLE_LBA_OFFSET: u64 = 48;
+const ENTRY_ARRAY_LBA_OFFSET: u64 = 72;
+const ENTRY_ARRAY_CRC_OFFSET: u64 = 88;
+// Offset of the last LBA in a partition entry
+const ENTRY_LAST_LBA_OFFSET: u64 = 40;
+
+/// Sector counted back from the end of the disk, in the `NUM_DISK_SECTORS-N.` form
+/// fh_loader evaluates (the trailing `.` marks a decimal number)
+fn from_disk_end(sectors: u64) -> String {
+    format!("NUM_DISK_SECTORS-{}.", sectors)
+}
+
+struct PatchList {
+    lun: u8,
+    sector_size: u64,
+    patches: Vec<Patch>,
+}
+
+impl PatchList {
+    fn add(&mut self, filename: &str, start_sector: &str, byte_offset: u64, size_in_bytes: u64, value: &str, what: &str) {
+        self.patches.push(Patch {
+            sector_size_in_bytes: self.sector_size,
+            byte_offset,
+            filename: filename.to_string(),
+            physical_partition_number: self.lun,
+            size_in_bytes,
+            start_sector: start_sector.to_string(),
+            value: value.to_string(),
+            what: what.to_string(),
+        });
+    }
+}
+
+/// Creates the patch entries that fix up the GPT of `lun` after it is flashed, as
+/// ptool writes them into `patch<lun>.xml`.
+///
+/// `gpt` is the parsed primary GPT (`gpt_main<lun>.bin`). The backup GPT is expected
+/// in `gpt_backup<lun>.bin`: the partition entry array followed by the backup header.
+/// Every fix is written twice, once for the file and once for the disk (`DISK`),
+/// since only the device knows its sector count:
+/// - the last usable LBA in both headers,
+/// - the backup header location, its current LBA and its entry array location,
+/// - the CRC32 of the entry arrays, then the header CRCs (zeroed first).
+///
+/// With `grow_last_partition`, the partition ending last is extended to the last
+/// usable LBA, like `userdata` in stock firmware.
+pub fn create_gpt_patches(lun: u8, gpt: &GptParser, grow_last_partition: bool) -> Result<Vec<Patch>> {
+    let header = gpt.header().ok_or(GptError::NotParsed)?;
+    let sector_size = gpt.sector_size() as u64;
+    let array_size = header.entry_array_size();
+    let array_sectors = array_size.div_ceil(sector_size);
+
+    let main_file = format!("gpt_main{}.bin", lun);
+    let backup_file = format!("gpt_backup{}.bin", lun);
+    let primary_header = "1";
+    let primary_array = header.part_entry_start_lba.to_string();
+    // The backup header sits in the last sector, right after the backup entry array
+    let backup_header_in_file = array_sectors.to_string();
+    let backup_header = from_disk_end(1);
+    let backup_array = from_disk_end(array_sectors + 1);
+    let last_usable_lba = from_disk_end(array_sectors + 2);
+
+    let mut list = PatchList { lun, sector_size, patches: Vec::new() };
+
+    if grow_last_partition && let Some(last) = gpt.partitions().iter().max_by_key(|p| p.last_lba) {
+        let offset = last.index as u64 * header.part_entry_size as u64 + ENTRY_LAST_LBA_OFFSET;
+        let (sector, byte_offset) = (offset / sector_size, offset % sector_size);
+        let what = format!("Update last partition {} '{}' with actual size in Primary Header.", last.index, last.name);
+        let primary_sector = (header.part_entry_start_lba + sector).to_string();
+        list.add(&main_file, &primary_sector, byte_offset, 8, &last_usable_lba, &what);
+        list.add(DISK, &primary_sector, byte_offset, 8, &last_usable_lba, &what);
+        let what = format!("Update last partition {} '{}' with actual size in Backup Header.", last.index, last.name);
+        list.add(&backup_file, &sector.to_string(), byte_offset, 8, &last_usable_lba, &what);
+        list.add(DISK, &from_disk_end(array_sectors + 1 - sector), byte_offset, 8, &last_usable_lba, &what);
+    }
+
+    for file in [main_file.as_str(), DISK] {
+        list.add(file, primary_header, LAST_USABLE_LBA_OFFSET, 8, &last_usable_lba, "Update Primary Header with LastUseableLBA.");
+    }
+    for (file, sector) in [(backup_file.as_str(), &backup_header_in_file), (DISK, &backup_header)] {
+        list.add(file, sector, LAST_USABLE_LBA_OFFSET, 8, &last_usable_lba, "Update Backup Header with LastUseableLBA.");
+    }
+    for file in [main_file.as_str(), DISK] {
+        list.add(file, primary_header, BACKUP_LBA_OFFSET, 8, &backup_header, "Update Primary Header with BackupGPT Header Location.");
+    }
+    for (file, sector) in [(backup_file.as_str(), &backup_header_in_file), (DISK, &backup_header)] {
+        list.add(file, sector, CURRENT_LBA_OFFSET, 8, &backup_header, "Update Backup Header with CurrentLBA.");
+        list.add(file, sector, ENTRY_ARRAY_LBA_OFFSET, 8, &backup_array, "Update Backup Header with Partition Array Location.");
+    }
+
+    // CRCs last, over the patched data; CRC32(sector,len) is relative to the file or disk
+    let primary_array_crc = format!("CRC32({},{})", primary_array, array_size);
+    let primary_header_crc = format!("CRC32({},{})", primary_header, header.header_size);
+    for file in [main_file.as_str(), DISK] {
+        list.add(file, primary_header, ENTRY_ARRAY_CRC_OFFSET, 4, &primary_array_crc, "Update Primary Header with CRC of Partition Array.");
+        list.add(file, primary_header, HEADER_CRC_OFFSET, 4, "0", "Zero Out Header CRC in Primary Header.");
+        list.add(file, primary_header, HEADER_CRC_OFFSET, 4, &primary_header_crc, "Update Primary Header with CRC of Primary Header.");
+    }
+    for (file, sector, array) in [(backup_file.as_str(), &backup_header_in_file, "0"), (DISK, &backup_header, backup_array.as_str())] {
+        let array_crc = format!("CRC32({},{})", array, array_size);
+        let header_crc = format!("CRC32({},{})", sector, header.header_size);
+        list.add(file, sector, ENTRY_ARRAY_CRC_OFFSET, 4, &array_crc, "Update Backup Header with CRC of Partition Array.");
+        list.add(file, sector, HEADER_CRC_OFFSET, 4, "0", "Zero Out Header CRC in Backup Header.");
+        list.add(file, sector, HEADER_CRC_OFFSET, 4, &header_crc, "Update Backup Header with CRC of Backup Header.");
+    }
+    Ok(list.patches)
+}
+
+/// Formats patches as a `patch*.xml` file
+pub fn to_patch_xml(patches: &[Patch]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" ?>\n<patches>\n");
+    for patch in patches {
+        xml.push_str(&xml_file_util::to_xml(patch));
+        xml.push('\n');
+    }
+    xml.push_str("</patches>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata").join(name)
+    }
+
+    /// `testdata/gpt_main0.bin`: LUN 0 of a 256 GB UFS device, 4096 byte sectors
+    fn lun0_gpt() -> GptParser {
+        let mut gpt = GptParser::new();
+        gpt.parse_file(fixture("gpt_main0.bin"), 4096).unwrap();
+        gpt
+    }
+
+    #[test]
+    fn patches_match_the_ptool_patch0_xml() {
+        let xml = to_patch_xml(&create_gpt_patches(0, &lun0_gpt(), true).unwrap());
+        let expected = std::fs::read_to_string(fixture("patch0.xml")).unwrap();
+        let patch_lines = |xml: &str| xml.lines().map(str::trim).filter(|line| line.starts_with("<patch ")).map(String::from).collect::<Vec<_>>();
+        assert_eq!(patch_lines(&xml), patch_lines(&expected));
+        assert!(xml.starts_with("<?xml version=\"1.0\" ?>\n<patches>\n") && xml.ends_with("</patches>\n"));
+    }
+
+    #[test]
+    fn crcs_follow_the_patches_they_cover() {
+        let patches = create_gpt_patches(3, &lun0_gpt(), false).unwrap();
+        assert_eq!(patches.len(), 22);
+        assert!(patches.iter().all(|p| p.physical_partition_number == 3 && p.sector_size_in_bytes == 4096));
+        assert!(!patches.iter().any(|p| p.what.contains("last partition")));
+        assert!(patches.iter().any(|p| p.filename == "gpt_main3.bin") && patches.iter().any(|p| p.filename == "gpt_backup3.bin"));
+
+        // Header fields, then the array CRC, the zeroed header CRC and the header CRC
+        for (file, header) in [("gpt_main3.bin", "Primary"), (DISK, "Primary"), ("gpt_backup3.bin", "Backup"), (DISK, "Backup")] {
+            let position = |what: String| patches.iter().position(|p| p.filename == file && p.what == what).unwrap();
+            let array_crc = position(format!("Update {} Header with CRC of Partition Array.", header));
+            let zero = position(format!("Zero Out Header CRC in {} Header.", header));
+            let crc = position(format!("Update {0} Header with CRC of {0} Header.", header));
+            assert_eq!((zero, crc), (array_crc + 1, array_crc + 2), "{} {}", file, header);
+            let last_field = patches.iter().rposition(|p| p.filename == file && p.what.starts_with(&format!("Update {} Header with", header)) && !p.what.contains("CRC")).unwrap();
+            assert!(last_field < array_crc, "{} {}", file, header);
+        }
+    }
+}
@@ -1,19 +1,28 @@
 ﻿mod command_worker;
+mod commands;
+mod device_scan;
 mod file_util;
+mod flash_resume;
 mod gpt_parser;
+mod gpt_patch;
+pub mod logger;
+mod ofp;
+mod payload;
 mod qdl;
 mod xml_file_util;
-mod super_image_creater;
+pub mod super_image_creater;
 
 use command_worker::CommandItem;
+use serde::Serialize;
 use serialport::{available_ports, SerialPortType};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
 use std::thread;
-use std::time::Duration;
-use tauri::{AppHandle, Emitter, Error, command, State};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Error, Manager, command, State};
 use tokio::process::Command;
 use crate::xml_file_util::DataRoot;
 
@@ -53,6 +62,42 @@ impl Default for ThreadState {
     }
 }
 
+/// Cancellation flag of the running `create_super_image` build, also stopping
+/// `stream_super_image` and `flash_super`
+#[derive(Default)]
+struct SuperBuildState {
+    cancel: Arc<AtomicBool>,
+}
+
+/// Background builds of `start_super_build`, by job id
+#[derive(Default)]
+struct SuperJobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, SuperJob>>,
+}
+
+struct SuperJob {
+    output: PathBuf,
+    cancel: Arc<AtomicBool>,
+    /// `None` while `wait_super_build` has it
+    job: Option<super_image_creater::BuildJob>,
+}
+
+/// Answer of `super_build_status`
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SuperJobStatus {
+    Running,
+    /// `wait_super_build` returns at once
+    Finished,
+}
+
+/// Stop flag of the running device watcher, if any
+#[derive(Default)]
+struct DeviceWatchState {
+    stop: Mutex<Option<Arc<AtomicBool>>>,
+}
+
 fn setup_env(app: &AppHandle) -> Config {
     let mut config = Config {
         fh_loader_path: String::new(),
@@ -226,6 +271,44 @@ fn update_port() -> (String, String) {
     }
 }
 
+/// Lists the devices in EDL mode found on USB, and those that could not be opened
+#[tauri::command]
+fn scan_edl_devices() -> Result<qdl::usb::EdlScan, String> {
+    qdl::usb::scan_edl_devices().map_err(|e| e.to_string())
+}
+
+/// Lists devices in EDL (9008), memory dump (900E) and diag mode for the device
+/// picker, from serial ports and USB
+#[tauri::command]
+fn list_edl_devices() -> Result<Vec<device_scan::ScannedDevice>, String> {
+    device_scan::scan_devices().map_err(|e| e.to_string())
+}
+
+/// Emits `edl-devices-changed` with the device list (or the scan error as a string)
+/// now and whenever a device appears or disappears, until `stop_watching_edl_devices`
+#[tauri::command]
+fn watch_edl_devices(app: AppHandle, watch_state: State<'_, DeviceWatchState>) {
+    let mut stop_flag = watch_state.stop.lock().unwrap();
+    if let Some(previous) = stop_flag.take() {
+        previous.store(true, Ordering::SeqCst);
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    *stop_flag = Some(stop.clone());
+    device_scan::watch_devices(Duration::from_secs(1), stop, move |scan| {
+        let _ = match scan {
+            Ok(devices) => app.emit("edl-devices-changed", Ok::<_, String>(devices)),
+            Err(e) => app.emit("edl-devices-changed", Err::<&[device_scan::ScannedDevice], _>(e.to_string())),
+        };
+    });
+}
+
+#[tauri::command]
+fn stop_watching_edl_devices(watch_state: State<'_, DeviceWatchState>) {
+    if let Some(stop) = watch_state.stop.lock().unwrap().take() {
+        stop.store(true, Ordering::SeqCst);
+    }
+}
+
 #[tauri::command]
 fn reboot_to_system(app: AppHandle) {
     let config = setup_env(&app);
@@ -640,6 +723,20 @@ fn init(app: AppHandle) {
     });
 }
 
+/// Builds the sparse `IMAGES/super.img` of a firmware package from its `super_def`
+/// config, whose image paths are relative to the package root (the directory above
+/// the config's)
+fn build_package_super_image(super_define: &str) -> Result<super_image_creater::BuildReport, String> {
+    let mut config = super_image_creater::read_partition_config(super_define).map_err(|e| e.to_string())?;
+    let package_dir = config_dir(super_define).parent().map(Path::to_path_buf).unwrap_or_default();
+    config.resolve_paths(&package_dir);
+    super_image_creater::SuperImageBuilder::new(config)
+        .output(package_dir.join("IMAGES").join("super.img"))
+        .format(super_image_creater::OutputFormat::Sparse { max_blob_size: None })
+        .build_with_cancel(&AtomicBool::new(false), |_| {})
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command] 
 fn start_flashing(app: AppHandle, path: String, is_protect_lun5: bool, thread_state: State<Arc<Mutex<ThreadState>>>,) -> Result<(), String> {
     // lock thread state
@@ -665,9 +762,9 @@ fn start_flashing(app: AppHandle, path: String, is_protect_lun5: bool, thread_st
                     let _ = app_clone.emit("log_event", &format!("Check necessary files...OK"));
                     let _ = app_clone.emit("update_percentage", 10);
                     let _ = app_clone.emit("log_event", &format!("Merging Super image..."));
-                    if super_image_creater::creat_super_image(&package.super_define) == false {
+                    if let Err(e) = build_package_super_image(&package.super_define) {
                         let _ = app_clone.emit("stop_edl_flashing", "");
-                        let _ = app_clone.emit("log_event", "Failed to create Super image.");
+                        let _ = app_clone.emit("log_event", &format!("Failed to create Super image: {}", e));
                         let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
                         state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
                         return;
@@ -739,16 +836,823 @@ fn stop_flashing(app: AppHandle, thread_state: State<Arc<Mutex<ThreadState>>>,)
     Ok(())
 }
 
+/// Directory of a config file, which relative image paths in it are resolved against
+fn config_dir(path: &str) -> PathBuf {
+    Path::new(path).parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// Sizes the output of a `create_super_image` build with the same arguments and
+/// checks the free space of the output directory, so the UI can warn before the
+/// build starts
+#[tauri::command]
+async fn estimate_super_size(
+    path: String,
+    output: String,
+    sparse: bool,
+    zstd_level: Option<i32>,
+    metadata_slots: Option<u32>,
+    variant: Option<String>,
+    nv_mapping: Option<String>,
+) -> Result<super_image_creater::SizeEstimate, String> {
+    let builder = commands::super_builder(&path, &output, sparse, zstd_level, None, metadata_slots, variant.as_deref(), nv_mapping.as_deref()).await?;
+    tokio::task::spawn_blocking(move || builder.estimate())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Starts a `create_super_image` build in the background and returns its job id
+/// right away. Builds to different outputs run side by side, each with its own
+/// job; a second build to the output of a running job is refused.
+///
+/// Progress is emitted as `super-job-progress` with `(job_id, progress)` (at most ~10
+/// per second per job). The result is fetched with `wait_super_build`, and
+/// `super_build_status` tells whether it is ready.
+#[tauri::command]
+async fn start_super_build(
+    app: AppHandle,
+    path: String,
+    output: String,
+    sparse: bool,
+    zstd_level: Option<i32>,
+    chunk_size: Option<u64>,
+    metadata_slots: Option<u32>,
+    variant: Option<String>,
+    nv_mapping: Option<String>,
+    ignore_free_space: Option<bool>,
+    jobs: State<'_, SuperJobs>,
+) -> Result<u64, String> {
+    let builder = commands::super_builder(&path, &output, sparse, zstd_level, chunk_size, metadata_slots, variant.as_deref(), nv_mapping.as_deref())
+        .await?
+        .check_free_space(!ignore_free_space.unwrap_or(false));
+    let output = PathBuf::from(output);
+    let mut running = jobs.jobs.lock().map_err(|e| e.to_string())?;
+    if running.values().any(|job| job.output == output) {
+        return Err(format!("A build to {} is already running", output.display()));
+    }
+    let job_id = jobs.next_id.fetch_add(1, Ordering::SeqCst);
+    let progress_app = app.clone();
+    let mut last_emit: Option<Instant> = None;
+    let job = builder.spawn(move |progress| {
+        if last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100)) {
+            last_emit = Some(Instant::now());
+            let _ = progress_app.emit("super-job-progress", (job_id, &progress));
+        }
+    });
+    let _ = app.emit("log_event", &format!("Creating Super image {} (job {})...", output.display(), job_id));
+    running.insert(job_id, SuperJob { output, cancel: job.cancel_flag(), job: Some(job) });
+    Ok(job_id)
+}
+
+/// Whether the build of a `start_super_build` job is still running
+#[tauri::command]
+fn super_build_status(job_id: u64, jobs: State<'_, SuperJobs>) -> Result<SuperJobStatus, String> {
+    let running = jobs.jobs.lock().map_err(|e| e.to_string())?;
+    match running.get(&job_id) {
+        Some(SuperJob { job: Some(job), .. }) if job.is_finished() => Ok(SuperJobStatus::Finished),
+        Some(_) => Ok(SuperJobStatus::Running),
+        None => Err(format!("No build job {}", job_id)),
+    }
+}
+
+/// Waits for a `start_super_build` job and returns its build report. The job is
+/// forgotten afterwards, so each result can be fetched once.
+#[tauri::command]
+async fn wait_super_build(app: AppHandle, job_id: u64, jobs: State<'_, SuperJobs>) -> Result<super_image_creater::BuildReport, String> {
+    let job = {
+        let mut running = jobs.jobs.lock().map_err(|e| e.to_string())?;
+        let entry = running.get_mut(&job_id).ok_or_else(|| format!("No build job {}", job_id))?;
+        entry.job.take().ok_or_else(|| format!("Build job {} is already awaited", job_id))?
+    };
+    let result = job.wait().await.map_err(|e| e.to_string());
+    if let Ok(mut running) = jobs.jobs.lock() {
+        running.remove(&job_id);
+    }
+    match &result {
+        Ok(report) => { let _ = app.emit("log_event", &format!("Create Super image...OK ({} bytes, job {})", report.image_size, job_id)); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to create Super image (job {}): {}", job_id, e)); }
+    }
+    result
+}
+
+/// Cancels a `start_super_build` job; `wait_super_build` then fails with the
+/// cancellation. A job nobody waits for is forgotten right away.
+#[tauri::command]
+fn cancel_super_job(app: AppHandle, job_id: u64, jobs: State<'_, SuperJobs>) -> Result<(), String> {
+    let mut running = jobs.jobs.lock().map_err(|e| e.to_string())?;
+    let entry = running.get(&job_id).ok_or_else(|| format!("No build job {}", job_id))?;
+    entry.cancel.store(true, Ordering::SeqCst);
+    if entry.job.is_some() {
+        // Dropping the handle cancels the build as well
+        running.remove(&job_id);
+    }
+    let _ = app.emit("log_event", &format!("Cancelling Super image job {}", job_id));
+    Ok(())
+}
+
+/// Layout of an existing raw super image from its LP metadata: the extents of every
+/// partition as byte offsets, groups, attributes and geometry
+#[tauri::command]
+fn inspect_super_layout(path: String) -> Result<super_image_creater::SuperLayout, String> {
+    super_image_creater::inspect_super(&path).map_err(|e| e.to_string())
+}
+
+/// Address map of a planned super image, every byte from 0 to the device size
+/// as a metadata, partition or free segment
+#[tauri::command]
+async fn super_address_map(path: String, variant: Option<String>, metadata_slots: Option<u32>) -> Result<Vec<super_image_creater::Segment>, String> {
+    Ok(commands::plan_super_image(path, variant, metadata_slots).await?.address_map())
+}
+
+/// Reads the firmware's NV mapping file, errors as strings for the commands
+fn read_nv_mapping(path: &str) -> Result<super_image_creater::NvMapping, String> {
+    super_image_creater::read_nv_mapping(path).map_err(|e| e.to_string())
+}
+
+/// NV IDs (with their region descriptions) of a firmware's NV mapping file, for
+/// the nv_id dropdown
+#[tauri::command]
+fn list_nv_ids(path: String) -> Result<Vec<super_image_creater::NvChoice>, String> {
+    Ok(read_nv_mapping(&path)?.choices())
+}
+
+/// Names of the storage variants a config defines, for the variant dropdown
+#[tauri::command]
+fn list_super_variants(path: String) -> Result<Vec<String>, super_image_creater::JsonParseError> {
+    Ok(super_image_creater::read_partition_config(&path)?.variant_names())
+}
+
+/// Differences from the config at `old_path` to the one at `new_path`, for the
+/// side-by-side view when porting a config to another firmware
+#[tauri::command]
+fn diff_super_configs(old_path: String, new_path: String) -> Result<super_image_creater::ConfigDiff, String> {
+    let old = super_image_creater::read_partition_config(&old_path).map_err(|e| e.to_string())?;
+    let new = super_image_creater::read_partition_config(&new_path).map_err(|e| e.to_string())?;
+    Ok(super_image_creater::diff_configs(&old, &new))
+}
+
+/// Checks the partition images referenced by a config before building it
+#[tauri::command]
+fn check_super_inputs(path: String) -> Result<Vec<super_image_creater::InputIssue>, String> {
+    let mut config = super_image_creater::read_partition_config(&path).map_err(|e| e.to_string())?;
+    config.resolve_paths(&config_dir(&path));
+    Ok(super_image_creater::check_inputs(&config))
+}
+
+/// Same as `check_super_inputs`, also listing every image with its size and
+/// detected filesystem
+#[tauri::command]
+fn inspect_super_inputs(path: String) -> Result<super_image_creater::InputReport, String> {
+    let mut config = super_image_creater::read_partition_config(&path).map_err(|e| e.to_string())?;
+    config.resolve_paths(&config_dir(&path));
+    Ok(super_image_creater::inspect_inputs(&config))
+}
+
+/// Filesystem of a partition image (raw or sparse), for the partition list
+#[tauri::command]
+async fn detect_image_fs(path: String) -> Result<super_image_creater::FsType, String> {
+    tokio::task::spawn_blocking(move || super_image_creater::detect_image_fs(Path::new(&path)))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Checks the images of a config against their `expected_sha256`, returning the
+/// partitions that do not match. `parallelism` is how many images are hashed at
+/// once (1-2 for hard disks, up to 8 for NVMe), `DEFAULT_HASH_PARALLELISM` if unset.
+#[tauri::command]
+async fn verify_super_hashes(path: String, parallelism: Option<usize>) -> Result<Vec<super_image_creater::HashMismatch>, String> {
+    let mut config = super_image_creater::read_partition_config(&path).map_err(|e| e.to_string())?;
+    config.resolve_paths(&config_dir(&path));
+    let parallelism = parallelism.unwrap_or(super_image_creater::DEFAULT_HASH_PARALLELISM);
+    tokio::task::spawn_blocking(move || config.verify_hashes_parallel(parallelism).err().unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Checks a built super image against the config it was built from before it is
+/// flashed, see `verify_super`. With `data` the partition data is compared with the
+/// source images too, which reads the whole image.
+#[tauri::command]
+async fn verify_super_image(app: AppHandle, path: String, image: String, variant: Option<String>, data: bool) -> Result<(), String> {
+    let config = super_image_creater::read_partition_config_async(&path).await.map_err(|e| e.to_string())?;
+    let mut config = commands::select_variant(config, variant.as_deref())?;
+    config.resolve_paths(&config_dir(&path));
+    let _ = app.emit("log_event", &format!("Verifying {}...", image));
+    let result = tokio::task::spawn_blocking(move || match data {
+        true => super_image_creater::verify_super_data(&image, &config),
+        false => super_image_creater::verify_super(&image, &config),
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string());
+    match &result {
+        Ok(()) => {
+            let _ = app.emit("log_event", "Verify Super image...OK");
+        }
+        Err(e) => {
+            let _ = app.emit("log_event", &format!("Super image verification failed: {}", e));
+        }
+    }
+    result
+}
+
+/// Blank partition config for the "new super image" form, see `PartitionConfig::new`
+#[tauri::command]
+fn new_super_config(super_path: String, super_size: u64) -> super_image_creater::PartitionConfig {
+    super_image_creater::PartitionConfig::new(&super_path, super_size)
+}
+
+/// Saves a partition config, e.g. one built in the UI from `new_super_config`
+#[tauri::command]
+fn save_super_config(path: String, config: super_image_creater::PartitionConfig) -> Result<(), String> {
+    super_image_creater::write_partition_config(&path, &config).map_err(|e| e.to_string())
+}
+
+/// Reads the LP metadata of a dumped super partition (or its metadata area) into a
+/// config template, with image paths left for the user to fill in
+#[tauri::command]
+fn import_super_layout(app: AppHandle, path: String) -> Result<super_image_creater::PartitionConfig, String> {
+    let config = super_image_creater::config_from_super_dump(Path::new(&path)).map_err(|e| e.to_string())?;
+    let _ = app.emit("log_event", "Warning: nv_text and nv_id are not stored in super metadata, fill them in before saving");
+    Ok(config)
+}
+
+/// Measures the `"auto"` partition sizes of a config, so the UI can show what a
+/// build would allocate
+#[tauri::command]
+fn resolve_super_sizes(path: String) -> Result<Vec<super_image_creater::ResolvedSize>, String> {
+    let mut config = super_image_creater::read_partition_config(&path).map_err(|e| e.to_string())?;
+    config.resolve_paths(&config_dir(&path));
+    super_image_creater::resolve_auto_sizes(&mut config).map_err(|e| e.to_string())
+}
+
+/// Reads the partition table of a dumped `gpt_main<lun>.bin` (4096 byte sectors
+/// unless given), along with the `super` block device entry for a config if the
+/// LUN has one
+#[tauri::command]
+fn read_gpt_table(path: String, sector_size: Option<u32>) -> Result<(gpt_parser::GptTable, Option<super_image_creater::BlockDevice>), String> {
+    let data = fs::read(&path).map_err(|e| e.to_string())?;
+    let table = gpt_parser::parse_gpt(&data, sector_size.unwrap_or(4096)).map_err(|e| e.to_string())?;
+    let block_device = table.super_block_device();
+    Ok((table, block_device))
+}
+
+/// Writes the GPT fixup patches for a dumped `gpt_main<lun>.bin` to `output`, e.g.
+/// after changing partition sizes
+#[tauri::command]
+fn create_gpt_patch_xml(lun: u8, gpt_path: String, output: String, grow_last_partition: bool) -> Result<(), String> {
+    let mut parser = gpt_parser::GptParser::new();
+    parser.parse_file(&gpt_path, 4096).map_err(|e| e.to_string())?;
+    let patches = gpt_patch::create_gpt_patches(lun, &parser, grow_last_partition).map_err(|e| e.to_string())?;
+    fs::write(&output, gpt_patch::to_patch_xml(&patches)).map_err(|e| e.to_string())
+}
+
+/// Sectors of the `super` partition in a GPT dumped from `lun` that `image` gets
+/// flashed to, after checking that it fits
+fn super_flash_target(image: &str, gpt_path: &str, lun: u8) -> anyhow::Result<flash_resume::FlashTarget> {
+    let sector_size = 4096u64;
+    let table = gpt_parser::parse_gpt(&fs::read(gpt_path)?, sector_size as u32)?;
+    let partition = table.find("super").ok_or_else(|| anyhow::anyhow!("No super partition in {}", gpt_path))?;
+    let (_, image_size) = super_image_creater::open_raw_or_sparse(Path::new(image))?;
+    let partition_size = partition.size_in_sectors() * sector_size;
+    if image_size > partition_size {
+        anyhow::bail!("Image is {} bytes, the super partition only {}", image_size, partition_size);
+    }
+    Ok(flash_resume::FlashTarget {
+        lun,
+        start_sector: partition.first_lba,
+        num_sectors: image_size.div_ceil(sector_size),
+        sector_size,
+    })
+}
+
+/// Flashes a built super image (raw, sparse or zstd compressed, expanded while
+/// sending) natively over Firehose to the `super` partition of a GPT dumped from
+/// `lun` (e.g. `img/gpt_main0.bin` from `read_gpt`). The programmer must already be
+/// running.
+///
+/// Progress is saved next to the image as the Device acknowledges it. With `resume`,
+/// an interrupted flash of the same image to the same place (see
+/// `check_flash_resume`) continues where it stopped, otherwise it starts over.
+///
+/// Progress is emitted as `flash-progress` with `(bytes_sent, total)` (at most ~10
+/// per second). `cancel_super_build` stops the flash after the segment being sent,
+/// which can then be resumed.
+#[tauri::command]
+async fn flash_super(
+    app: AppHandle,
+    image: String,
+    gpt_path: String,
+    lun: u8,
+    resume: bool,
+    build_state: State<'_, SuperBuildState>,
+) -> Result<(), String> {
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Flashing Super image {}...", image));
+    build_state.cancel.store(false, Ordering::SeqCst);
+    let cancel = build_state.cancel.clone();
+    let progress_app = app.clone();
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let target = super_flash_target(&image, &gpt_path, lun)?;
+        let mut channel = qdl::open_firehose(Some(port_path))?;
+        let mut last_emit: Option<Instant> = None;
+        flash_resume::flash_resumable(&mut channel, Path::new(&image), target, resume, &cancel, |sent, total| {
+            if sent == total || last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100)) {
+                last_emit = Some(Instant::now());
+                let _ = progress_app.emit("flash-progress", (sent, total));
+            }
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(()) => { let _ = app.emit("log_event", "Flash Super image...OK"); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to flash Super image: {}", e)); }
+    }
+    result
+}
+
+/// Decompresses a zstd super image (`super.img.zst`) to a raw one at `output`, for
+/// tools that need the plain image; `flash_super` takes the compressed file as it is.
+/// Returns the size of the raw image.
+#[tauri::command]
+async fn decompress_super_image(app: AppHandle, path: String, output: String) -> Result<u64, String> {
+    let _ = app.emit("log_event", &format!("Decompressing {} to {}...", path, output));
+    let result = tokio::task::spawn_blocking(move || super_image_creater::decompress_super(Path::new(&path), Path::new(&output)))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(size) => { let _ = app.emit("log_event", &format!("Decompress Super image...OK ({} bytes)", size)); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to decompress Super image: {}", e)); }
+    }
+    result
+}
+
+/// Splits an image into chunks of `chunk_size` bytes (by default the largest that
+/// fits FAT32) in `out_dir`, with a `<image>.chunks.json` index of their sizes and
+/// SHA-256, for copying to a FAT32 card
+#[tauri::command]
+async fn split_super_image(app: AppHandle, path: String, chunk_size: Option<u64>, out_dir: String) -> Result<super_image_creater::SplitIndex, String> {
+    let _ = app.emit("log_event", &format!("Splitting {} into {}...", path, out_dir));
+    let chunk_size = chunk_size.unwrap_or(super_image_creater::FAT32_CHUNK_SIZE);
+    let result = tokio::task::spawn_blocking(move || super_image_creater::split_image(Path::new(&path), chunk_size, Path::new(&out_dir)))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(index) => { let _ = app.emit("log_event", &format!("Split Super image...OK ({} chunks)", index.chunks.len())); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to split Super image: {}", e)); }
+    }
+    result
+}
+
+/// Joins the chunks of a split image back into `output` from their index, checking
+/// every chunk's size and SHA-256. A missing, corrupt or out-of-order chunk fails the
+/// join without leaving an image at `output`. Returns the size of the image.
+#[tauri::command]
+async fn join_super_image(app: AppHandle, index: String, output: String) -> Result<u64, String> {
+    let _ = app.emit("log_event", &format!("Joining {} into {}...", index, output));
+    let result = tokio::task::spawn_blocking(move || super_image_creater::join_image(Path::new(&index), Path::new(&output)))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(size) => { let _ = app.emit("log_event", &format!("Join Super image...OK ({} bytes)", size)); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to join Super image: {}", e)); }
+    }
+    result
+}
+
+/// Saved progress of an interrupted `flash_super` with the same arguments, `None`
+/// when there is nothing to resume, so the UI can ask before resuming
+#[tauri::command]
+async fn check_flash_resume(image: String, gpt_path: String, lun: u8) -> Result<Option<flash_resume::FlashState>, String> {
+    tokio::task::spawn_blocking(move || -> anyhow::Result<Option<flash_resume::FlashState>> {
+        let target = super_flash_target(&image, &gpt_path, lun)?;
+        Ok(flash_resume::resumable_state(Path::new(&image), &target)?)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// Discards the saved flash progress of `image`
+#[tauri::command]
+fn reset_flash_resume(image: String) -> Result<(), String> {
+    flash_resume::reset(Path::new(&image)).map_err(|e| e.to_string())
+}
+
+/// Builds a super image from a partition config straight onto the `super` partition
+/// of a GPT dumped from `lun`, without writing an image file first. Free space
+/// between partitions is not sent. The programmer must already be running, and
+/// `cancel_super_build` stops the transfer.
+///
+/// Progress is emitted as `super-build-progress` (at most ~10 per second), following
+/// the data sent to the Device.
+#[tauri::command]
+async fn stream_super_image(
+    app: AppHandle,
+    path: String,
+    gpt_path: String,
+    lun: u8,
+    build_state: State<'_, SuperBuildState>,
+) -> Result<super_image_creater::BuildReport, String> {
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Streaming Super image from {}...", path));
+    build_state.cancel.store(false, Ordering::SeqCst);
+    let cancel = build_state.cancel.clone();
+    let progress_app = app.clone();
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<super_image_creater::BuildReport> {
+        let mut config = super_image_creater::read_partition_config(&path)?;
+        config.resolve_paths(&config_dir(&path));
+        let label = config.block_devices.first().map(|d| d.name.clone()).unwrap_or_else(|| "super".to_string());
+        let sector_size = 4096u64;
+        let table = gpt_parser::parse_gpt(&fs::read(&gpt_path)?, sector_size as u32)?;
+        let partition = table.find(&label).ok_or_else(|| anyhow::anyhow!("No {} partition in {}", label, gpt_path))?;
+        let target = super_image_creater::PhysicalPartition {
+            lun,
+            start_sector: partition.first_lba,
+            num_sectors: partition.size_in_sectors(),
+            sector_size,
+        };
+        let mut session = qdl::firehose::FirehoseSession::new(qdl::open_firehose(Some(port_path))?);
+        let builder = super_image_creater::SuperImageBuilder::new(config);
+        let mut last_emit: Option<Instant> = None;
+        Ok(session.flash_super_stream(&builder, &target, &cancel, |progress| {
+            if last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100)) {
+                last_emit = Some(Instant::now());
+                let _ = progress_app.emit("super-build-progress", &progress);
+            }
+        })?)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(report) => {
+            let _ = app.emit("log_event", &format!("Stream Super image...OK ({} bytes sent)", report.bytes_written));
+        }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to stream Super image: {}", e)); }
+    }
+    result
+}
+
+/// Format and files of a firmware archive, see `open_firmware`
+#[derive(Serialize)]
+struct FirmwareListing {
+    kind: ofp::ArchiveKind,
+    entries: Vec<ofp::ArchiveEntry>,
+}
+
+/// Opens a firmware archive like `ofp::open_firmware`, or as an OFP file of
+/// `platform` (`qualcomm` or `mtk`) when one is given
+fn open_firmware_archive(path: &str, platform: Option<&str>) -> Result<Box<dyn ofp::FirmwareArchive>, ofp::ArchiveError> {
+    match platform {
+        Some(platform) => Ok(Box::new(ofp::OfpArchive::open_encrypted(path, platform.parse()?)?)),
+        None => ofp::open_firmware(Path::new(path)),
+    }
+}
+
+/// Lists the files of an OFP or OPS firmware archive, detected by extension or
+/// footer (or of `platform`, see `open_firmware_archive`), after decrypting its
+/// manifest
+#[tauri::command]
+async fn open_firmware(app: AppHandle, path: String, platform: Option<String>) -> Result<FirmwareListing, String> {
+    let archive = tokio::task::spawn_blocking(move || open_firmware_archive(&path, platform.as_deref()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let entries = archive.list();
+    let _ = app.emit("log_event", &format!("{} manifest decrypted ({} files)", archive.kind(), entries.len()));
+    Ok(FirmwareListing { kind: archive.kind(), entries })
+}
+
+/// What flashing a firmware archive over Firehose takes, see `firmware_flash_files`
+#[derive(Serialize)]
+struct FirmwareFlashFiles {
+    files: ofp::FlashFiles,
+    /// Labels of the partitions the rawprogram files flash an image to, in file order
+    labels: Vec<String>,
+}
+
+/// Lists the programmer, rawprogram and patch files of an OFP or OPS firmware
+/// archive and the partitions its rawprogram files flash, so the flash targets can
+/// be shown before `extract_firmware` unpacks it for `start_flashing`
+#[tauri::command]
+async fn firmware_flash_files(path: String) -> Result<FirmwareFlashFiles, String> {
+    tokio::task::spawn_blocking(move || -> Result<FirmwareFlashFiles, ofp::ArchiveError> {
+        let archive = ofp::open_firmware(Path::new(&path))?;
+        let files = ofp::flash_files(archive.as_ref());
+        let mut labels = Vec::new();
+        for entry in &files.rawprogram {
+            let rawprogram = ofp::read_rawprogram(archive.as_ref(), &entry.name)?;
+            labels.extend(rawprogram.images().map(|p| p.label.clone()));
+        }
+        Ok(FirmwareFlashFiles { files, labels })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// Extracts images from an OFP or OPS firmware archive into `work_dir`.
+///
+/// With `config`, a config file or the name of a `super_def*.json` inside the
+/// archive, only the images of its partitions are extracted, and a copy of the
+/// config pointing at them is written to `work_dir` for `create_super_image`.
+/// Without it, every file is extracted. `platform` is as for `open_firmware`.
+///
+/// Progress is emitted per file as `firmware-extract-progress` (at most ~10 per
+/// second, plus once when each file is done).
+#[tauri::command]
+async fn extract_firmware(app: AppHandle, path: String, work_dir: String, config: Option<String>, platform: Option<String>) -> Result<ofp::ConfigExtraction, String> {
+    let _ = app.emit("log_event", &format!("Extracting {}...", path));
+    let task_app = app.clone();
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<ofp::ConfigExtraction> {
+        let archive = open_firmware_archive(&path, platform.as_deref())?;
+        let archive = &*archive;
+        let work_dir = PathBuf::from(&work_dir);
+        let mut last_emit: Option<Instant> = None;
+        let progress = |progress: ofp::ExtractProgress| {
+            let done = progress.bytes_done == progress.bytes_total;
+            if done || last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100)) {
+                last_emit = Some(Instant::now());
+                let _ = task_app.emit("firmware-extract-progress", &progress);
+            }
+        };
+        let Some(config) = config else {
+            let files = ofp::extract_entries(archive, &archive.list(), &work_dir, progress)?;
+            return Ok(ofp::ConfigExtraction { files, missing: Vec::new() });
+        };
+        let (mut partition_config, file_name) = match archive.find(&config) {
+            Some(entry) if !Path::new(&config).is_file() => (ofp::read_config(archive, &entry.name)?, entry.name),
+            _ => {
+                let mut partition_config = super_image_creater::read_partition_config(&config)?;
+                partition_config.resolve_paths(&config_dir(&config));
+                let file_name = Path::new(&config).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                (partition_config, file_name)
+            }
+        };
+        let extraction = ofp::extract_for_config(archive, &mut partition_config, &work_dir, progress)?;
+        let config_path = work_dir.join(file_name);
+        super_image_creater::write_partition_config(&config_path, &partition_config)?;
+        let _ = task_app.emit("log_event", &format!("Wrote config {}", config_path.display()));
+        for name in &extraction.missing {
+            let _ = task_app.emit("log_event", &format!("Warning: no image for partition '{}' in the {} file", name, archive.kind()));
+        }
+        Ok(extraction)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(extraction) => { let _ = app.emit("log_event", &format!("Extract firmware...OK ({} files)", extraction.files.len())); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to extract firmware: {}", e)); }
+    }
+    result
+}
+
+/// Lists the partitions of an OTA `payload.bin`
+#[tauri::command]
+async fn list_payload_partitions(path: String) -> Result<Vec<payload::PayloadPartition>, String> {
+    tokio::task::spawn_blocking(move || payload::Payload::open(&path).map(|p| p.partitions))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Extracts partitions of a full-OTA `payload.bin` (all of them if `names` is
+/// empty) to `<out_dir>/<name>.img`, each verified against its SHA-256
+#[tauri::command]
+async fn extract_payload(app: AppHandle, path: String, names: Vec<String>, out_dir: String) -> Result<Vec<String>, String> {
+    let _ = app.emit("log_event", &format!("Extracting {}...", path));
+    let result = tokio::task::spawn_blocking(move || {
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        payload::extract_payload_partitions(Path::new(&path), &names, Path::new(&out_dir))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(paths) => { let _ = app.emit("log_event", &format!("Extract payload...OK ({} images)", paths.len())); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to extract payload: {}", e)); }
+    }
+    result.map(|paths| paths.iter().map(|p| p.to_string_lossy().to_string()).collect())
+}
+
+/// Sends the Firehose programmer over Sahara natively, logging the serial number,
+/// HWID and PK hash read before the upload. A device that stops answering fails the
+/// command after the Sahara timeout instead of hanging it.
+#[tauri::command]
+async fn upload_loader(app: AppHandle, loader_path: String) -> Result<qdl::sahara::DeviceIds, String> {
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Sending loader {}...", loader_path));
+    let result = tokio::task::spawn_blocking(move || qdl::upload_loader(Some(port_path), &loader_path))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(ids) => {
+            let unknown = || "unknown".to_string();
+            let _ = app.emit("log_event", &format!("Serial number: {}", ids.serial_number.clone().unwrap_or_else(unknown)));
+            let _ = app.emit("log_event", &format!("HWID: {}", ids.hw_id.clone().unwrap_or_else(unknown)));
+            let _ = app.emit("log_event", &format!("PK hash: {}", ids.oem_pk_hash.clone().unwrap_or_else(unknown)));
+            let _ = app.emit("log_event", "Send loader...OK");
+        }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to send loader: {}", e)); }
+    }
+    result
+}
+
+/// Reads the GPT of each LUN a rawprogram XML writes to and logs every entry that
+/// does not fit the partition of the same name, before anything is flashed
+#[tauri::command]
+async fn verify_partition_layout(app: AppHandle, rawprogram_path: String) -> Result<Vec<String>, String> {
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Checking partition layout of {}...", rawprogram_path));
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<String>> {
+        let rawprogram = super_image_creater::parse_rawprogram(&rawprogram_path)?;
+        let mut session = qdl::firehose::FirehoseSession::new(qdl::open_firehose(Some(port_path))?);
+        let mismatches = qdl::firehose::verify_layout(&mut session, &rawprogram)?;
+        Ok(mismatches.iter().map(|m| m.to_string()).collect())
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(mismatches) if mismatches.is_empty() => { let _ = app.emit("log_event", "Check partition layout...OK"); }
+        Ok(mismatches) => {
+            for mismatch in mismatches {
+                let _ = app.emit("log_event", &format!("Warning: {}", mismatch));
+            }
+        }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to check partition layout: {}", e)); }
+    }
+    result
+}
+
+/// Reads the sector size and capacity of `lun` from the programmer. With a
+/// `rawprogram_path`, every entry for the LUN with another sector size is logged as
+/// a warning, e.g. an eMMC rawprogram about to be flashed to UFS.
+#[tauri::command]
+async fn get_storage_info(app: AppHandle, lun: u8, rawprogram_path: Option<String>) -> Result<qdl::firehose::StorageInfo, String> {
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Reading storage info of LUN {}...", lun));
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let rawprogram = rawprogram_path.map(super_image_creater::parse_rawprogram).transpose()?;
+        let mut session = qdl::firehose::FirehoseSession::new(qdl::open_firehose(Some(port_path))?);
+        let info = session.get_storage_info(lun)?;
+        let mismatches = rawprogram.map(|r| qdl::firehose::check_sector_size(&info, lun, &r)).unwrap_or_default();
+        Ok((info, mismatches))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match result {
+        Ok((info, mismatches)) => {
+            let _ = app.emit("log_event", &format!(
+                "Read storage info...OK ({} sectors of {} bytes)", info.total_blocks, info.sector_size
+            ));
+            for mismatch in mismatches {
+                let _ = app.emit("log_event", &format!("Warning: {}", mismatch));
+            }
+            Ok(info)
+        }
+        Err(e) => {
+            let _ = app.emit("log_event", &format!("Failed to read storage info: {}", e));
+            Err(e)
+        }
+    }
+}
+
+/// Backs up the named partitions to `<output_dir>/<name>.img`, one after the other
+/// over a single Firehose connection, stopping at the first failure.
+///
+/// Progress is emitted as `dump-progress` with `(name, progress)` (at most ~10 per
+/// second), where the progress includes the transfer rate.
+#[tauri::command]
+async fn dump_partitions(app: AppHandle, names: Vec<String>, output_dir: String) -> Result<Vec<String>, String> {
+    let (port_path, _port_info) = update_port();
+    let progress_app = app.clone();
+    let result = tokio::task::spawn_blocking(move || -> Result<Vec<String>, String> {
+        let channel = qdl::open_firehose(Some(port_path)).map_err(|e| e.to_string())?;
+        let mut session = qdl::firehose::FirehoseSession::new(channel);
+        let mut dumped = Vec::new();
+        for name in names {
+            let _ = progress_app.emit("log_event", &format!("Dumping {}...", name));
+            let output = Path::new(&output_dir).join(format!("{}.img", name));
+            let mut last_emit: Option<Instant> = None;
+            let bytes = session
+                .dump_by_name(&name, &output, |progress| {
+                    if progress.bytes_done == progress.bytes_total
+                        || last_emit.is_none_or(|t| t.elapsed() >= Duration::from_millis(100))
+                    {
+                        last_emit = Some(Instant::now());
+                        let _ = progress_app.emit("dump-progress", (&name, progress));
+                    }
+                })
+                .map_err(|e| format!("{}: {}", name, e))?;
+            let _ = progress_app.emit("log_event", &format!("Dump {}...OK ({} bytes)", name, bytes));
+            dumped.push(output.to_string_lossy().to_string());
+        }
+        Ok(dumped)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r);
+    if let Err(e) = &result {
+        let _ = app.emit("log_event", &format!("Failed to dump partitions: {}", e));
+    }
+    result
+}
+
+/// Resets (`"reset"`), powers off (`"off"`) or reboots to EDL (`"edl"`) the Device
+/// through the running programmer, the step after a flash. A Device that drops off
+/// the bus before acknowledging counts as done.
+#[tauri::command]
+async fn power_device(app: AppHandle, action: String) -> Result<(), String> {
+    let action = match action.as_str() {
+        "reset" => qdl::firehose::PowerAction::Reset,
+        "off" => qdl::firehose::PowerAction::PowerOff,
+        "edl" => qdl::firehose::PowerAction::ResetToEdl,
+        other => return Err(format!("Unknown power action '{}'", other)),
+    };
+    let (port_path, _port_info) = update_port();
+    let _ = app.emit("log_event", &format!("Sending power {}...", action.value()));
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut session = qdl::firehose::FirehoseSession::new(qdl::open_firehose(Some(port_path))?);
+        Ok(session.power(action, 0)?)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r.map_err(|e| e.to_string()));
+    match &result {
+        Ok(()) => { let _ = app.emit("log_event", &format!("Power {}...OK", action.value())); }
+        Err(e) => { let _ = app.emit("log_event", &format!("Failed to send power {}: {}", action.value(), e)); }
+    }
+    result
+}
+
+#[tauri::command]
+fn cancel_super_build(app: AppHandle, build_state: State<'_, SuperBuildState>) {
+    build_state.cancel.store(true, Ordering::SeqCst);
+    let _ = app.emit("log_event", "Cancelling Super image creation");
+}
+
+/// Path of this session's log file, for an "export logs" action
+#[tauri::command]
+fn get_log_path() -> Result<String, String> {
+    logger::log_path().map(|path| path.to_string_lossy().into_owned()).ok_or_else(|| "No session log is open".to_string())
+}
+
+/// The last `lines` lines of this session's log, oldest first
+#[tauri::command]
+fn read_recent_log(lines: usize) -> Result<Vec<String>, String> {
+    logger::read_recent(lines).map_err(|e| e.to_string())
+}
+
+/// Switches the log filter, e.g. to `debug` or `trace`, or `info,edl_toolkit_lib::qdl=trace`
+#[tauri::command]
+fn set_log_level(level: String) -> Result<(), String> {
+    logger::set_level(&level)?;
+    log::info!("Log level set to {}", level);
+    Ok(())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    logger::init();
     tauri::Builder::default()
+        .setup(|app| {
+            // Without a session log the app still logs to stderr
+            match app.path().app_data_dir() {
+                Ok(dir) => match logger::start_session_log(&dir.join("logs")) {
+                    Ok(path) => log::info!("Logging to {}", path.display()),
+                    Err(e) => log::warn!("Cannot open the session log in {}: {}", dir.display(), e),
+                },
+                Err(e) => log::warn!("Cannot resolve the app data directory for the session log: {}", e),
+            }
+            Ok(())
+        })
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
         .manage(Arc::new(Mutex::new(ThreadState::default())))
+        .manage(SuperBuildState::default())
+        .manage(SuperJobs::default())
+        .manage(DeviceWatchState::default())
+        .manage(commands::ConfigWatchState::default())
         .invoke_handler(tauri::generate_handler![init, update_port, send_loader, read_part, write_part, read_gpt,
         reboot_to_system, reboot_to_recovery, reboot_to_fastboot, reboot_to_edl, save_to_xml, write_from_xml, 
-        read_device_info, switch_slot, start_flashing, stop_flashing])
+        read_device_info, switch_slot, start_flashing, stop_flashing, commands::create_super_image, commands::plan_super_image, estimate_super_size, super_address_map, inspect_super_layout,
+        cancel_super_build, start_super_build, super_build_status, wait_super_build, cancel_super_job, check_super_inputs, inspect_super_inputs, detect_image_fs, verify_super_hashes, verify_super_image, import_super_layout, commands::unpack_super_image, new_super_config, commands::open_super_config, commands::parse_super_config, commands::import_lpmake_command, save_super_config,
+        resolve_super_sizes, commands::validate_super_config, commands::watch_config, commands::unwatch_config, list_super_variants, list_nv_ids, diff_super_configs, create_gpt_patch_xml,
+        read_gpt_table, scan_edl_devices, flash_super, decompress_super_image, split_super_image, join_super_image, check_flash_resume, reset_flash_resume, power_device, stream_super_image, upload_loader,
+        verify_partition_layout, get_storage_info, dump_partitions, open_firmware, firmware_flash_files, extract_firmware, list_payload_partitions, extract_payload, list_edl_devices, watch_edl_devices, stop_watching_edl_devices,
+        get_log_path, read_recent_log, set_log_level])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
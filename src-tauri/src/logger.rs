@@ -0,0 +1,450 @@
+//! Minimal `log` backend and `tracing` subscriber writing to stderr and, once
+//! `start_session_log` is called, to a log file of the session. Filtered by
+//! `RUST_LOG` like env_logger: a level (`debug`), `module=level` directives, or both
+//! (`info,edl_toolkit_lib::qdl=trace`); `set_level` changes the filter at runtime.
+//!
+//! Phases of work are `tracing` spans (`debug_span!("copy", %name).entered()`);
+//! records logged on a thread inside one are prefixed with its name and fields.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::{Event, Subscriber};
+
+/// Session logs kept in the log directory, the current one included
+const MAX_SESSION_LOGS: usize = 10;
+
+/// Bytes read at a time from the end of the session log by `read_recent`
+const TAIL_BLOCK: u64 = 64 * 1024;
+
+thread_local! {
+    /// Whether `Sensitive` values are shown in the record being formatted
+    static REVEAL: Cell<bool> = const { Cell::new(false) };
+    /// (id, label) of the spans entered on this thread, outermost first
+    static SPANS: RefCell<Vec<(u64, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+static LOGGER: OnceLock<SessionLogger> = OnceLock::new();
+
+struct Filter {
+    /// Level of targets no directive matches
+    default: LevelFilter,
+    /// (target prefix, level), the longest matching prefix wins
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// The filter of `spec`, and the `target=level` directives whose level is not one
+    fn parse(spec: &str) -> (Self, Vec<String>) {
+        let mut default = LevelFilter::Info;
+        let mut directives = Vec::new();
+        let mut invalid = Vec::new();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((target, level)) => match level.trim().parse() {
+                    Ok(level) => directives.push((target.trim().to_string(), level)),
+                    Err(_) => invalid.push(part.to_string()),
+                },
+                None => match part.parse() {
+                    Ok(level) => default = level,
+                    // A bare module name turns everything in it on, as with env_logger
+                    Err(_) => directives.push((part.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        (Filter { default, directives }, invalid)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives.iter().map(|&(_, level)| level).fold(self.default, Ord::max)
+    }
+}
+
+/// A span that is not closed yet
+struct OpenSpan {
+    /// Its name followed by the values of its fields, `copy system_a`
+    label: String,
+    level: Level,
+    target: &'static str,
+    started: Instant,
+    /// Handles to the span, it closes when the last one is dropped
+    refs: usize,
+}
+
+struct SessionLogger {
+    filter: RwLock<Filter>,
+    /// The session log and its path, once `start_session_log` opened it
+    file: Mutex<Option<(PathBuf, File)>>,
+    started: Instant,
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+    next_span: AtomicU64,
+}
+
+impl SessionLogger {
+    fn new(filter: Filter) -> Self {
+        SessionLogger {
+            filter: RwLock::new(filter),
+            file: Mutex::new(None),
+            started: Instant::now(),
+            spans: Mutex::new(HashMap::new()),
+            next_span: AtomicU64::new(0),
+        }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.filter.read().unwrap_or_else(PoisonError::into_inner).level_for(target)
+    }
+
+    /// Runs `format` with `Sensitive` values shown if the filter of `target` is at
+    /// debug or finer
+    fn reveal_for<T>(&self, target: &str, format: impl FnOnce() -> T) -> T {
+        REVEAL.with(|reveal| reveal.set(self.level_for(target) >= LevelFilter::Debug));
+        let formatted = format();
+        REVEAL.with(|reveal| reveal.set(false));
+        formatted
+    }
+
+    /// Writes the line of `text`, prefixed with the spans entered on this thread
+    fn write_line(&self, level: Level, target: &str, text: impl FnOnce() -> String) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let spans: String = SPANS.with(|spans| spans.borrow().iter().map(|(_, label)| format!("{}: ", label)).collect());
+        let text = self.reveal_for(target, text);
+        let line = format!("[{:>9.3} {:<5} {}] {}{}\n", elapsed, level, target, spans, text);
+        let _ = std::io::stderr().write_all(line.as_bytes());
+        if let Some((_, file)) = self.file.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn open_spans(&self) -> std::sync::MutexGuard<'_, HashMap<u64, OpenSpan>> {
+        self.spans.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Log for SessionLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() > self.level_for(record.target()) {
+            return;
+        }
+        self.write_line(record.level(), record.target(), || record.args().to_string());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// The `log` level of a `tracing` one
+fn log_level(level: &tracing::Level) -> Level {
+    match *level {
+        tracing::Level::ERROR => Level::Error,
+        tracing::Level::WARN => Level::Warn,
+        tracing::Level::INFO => Level::Info,
+        tracing::Level::DEBUG => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// Collects the fields of a span or event as text: the `message` of an event
+/// first, then ` value` for a span or ` name=value` for an event
+struct FieldText {
+    text: String,
+    named: bool,
+}
+
+impl Visit for FieldText {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match (field.name(), self.named) {
+            ("message", _) => write!(self.text, "{:?}", value),
+            (name, true) => write!(self.text, " {}={:?}", name, value),
+            (_, false) => write!(self.text, " {:?}", value),
+        };
+    }
+}
+
+/// `SessionLogger` as the `tracing` subscriber
+struct Tracing(&'static SessionLogger);
+
+impl Subscriber for Tracing {
+    fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        log_level(metadata.level()) <= self.0.level_for(metadata.target())
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let metadata = span.metadata();
+        let (level, target) = (log_level(metadata.level()), metadata.target());
+        let mut fields = FieldText { text: metadata.name().to_string(), named: false };
+        self.0.reveal_for(target, || span.record(&mut fields));
+        self.0.write_line(level, target, || format!("{} started", fields.text));
+        let id = self.0.next_span.fetch_add(1, Ordering::Relaxed) + 1;
+        let open = OpenSpan { label: fields.text, level, target, started: Instant::now(), refs: 1 };
+        self.0.open_spans().insert(id, open);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &SpanRecord<'_>) {
+        if let Some(open) = self.0.open_spans().get_mut(&span.into_u64()) {
+            let mut fields = FieldText { text: std::mem::take(&mut open.label), named: false };
+            self.0.reveal_for(open.target, || values.record(&mut fields));
+            open.label = fields.text;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        self.0.write_line(log_level(metadata.level()), metadata.target(), || {
+            let mut fields = FieldText { text: String::new(), named: true };
+            event.record(&mut fields);
+            fields.text
+        });
+    }
+
+    fn enter(&self, span: &Id) {
+        let label = self.0.open_spans().get(&span.into_u64()).map(|open| open.label.clone());
+        if let Some(label) = label {
+            SPANS.with(|spans| spans.borrow_mut().push((span.into_u64(), label)));
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            if let Some(i) = spans.iter().rposition(|&(id, _)| id == span.into_u64()) {
+                spans.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self.0.open_spans().get_mut(&span.into_u64()) {
+            open.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.0.open_spans();
+        let Some(open) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        open.refs -= 1;
+        if open.refs > 0 {
+            return false;
+        }
+        let open = spans.remove(&span.into_u64()).expect("the span is open");
+        drop(spans);
+        self.0.write_line(open.level, open.target, || format!("{} finished in {:?}", open.label, open.started.elapsed()));
+        true
+    }
+}
+
+/// Installs the logger, and the `tracing` subscriber, for the `RUST_LOG` setting,
+/// `info` if it is not set. Does nothing if a logger is installed already.
+pub fn init() {
+    let (filter, _) = Filter::parse(&env::var("RUST_LOG").unwrap_or_default());
+    let max_level = filter.max_level();
+    let mut created = false;
+    let logger = LOGGER.get_or_init(|| {
+        created = true;
+        SessionLogger::new(filter)
+    });
+    if created && log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+        let _ = tracing::subscriber::set_global_default(Tracing(logger));
+    }
+}
+
+/// Replaces the filter with `spec`, in the syntax of `RUST_LOG` (`debug`,
+/// `info,edl_toolkit_lib::qdl=trace`)
+pub fn set_level(spec: &str) -> Result<(), String> {
+    let logger = LOGGER.get().ok_or("The logger is not installed")?;
+    let (filter, invalid) = Filter::parse(spec);
+    if !invalid.is_empty() {
+        return Err(format!("Invalid log level in '{}'", invalid.join(",")));
+    }
+    log::set_max_level(filter.max_level());
+    *logger.filter.write().unwrap_or_else(PoisonError::into_inner) = filter;
+    // `tracing` caches whether each callsite is enabled
+    tracing::callsite::rebuild_interest_cache();
+    Ok(())
+}
+
+/// Starts writing the log to a new `session-<unix time>.log` in `dir` as well, and
+/// removes the oldest session logs there so `MAX_SESSION_LOGS` are left. Returns
+/// the path of the new log.
+pub fn start_session_log(dir: &Path) -> io::Result<PathBuf> {
+    let logger = LOGGER.get().ok_or_else(|| io::Error::other("The logger is not installed"))?;
+    fs::create_dir_all(dir)?;
+    prune_session_logs(dir, MAX_SESSION_LOGS - 1)?;
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let path = dir.join(format!("session-{}.log", started));
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "Oplus EDL Toolkit {} session log, started at Unix time {}", env!("CARGO_PKG_VERSION"), started)?;
+    *logger.file.lock().unwrap_or_else(PoisonError::into_inner) = Some((path.clone(), file));
+    Ok(path)
+}
+
+/// Removes the oldest `session-<unix time>.log` files in `dir` until `keep` are left
+fn prune_session_logs(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut logs: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let started = name.strip_prefix("session-")?.strip_suffix(".log")?.parse().ok()?;
+            Some((started, entry.path()))
+        })
+        .collect();
+    logs.sort();
+    for (_, path) in &logs[..logs.len().saturating_sub(keep)] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Path of the session log, if `start_session_log` opened one
+pub fn log_path() -> Option<PathBuf> {
+    let file = LOGGER.get()?.file.lock().unwrap_or_else(PoisonError::into_inner);
+    file.as_ref().map(|(path, _)| path.clone())
+}
+
+/// The last `lines` lines of the session log, oldest first
+pub fn read_recent(lines: usize) -> io::Result<Vec<String>> {
+    let path = log_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No session log is open"))?;
+    read_tail(&path, lines)
+}
+
+/// The last `lines` lines of the file at `path`, oldest first
+fn read_tail(path: &Path, lines: usize) -> io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let mut start = file.metadata()?.len();
+    // Read from the end until the tail holds one newline more than `lines`, so the
+    // first line kept is complete
+    let mut tail = Vec::new();
+    while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= lines {
+        let block = start.min(TAIL_BLOCK);
+        start -= block;
+        let mut buf = vec![0u8; block as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        buf.extend_from_slice(&tail);
+        tail = buf;
+    }
+    let text = String::from_utf8_lossy(&tail);
+    let all: Vec<&str> = text.lines().collect();
+    Ok(all[all.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect())
+}
+
+/// A value such as the NV text of a build, logged in full only where the filter is
+/// at `debug` or finer; at `info` the log shows its length
+pub struct Sensitive<'a>(pub &'a str);
+
+impl fmt::Display for Sensitive<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match REVEAL.with(Cell::get) {
+            true => f.write_str(self.0),
+            false => write!(f, "<redacted, {} bytes>", self.0.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_parse_like_rust_log() {
+        let (filter, invalid) = Filter::parse("");
+        assert_eq!((filter.default, filter.directives.len(), invalid.len()), (LevelFilter::Info, 0, 0));
+
+        let (filter, invalid) = Filter::parse(" warn , edl_toolkit_lib::qdl = trace,edl_toolkit_lib::qdl::serial=error,edl_toolkit_lib::ofp");
+        assert!(invalid.is_empty());
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(filter.level_for("edl_toolkit_lib::qdl::firehose"), LevelFilter::Trace);
+        // The longest matching prefix wins, whatever the order
+        assert_eq!(filter.level_for("edl_toolkit_lib::qdl::serial"), LevelFilter::Error);
+        // A bare module name turns its records on
+        assert_eq!(filter.level_for("edl_toolkit_lib::ofp::crypto"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("edl_toolkit_lib::payload"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        let (filter, invalid) = Filter::parse("DEBUG,edl_toolkit_lib::qdl=loud,edl_toolkit_lib=off");
+        assert_eq!(invalid, ["edl_toolkit_lib::qdl=loud"]);
+        assert_eq!(filter.default, LevelFilter::Debug);
+        assert_eq!(filter.level_for("edl_toolkit_lib::qdl"), LevelFilter::Off);
+        assert_eq!(filter.level_for("tauri"), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn recent_lines_are_read_from_the_end() {
+        let dir = env::temp_dir().join(format!("logger-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session-1.log");
+        // Several tail blocks of lines
+        let all: Vec<String> = (0..20_000).map(|i| format!("line {:05}", i)).collect();
+        fs::write(&path, all.join("\n") + "\n").unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > 3 * TAIL_BLOCK);
+        assert_eq!(read_tail(&path, 3).unwrap(), ["line 19997", "line 19998", "line 19999"]);
+        let many = read_tail(&path, 9000).unwrap();
+        assert_eq!(many, all[11_000..]);
+        assert_eq!(read_tail(&path, 50_000).unwrap(), all);
+        assert!(read_tail(&path, 0).unwrap().is_empty());
+
+        // Without the final newline the last line still counts
+        fs::write(&path, "first\nsecond\nthird").unwrap();
+        assert_eq!(read_tail(&path, 2).unwrap(), ["second", "third"]);
+        assert!(read_tail(&dir.join("missing.log"), 2).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sensitive_values_show_only_where_the_filter_is_at_debug() {
+        let logger = SessionLogger::new(Filter::parse("info,edl_toolkit_lib::super_image_creater=debug").0);
+        let nv_text = || Sensitive("Global").to_string();
+        assert_eq!(logger.reveal_for("edl_toolkit_lib::qdl", nv_text), "<redacted, 6 bytes>");
+        assert_eq!(logger.reveal_for("edl_toolkit_lib::super_image_creater::builder", nv_text), "Global");
+        // Outside of a record, e.g. in a message for the UI
+        assert_eq!(nv_text(), "<redacted, 6 bytes>");
+
+        let dir = env::temp_dir().join(format!("logger-sensitive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session-1.log");
+        *logger.file.lock().unwrap() = Some((path.clone(), File::create(&path).unwrap()));
+        for target in ["edl_toolkit_lib::qdl", "edl_toolkit_lib::super_image_creater"] {
+            logger.log(&Record::builder().args(format_args!("NV {}", Sensitive("India"))).level(Level::Info).target(target).build());
+        }
+        logger.log(&Record::builder().args(format_args!("dropped")).level(Level::Debug).target("edl_toolkit_lib::qdl").build());
+        let lines = read_tail(&path, 10).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("edl_toolkit_lib::qdl] NV <redacted, 5 bytes>"), "{}", lines[0]);
+        assert!(lines[1].ends_with("edl_toolkit_lib::super_image_creater] NV India"), "{}", lines[1]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,308 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use super::{OfpArchive, ops};
+use crate::super_image_creater::{
+    JsonParseError, Partition, PartitionConfig, RawProgram, XmlParseError, parse_partition_config_str, parse_rawprogram_str,
+};
+
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Container format of a firmware archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveKind {
+    /// OPPO/realme/OPlus `.ofp` (Qualcomm)
+    Ofp,
+    /// OnePlus `.ops`
+    Ops,
+}
+
+impl fmt::Display for ArchiveKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArchiveKind::Ofp => "OFP",
+            ArchiveKind::Ops => "OPS",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not an {0} file (no footer found)")]
+    NotArchive(ArchiveKind),
+    #[error("No supported {0} key scheme decrypts the manifest")]
+    UnknownKey(ArchiveKind),
+    /// A platform name `OfpPlatform` does not know, e.g. from the UI
+    #[error("Unsupported OFP platform '{0}', expected qualcomm or mtk")]
+    UnsupportedPlatform(String),
+    #[error("Invalid firmware manifest: {0}")]
+    Xml(String),
+    #[error("'{0}' ends past the end of the firmware file")]
+    Truncated(String),
+    #[error("No '{0}' in the firmware file")]
+    NotFound(String),
+    #[error("{0}")]
+    Config(#[from] JsonParseError),
+    /// A rawprogram file of the archive does not parse
+    #[error("{0}")]
+    Program(#[from] XmlParseError),
+}
+
+/// A file of a firmware archive
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArchiveEntry {
+    /// File name, without any directory
+    pub name: String,
+    /// Partition the file is flashed to, if the manifest says
+    pub label: Option<String>,
+    /// Size of the extracted file in bytes
+    pub size: u64,
+    /// Byte offset in the archive
+    pub offset: u64,
+}
+
+/// An opened firmware archive, whatever its format, so the super image workflow can
+/// take images from either
+pub trait FirmwareArchive {
+    fn kind(&self) -> ArchiveKind;
+
+    /// Files in the archive, each name once, in manifest order
+    fn list(&self) -> Vec<ArchiveEntry>;
+
+    /// Reader of the decrypted contents of the file `name`
+    fn open_entry(&self, name: &str) -> Result<Box<dyn Read + '_>, ArchiveError>;
+
+    fn find(&self, name: &str) -> Option<ArchiveEntry> {
+        let wanted = Path::new(name.trim()).file_name().and_then(|n| n.to_str())?;
+        self.list().into_iter().find(|e| e.name.eq_ignore_ascii_case(wanted))
+    }
+
+    /// Decrypts the file `name` to `path`. `progress` gets the bytes written and the
+    /// total after every MiB. A partial file is removed on error.
+    fn extract_to(&self, name: &str, path: &Path, progress: &mut dyn FnMut(u64, u64)) -> Result<(), ArchiveError> {
+        let entry = self.find(name).ok_or_else(|| ArchiveError::NotFound(name.to_string()))?;
+        let result = self.open_entry(&entry.name).and_then(|mut reader| {
+            let mut out = BufWriter::with_capacity(COPY_BUFFER_SIZE, File::create(path)?);
+            copy_with_progress(&mut reader, &mut out, entry.size, progress)?;
+            Ok(out.flush()?)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+}
+
+/// Copies `total` bytes in 1 MiB steps, failing if `reader` ends early
+fn copy_with_progress(reader: &mut dyn Read, out: &mut dyn Write, total: u64, progress: &mut dyn FnMut(u64, u64)) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut done = 0u64;
+    progress(0, total);
+    while done < total {
+        let len = (total - done).min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..len])?;
+        out.write_all(&buf[..len])?;
+        done += len as u64;
+        progress(done, total);
+    }
+    Ok(())
+}
+
+/// Format of a firmware file: by its `.ofp` / `.ops` extension, otherwise by the
+/// footer both formats end with (or the entry table of MTK OFP files)
+pub fn detect_archive(path: &Path) -> Result<ArchiveKind, ArchiveError> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "ofp" => Ok(ArchiveKind::Ofp),
+        "ops" => Ok(ArchiveKind::Ops),
+        // Renamed OPS files are taken for OFP, whose keys then do not fit
+        _ if super::find_footer(&mut File::open(path)?)?.is_some() => Ok(ArchiveKind::Ofp),
+        _ if super::mtk::read_entries(&mut File::open(path)?)?.is_some() => Ok(ArchiveKind::Ofp),
+        _ => Err(ArchiveError::NotArchive(ArchiveKind::Ofp)),
+    }
+}
+
+/// Opens a firmware archive of either format, see `detect_archive`
+pub fn open_firmware(path: &Path) -> Result<Box<dyn FirmwareArchive>, ArchiveError> {
+    match detect_archive(path)? {
+        ArchiveKind::Ofp => Ok(Box::new(OfpArchive::open(path)?)),
+        ArchiveKind::Ops => Err(ops::open(path)),
+    }
+}
+
+/// A file extracted by `extract_entries`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtractedFile {
+    pub name: String,
+    pub label: Option<String>,
+    pub path: PathBuf,
+}
+
+/// Progress of `extract_entries`, per file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtractProgress {
+    pub filename: String,
+    /// 0-based index of the file among those extracted
+    pub index: usize,
+    pub count: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// Result of `extract_for_config`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigExtraction {
+    pub files: Vec<ExtractedFile>,
+    /// Partitions of the config that have no image in the archive
+    pub missing: Vec<String>,
+}
+
+/// Extracts several entries into `out_dir` (created if missing), each name once
+pub fn extract_entries(
+    archive: &dyn FirmwareArchive,
+    entries: &[ArchiveEntry],
+    out_dir: &Path,
+    mut progress: impl FnMut(ExtractProgress),
+) -> Result<Vec<ExtractedFile>, ArchiveError> {
+    fs::create_dir_all(out_dir)?;
+    let mut unique: Vec<&ArchiveEntry> = Vec::new();
+    for entry in entries {
+        if !unique.iter().any(|e| e.name.eq_ignore_ascii_case(&entry.name)) {
+            unique.push(entry);
+        }
+    }
+    let count = unique.len();
+    let mut files = Vec::new();
+    for (index, entry) in unique.into_iter().enumerate() {
+        let path = out_dir.join(&entry.name);
+        archive.extract_to(&entry.name, &path, &mut |bytes_done, bytes_total| {
+            progress(ExtractProgress { filename: entry.name.clone(), index, count, bytes_done, bytes_total })
+        })?;
+        files.push(ExtractedFile { name: entry.name.clone(), label: entry.label.clone(), path });
+    }
+    Ok(files)
+}
+
+/// Entry holding the image of a config partition: the file named like the file name
+/// of its `path`, or else the entry labelled like the partition (or, for an `_a`
+/// partition, like its un-suffixed name), or else `<name>.img`. Only the `_a` slot
+/// falls back to the un-suffixed image, `_b` partitions stay empty as in stock
+/// configs.
+pub fn find_image(archive: &dyn FirmwareArchive, partition: &Partition) -> Option<ArchiveEntry> {
+    if !partition.path.trim().is_empty()
+        && let Some(entry) = archive.find(&partition.path.replace('\\', "/"))
+    {
+        return Some(entry);
+    }
+    let entries = archive.list();
+    let mut names = vec![partition.name.as_str()];
+    if let Some(base) = partition.name.strip_suffix("_a") {
+        names.push(base);
+    }
+    for name in names {
+        let labelled = entries.iter().find(|e| e.label.as_deref().is_some_and(|l| l.eq_ignore_ascii_case(name)));
+        if let Some(entry) = labelled.cloned().or_else(|| archive.find(&format!("{}.img", name))) {
+            return Some(entry);
+        }
+    }
+    None
+}
+
+/// Extracts only the images of the partitions of `config` (see `find_image`) and
+/// points their `path` at the extracted files.
+///
+/// Paths are set to the bare file names, so a copy of the config written into
+/// `out_dir` loads them from there (see `PartitionConfig::resolve_paths`).
+/// Partitions without an image in the archive keep their path and are listed in
+/// `missing`, except `_b` partitions without a path, which are empty on purpose.
+pub fn extract_for_config(
+    archive: &dyn FirmwareArchive,
+    config: &mut PartitionConfig,
+    out_dir: &Path,
+    progress: impl FnMut(ExtractProgress),
+) -> Result<ConfigExtraction, ArchiveError> {
+    let mut selected = Vec::new();
+    let mut missing = Vec::new();
+    for (i, partition) in config.partitions.iter().enumerate() {
+        match find_image(archive, partition) {
+            Some(entry) => selected.push((i, entry)),
+            None if partition.path.trim().is_empty() && partition.name.ends_with("_b") => {}
+            None => missing.push(partition.name.clone()),
+        }
+    }
+    let entries: Vec<ArchiveEntry> = selected.iter().map(|(_, entry)| entry.clone()).collect();
+    let files = extract_entries(archive, &entries, out_dir, progress)?;
+    for (i, entry) in selected {
+        config.partitions[i].path = entry.name;
+    }
+    Ok(ConfigExtraction { files, missing })
+}
+
+/// `super_def*.json` configs in the archive, one per NV ID
+pub fn super_configs(archive: &dyn FirmwareArchive) -> Vec<ArchiveEntry> {
+    archive
+        .list()
+        .into_iter()
+        .filter(|e| {
+            let name = e.name.to_ascii_lowercase();
+            name.starts_with("super_def") && name.ends_with(".json")
+        })
+        .collect()
+}
+
+/// Decrypts a config stored in the archive, e.g. one of `super_configs`, with its
+/// image paths as they are stored (see `extract_for_config` to point them at
+/// extracted files)
+pub fn read_config(archive: &dyn FirmwareArchive, name: &str) -> Result<PartitionConfig, ArchiveError> {
+    let mut content = String::new();
+    archive.open_entry(name)?.read_to_string(&mut content)?;
+    Ok(parse_partition_config_str(&content)?)
+}
+
+/// The files of a firmware archive that flashing it over Firehose takes, see
+/// `flash_files`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FlashFiles {
+    /// Firehose programmer to send over Sahara, e.g. `prog_firehose_ddr.elf`
+    pub programmer: Option<ArchiveEntry>,
+    /// `rawprogram*.xml`, in manifest order
+    pub rawprogram: Vec<ArchiveEntry>,
+    /// `patch*.xml`, in manifest order
+    pub patch: Vec<ArchiveEntry>,
+}
+
+/// The programmer, rawprogram and patch files of the archive, recognised by their
+/// names. Extracted next to the images (see `extract_entries`), they are the folder
+/// the Firehose workflow flashes.
+pub fn flash_files(archive: &dyn FirmwareArchive) -> FlashFiles {
+    let mut files = FlashFiles::default();
+    for entry in archive.list() {
+        let name = entry.name.to_ascii_lowercase();
+        if name.ends_with(".xml") && name.starts_with("rawprogram") {
+            files.rawprogram.push(entry);
+        } else if name.ends_with(".xml") && name.starts_with("patch") {
+            files.patch.push(entry);
+        } else if files.programmer.is_none()
+            && name.contains("firehose")
+            && [".elf", ".melf", ".mbn"].iter().any(|ext| name.ends_with(ext))
+        {
+            files.programmer = Some(entry);
+        }
+    }
+    files
+}
+
+/// Decrypts and parses a rawprogram file stored in the archive, e.g. one of
+/// `flash_files`, for the partitions it flashes
+pub fn read_rawprogram(archive: &dyn FirmwareArchive, name: &str) -> Result<RawProgram, ArchiveError> {
+    let mut content = String::new();
+    archive.open_entry(name)?.read_to_string(&mut content)?;
+    Ok(parse_rawprogram_str(&content)?)
+}
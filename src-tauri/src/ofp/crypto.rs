@@ -0,0 +1,224 @@
+//! AES-128 in CFB-128 mode and MD5, the primitives OFP decryption needs. Only the
+//! encryption direction of AES is used, CFB decrypts with the block encryption.
+
+/// AES-128 with expanded round keys
+struct Aes128 {
+    sbox: [u8; 256],
+    round_keys: [[u8; 16]; 11],
+}
+
+/// GF(2^8) multiplication by x
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// S-box from the multiplicative inverses in GF(2^8) and the affine transform
+fn sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let (mut p, mut q) = (1u8, 1u8);
+    loop {
+        // p walks the field multiplying by 3, q divides by 3, so q = p^-1
+        p ^= xtime(p);
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        let affine = q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4);
+        sbox[p as usize] = affine ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    sbox[0] = 0x63;
+    sbox
+}
+
+impl Aes128 {
+    fn new(key: &[u8; 16]) -> Self {
+        let sbox = sbox();
+        let mut round_keys = [[0u8; 16]; 11];
+        round_keys[0] = *key;
+        let mut rcon = 1u8;
+        for round in 1..11 {
+            let prev = round_keys[round - 1];
+            let mut word = [prev[13], prev[14], prev[15], prev[12]];
+            for b in &mut word {
+                *b = sbox[*b as usize];
+            }
+            word[0] ^= rcon;
+            rcon = xtime(rcon);
+            let mut next = [0u8; 16];
+            for i in 0..16 {
+                let prior = if i < 4 { word[i] } else { next[i - 4] };
+                next[i] = prev[i] ^ prior;
+            }
+            round_keys[round] = next;
+        }
+        Aes128 { sbox, round_keys }
+    }
+
+    fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let add_round_key = |state: &mut [u8; 16], key: &[u8; 16]| state.iter_mut().zip(key).for_each(|(s, k)| *s ^= k);
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..11 {
+            // SubBytes and ShiftRows; the state is column-major, row r moves left by r
+            let state = *block;
+            for col in 0..4 {
+                for row in 0..4 {
+                    block[col * 4 + row] = self.sbox[state[((col + row) % 4) * 4 + row] as usize];
+                }
+            }
+            if round < 10 {
+                for col in block.chunks_exact_mut(4) {
+                    let [a0, a1, a2, a3] = [col[0], col[1], col[2], col[3]];
+                    let all = a0 ^ a1 ^ a2 ^ a3;
+                    col[0] ^= all ^ xtime(a0 ^ a1);
+                    col[1] ^= all ^ xtime(a1 ^ a2);
+                    col[2] ^= all ^ xtime(a2 ^ a3);
+                    col[3] ^= all ^ xtime(a3 ^ a0);
+                }
+            }
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+}
+
+/// AES-128-CFB decryption with 128-bit segments, over a stream split at any point
+pub(super) struct CfbDecryptor {
+    cipher: Aes128,
+    /// Ciphertext of the current block, the next block's input
+    feedback: [u8; 16],
+    keystream: [u8; 16],
+    pos: usize,
+}
+
+impl CfbDecryptor {
+    pub(super) fn new(key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        let cipher = Aes128::new(key);
+        let mut keystream = *iv;
+        cipher.encrypt_block(&mut keystream);
+        CfbDecryptor { cipher, feedback: [0u8; 16], keystream, pos: 0 }
+    }
+
+    /// Decrypts `data` in place, continuing where the previous call stopped
+    pub(super) fn decrypt(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.pos == 16 {
+                self.keystream = self.feedback;
+                self.cipher.encrypt_block(&mut self.keystream);
+                self.pos = 0;
+            }
+            self.feedback[self.pos] = *byte;
+            *byte ^= self.keystream[self.pos];
+            self.pos += 1;
+        }
+    }
+}
+
+/// MD5 digest of `data`
+pub(super) fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    // K[i] = floor(|sin(i + 1)| * 2^32), exact in f64
+    let k: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32).collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks_exact(64) {
+        let m: Vec<u32> = chunk.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(k[i]).wrapping_add(m[g]).rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut digest = [0u8; 16];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex<const N: usize>(hex: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn aes_matches_fips_197() {
+        let sbox = sbox();
+        assert_eq!((sbox[0x00], sbox[0x01], sbox[0x53], sbox[0xff]), (0x63, 0x7c, 0xed, 0x16));
+        // Appendix C.1
+        let mut block = unhex("00112233445566778899aabbccddeeff");
+        Aes128::new(&unhex("000102030405060708090a0b0c0d0e0f")).encrypt_block(&mut block);
+        assert_eq!(hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+        // Appendix A.1 key expansion
+        let cipher = Aes128::new(&unhex("2b7e151628aed2a6abf7158809cf4f3c"));
+        assert_eq!(hex(&cipher.round_keys[10]), "d014f9a8c9ee2589e13f0cc8b6630ca6");
+    }
+
+    #[test]
+    fn cfb_matches_sp_800_38a() {
+        // F.3.14, CFB128-AES128.Decrypt, split at arbitrary points
+        let key = unhex("2b7e151628aed2a6abf7158809cf4f3c");
+        let iv = unhex("000102030405060708090a0b0c0d0e0f");
+        let mut data: [u8; 64] = unhex(concat!(
+            "3b3fd92eb72dad20333449f8e83cfb4a",
+            "c8a64537a0b3a93fcde3cdad9f1ce58b",
+            "26751f67a3cbb140b1808cf187a4f4df",
+            "c04b05357c5d1c0eeac4c66f9ff7f2e6"
+        ));
+        let mut decryptor = CfbDecryptor::new(&key, &iv);
+        let (first, rest) = data.split_at_mut(7);
+        let (second, third) = rest.split_at_mut(25);
+        decryptor.decrypt(first);
+        decryptor.decrypt(second);
+        decryptor.decrypt(&mut []);
+        decryptor.decrypt(third);
+        assert_eq!(
+            hex(&data),
+            concat!(
+                "6bc1bee22e409f96e93d7e117393172a",
+                "ae2d8a571e03ac9c9eb76fac45af8e51",
+                "30c81c46a35ce411e5fbc1191a0a52ef",
+                "f69f2445df4f9b17ad2b417be66c3710"
+            )
+        );
+    }
+
+    #[test]
+    fn md5_matches_rfc_1321() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&md5(b"message digest")), "f96b697d7cb7938d525a2f31aaf161d0");
+        let digits = b"12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+        assert_eq!(hex(&md5(digits)), "57edf4a22be3c955ac49da2e2107b67a");
+        // Padding that needs a second block
+        assert_eq!(hex(&md5(&[b'a'; 56])), "3b0c8ac703f828b04c6c197006d17218");
+    }
+}
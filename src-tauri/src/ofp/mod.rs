@@ -0,0 +1,479 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use xmltree::Element;
+
+mod archive;
+mod crypto;
+mod mtk;
+mod ops;
+
+pub use archive::{
+    ArchiveEntry, ArchiveError, ArchiveKind, ConfigExtraction, ExtractProgress, ExtractedFile, FirmwareArchive, FlashFiles,
+    detect_archive, extract_entries, extract_for_config, find_image, flash_files, open_firmware, read_config, read_rawprogram,
+    super_configs,
+};
+use crypto::{CfbDecryptor, md5};
+
+/// Magic at offset 0x10 of the footer page, the last page of the file
+const FOOTER_MAGIC: u32 = 0x7cef;
+/// Page sizes of Qualcomm OFP files, all offsets in the footer and manifest are in pages
+const PAGE_SIZES: [u64; 2] = [0x200, 0x1000];
+/// Images are only encrypted up to here, the rest is stored in plain
+const ENCRYPTED_PREFIX: u64 = 0x40000;
+
+/// Obfuscated key sets of Qualcomm OFP files as (OFP tool versions, mask, key, IV),
+/// tried in order until one decrypts the manifest, see `key_sets`
+const QUALCOMM_KEY_SETS: [(&str, &str, &str, &str); 6] = [
+    ("V1.4.17/1.4.27", "27827963787265EF89D126B69A495A21", "82C50203285A2CE7D8C3E198383CE94C", "422DD5399181E223813CD8ECDF2E4D72"),
+    ("V1.6.17", "E11AA7BB558A436A8375FD15DDD4651F", "77DDF6A0696841F6B74782C097835169", "A739742384A44E8BA45207AD5C3700EA"),
+    ("V1.5.13", "67657963787565E837D226B69A495D21", "F6C50203515A2CE7D8C3E1F938B7E94C", "42F2D5399137E2B2813CD8ECDF2F4D72"),
+    (
+        "V1.6.6/1.6.9/1.6.17/1.6.24/1.6.26/1.7.6",
+        "3C2D518D9BF2E4279DC758CD535147C3",
+        "87C74A29709AC1BF2382276C4E8DF232",
+        "598D92E967265E9BCABE2469FE4A915E",
+    ),
+    ("V1.7.2", "8FB8FB261930260BE945B841AEFA9FD4", "E529E82B28F5A2F8831D860AE39E425D", "8A09DA60ED36F125D64709973372C1CF"),
+    ("V2.0.3", "E8AE288C0192C54BF10C5707E9C4705B", "D64FC385DCD52A3C9B5FBA8650F92EDA", "79051FD8D8B6297E2E4559E997F63B7F"),
+];
+
+/// SoC platform an OFP file was made for. Each has its own layout and key sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfpPlatform {
+    /// A footer page pointing at an encrypted XML manifest
+    Qualcomm,
+    /// A shuffled entry table at the end of the file, see `mtk`
+    Mtk,
+}
+
+impl fmt::Display for OfpPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OfpPlatform::Qualcomm => "Qualcomm",
+            OfpPlatform::Mtk => "MTK",
+        })
+    }
+}
+
+impl FromStr for OfpPlatform {
+    type Err = ArchiveError;
+
+    /// `qualcomm` (or `qcom`) and `mtk` (or `mediatek`), ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "qualcomm" | "qcom" => Ok(OfpPlatform::Qualcomm),
+            "mtk" | "mediatek" => Ok(OfpPlatform::Mtk),
+            _ => Err(ArchiveError::UnsupportedPlatform(s.to_string())),
+        }
+    }
+}
+
+/// AES key and IV of one key set, see `key_sets`
+struct KeySet {
+    /// OFP tool versions (Qualcomm) or table index (MTK) of the key set
+    version: &'static str,
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+/// Key sets of `platform`, in the order they are tried. All key derivation is done
+/// here, a new platform only needs its table added.
+///
+/// Obfuscated sets (mask, key, IV) have key and IV XORed with the mask and
+/// nibble-swapped, the AES key (IV) is then the first 16 characters of the hex MD5
+/// of the result. Some MTK tools use plain keys, 16 ASCII characters each.
+fn key_sets(platform: OfpPlatform) -> Vec<KeySet> {
+    let obfuscated = |&(version, mask, key, iv): &(&'static str, &str, &str, &str)| KeySet {
+        version,
+        key: derive_key(mask, key),
+        iv: derive_key(mask, iv),
+    };
+    match platform {
+        OfpPlatform::Qualcomm => QUALCOMM_KEY_SETS.iter().map(obfuscated).collect(),
+        OfpPlatform::Mtk => {
+            let plain = |&(version, key, iv): &(&'static str, &str, &str)| KeySet {
+                version,
+                key: ascii_key(key),
+                iv: ascii_key(iv),
+            };
+            mtk::KEY_SETS.iter().map(obfuscated).chain(mtk::PLAIN_KEY_SETS.iter().map(plain)).collect()
+        }
+    }
+}
+
+/// A file stored in an OFP archive, as listed in its manifest
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OfpEntry {
+    /// Manifest section of the entry, e.g. `Sahara`, `Config` or `Program0`; `MTK`
+    /// for the entries of MTK files, which have no sections
+    pub section: String,
+    /// Partition label, for the `<program>`-like entries of the `Program*` sections
+    pub label: Option<String>,
+    /// Name of the file, as flashing tools expect it
+    pub filename: String,
+    /// Byte offset in the OFP file
+    pub offset: u64,
+    /// Size of the file in bytes
+    pub length: u64,
+    /// Leading bytes that are encrypted: all of a Sahara programmer, none of the
+    /// digest tables, the first 256 KiB of everything else
+    pub encrypted_length: u64,
+    /// SHA-256 as listed in the manifest, if any
+    pub sha256: Option<String>,
+    pub sparse: bool,
+}
+
+impl OfpEntry {
+    /// `filename` without any directory, which is what it is extracted as
+    pub fn file_name(&self) -> Option<&str> {
+        Path::new(&self.filename).file_name().and_then(|n| n.to_str())
+    }
+}
+
+/// An opened OPlus `.ofp` firmware archive with its decrypted manifest
+#[derive(Debug, Clone)]
+pub struct OfpArchive {
+    path: PathBuf,
+    key: [u8; 16],
+    iv: [u8; 16],
+    pub platform: OfpPlatform,
+    /// OFP tool versions of the key set that decrypted the manifest
+    pub key_version: &'static str,
+    /// 1 for MTK files, whose offsets are in bytes
+    pub page_size: u64,
+    /// Decrypted manifest XML, empty for MTK files
+    pub manifest: String,
+    /// Manifest entries in order; the same file may be listed in several sections
+    pub entries: Vec<OfpEntry>,
+}
+
+impl OfpArchive {
+    /// Opens an OFP file of either platform: Qualcomm when it ends with the footer
+    /// page, MTK otherwise (see `open_encrypted`)
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ArchiveError> {
+        let path = path.as_ref();
+        match find_footer(&mut File::open(path)?)? {
+            Some(_) => Self::open_encrypted(path, OfpPlatform::Qualcomm),
+            None => Self::open_encrypted(path, OfpPlatform::Mtk),
+        }
+    }
+
+    /// Opens an OFP file of `platform`, trying only its key sets. Entries are
+    /// decrypted on the fly by `reader`, for both platforms alike.
+    ///
+    /// A Qualcomm file ends with a footer page, with the page size telling where it
+    /// starts. It holds the offset (in pages) and length of the encrypted manifest,
+    /// which is decrypted with each key set until one yields XML that parses. MTK
+    /// files are described in `mtk`. A file without the layout of `platform` is
+    /// `NotArchive`, one that no key set decrypts `UnknownKey`.
+    pub fn open_encrypted<P: AsRef<Path>>(path: P, platform: OfpPlatform) -> Result<Self, ArchiveError> {
+        match platform {
+            OfpPlatform::Qualcomm => Self::open_qualcomm(path.as_ref()),
+            OfpPlatform::Mtk => mtk::open(path.as_ref()),
+        }
+    }
+
+    fn open_qualcomm(path: &Path) -> Result<Self, ArchiveError> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        let page_size = find_footer(&mut file)?.ok_or(ArchiveError::NotArchive(ArchiveKind::Ofp))?;
+        let footer = file_size - page_size;
+        file.seek(SeekFrom::Start(footer + 0x14))?;
+        let offset = file.read_u32::<LittleEndian>()? as u64 * page_size;
+        let mut length = file.read_u32::<LittleEndian>()? as u64;
+        // Older files store a bogus length, the manifest then runs up to the footer
+        if length < 200 {
+            length = footer.saturating_sub(offset).saturating_sub(0x57);
+        }
+        if offset + length > footer {
+            return Err(ArchiveError::NotArchive(ArchiveKind::Ofp));
+        }
+        let mut encrypted = vec![0u8; length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut encrypted)?;
+
+        // A manifest that decrypts to something XML-like but does not parse is
+        // reported if no other key set does better
+        let mut manifest_error = None;
+        for KeySet { version: key_version, key, iv } in key_sets(OfpPlatform::Qualcomm) {
+            let mut data = encrypted.clone();
+            CfbDecryptor::new(&key, &iv).decrypt(&mut data);
+            let Some(start) = data.windows(5).position(|w| w == b"<?xml") else {
+                continue;
+            };
+            let end = data.iter().rposition(|&b| b == b'>').map_or(data.len(), |i| i + 1);
+            let manifest = String::from_utf8_lossy(&data[start..end.max(start)]).to_string();
+            let entries = match parse_manifest(&manifest, page_size) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    manifest_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            for entry in &entries {
+                if entry.offset + entry.length > file_size {
+                    return Err(ArchiveError::Truncated(entry.filename.clone()));
+                }
+            }
+            return Ok(OfpArchive {
+                path: path.to_path_buf(),
+                key,
+                iv,
+                platform: OfpPlatform::Qualcomm,
+                key_version,
+                page_size,
+                manifest,
+                entries,
+            });
+        }
+        Err(manifest_error.unwrap_or(ArchiveError::UnknownKey(ArchiveKind::Ofp)))
+    }
+
+    /// First entry whose file name is `filename`, ignoring case and directories
+    pub fn entry(&self, filename: &str) -> Option<&OfpEntry> {
+        let wanted = Path::new(filename.trim()).file_name().and_then(|n| n.to_str())?;
+        self.entries.iter().find(|e| e.length > 0 && e.file_name().is_some_and(|n| n.eq_ignore_ascii_case(wanted)))
+    }
+
+    /// Reader of the decrypted contents of `entry`
+    pub fn reader(&self, entry: &OfpEntry) -> Result<EntryReader, ArchiveError> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        Ok(EntryReader {
+            source: file.take(entry.length),
+            decryptor: CfbDecryptor::new(&self.key, &self.iv),
+            encrypted_left: entry.encrypted_length,
+        })
+    }
+}
+
+impl FirmwareArchive for OfpArchive {
+    fn kind(&self) -> ArchiveKind {
+        ArchiveKind::Ofp
+    }
+
+    fn list(&self) -> Vec<ArchiveEntry> {
+        let mut list: Vec<ArchiveEntry> = Vec::new();
+        for entry in self.entries.iter().filter(|e| e.length > 0) {
+            let Some(name) = entry.file_name() else { continue };
+            if list.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            list.push(ArchiveEntry { name: name.to_string(), label: entry.label.clone(), size: entry.length, offset: entry.offset });
+        }
+        list
+    }
+
+    fn open_entry(&self, name: &str) -> Result<Box<dyn Read + '_>, ArchiveError> {
+        let entry = self.entry(name).ok_or_else(|| ArchiveError::NotFound(name.to_string()))?;
+        Ok(Box::new(self.reader(entry)?))
+    }
+}
+
+/// Decrypted contents of an OFP entry, see `OfpArchive::reader`
+pub struct EntryReader {
+    source: Take<File>,
+    decryptor: CfbDecryptor,
+    /// Bytes still to decrypt, the rest of the entry is plain
+    encrypted_left: u64,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read(buf)?;
+        let encrypted = self.encrypted_left.min(n as u64) as usize;
+        self.decryptor.decrypt(&mut buf[..encrypted]);
+        self.encrypted_left -= encrypted as u64;
+        Ok(n)
+    }
+}
+
+/// Page size of the footer both OFP and OPS files end with, `None` without one
+fn find_footer(file: &mut File) -> io::Result<Option<u64>> {
+    let file_size = file.metadata()?.len();
+    for size in PAGE_SIZES {
+        if file_size < size {
+            continue;
+        }
+        file.seek(SeekFrom::Start(file_size - size + 0x10))?;
+        if file.read_u32::<LittleEndian>()? == FOOTER_MAGIC {
+            return Ok(Some(size));
+        }
+    }
+    Ok(None)
+}
+
+/// Key or IV of a key set: `data` XORed with `mask`, nibbles swapped, then the first
+/// half of the hex MD5 as ASCII
+fn derive_key(mask: &str, data: &str) -> [u8; 16] {
+    let plain: Vec<u8> = from_hex(data).iter().zip(from_hex(mask)).map(|(d, m)| (d ^ m).rotate_left(4)).collect();
+    let hex: String = md5(&plain).iter().map(|b| format!("{:02x}", b)).collect();
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&hex.as_bytes()[..16]);
+    key
+}
+
+/// Key or IV of a plain key set, the ASCII characters of `text`
+fn ascii_key(text: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&text.as_bytes()[..16]);
+    key
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2).filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()).collect()
+}
+
+/// Reads the file entries of the manifest: every element below a section with a
+/// `Path` or `filename` and a `FileOffsetInSrc`
+fn parse_manifest(xml: &str, page_size: u64) -> Result<Vec<OfpEntry>, ArchiveError> {
+    let root = Element::parse(xml.as_bytes()).map_err(|e| ArchiveError::Xml(e.to_string()))?;
+    let mut entries = Vec::new();
+    for section in root.children.iter().filter_map(|n| n.as_element()) {
+        for item in section.children.iter().filter_map(|n| n.as_element()) {
+            let attr = |name: &str| item.attributes.get(name).map(|v| v.trim()).filter(|v| !v.is_empty());
+            let number = |name: &str| attr(name).and_then(|v| v.parse::<u64>().ok());
+            let (Some(filename), Some(offset)) = (attr("Path").or_else(|| attr("filename")), number("FileOffsetInSrc")) else {
+                continue;
+            };
+            let length = number("SizeInByteInSrc").or_else(|| number("SizeInSectorInSrc").map(|s| s * page_size)).unwrap_or(0);
+            let encrypted_length = match section.name.as_str() {
+                "Sahara" => length,
+                "DigestsToSign" | "ChainedTableOfDigests" | "Firmware" => 0,
+                _ => length.min(ENCRYPTED_PREFIX),
+            };
+            entries.push(OfpEntry {
+                section: section.name.clone(),
+                label: attr("label").map(str::to_string),
+                filename: filename.to_string(),
+                offset: offset * page_size,
+                length,
+                encrypted_length,
+                sha256: attr("Sha256").or_else(|| attr("sha256")).map(str::to_string),
+                sparse: attr("sparse").is_some_and(|v| v.eq_ignore_ascii_case("true")),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 0x1000;
+
+    /// AES-128-CFB encryption, built from the decryptor: the keystream of a block is
+    /// what decrypting zeros with the previous ciphertext block as IV yields
+    fn encrypt(key: &[u8; 16], iv: &[u8; 16], data: &mut [u8]) {
+        let mut previous = *iv;
+        for block in data.chunks_mut(16) {
+            let mut keystream = [0u8; 16];
+            CfbDecryptor::new(key, &previous).decrypt(&mut keystream);
+            block.iter_mut().zip(keystream).for_each(|(b, k)| *b ^= k);
+            if block.len() == 16 {
+                previous.copy_from_slice(block);
+            }
+        }
+    }
+
+    /// A Qualcomm OFP file of `system.img` and a programmer, encrypted with the
+    /// `key_set`th key set, as (file, system.img)
+    fn qualcomm_ofp(key_set: usize) -> (Vec<u8>, Vec<u8>) {
+        let (_, mask, key, iv) = QUALCOMM_KEY_SETS[key_set];
+        let (key, iv) = (derive_key(mask, key), derive_key(mask, iv));
+        let mut file = Vec::new();
+        // Appends `data` at the next page with its first `encrypted` bytes encrypted
+        let mut put = |data: &[u8], encrypted: usize| {
+            let page = file.len() / PAGE;
+            let mut data = data.to_vec();
+            let encrypted = encrypted.min(data.len());
+            encrypt(&key, &iv, &mut data[..encrypted]);
+            file.extend_from_slice(&data);
+            file.resize(file.len().div_ceil(PAGE) * PAGE, 0);
+            page
+        };
+        let system: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        let system_page = put(&system, ENCRYPTED_PREFIX as usize);
+        let programmer_page = put(b"\x7fELF programmer", usize::MAX);
+        let manifest = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<ProFile>
+  <Sahara><File Path="prog_firehose_ddr.elf" FileOffsetInSrc="{programmer_page}" SizeInByteInSrc="15"/></Sahara>
+  <Program0>
+    <program label="system_a" filename="system.img" FileOffsetInSrc="{system_page}" SizeInByteInSrc="{}" sparse="false" Sha256="ab12"/>
+    <program label="misc" filename="" FileOffsetInSrc="0"/>
+  </Program0>
+</ProFile>"#,
+            system.len()
+        );
+        let manifest_page = put(manifest.as_bytes(), usize::MAX);
+        let mut footer = vec![0u8; PAGE];
+        footer[0x10..0x14].copy_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        footer[0x14..0x18].copy_from_slice(&(manifest_page as u32).to_le_bytes());
+        footer[0x18..0x1c].copy_from_slice(&(manifest.len() as u32).to_le_bytes());
+        file.extend_from_slice(&footer);
+        (file, system)
+    }
+
+    fn write_ofp(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ofp-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn the_decrypted_manifest_parses_as_xml() {
+        let (file, system) = qualcomm_ofp(5);
+        let path = write_ofp("qualcomm.ofp", &file);
+        let archive = OfpArchive::open_encrypted(&path, OfpPlatform::Qualcomm).unwrap();
+        assert_eq!((archive.platform, archive.key_version, archive.page_size), (OfpPlatform::Qualcomm, "V2.0.3", PAGE as u64));
+        let root = Element::parse(archive.manifest.as_bytes()).unwrap();
+        assert_eq!(root.name, "ProFile");
+        assert!(archive.manifest.ends_with("</ProFile>"));
+
+        let names: Vec<(&str, &str, u64)> = archive.entries.iter().map(|e| (e.section.as_str(), e.filename.as_str(), e.encrypted_length)).collect();
+        assert_eq!(names, [("Sahara", "prog_firehose_ddr.elf", 15), ("Program0", "system.img", ENCRYPTED_PREFIX)]);
+        assert_eq!(archive.entries[1].label.as_deref(), Some("system_a"));
+        assert_eq!(archive.entries[1].sha256.as_deref(), Some("ab12"));
+
+        // Entries come out decrypted, images only had their first 256 KiB encrypted
+        let mut data = Vec::new();
+        archive.open_entry("SYSTEM.IMG").unwrap().read_to_end(&mut data).unwrap();
+        assert!(data == system);
+        data.clear();
+        archive.open_entry("prog_firehose_ddr.elf").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"\x7fELF programmer");
+        assert_eq!(OfpArchive::open(&path).unwrap().key_version, "V2.0.3");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unknown_keys_and_platforms_are_rejected() {
+        // A key set that is not in the table decrypts the manifest to garbage
+        let (mut file, _) = qualcomm_ofp(0);
+        let footer = file.len() - PAGE;
+        let manifest = u32::from_le_bytes(file[footer + 0x14..footer + 0x18].try_into().unwrap()) as usize * PAGE;
+        let mut garbage = [0u8; 16];
+        CfbDecryptor::new(&[7; 16], &[9; 16]).decrypt(&mut garbage);
+        file[manifest..manifest + 16].iter_mut().zip(garbage).for_each(|(b, g)| *b ^= g);
+        let path = write_ofp("unknown-key.ofp", &file);
+        assert!(matches!(OfpArchive::open_encrypted(&path, OfpPlatform::Qualcomm), Err(ArchiveError::UnknownKey(ArchiveKind::Ofp))));
+        let _ = std::fs::remove_file(&path);
+
+        let path = write_ofp("no-footer.ofp", &[0u8; 2 * PAGE]);
+        assert!(matches!(OfpArchive::open_encrypted(&path, OfpPlatform::Qualcomm), Err(ArchiveError::NotArchive(ArchiveKind::Ofp))));
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!("QCOM".parse::<OfpPlatform>().unwrap(), OfpPlatform::Qualcomm);
+        assert_eq!(" MediaTek".parse::<OfpPlatform>().unwrap(), OfpPlatform::Mtk);
+        assert!(matches!("exynos".parse::<OfpPlatform>(), Err(ArchiveError::UnsupportedPlatform(name)) if name == "exynos"));
+    }
+}
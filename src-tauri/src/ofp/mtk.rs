@@ -0,0 +1,125 @@
+//! MediaTek `.ofp` archives. They have no footer page or XML manifest: the last
+//! 0x6C bytes are a header with the number of entries, preceded by a table of
+//! 0x60 bytes per entry (name, offset, length, encrypted length, file name). Header
+//! and table are obfuscated with a fixed shuffle, the entries are AES-CFB encrypted
+//! up to their encrypted length like Qualcomm ones. The file starts with the
+//! preloader, which decrypts to `MMM`, so the key set is found from the first block.
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::archive::{ArchiveError, ArchiveKind};
+use super::{CfbDecryptor, KeySet, OfpArchive, OfpEntry, OfpPlatform, key_sets};
+
+/// Key of the header and entry table shuffle
+const SHUFFLE_KEY: &[u8] = b"geyixue";
+const HEADER_LENGTH: u64 = 0x6c;
+const ENTRY_LENGTH: u64 = 0x60;
+/// Offset of the entry count in the header
+const ENTRY_COUNT_OFFSET: usize = 0x48;
+/// Start of the decrypted preloader
+const PRELOADER_MAGIC: &[u8] = b"MMM";
+
+/// Obfuscated key sets of MTK OFP files as (name, mask, key, IV), see `key_sets`
+pub(super) const KEY_SETS: [(&str, &str, &str, &str); 8] = [
+    ("MTK 1", "67657963787565E837D226B69A495D21", "F6C50203515A2CE7D8C3E1F938B7E94C", "42F2D5399137E2B2813CD8ECDF2F4D72"),
+    ("MTK 2", "9E4F32639D21357D37D226B69A495D21", "A3D8D358E42F5A9E931DD3917D9A3218", "386935399137416B67416BECF22F519A"),
+    ("MTK 3", "892D57E92A4D8A975E3C216B7C9DE189", "D26DF2D9913785B145D18C7219B89F26", "516989E4A1BFC78B365C6BC57D944391"),
+    ("MTK 4", "27827963787265EF89D126B69A495A21", "82C50203285A2CE7D8C3E198383CE94C", "422DD5399181E223813CD8ECDF2E4D72"),
+    ("MTK 5", "3C4A618D9BF2E4279DC758CD535147C3", "87B13D29709AC1BF2382276C4E8DF232", "59B7A8E967265E9BCABE2469FE4A915E"),
+    ("MTK 6", "1C3288822BF824259DC852C1733127D3", "E7918D22799181CF2312176C9E2DF298", "3247F889A7B6DECBCA3E28693E4AAAFE"),
+    ("MTK 7", "1E4F32239D65A57D37D2266D9A775D43", "A332D3C3E42F5A3E931DD991729A321D", "3F2A35399A373377674155ECF28FD19A"),
+    ("MTK 8", "122D57E92A518AFF5E3C786B7C34E189", "DD6DF2D9543785674522717219989FB0", "12698965A132C76136CC88C5DD94EE91"),
+];
+
+/// Plain key sets of MTK OFP files as (name, key, IV)
+pub(super) const PLAIN_KEY_SETS: [(&str, &str, &str); 1] = [("MTK 9", "ab3f76d7989207f2", "2bf515b3a9737835")];
+
+/// Undoes the shuffle of the header and entry table: each byte is nibble-swapped
+/// and XORed with the key
+fn unshuffle(data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = byte.rotate_left(4) ^ SHUFFLE_KEY[i % SHUFFLE_KEY.len()];
+    }
+}
+
+/// Text of a NUL padded name field, `None` if it is not UTF-8
+fn name_field(field: &[u8]) -> Option<String> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).ok().map(|name| name.trim().to_string())
+}
+
+/// The entry table at the end of `file`, `None` if the file does not end with one:
+/// no entries, a table larger than the file, entries outside the data before it or
+/// names that are not text
+pub(super) fn read_entries(file: &mut File) -> io::Result<Option<Vec<OfpEntry>>> {
+    let file_size = file.metadata()?.len();
+    if file_size < HEADER_LENGTH {
+        return Ok(None);
+    }
+    let mut header = [0u8; HEADER_LENGTH as usize];
+    file.seek(SeekFrom::Start(file_size - HEADER_LENGTH))?;
+    file.read_exact(&mut header)?;
+    unshuffle(&mut header);
+    let count = LittleEndian::read_u16(&header[ENTRY_COUNT_OFFSET..]) as u64;
+    let table_length = count * ENTRY_LENGTH;
+    if count == 0 || table_length + HEADER_LENGTH > file_size {
+        return Ok(None);
+    }
+    let data_end = file_size - HEADER_LENGTH - table_length;
+    let mut table = vec![0u8; table_length as usize];
+    file.seek(SeekFrom::Start(data_end))?;
+    file.read_exact(&mut table)?;
+    unshuffle(&mut table);
+
+    let mut entries = Vec::new();
+    for raw in table.chunks_exact(ENTRY_LENGTH as usize) {
+        let offset = LittleEndian::read_u64(&raw[0x20..]);
+        let length = LittleEndian::read_u64(&raw[0x28..]);
+        let encrypted_length = LittleEndian::read_u64(&raw[0x30..]);
+        let (Some(label), Some(filename)) = (name_field(&raw[..0x20]), name_field(&raw[0x38..0x58])) else {
+            return Ok(None);
+        };
+        if encrypted_length > length || offset.checked_add(length).is_none_or(|end| end > data_end) {
+            return Ok(None);
+        }
+        entries.push(OfpEntry {
+            section: "MTK".to_string(),
+            label: Some(label).filter(|l| !l.is_empty()),
+            filename,
+            offset,
+            length,
+            encrypted_length,
+            sha256: None,
+            sparse: false,
+        });
+    }
+    Ok(Some(entries))
+}
+
+/// Opens an MTK OFP file, see `OfpArchive::open_encrypted`
+pub(super) fn open(path: &Path) -> Result<OfpArchive, ArchiveError> {
+    let mut file = File::open(path)?;
+    let entries = read_entries(&mut file)?.ok_or(ArchiveError::NotArchive(ArchiveKind::Ofp))?;
+    let mut head = [0u8; 16];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut head)?;
+    for KeySet { version, key, iv } in key_sets(OfpPlatform::Mtk) {
+        let mut probe = head;
+        CfbDecryptor::new(&key, &iv).decrypt(&mut probe);
+        if probe.starts_with(PRELOADER_MAGIC) {
+            return Ok(OfpArchive {
+                path: path.to_path_buf(),
+                key,
+                iv,
+                platform: OfpPlatform::Mtk,
+                key_version: version,
+                page_size: 1,
+                manifest: String::new(),
+                entries,
+            });
+        }
+    }
+    Err(ArchiveError::UnknownKey(ArchiveKind::Ofp))
+}
@@ -0,0 +1,18 @@
+//! OnePlus `.ops` archives. They end with the same footer as OFP files (in a 512
+//! byte page), but `settings.xml` and the images are encrypted with OnePlus' own
+//! cipher, whose key schemes are not implemented here. Opening one therefore stops
+//! at `ArchiveError::UnknownKey(ArchiveKind::Ops)` once the footer is found.
+use std::fs::File;
+use std::path::Path;
+
+use super::archive::{ArchiveError, ArchiveKind};
+
+/// Why an OPS file cannot be opened
+pub(super) fn open(path: &Path) -> ArchiveError {
+    let footer = File::open(path).map_err(ArchiveError::from).and_then(|mut file| Ok(super::find_footer(&mut file)?));
+    match footer {
+        Ok(Some(_)) => ArchiveError::UnknownKey(ArchiveKind::Ops),
+        Ok(None) => ArchiveError::NotArchive(ArchiveKind::Ops),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,620 @@
+//! Partition images from `payload.bin`, the update_engine package inside Android
+//! A/B OTA zips.
+//!
+//! A payload starts with a `CrAU` header and a protobuf `DeltaArchiveManifest`
+//! listing every partition with its size, SHA-256 and install operations, followed
+//! by the operation data. Full OTAs write each partition with `REPLACE`,
+//! `REPLACE_BZ`, `REPLACE_XZ` and `ZERO` operations only, which is all that is
+//! supported here; delta OTAs patch the previous build and are rejected.
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+mod bzip2;
+mod proto;
+pub(crate) mod xz;
+pub(crate) mod zstd;
+
+use proto::Fields;
+
+const PAYLOAD_MAGIC: &[u8; 4] = b"CrAU";
+/// Minor version of full payloads, anything else needs the source build
+const FULL_PAYLOAD_MINOR_VERSION: u64 = 0;
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not an update_engine payload (no CrAU header)")]
+    NotPayload,
+    #[error("Unsupported payload version {0}")]
+    UnsupportedVersion(u64),
+    #[error("Invalid payload manifest: {0}")]
+    Manifest(String),
+    #[error("No partition '{0}' in the payload")]
+    NotFound(String),
+    #[error("'{partition}' is part of a delta OTA ({operation}), only full OTAs can be extracted")]
+    Delta { partition: String, operation: String },
+    #[error("Payload has no SHA-256 for '{0}', refusing to extract it unverified")]
+    MissingHash(String),
+    #[error("Failed to decompress '{partition}': {reason}")]
+    Decompress { partition: String, reason: &'static str },
+    #[error("'{partition}' does not fit its operations: {reason}")]
+    BadOperation { partition: String, reason: String },
+    #[error("SHA-256 of '{partition}' is {actual}, the payload expects {expected}")]
+    HashMismatch { partition: String, expected: String, actual: String },
+}
+
+/// Install operation types of `update_metadata.proto`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OperationKind {
+    Replace,
+    ReplaceBz,
+    ReplaceXz,
+    Zero,
+    Discard,
+    /// Needs the source partition (`SOURCE_COPY`, the diff types, ...)
+    Delta(&'static str),
+}
+
+impl OperationKind {
+    fn from_proto(value: u64) -> Self {
+        match value {
+            0 => OperationKind::Replace,
+            1 => OperationKind::ReplaceBz,
+            8 => OperationKind::ReplaceXz,
+            6 => OperationKind::Zero,
+            7 => OperationKind::Discard,
+            2 => OperationKind::Delta("MOVE"),
+            3 => OperationKind::Delta("BSDIFF"),
+            4 => OperationKind::Delta("SOURCE_COPY"),
+            5 => OperationKind::Delta("SOURCE_BSDIFF"),
+            9 => OperationKind::Delta("PUFFDIFF"),
+            10 => OperationKind::Delta("BROTLI_BSDIFF"),
+            11 => OperationKind::Delta("ZUCCHINI"),
+            12 => OperationKind::Delta("LZ4DIFF_BSDIFF"),
+            13 => OperationKind::Delta("LZ4DIFF_PUFFDIFF"),
+            _ => OperationKind::Delta("unknown operation"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Operation {
+    kind: OperationKind,
+    /// Offset from the start of the payload data
+    data_offset: u64,
+    data_length: u64,
+    /// Destination as (start block, block count)
+    dst_extents: Vec<(u64, u64)>,
+}
+
+/// A partition listed in the payload manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct PayloadPartition {
+    pub name: String,
+    /// Size of the extracted image in bytes
+    pub size: u64,
+    /// SHA-256 (hex) of the extracted image
+    pub sha256: Option<String>,
+    /// Whether the partition is patched from the previous build
+    pub delta: bool,
+    #[serde(skip)]
+    operations: Vec<Operation>,
+}
+
+/// An opened `payload.bin` with its parsed manifest
+#[derive(Debug, Clone)]
+pub struct Payload {
+    path: PathBuf,
+    /// Offset of the operation data in the file
+    data_offset: u64,
+    pub version: u64,
+    pub block_size: u64,
+    /// 0 for full payloads
+    pub minor_version: u64,
+    pub partitions: Vec<PayloadPartition>,
+}
+
+impl Payload {
+    /// Reads the header and manifest of a payload file.
+    ///
+    /// The header is `CrAU`, a big endian u64 version (1 or 2), the u64 manifest size
+    /// and, from version 2 on, the u32 size of the metadata signature following the
+    /// manifest. Operation data starts right after the signature.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PayloadError> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 24];
+        file.read_exact(&mut header[..20]).map_err(|_| PayloadError::NotPayload)?;
+        if &header[..4] != PAYLOAD_MAGIC {
+            return Err(PayloadError::NotPayload);
+        }
+        let version = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let manifest_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let (header_size, signature_size) = match version {
+            1 => (20, 0),
+            2 => {
+                file.read_exact(&mut header[20..24])?;
+                (24, u32::from_be_bytes(header[20..24].try_into().unwrap()) as u64)
+            }
+            _ => return Err(PayloadError::UnsupportedVersion(version)),
+        };
+        let past_end = || PayloadError::Manifest("manifest runs past the end of the file".to_string());
+        let data_offset = manifest_size.checked_add(header_size + signature_size).ok_or_else(past_end)?;
+        if data_offset > file.metadata()?.len() {
+            return Err(past_end());
+        }
+        let mut manifest = vec![0u8; manifest_size as usize];
+        file.read_exact(&mut manifest)?;
+
+        let (block_size, minor_version, partitions) = parse_manifest(&manifest).map_err(|e| PayloadError::Manifest(e.to_string()))?;
+        Ok(Payload {
+            path: path.to_path_buf(),
+            data_offset,
+            version,
+            block_size,
+            minor_version,
+            partitions,
+        })
+    }
+
+    pub fn partition(&self, name: &str) -> Option<&PayloadPartition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Reader of the image of partition `name`, see `PartitionReader`
+    pub fn reader(&self, name: &str) -> Result<PartitionReader, PayloadError> {
+        let partition = self.partition(name).ok_or_else(|| PayloadError::NotFound(name.to_string()))?;
+        if let Some(operation) = partition.operations.iter().find_map(|op| match op.kind {
+            OperationKind::Delta(operation) => Some(operation),
+            _ => None,
+        }) {
+            return Err(PayloadError::Delta { partition: name.to_string(), operation: operation.to_string() });
+        }
+        if self.minor_version != FULL_PAYLOAD_MINOR_VERSION || partition.delta {
+            let operation = format!("minor version {}", self.minor_version);
+            return Err(PayloadError::Delta { partition: name.to_string(), operation });
+        }
+        let expected = partition.sha256.clone().ok_or_else(|| PayloadError::MissingHash(name.to_string()))?;
+
+        // Operation data laid out in partition order, so the image reads front to back
+        let bad = |reason: &str| PayloadError::BadOperation { partition: name.to_string(), reason: reason.to_string() };
+        let file = File::open(&self.path)?;
+        let file_size = file.metadata()?.len();
+        let partition_end = partition.size.checked_next_multiple_of(self.block_size).ok_or_else(|| bad("partition size overflows"))?;
+        let mut pieces = Vec::new();
+        for (index, op) in partition.operations.iter().enumerate() {
+            // Checked before anything is allocated for the data
+            let data_end = op.data_offset.checked_add(op.data_length).and_then(|end| end.checked_add(self.data_offset));
+            if data_end.is_none_or(|end| end > file_size) {
+                return Err(bad("operation data past the end of the file"));
+            }
+            let mut op_offset = 0u64;
+            for &(start_block, num_blocks) in &op.dst_extents {
+                let offset = start_block.checked_mul(self.block_size).ok_or_else(|| bad("extent offset overflows"))?;
+                let len = num_blocks.checked_mul(self.block_size).ok_or_else(|| bad("extent length overflows"))?;
+                if offset.checked_add(len).is_none_or(|end| end > partition_end) {
+                    return Err(bad("extent past the partition end"));
+                }
+                pieces.push(Piece { offset, len, op: index, op_offset });
+                op_offset = op_offset.checked_add(len).ok_or_else(|| bad("operation output overflows"))?;
+            }
+        }
+        pieces.sort_by_key(|p| p.offset);
+        if pieces.windows(2).any(|w| w[0].offset + w[0].len > w[1].offset) {
+            return Err(bad("overlapping extents"));
+        }
+
+        let mut file = file;
+        file.seek(SeekFrom::Start(self.data_offset))?;
+        Ok(PartitionReader {
+            file,
+            data_offset: self.data_offset,
+            name: name.to_string(),
+            size: partition.size,
+            expected,
+            operations: partition.operations.clone(),
+            pieces,
+            next_piece: 0,
+            decoded: None,
+            pos: 0,
+            hasher: Sha256::new(),
+            verified: false,
+        })
+    }
+
+    /// Writes the image of partition `name` to `out`, verified against the SHA-256 of
+    /// the manifest. The file is removed again if extraction or verification fails.
+    pub fn extract(&self, name: &str, out: &Path) -> Result<(), PayloadError> {
+        let mut reader = self.reader(name)?;
+        let result = (|| {
+            let mut file = BufWriter::with_capacity(COPY_BUFFER_SIZE, File::create(out)?);
+            io::copy(&mut reader, &mut file).map_err(from_read_error)?;
+            Ok(file.flush()?)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(out);
+        }
+        result
+    }
+}
+
+/// Destination range of (part of) an operation's output
+#[derive(Debug, Clone)]
+struct Piece {
+    /// Byte offset and length in the partition
+    offset: u64,
+    len: u64,
+    /// Index into `operations` and offset into the operation's output
+    op: usize,
+    op_offset: u64,
+}
+
+/// The image of a payload partition, decoded as it is read.
+///
+/// Ranges no operation writes read as zeros, like the zero-filled partition
+/// update_engine starts from. The SHA-256 of everything read is checked at the end:
+/// the read delivering the last byte of the image fails with `ErrorKind::InvalidData`
+/// (wrapping `PayloadError::HashMismatch`) if it does not match, so a corrupt
+/// image never reads through to a successful end.
+pub struct PartitionReader {
+    file: File,
+    data_offset: u64,
+    name: String,
+    size: u64,
+    expected: String,
+    operations: Vec<Operation>,
+    pieces: Vec<Piece>,
+    next_piece: usize,
+    /// Output of the last decoded operation, by index
+    decoded: Option<(usize, Vec<u8>)>,
+    pos: u64,
+    hasher: Sha256,
+    verified: bool,
+}
+
+impl PartitionReader {
+    /// Size of the image in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Output of operation `index`, decoded once and kept until the next one
+    fn operation_output(&mut self, index: usize) -> Result<&[u8], PayloadError> {
+        if self.decoded.as_ref().is_none_or(|(i, _)| *i != index) {
+            let op = &self.operations[index];
+            let mut data = vec![0u8; op.data_length as usize];
+            self.file.seek(SeekFrom::Start(self.data_offset + op.data_offset))?;
+            self.file.read_exact(&mut data)?;
+            let decompress_error = |reason| PayloadError::Decompress { partition: self.name.clone(), reason };
+            let output = match op.kind {
+                OperationKind::ReplaceBz => bzip2::decompress(&data).map_err(decompress_error)?,
+                OperationKind::ReplaceXz => xz::decompress(&data).map_err(decompress_error)?,
+                _ => data,
+            };
+            self.decoded = Some((index, output));
+        }
+        Ok(&self.decoded.as_ref().unwrap().1)
+    }
+
+    /// Fills `buf` from `self.pos` on, within a single piece or gap
+    fn fill(&mut self, buf: &mut [u8]) -> Result<usize, PayloadError> {
+        while self.pieces.get(self.next_piece).is_some_and(|p| p.offset + p.len <= self.pos) {
+            self.next_piece += 1;
+        }
+        let left = (self.size - self.pos).min(buf.len() as u64);
+        let piece = self.pieces.get(self.next_piece).cloned();
+        let Some(piece) = piece.clone().filter(|p| p.offset <= self.pos) else {
+            // Gap up to the next piece
+            let gap_end = piece.map_or(self.size, |p| p.offset);
+            let n = left.min(gap_end - self.pos) as usize;
+            buf[..n].fill(0);
+            return Ok(n);
+        };
+        let n = left.min(piece.offset + piece.len - self.pos) as usize;
+        let start = (piece.op_offset + self.pos - piece.offset) as usize;
+        let (name, kind) = (self.name.clone(), self.operations[piece.op].kind);
+        if matches!(kind, OperationKind::Zero | OperationKind::Discard) {
+            buf[..n].fill(0);
+            return Ok(n);
+        }
+        let output = self.operation_output(piece.op)?;
+        let data = output.get(start..start + n).ok_or_else(|| PayloadError::BadOperation {
+            partition: name,
+            reason: format!("operation {} yields {} bytes, its extents need more", piece.op, output.len()),
+        })?;
+        buf[..n].copy_from_slice(data);
+        Ok(n)
+    }
+
+    fn verify(&mut self) -> Result<(), PayloadError> {
+        let actual: String = self.hasher.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        if !actual.eq_ignore_ascii_case(&self.expected) {
+            return Err(PayloadError::HashMismatch { partition: self.name.clone(), expected: self.expected.clone(), actual });
+        }
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl Read for PartitionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.size {
+            if !self.verified {
+                self.verify().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            return Ok(0);
+        }
+        let n = self.fill(buf).map_err(|e| match e {
+            PayloadError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        self.hasher.update(&buf[..n]);
+        self.pos += n as u64;
+        // Readers that stop at `size()` (`read_exact`, `take`) never ask past the end
+        if self.pos >= self.size {
+            self.verify().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(n)
+    }
+}
+
+/// The `PayloadError` behind an error of `PartitionReader::read`
+fn from_read_error(e: io::Error) -> PayloadError {
+    if e.get_ref().is_some_and(|inner| inner.is::<PayloadError>()) {
+        return *e.into_inner().unwrap().downcast::<PayloadError>().unwrap();
+    }
+    PayloadError::Io(e)
+}
+
+/// Splits a config image path like `payload.bin#system` into the payload path and
+/// the partition name; `None` without a `#` or with an empty side
+pub fn split_payload_path(path: &str) -> Option<(&str, &str)> {
+    let (payload, partition) = path.trim().rsplit_once('#')?;
+    if payload.is_empty() || partition.is_empty() || partition.contains(['/', '\\']) {
+        return None;
+    }
+    Some((payload, partition))
+}
+
+/// Extracts partition `name` of the payload at `payload` to `out`, verified
+/// against the SHA-256 of the manifest
+pub fn extract_payload_partition(payload: &Path, name: &str, out: &Path) -> Result<(), PayloadError> {
+    Payload::open(payload)?.extract(name, out)
+}
+
+/// Extracts several partitions (all of them if `names` is empty) to
+/// `<out_dir>/<name>.img`, reading the manifest once. Stops at the first failure,
+/// keeping the images extracted before it.
+pub fn extract_payload_partitions(payload: &Path, names: &[&str], out_dir: &Path) -> Result<Vec<PathBuf>, PayloadError> {
+    let payload = Payload::open(payload)?;
+    let names: Vec<&str> = if names.is_empty() { payload.partitions.iter().map(|p| p.name.as_str()).collect() } else { names.to_vec() };
+    if let Some(missing) = names.iter().find(|name| payload.partition(name).is_none()) {
+        return Err(PayloadError::NotFound(missing.to_string()));
+    }
+    fs::create_dir_all(out_dir)?;
+    let mut paths = Vec::new();
+    for name in names {
+        let path = out_dir.join(format!("{}.img", name));
+        payload.extract(name, &path)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+type Manifest = (u64, u64, Vec<PayloadPartition>);
+
+/// Block size, minor version and partitions of a `DeltaArchiveManifest`
+fn parse_manifest(data: &[u8]) -> Result<Manifest, &'static str> {
+    let (mut block_size, mut minor_version, mut partitions) = (4096, 0, Vec::new());
+    for field in Fields::new(data) {
+        match field? {
+            (3, value) => block_size = value.as_u64().ok_or("block_size is not a number")?,
+            (12, value) => minor_version = value.as_u64().ok_or("minor_version is not a number")?,
+            (13, value) => partitions.push(parse_partition(value.as_bytes().ok_or("partition is not a message")?)?),
+            _ => {}
+        }
+    }
+    if block_size == 0 {
+        return Err("block size is 0");
+    }
+    Ok((block_size, minor_version, partitions))
+}
+
+/// `PartitionUpdate`: name (1), old (6) and new (7) `PartitionInfo` and operations (8)
+fn parse_partition(data: &[u8]) -> Result<PayloadPartition, &'static str> {
+    let mut partition = PayloadPartition { name: String::new(), size: 0, sha256: None, delta: false, operations: Vec::new() };
+    for field in Fields::new(data) {
+        let (number, value) = field?;
+        let bytes = || value.as_bytes().ok_or("expected a length-delimited field");
+        match number {
+            1 => partition.name = String::from_utf8_lossy(bytes()?).to_string(),
+            6 => partition.delta = true,
+            7 => {
+                for field in Fields::new(bytes()?) {
+                    match field? {
+                        (1, size) => partition.size = size.as_u64().ok_or("size is not a number")?,
+                        (2, hash) => {
+                            let hash = hash.as_bytes().ok_or("hash is not bytes")?;
+                            partition.sha256 = Some(hash.iter().map(|b| format!("{:02x}", b)).collect());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            8 => partition.operations.push(parse_operation(bytes()?)?),
+            _ => {}
+        }
+    }
+    if partition.name.is_empty() {
+        return Err("partition without a name");
+    }
+    Ok(partition)
+}
+
+/// `InstallOperation`: type (1), data offset (2) and length (3), destination
+/// extents (6)
+fn parse_operation(data: &[u8]) -> Result<Operation, &'static str> {
+    let mut op = Operation { kind: OperationKind::Replace, data_offset: 0, data_length: 0, dst_extents: Vec::new() };
+    for field in Fields::new(data) {
+        let (number, value) = field?;
+        match number {
+            1 => op.kind = OperationKind::from_proto(value.as_u64().ok_or("type is not a number")?),
+            2 => op.data_offset = value.as_u64().ok_or("data_offset is not a number")?,
+            3 => op.data_length = value.as_u64().ok_or("data_length is not a number")?,
+            6 => {
+                let (mut start, mut count) = (0, 0);
+                for field in Fields::new(value.as_bytes().ok_or("extent is not a message")?) {
+                    match field? {
+                        (1, v) => start = v.as_u64().ok_or("start_block is not a number")?,
+                        (2, v) => count = v.as_u64().ok_or("num_blocks is not a number")?,
+                        _ => {}
+                    }
+                }
+                op.dst_extents.push((start, count));
+            }
+            _ => {}
+        }
+    }
+    Ok(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::{build_super_image, parse_partition_config_str};
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn field_varint(out: &mut Vec<u8>, number: u64, value: u64) {
+        varint(out, number << 3);
+        varint(out, value);
+    }
+
+    fn field_bytes(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        varint(out, number << 3 | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    /// A version 2 full payload with partition `system` written by one `REPLACE`
+    /// operation of `data_length` bytes (the image length if `None`)
+    fn payload_bytes(image: &[u8], data_length: Option<u64>) -> Vec<u8> {
+        let mut extent = Vec::new();
+        field_varint(&mut extent, 1, 0);
+        field_varint(&mut extent, 2, image.len() as u64 / BLOCK_SIZE);
+        let mut operation = Vec::new();
+        field_varint(&mut operation, 1, 0);
+        field_varint(&mut operation, 2, 0);
+        field_varint(&mut operation, 3, data_length.unwrap_or(image.len() as u64));
+        field_bytes(&mut operation, 6, &extent);
+        let mut info = Vec::new();
+        field_varint(&mut info, 1, image.len() as u64);
+        field_bytes(&mut info, 2, &Sha256::digest(image));
+        let mut partition = Vec::new();
+        field_bytes(&mut partition, 1, b"system");
+        field_bytes(&mut partition, 7, &info);
+        field_bytes(&mut partition, 8, &operation);
+        let mut manifest = Vec::new();
+        field_varint(&mut manifest, 3, BLOCK_SIZE);
+        field_varint(&mut manifest, 12, 0);
+        field_bytes(&mut manifest, 13, &partition);
+
+        let mut payload = PAYLOAD_MAGIC.to_vec();
+        payload.extend_from_slice(&2u64.to_be_bytes());
+        payload.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&manifest);
+        payload.extend_from_slice(image);
+        payload
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("payload-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn image() -> Vec<u8> {
+        (0..3 * BLOCK_SIZE as usize).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_exact_checks_the_hash() {
+        let dir = test_dir("read-exact");
+        let image = image();
+        let path = dir.join("payload.bin");
+        fs::write(&path, payload_bytes(&image, None)).unwrap();
+        let mut data = vec![0u8; image.len()];
+        Payload::open(&path).unwrap().reader("system").unwrap().read_exact(&mut data).unwrap();
+        assert_eq!(data, image);
+
+        let mut corrupt = payload_bytes(&image, None);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        fs::write(&path, corrupt).unwrap();
+        let mut reader = Payload::open(&path).unwrap().reader("system").unwrap();
+        let e = reader.read_exact(&mut data).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(from_read_error(e), PayloadError::HashMismatch { .. }));
+    }
+
+    #[test]
+    fn flipped_data_byte_fails_the_build() {
+        let dir = test_dir("build");
+        let payload = dir.join("payload.bin");
+        let config = format!(
+            r#"{{
+  "super_meta": {{"path": "super.img", "size": "65536"}},
+  "nv_text": "",
+  "nv_id": "",
+  "block_devices": [{{"name": "super", "size": "4MiB", "block_size": "4096", "alignment": "1MiB"}}],
+  "groups": [{{"name": "main", "maximum_size": "2MiB"}}],
+  "partitions": [{{"is_dynamic": true, "name": "system", "group_name": "main", "path": {:?}, "size": "AUTO"}}]
+}}"#,
+            format!("{}#system", payload.display())
+        );
+        let config = parse_partition_config_str(&config).unwrap();
+        let image = image();
+        let mut bytes = payload_bytes(&image, None);
+        fs::write(&payload, &bytes).unwrap();
+        build_super_image(&config, &dir.join("super.img")).unwrap();
+
+        let data_start = bytes.len() - image.len();
+        bytes[data_start + 5000] ^= 0x40;
+        fs::write(&payload, &bytes).unwrap();
+        let e = build_super_image(&config, &dir.join("super.img")).unwrap_err();
+        assert!(e.to_string().contains("SHA-256"), "{}", e);
+    }
+
+    #[test]
+    fn operation_data_past_the_end_is_rejected() {
+        let dir = test_dir("past-end");
+        let path = dir.join("payload.bin");
+        fs::write(&path, payload_bytes(&image(), Some(u64::MAX - 8))).unwrap();
+        let e = Payload::open(&path).unwrap().reader("system").err().unwrap();
+        assert!(matches!(e, PayloadError::BadOperation { .. }), "{}", e);
+
+        let mut bytes = payload_bytes(&image(), None);
+        bytes[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert!(matches!(Payload::open(&path), Err(PayloadError::Manifest(_))));
+    }
+}
@@ -0,0 +1,892 @@
+//! xz decompression: the `.xz` container with LZMA2 as its only filter, as
+//! update_engine and the `xz` tool write it. `XzReader` streams the output and
+//! only keeps the LZMA dictionary of it (8 MiB for `xz -6`), so whole partition
+//! images can be decompressed on the fly. The check of every block (CRC32, CRC64
+//! or SHA-256), the CRC32s of the headers and the index are verified as the data
+//! is read, so corrupt data fails before the read that would return its last byte
+//! completes.
+
+use flate2::Crc;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom};
+
+const STREAM_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+const FILTER_LZMA2: u64 = 0x21;
+/// Smallest dictionary `xz` writes, kept even when a header asks for less
+const MIN_DICT_SIZE: usize = 4096;
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, StaticError(reason))
+}
+
+/// Decompresses one or more concatenated xz streams held in memory
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::new();
+    XzReader::new(data).read_to_end(&mut out).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => "xz data truncated",
+        // `invalid` errors, the only other kind a slice produces
+        _ => e.get_ref().and_then(|inner| inner.downcast_ref::<StaticError>()).map_or("invalid xz data", |e| e.0),
+    })?;
+    Ok(out)
+}
+
+/// Carries the `&'static str` reason of `invalid` through `io::Error`
+#[derive(Debug)]
+struct StaticError(&'static str);
+
+impl std::fmt::Display for StaticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for StaticError {}
+
+/// CRC-64 (ECMA-182, reflected) lookup table
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xC96C_5795_D787_0F42 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Integrity check of the blocks of a stream, as selected by the stream flags
+enum Check {
+    None,
+    Crc32(Crc),
+    Crc64(u64),
+    Sha256(Sha256),
+}
+
+impl Check {
+    fn new(check_type: u8) -> io::Result<Self> {
+        match check_type {
+            0 => Ok(Check::None),
+            1 => Ok(Check::Crc32(Crc::new())),
+            4 => Ok(Check::Crc64(!0)),
+            10 => Ok(Check::Sha256(Sha256::new())),
+            _ => Err(invalid("unsupported xz check type")),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Check::None => {}
+            Check::Crc32(crc) => crc.update(data),
+            Check::Crc64(crc) => {
+                for &byte in data {
+                    *crc = CRC64_TABLE[(*crc as u8 ^ byte) as usize] ^ (*crc >> 8);
+                }
+            }
+            Check::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The check of the data so far as a block stores it, resetting for the next block
+    fn finish(&mut self) -> Vec<u8> {
+        match self {
+            Check::None => Vec::new(),
+            Check::Crc32(crc) => std::mem::replace(crc, Crc::new()).sum().to_le_bytes().to_vec(),
+            Check::Crc64(crc) => (!std::mem::replace(crc, !0)).to_le_bytes().to_vec(),
+            Check::Sha256(hasher) => hasher.finalize_reset().to_vec(),
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Where `XzReader` is in the container
+enum State {
+    /// Before a stream header, or stream padding when `first` is false
+    Stream { first: bool },
+    /// Before a block header or the index
+    Block,
+    /// Inside the LZMA2 data of a block
+    Chunks,
+    Done,
+}
+
+/// Streaming decoder of concatenated xz streams
+pub(crate) struct XzReader<R: Read> {
+    input: R,
+    state: State,
+    /// Bytes read since the current stream started, blocks are 4 byte aligned
+    consumed: u64,
+    /// Stream flags of the header, repeated in the footer
+    stream_flags: [u8; 2],
+    check: Check,
+    /// Header size, start of the data (in `consumed`) and output size of the current block
+    block_header_size: u64,
+    block_start: u64,
+    block_output: u64,
+    /// Unpadded and uncompressed size of every block of the stream, for the index
+    blocks: Vec<(u64, u64)>,
+    /// CRC32 of the index while it is read
+    index_crc: Option<Crc>,
+    /// Decoded output, of which the last `dict_size` bytes are the LZMA dictionary
+    window: Vec<u8>,
+    /// Start of the bytes in `window` that `read` has not returned yet
+    unread: usize,
+    dict_size: usize,
+    lzma: Option<Lzma>,
+    /// Bytes decoded since the last dictionary reset
+    dict_pos: usize,
+    need_dict_reset: bool,
+    /// Compressed data of the current chunk
+    chunk: Vec<u8>,
+}
+
+impl<R: Read> XzReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        XzReader {
+            input,
+            state: State::Stream { first: true },
+            consumed: 0,
+            stream_flags: [0; 2],
+            check: Check::None,
+            block_header_size: 0,
+            block_start: 0,
+            block_output: 0,
+            blocks: Vec::new(),
+            index_crc: None,
+            window: Vec::new(),
+            unread: 0,
+            dict_size: MIN_DICT_SIZE,
+            lzma: None,
+            dict_pos: 0,
+            need_dict_reset: true,
+            chunk: Vec::new(),
+        }
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.input.read_exact(buf)?;
+        self.consumed += buf.len() as u64;
+        if let Some(crc) = &mut self.index_crc {
+            crc.update(buf);
+        }
+        Ok(())
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8];
+        self.read_bytes(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn be16(&mut self) -> io::Result<usize> {
+        let mut bytes = [0u8; 2];
+        self.read_bytes(&mut bytes)?;
+        Ok(u16::from_be_bytes(bytes) as usize)
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for i in 0..9 {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("xz integer too long"))
+    }
+
+    fn skip_padding(&mut self) -> io::Result<()> {
+        while !self.consumed.is_multiple_of(4) {
+            if self.byte()? != 0 {
+                return Err(invalid("non-zero xz padding"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes until new output is in `window`, `false` at the end of the data
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            match self.state {
+                State::Done => return Ok(false),
+                State::Stream { first } => {
+                    let mut header = [0u8; 12];
+                    let n = read_up_to(&mut self.input, &mut header[..4])?;
+                    if n == 0 && !first {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    if n < 4 {
+                        return Err(invalid("xz stream header truncated"));
+                    }
+                    // Stream padding comes in multiples of 4 zero bytes
+                    if !first && header[..4] == [0; 4] {
+                        continue;
+                    }
+                    self.input.read_exact(&mut header[4..])?;
+                    if header[..6] != STREAM_MAGIC {
+                        return Err(invalid("not xz data (no stream header)"));
+                    }
+                    if u32::from_le_bytes(header[8..].try_into().unwrap()) != crc32(&header[6..8]) {
+                        return Err(invalid("xz stream header CRC32 mismatch"));
+                    }
+                    if header[6] != 0 || header[7] & 0xf0 != 0 {
+                        return Err(invalid("unsupported xz stream flags"));
+                    }
+                    self.stream_flags = [header[6], header[7]];
+                    self.check = Check::new(header[7])?;
+                    self.blocks.clear();
+                    self.consumed = 12;
+                    self.state = State::Block;
+                }
+                State::Block => {
+                    let size = self.byte()?;
+                    if size == 0 {
+                        self.verify_index()?;
+                        self.state = State::Stream { first: false };
+                        continue;
+                    }
+                    let mut header = vec![0u8; (size as usize + 1) * 4];
+                    header[0] = size;
+                    self.read_bytes(&mut header[1..])?;
+                    let (fields, crc) = header.split_at(header.len() - 4);
+                    if u32::from_le_bytes(crc.try_into().unwrap()) != crc32(fields) {
+                        return Err(invalid("xz block header CRC32 mismatch"));
+                    }
+                    self.dict_size = parse_block_header(&header)?.max(MIN_DICT_SIZE);
+                    self.block_header_size = header.len() as u64;
+                    self.block_start = self.consumed;
+                    self.block_output = 0;
+                    self.lzma = None;
+                    self.need_dict_reset = true;
+                    self.state = State::Chunks;
+                }
+                State::Chunks => {
+                    // Only the dictionary has to stay, older output was read already
+                    if self.unread == self.window.len() && self.window.len() > self.dict_size {
+                        let drop = self.window.len() - self.dict_size;
+                        self.window.drain(..drop);
+                        self.unread -= drop;
+                    }
+                    let before = self.window.len();
+                    if self.chunk_step()? {
+                        self.check.update(&self.window[before..]);
+                        self.block_output += (self.window.len() - before) as u64;
+                    } else {
+                        // End of the block: padding, then the check
+                        let unpadded = self.block_header_size + self.consumed - self.block_start;
+                        self.skip_padding()?;
+                        let expected = self.check.finish();
+                        let mut stored = vec![0u8; expected.len()];
+                        self.read_bytes(&mut stored)?;
+                        if stored != expected {
+                            return Err(invalid("xz block check mismatch, the data is corrupt"));
+                        }
+                        self.blocks.push((unpadded + expected.len() as u64, self.block_output));
+                        self.state = State::Block;
+                    }
+                    if self.window.len() > before {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decodes one LZMA2 chunk into `window`, `false` for the end marker
+    fn chunk_step(&mut self) -> io::Result<bool> {
+        let control = self.byte()?;
+        match control {
+            0x00 => Ok(false),
+            0x01 | 0x02 => {
+                if control == 0x01 {
+                    self.dict_pos = 0;
+                    self.need_dict_reset = false;
+                    // A dictionary reset needs new properties before the next LZMA chunk
+                    self.lzma = None;
+                } else if self.need_dict_reset {
+                    return Err(invalid("LZMA2 data does not start with a dictionary reset"));
+                }
+                let size = self.be16()? + 1;
+                let start = self.window.len();
+                self.window.resize(start + size, 0);
+                self.input.read_exact(&mut self.window[start..])?;
+                self.consumed += size as u64;
+                self.dict_pos += size;
+                Ok(true)
+            }
+            0x80..=0xff => {
+                let unpacked = ((control as usize & 0x1f) << 16) + self.be16()? + 1;
+                let packed = self.be16()? + 1;
+                let reset = control >> 5 & 3;
+                if reset == 3 {
+                    self.dict_pos = 0;
+                    self.need_dict_reset = false;
+                } else if self.need_dict_reset {
+                    return Err(invalid("LZMA2 data does not start with a dictionary reset"));
+                }
+                if reset >= 2 {
+                    let props = self.byte()?;
+                    self.lzma = Some(Lzma::new(props).map_err(invalid)?);
+                }
+                let decoder = self.lzma.as_mut().ok_or_else(|| invalid("LZMA2 chunk without properties"))?;
+                if reset == 1 {
+                    decoder.reset();
+                }
+                self.chunk.resize(packed, 0);
+                self.input.read_exact(&mut self.chunk)?;
+                self.consumed += packed as u64;
+                decoder.decode_chunk(&self.chunk, &mut self.window, self.dict_pos, unpacked).map_err(invalid)?;
+                self.dict_pos += unpacked;
+                Ok(true)
+            }
+            _ => Err(invalid("invalid LZMA2 control byte")),
+        }
+    }
+
+    /// Checks the index (its indicator byte is read) against the blocks decoded, and
+    /// the stream footer against the index and the stream header
+    fn verify_index(&mut self) -> io::Result<()> {
+        let index_start = self.consumed - 1;
+        let mut crc = Crc::new();
+        crc.update(&[0]);
+        self.index_crc = Some(crc);
+        if self.varint()? != self.blocks.len() as u64 {
+            return Err(invalid("xz index does not match the blocks"));
+        }
+        for i in 0..self.blocks.len() {
+            if (self.varint()?, self.varint()?) != self.blocks[i] {
+                return Err(invalid("xz index does not match the blocks"));
+            }
+        }
+        self.skip_padding()?;
+        // The backward size of the footer counts the CRC32 of the index too
+        let index_size = self.consumed - index_start + 4;
+        let crc = self.index_crc.take().map_or(0, |crc| crc.sum());
+        let mut crc_and_footer = [0u8; 4 + 12];
+        self.read_bytes(&mut crc_and_footer)?;
+        let footer = &crc_and_footer[4..];
+        if footer[10..] != FOOTER_MAGIC {
+            return Err(invalid("xz stream footer missing"));
+        }
+        if u32::from_le_bytes(crc_and_footer[..4].try_into().unwrap()) != crc {
+            return Err(invalid("xz index CRC32 mismatch"));
+        }
+        if u32::from_le_bytes(footer[..4].try_into().unwrap()) != crc32(&footer[4..10]) {
+            return Err(invalid("xz stream footer CRC32 mismatch"));
+        }
+        let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        if backward_size != index_size || footer[8..10] != self.stream_flags {
+            return Err(invalid("xz stream footer does not match the stream"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for XzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.unread == self.window.len() {
+            let more = self.fill().map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, StaticError("xz data truncated")),
+                _ => e,
+            })?;
+            if !more {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.window.len() - self.unread);
+        buf[..n].copy_from_slice(&self.window[self.unread..self.unread + n]);
+        self.unread += n;
+        Ok(n)
+    }
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Checks a block header for a lone LZMA2 filter, returns its dictionary size
+fn parse_block_header(header: &[u8]) -> io::Result<usize> {
+    let flags = header[1];
+    let mut at = 2;
+    if flags & 0x40 != 0 {
+        read_varint(header, &mut at)?;
+    }
+    if flags & 0x80 != 0 {
+        read_varint(header, &mut at)?;
+    }
+    if flags & 0x03 != 0 || read_varint(header, &mut at)? != FILTER_LZMA2 {
+        return Err(invalid("unsupported xz filter chain (only LZMA2 is supported)"));
+    }
+    if read_varint(header, &mut at)? != 1 {
+        return Err(invalid("invalid LZMA2 filter properties"));
+    }
+    let bits = *header.get(at).ok_or_else(|| invalid("xz block header truncated"))? as u32;
+    match bits {
+        0..=39 => Ok(((2 | (bits & 1)) << (bits / 2 + 11)) as usize),
+        40 => Ok(u32::MAX as usize),
+        _ => Err(invalid("invalid LZMA2 dictionary size")),
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *data.get(*pos).ok_or_else(|| invalid("xz integer truncated"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("xz integer too long"))
+}
+
+/// Decompressed size of an xz file, summed from the index of every stream, which
+/// are read from the end without decoding anything
+pub(crate) fn uncompressed_size<R: Read + Seek>(file: &mut R) -> io::Result<u64> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut total = 0u64;
+    loop {
+        let mut word = [0u8; 4];
+        // Stream padding
+        loop {
+            if end < 12 + 12 {
+                return Err(invalid("xz file truncated"));
+            }
+            file.seek(SeekFrom::Start(end - 4))?;
+            file.read_exact(&mut word)?;
+            if word != [0; 4] {
+                break;
+            }
+            end -= 4;
+        }
+        let mut footer = [0u8; 12];
+        file.seek(SeekFrom::Start(end - 12))?;
+        file.read_exact(&mut footer)?;
+        if footer[10..] != FOOTER_MAGIC {
+            return Err(invalid("xz stream footer missing"));
+        }
+        let index_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        let index_start = (end - 12).checked_sub(index_size).ok_or_else(|| invalid("xz index out of range"))?;
+        let mut index = vec![0u8; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        if index[0] != 0 {
+            return Err(invalid("xz index missing"));
+        }
+        let mut at = 1;
+        let records = read_varint(&index, &mut at)?;
+        let mut blocks_size = 0u64;
+        for _ in 0..records {
+            let unpadded = read_varint(&index, &mut at)?;
+            blocks_size += unpadded.div_ceil(4) * 4;
+            total += read_varint(&index, &mut at)?;
+        }
+        let stream_start = index_start.checked_sub(blocks_size + 12).ok_or_else(|| invalid("xz index out of range"))?;
+        if stream_start == 0 {
+            return Ok(total);
+        }
+        end = stream_start;
+    }
+}
+
+const STATES: usize = 12;
+const POS_STATES_MAX: usize = 16;
+const END_POS_MODEL_INDEX: u32 = 14;
+const FULL_DISTANCES: usize = 128;
+const ALIGN_BITS: u32 = 4;
+const MATCH_MIN_LEN: usize = 2;
+const PROB_INIT: u16 = 1024;
+
+/// Range decoder over one LZMA2 chunk. Reading past the chunk yields zeros and
+/// marks the chunk corrupt.
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+    overrun: bool,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < 5 || data[0] != 0 {
+            return Err("invalid LZMA range coder start");
+        }
+        let code = u32::from_be_bytes(data[1..5].try_into().unwrap());
+        Ok(RangeDecoder { data, pos: 5, range: u32::MAX, code, overrun: false })
+    }
+
+    fn normalize(&mut self) {
+        if self.range < 1 << 24 {
+            let byte = match self.data.get(self.pos) {
+                Some(&b) => b,
+                None => {
+                    self.overrun = true;
+                    0
+                }
+            };
+            self.pos += 1;
+            self.range <<= 8;
+            self.code = (self.code << 8) | byte as u32;
+        }
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> u32 {
+        let bound = (self.range >> 11) * *prob as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += (2048 - *prob) >> 5;
+            0
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *prob -= *prob >> 5;
+            1
+        };
+        self.normalize();
+        bit
+    }
+
+    fn direct_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = if self.code >= self.range {
+                self.code -= self.range;
+                1
+            } else {
+                0
+            };
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        value
+    }
+
+    fn tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let mut m = 1;
+        for _ in 0..bits {
+            m = (m << 1) | self.bit(&mut probs[m as usize]);
+        }
+        m - (1 << bits)
+    }
+
+    fn reverse_tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let (mut m, mut symbol) = (1, 0);
+        for i in 0..bits {
+            let bit = self.bit(&mut probs[m as usize]);
+            m = (m << 1) | bit;
+            symbol |= bit << i;
+        }
+        symbol
+    }
+}
+
+#[derive(Clone)]
+struct LenDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 8]; POS_STATES_MAX],
+    mid: [[u16; 8]; POS_STATES_MAX],
+    high: [u16; 256],
+}
+
+impl LenDecoder {
+    fn new() -> Self {
+        LenDecoder {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; 8]; POS_STATES_MAX],
+            mid: [[PROB_INIT; 8]; POS_STATES_MAX],
+            high: [PROB_INIT; 256],
+        }
+    }
+
+    /// Match length minus `MATCH_MIN_LEN`
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> usize {
+        if rc.bit(&mut self.choice) == 0 {
+            rc.tree(&mut self.low[pos_state], 3) as usize
+        } else if rc.bit(&mut self.choice2) == 0 {
+            8 + rc.tree(&mut self.mid[pos_state], 3) as usize
+        } else {
+            16 + rc.tree(&mut self.high, 8) as usize
+        }
+    }
+}
+
+/// LZMA decoder state, kept across the chunks of an LZMA2 stream
+struct Lzma {
+    lc: u32,
+    lp: u32,
+    pb: u32,
+    state: usize,
+    reps: [usize; 4],
+    literal: Vec<u16>,
+    is_match: [u16; STATES * POS_STATES_MAX],
+    is_rep: [u16; STATES],
+    is_rep_g0: [u16; STATES],
+    is_rep_g1: [u16; STATES],
+    is_rep_g2: [u16; STATES],
+    is_rep0_long: [u16; STATES * POS_STATES_MAX],
+    pos_slot: [[u16; 64]; 4],
+    pos_special: [u16; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << ALIGN_BITS],
+    len: LenDecoder,
+    rep_len: LenDecoder,
+}
+
+impl Lzma {
+    /// Decoder for the `(pb * 5 + lp) * 9 + lc` properties byte
+    fn new(props: u8) -> Result<Self, &'static str> {
+        if props >= 9 * 5 * 5 {
+            return Err("invalid LZMA properties");
+        }
+        let (lc, lp, pb) = (props as u32 % 9, props as u32 / 9 % 5, props as u32 / 45);
+        if lc + lp > 4 {
+            return Err("invalid LZMA2 properties (lc + lp > 4)");
+        }
+        Ok(Lzma {
+            lc,
+            lp,
+            pb,
+            state: 0,
+            reps: [0; 4],
+            literal: vec![PROB_INIT; 0x300 << (lc + lp)],
+            is_match: [PROB_INIT; STATES * POS_STATES_MAX],
+            is_rep: [PROB_INIT; STATES],
+            is_rep_g0: [PROB_INIT; STATES],
+            is_rep_g1: [PROB_INIT; STATES],
+            is_rep_g2: [PROB_INIT; STATES],
+            is_rep0_long: [PROB_INIT; STATES * POS_STATES_MAX],
+            pos_slot: [[PROB_INIT; 64]; 4],
+            pos_special: [PROB_INIT; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROB_INIT; 1 << ALIGN_BITS],
+            len: LenDecoder::new(),
+            rep_len: LenDecoder::new(),
+        })
+    }
+
+    /// State reset: same properties, fresh probabilities
+    fn reset(&mut self) {
+        let props = ((self.pb * 5 + self.lp) * 9 + self.lc) as u8;
+        *self = Lzma::new(props).expect("properties were valid before");
+    }
+
+    /// Decodes `unpacked` bytes onto `out`, whose end is `dict_pos` bytes past the
+    /// last dictionary reset
+    fn decode_chunk(&mut self, data: &[u8], out: &mut Vec<u8>, dict_pos: usize, unpacked: usize) -> Result<(), &'static str> {
+        let mut rc = RangeDecoder::new(data)?;
+        let start = out.len();
+        let end = start + unpacked;
+        let pos_mask = (1 << self.pb) - 1;
+        let lp_mask = (1 << self.lp) - 1;
+        while out.len() < end {
+            let pos = dict_pos + out.len() - start;
+            let pos_state = pos & pos_mask;
+            let state = self.state;
+
+            if rc.bit(&mut self.is_match[state * POS_STATES_MAX + pos_state]) == 0 {
+                let prev = if pos > 0 { out.last().copied().unwrap_or(0) } else { 0 };
+                let lit_state = ((pos & lp_mask) << self.lc) + (prev as usize >> (8 - self.lc));
+                let probs = &mut self.literal[0x300 * lit_state..0x300 * (lit_state + 1)];
+                let mut symbol = 1usize;
+                if state >= 7 {
+                    let mut match_byte = *out.len().checked_sub(self.reps[0] + 1).and_then(|i| out.get(i)).ok_or("LZMA distance out of range")? as usize;
+                    while symbol < 0x100 {
+                        let match_bit = (match_byte >> 7) & 1;
+                        match_byte <<= 1;
+                        let bit = rc.bit(&mut probs[((1 + match_bit) << 8) + symbol]) as usize;
+                        symbol = (symbol << 1) | bit;
+                        if match_bit != bit {
+                            break;
+                        }
+                    }
+                }
+                while symbol < 0x100 {
+                    symbol = (symbol << 1) | rc.bit(&mut probs[symbol]) as usize;
+                }
+                out.push(symbol as u8);
+                self.state = match state {
+                    0..=3 => 0,
+                    4..=9 => state - 3,
+                    _ => state - 6,
+                };
+                continue;
+            }
+
+            let len = if rc.bit(&mut self.is_rep[state]) == 1 {
+                if pos == 0 {
+                    return Err("LZMA repeat before any data");
+                }
+                if rc.bit(&mut self.is_rep_g0[state]) == 0 {
+                    if rc.bit(&mut self.is_rep0_long[state * POS_STATES_MAX + pos_state]) == 0 {
+                        // Short rep: a single byte at rep0
+                        self.state = if state < 7 { 9 } else { 11 };
+                        let byte = *out.len().checked_sub(self.reps[0] + 1).and_then(|i| out.get(i)).ok_or("LZMA distance out of range")?;
+                        out.push(byte);
+                        continue;
+                    }
+                } else {
+                    let dist = if rc.bit(&mut self.is_rep_g1[state]) == 0 {
+                        self.reps[1]
+                    } else if rc.bit(&mut self.is_rep_g2[state]) == 0 {
+                        let dist = self.reps[2];
+                        self.reps[2] = self.reps[1];
+                        dist
+                    } else {
+                        let dist = self.reps[3];
+                        self.reps[3] = self.reps[2];
+                        self.reps[2] = self.reps[1];
+                        dist
+                    };
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = dist;
+                }
+                self.state = if state < 7 { 8 } else { 11 };
+                self.rep_len.decode(&mut rc, pos_state)
+            } else {
+                self.reps[3] = self.reps[2];
+                self.reps[2] = self.reps[1];
+                self.reps[1] = self.reps[0];
+                let len = self.len.decode(&mut rc, pos_state);
+                self.state = if state < 7 { 7 } else { 10 };
+                self.reps[0] = self.distance(&mut rc, len);
+                len
+            } + MATCH_MIN_LEN;
+
+            let dist = self.reps[0] + 1;
+            if dist > pos || dist > out.len() {
+                return Err("LZMA distance past the dictionary start");
+            }
+            if out.len() + len > end {
+                return Err("LZMA match runs past the chunk end");
+            }
+            for _ in 0..len {
+                out.push(out[out.len() - dist]);
+            }
+        }
+        if rc.overrun || rc.pos != data.len() {
+            return Err("LZMA2 chunk size does not match its data");
+        }
+        Ok(())
+    }
+
+    fn distance(&mut self, rc: &mut RangeDecoder, len: usize) -> usize {
+        let slot = rc.tree(&mut self.pos_slot[len.min(3)], 6);
+        if slot < 4 {
+            return slot as usize;
+        }
+        let direct = (slot >> 1) - 1;
+        let mut dist = (2 | (slot & 1)) << direct;
+        if slot < END_POS_MODEL_INDEX {
+            let base = (dist - slot) as usize;
+            dist += rc.reverse_tree(&mut self.pos_special[base..], direct);
+        } else {
+            dist += rc.direct_bits(direct - ALIGN_BITS) << ALIGN_BITS;
+            dist += rc.reverse_tree(&mut self.align, ALIGN_BITS);
+        }
+        dist as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the files of `testdata/` hold: `xz -6 --check=<check> --block-size=32KiB`
+    /// of these lines, three blocks
+    fn lines() -> Vec<u8> {
+        (0..8000).flat_map(|i| format!("{:05} {:03}\n", i, i * 37 % 1000).into_bytes()).collect()
+    }
+
+    const CRC64: &[u8] = include_bytes!("testdata/lines_crc64.xz");
+
+    /// Start of the index of a single stream file, from the backward size of its footer
+    fn index_start(xz: &[u8]) -> usize {
+        let footer = &xz[xz.len() - 12..];
+        xz.len() - 12 - (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as usize + 1) * 4
+    }
+
+    #[test]
+    fn decodes_every_check_type() {
+        let lines = lines();
+        for xz in [
+            &include_bytes!("testdata/lines_none.xz")[..],
+            include_bytes!("testdata/lines_crc32.xz"),
+            CRC64,
+            include_bytes!("testdata/lines_sha256.xz"),
+        ] {
+            assert!(decompress(xz).unwrap() == lines);
+            assert_eq!(uncompressed_size(&mut io::Cursor::new(xz)).unwrap(), lines.len() as u64);
+        }
+        let twice = [CRC64, CRC64].concat();
+        assert!(decompress(&twice).unwrap() == [lines.clone(), lines].concat());
+    }
+
+    #[test]
+    fn corrupt_check_fails() {
+        let mut xz = CRC64.to_vec();
+        // The CRC64 of the last block sits right before the index
+        let at = index_start(&xz) - 1;
+        xz[at] ^= 1;
+        assert_eq!(decompress(&xz), Err("xz block check mismatch, the data is corrupt"));
+
+        let mut xz = include_bytes!("testdata/lines_sha256.xz").to_vec();
+        xz[3000] ^= 0x10;
+        assert!(decompress(&xz).is_err());
+    }
+
+    #[test]
+    fn corrupt_index_and_headers_fail() {
+        let mut xz = CRC64.to_vec();
+        // Unpadded size of the first index record
+        let at = index_start(&xz) + 2;
+        xz[at] ^= 1;
+        assert_eq!(decompress(&xz), Err("xz index does not match the blocks"));
+
+        let mut xz = CRC64.to_vec();
+        let at = xz.len() - 12;
+        xz[at] ^= 1;
+        assert_eq!(decompress(&xz), Err("xz stream footer CRC32 mismatch"));
+
+        let mut xz = CRC64.to_vec();
+        xz[8] ^= 1;
+        assert_eq!(decompress(&xz), Err("xz stream header CRC32 mismatch"));
+
+        let mut xz = CRC64.to_vec();
+        // Last byte of the block header CRC32, the header follows the stream header
+        let at = 12 + (xz[12] as usize + 1) * 4 - 1;
+        xz[at] ^= 1;
+        assert_eq!(decompress(&xz), Err("xz block header CRC32 mismatch"));
+    }
+
+    #[test]
+    fn truncated_data_fails() {
+        assert_eq!(decompress(&CRC64[..CRC64.len() / 2]), Err("xz data truncated"));
+    }
+}
@@ -0,0 +1,148 @@
+//! Bit streams of zstd. The table descriptions are read forward, least significant
+//! bit first. Entropy coded streams are written the same way and closed with a 1
+//! bit, then read backward from that bit, so the last value written is read first.
+
+use super::invalid;
+use std::io;
+
+/// `bits` bits of `data` starting at bit `start`, zero past the end. `bits` is at
+/// most 32.
+fn bits_at(data: &[u8], start: usize, bits: u32) -> u64 {
+    if bits == 0 {
+        return 0;
+    }
+    let byte = start / 8;
+    let mut word = [0u8; 8];
+    if byte < data.len() {
+        let n = (data.len() - byte).min(8);
+        word[..n].copy_from_slice(&data[byte..byte + n]);
+    }
+    (u64::from_le_bytes(word) >> (start % 8)) & ((1u64 << bits) - 1)
+}
+
+/// Writer of forward bit streams
+pub(super) struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    pub(super) fn new() -> Self {
+        BitWriter { out: Vec::new(), acc: 0, count: 0 }
+    }
+
+    /// Appends the low `bits` bits of `value`, at most 32
+    pub(super) fn add(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc |= (value & ((1u64 << bits) - 1)) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// The stream padded with zero bits to a whole byte
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+
+    /// The stream with the closing 1 bit, for `BackwardReader`
+    pub(super) fn close(mut self) -> Vec<u8> {
+        self.add(1, 1);
+        self.finish()
+    }
+}
+
+/// Reader of forward bit streams
+pub(super) struct ForwardReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ForwardReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        ForwardReader { data, pos: 0 }
+    }
+
+    pub(super) fn peek(&self, bits: u32) -> u64 {
+        bits_at(self.data, self.pos, bits)
+    }
+
+    pub(super) fn consume(&mut self, bits: u32) {
+        self.pos += bits as usize;
+    }
+
+    pub(super) fn read(&mut self, bits: u32) -> u64 {
+        let value = self.peek(bits);
+        self.consume(bits);
+        value
+    }
+
+    /// Whole bytes started so far, an error when that is past the data
+    pub(super) fn bytes_consumed(&self) -> io::Result<usize> {
+        let bytes = self.pos.div_ceil(8);
+        match bytes <= self.data.len() {
+            true => Ok(bytes),
+            false => Err(invalid("zstd table description truncated")),
+        }
+    }
+}
+
+/// Reader of closed bit streams, from the last bit written to the first
+pub(super) struct BackwardReader<'a> {
+    data: &'a [u8],
+    /// Bits left to read, negative once more were read than the stream has
+    left: i64,
+}
+
+impl<'a> BackwardReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> io::Result<Self> {
+        match data.last() {
+            Some(&last) if last != 0 => {
+                let left = data.len() as i64 * 8 - last.leading_zeros() as i64 - 1;
+                Ok(BackwardReader { data, left })
+            }
+            _ => Err(invalid("zstd bit stream not closed")),
+        }
+    }
+
+    /// The next `bits` bits, most significant first; missing bits past the start
+    /// of the stream read as zeros
+    pub(super) fn peek(&self, bits: u32) -> u64 {
+        if self.left >= bits as i64 {
+            bits_at(self.data, (self.left - bits as i64) as usize, bits)
+        } else if self.left > 0 {
+            bits_at(self.data, 0, self.left as u32) << (bits as i64 - self.left)
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn consume(&mut self, bits: u32) {
+        self.left -= bits as i64;
+    }
+
+    pub(super) fn read(&mut self, bits: u32) -> u64 {
+        let value = self.peek(bits);
+        self.consume(bits);
+        value
+    }
+
+    /// More bits were read than the stream has
+    pub(super) fn overflowed(&self) -> bool {
+        self.left < 0
+    }
+
+    /// Every bit was read, no more and no less
+    pub(super) fn is_finished(&self) -> bool {
+        self.left == 0
+    }
+}
@@ -0,0 +1,201 @@
+//! Decoding of compressed blocks: the literals section, then the sequences that
+//! interleave the literals with matches into earlier output.
+
+use super::bits::BackwardReader;
+use super::fse::{self, DecodeTable};
+use super::huffman::HuffmanTable;
+use super::invalid;
+use super::sequences::*;
+use std::io;
+
+/// Entropy tables of one kind of sequence code, kept for blocks that repeat them
+#[derive(Default)]
+struct CodeTables {
+    literal_length: Option<DecodeTable>,
+    offset: Option<DecodeTable>,
+    match_length: Option<DecodeTable>,
+}
+
+/// State carried from block to block within a frame
+pub(super) struct BlockDecoder {
+    huffman: Option<HuffmanTable>,
+    tables: CodeTables,
+    repeat_offsets: [usize; 3],
+    literals: Vec<u8>,
+}
+
+impl BlockDecoder {
+    pub(super) fn new() -> Self {
+        BlockDecoder { huffman: None, tables: CodeTables::default(), repeat_offsets: [1, 4, 8], literals: Vec::new() }
+    }
+
+    /// Decodes a compressed block onto the end of `out`, whose last `history` bytes
+    /// matches may copy from
+    pub(super) fn decode(&mut self, block: &[u8], out: &mut Vec<u8>, history: usize) -> io::Result<()> {
+        let consumed = self.read_literals(block)?;
+        let sequences = self.read_sequences(&block[consumed..])?;
+
+        let out_start = out.len();
+        let available = |out: &Vec<u8>| history + out.len() - out_start;
+        let mut literal_pos = 0;
+        for (literals, offset_value, match_length) in sequences {
+            let literal_end = literal_pos + literals;
+            let run = self.literals.get(literal_pos..literal_end).ok_or_else(|| invalid("zstd sequence past the literals"))?;
+            out.extend_from_slice(run);
+            literal_pos = literal_end;
+
+            let offset = self.resolve_offset(offset_value, literals)?;
+            if offset == 0 || offset > available(out) {
+                return Err(invalid("zstd match offset out of range"));
+            }
+            let start = out.len() - offset;
+            if offset >= match_length {
+                out.extend_from_within(start..start + match_length);
+            } else {
+                for i in 0..match_length {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+        out.extend_from_slice(&self.literals[literal_pos..]);
+        Ok(())
+    }
+
+    /// Offset of a sequence from its offset value, updating the repeat offsets
+    fn resolve_offset(&mut self, value: usize, literals: usize) -> io::Result<usize> {
+        let rep = &mut self.repeat_offsets;
+        if value > 3 {
+            let offset = value - 3;
+            *rep = [offset, rep[0], rep[1]];
+            return Ok(offset);
+        }
+        // Without literals the repeat offsets shift by one, the last meaning the
+        // most recent offset minus one
+        let index = if literals == 0 { value } else { value - 1 };
+        let offset = match index {
+            0 => return Ok(rep[0]),
+            3 => rep[0].checked_sub(1).ok_or_else(|| invalid("zstd repeat offset out of range"))?,
+            i => rep[i],
+        };
+        *rep = match index {
+            1 => [offset, rep[0], rep[2]],
+            _ => [offset, rep[0], rep[1]],
+        };
+        Ok(offset)
+    }
+
+    /// Decodes the literals section into `self.literals`, returns its size
+    fn read_literals(&mut self, block: &[u8]) -> io::Result<usize> {
+        let truncated = || invalid("zstd literals section truncated");
+        let byte = |i: usize| block.get(i).map(|&b| b as usize).ok_or_else(truncated);
+        let kind = byte(0)? & 3;
+        let size_format = (byte(0)? >> 2) & 3;
+        self.literals.clear();
+        if kind < 2 {
+            let (size, header) = match size_format {
+                0 | 2 => (byte(0)? >> 3, 1),
+                1 => ((byte(0)? >> 4) | byte(1)? << 4, 2),
+                _ => ((byte(0)? >> 4) | byte(1)? << 4 | byte(2)? << 12, 3),
+            };
+            if kind == 0 {
+                self.literals.extend_from_slice(block.get(header..header + size).ok_or_else(truncated)?);
+                return Ok(header + size);
+            }
+            self.literals.resize(size, byte(header)? as u8);
+            return Ok(header + 1);
+        }
+
+        let (header, bits) = match size_format {
+            0 | 1 => (3, 10),
+            2 => (4, 14),
+            _ => (5, 18),
+        };
+        let mut value = 0u64;
+        for i in 0..header {
+            value |= (byte(i)? as u64) << (8 * i);
+        }
+        let mask = (1u64 << bits) - 1;
+        let regenerated = ((value >> 4) & mask) as usize;
+        let compressed = ((value >> (4 + bits)) & mask) as usize;
+        let mut data = block.get(header..header + compressed).ok_or_else(truncated)?;
+        if kind == 2 {
+            let (table, consumed) = HuffmanTable::read(data)?;
+            self.huffman = Some(table);
+            data = &data[consumed..];
+        }
+        let table = self.huffman.as_ref().ok_or_else(|| invalid("zstd literals repeat a missing Huffman table"))?;
+        table.decode(data, regenerated, size_format != 0, &mut self.literals)?;
+        Ok(header + compressed)
+    }
+
+    /// Decodes the sequences section as (literals, offset value, match length)
+    fn read_sequences(&mut self, data: &[u8]) -> io::Result<Vec<(usize, usize, usize)>> {
+        let truncated = || invalid("zstd sequences section truncated");
+        let byte = |i: usize| data.get(i).map(|&b| b as usize).ok_or_else(truncated);
+        let (count, mut pos) = match byte(0)? {
+            0 => return Ok(Vec::new()),
+            b @ 1..=127 => (b, 1),
+            b @ 128..=254 => (((b - 128) << 8) + byte(1)?, 2),
+            _ => (byte(1)? + (byte(2)? << 8) + 0x7f00, 3),
+        };
+        let modes = byte(pos)?;
+        pos += 1;
+        if modes & 3 != 0 {
+            return Err(invalid("zstd sequences reserved bits set"));
+        }
+        let kinds = [
+            (modes >> 6, &LITERAL_LENGTH_DEFAULT[..], LITERAL_LENGTH_DEFAULT_LOG, LITERAL_LENGTH_CODES.len(), LITERAL_LENGTH_MAX_LOG),
+            ((modes >> 4) & 3, &OFFSET_DEFAULT[..], OFFSET_DEFAULT_LOG, MAX_OFFSET_CODE + 1, OFFSET_MAX_LOG),
+            ((modes >> 2) & 3, &MATCH_LENGTH_DEFAULT[..], MATCH_LENGTH_DEFAULT_LOG, MATCH_LENGTH_CODES.len(), MATCH_LENGTH_MAX_LOG),
+        ];
+        let slots = [&mut self.tables.literal_length, &mut self.tables.offset, &mut self.tables.match_length];
+        for ((mode, default, default_log, symbols, max_log), slot) in kinds.into_iter().zip(slots) {
+            match mode {
+                0 => *slot = Some(DecodeTable::new(default, default_log)),
+                1 => {
+                    let symbol = byte(pos)?;
+                    if symbol >= symbols {
+                        return Err(invalid("zstd sequence code out of range"));
+                    }
+                    *slot = Some(DecodeTable::rle(symbol as u8));
+                    pos += 1;
+                }
+                2 => {
+                    let (norm, log, consumed) = fse::read_distribution(&data[pos..], symbols, max_log)?;
+                    *slot = Some(DecodeTable::new(&norm, log));
+                    pos += consumed;
+                }
+                _ if slot.is_none() => return Err(invalid("zstd sequences repeat a missing table")),
+                _ => {}
+            }
+        }
+        let (Some(ll), Some(of), Some(ml)) = (&self.tables.literal_length, &self.tables.offset, &self.tables.match_length)
+        else {
+            unreachable!("every table was set above")
+        };
+
+        let mut bits = BackwardReader::new(&data[pos..])?;
+        let mut ll_state = ll.initial_state(&mut bits);
+        let mut of_state = of.initial_state(&mut bits);
+        let mut ml_state = ml.initial_state(&mut bits);
+        let mut sequences = Vec::with_capacity(count);
+        for i in 0..count {
+            let of_code = of.symbol(of_state) as u32;
+            let (ml_base, ml_bits) = MATCH_LENGTH_CODES[ml.symbol(ml_state) as usize];
+            let (ll_base, ll_bits) = LITERAL_LENGTH_CODES[ll.symbol(ll_state) as usize];
+            let offset_value = (1usize << of_code) + bits.read(of_code) as usize;
+            let match_length = (ml_base + bits.read(ml_bits) as u32) as usize;
+            let literals = (ll_base + bits.read(ll_bits) as u32) as usize;
+            sequences.push((literals, offset_value, match_length));
+            if i + 1 < count {
+                ll_state = ll.next_state(ll_state, &mut bits);
+                ml_state = ml.next_state(ml_state, &mut bits);
+                of_state = of.next_state(of_state, &mut bits);
+            }
+        }
+        match bits.is_finished() {
+            true => Ok(sequences),
+            false => Err(invalid("corrupt zstd sequences")),
+        }
+    }
+}
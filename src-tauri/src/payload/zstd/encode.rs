@@ -0,0 +1,349 @@
+//! Encoding of compressed blocks: a hash chain match finder (the level sets how
+//! many candidates it tries) and the entropy coding of its literals and sequences.
+//! Offsets are always sent explicitly, so blocks do not depend on each other's
+//! tables.
+
+use super::bits::BitWriter;
+use super::fse::{self, EncodeTable};
+use super::huffman::HuffmanCode;
+use super::sequences::*;
+
+const MIN_MATCH: usize = 4;
+const HASH_LOG: u32 = 17;
+/// Literals below this are stored raw, a Huffman table would not pay off
+const MIN_HUFFMAN_LITERALS: usize = 64;
+/// Fewer sequences than this use the predefined tables without comparing
+const MIN_CUSTOM_TABLE_SEQUENCES: usize = 32;
+
+/// Match finder over the data of a frame
+pub(super) struct BlockEncoder {
+    window_log: u32,
+    /// Candidates tried per position
+    depth: usize,
+    /// Also try a match one byte later before taking one
+    lazy: bool,
+    /// Last position + 1 of each hash, 0 for none
+    head: Vec<u64>,
+    /// Distance to the previous position with the same hash, by position in the window
+    chain: Vec<u32>,
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let word = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    (word.wrapping_mul(0x9e37_79b1) >> (32 - HASH_LOG)) as usize
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let n = a.len().min(b.len());
+    let mut i = 0;
+    while i + 8 <= n {
+        let x = u64::from_le_bytes(a[i..i + 8].try_into().unwrap()) ^ u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        if x != 0 {
+            return i + (x.trailing_zeros() / 8) as usize;
+        }
+        i += 8;
+    }
+    while i < n && a[i] == b[i] {
+        i += 1;
+    }
+    i
+}
+
+impl BlockEncoder {
+    /// Encoder for `level` 1 (fastest) to 19
+    pub(super) fn new(level: u32, window_log: u32) -> Self {
+        BlockEncoder {
+            window_log,
+            depth: 1 << level.div_ceil(2),
+            lazy: level >= 5,
+            head: vec![0; 1 << HASH_LOG],
+            chain: vec![0; 1 << window_log],
+        }
+    }
+
+    fn insert(&mut self, data: &[u8], base: u64, pos: usize) {
+        let absolute = base + pos as u64;
+        let h = hash(data, pos);
+        let previous = self.head[h];
+        let mask = (1u64 << self.window_log) - 1;
+        self.chain[(absolute & mask) as usize] = match previous {
+            0 => 0,
+            p => (absolute - (p - 1)).min(mask + 1) as u32,
+        };
+        self.head[h] = absolute + 1;
+    }
+
+    /// Longest match for `pos` as (length, offset), before inserting `pos`
+    fn find_match(&self, data: &[u8], base: u64, pos: usize, end: usize) -> (usize, usize) {
+        let absolute = base + pos as u64;
+        let max_offset = (1u64 << self.window_log) - MIN_MATCH as u64;
+        let mask = (1u64 << self.window_log) - 1;
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(data, pos)];
+        for _ in 0..self.depth {
+            if candidate == 0 {
+                break;
+            }
+            let c = candidate - 1;
+            if c < base || absolute - c > max_offset {
+                break;
+            }
+            let at = (c - base) as usize;
+            let length = common_prefix(&data[at..end], &data[pos..end]);
+            if length > best.0 {
+                best = (length, pos - at);
+                if pos + length == end {
+                    break;
+                }
+            }
+            match self.chain[(c & mask) as usize] {
+                0 => break,
+                d => candidate = candidate.saturating_sub(d as u64),
+            }
+        }
+        best
+    }
+
+    /// Sequences and literals of `data[start..]`, where `data` also holds the
+    /// window before the block and `data[0]` is at stream position `base`
+    fn parse(&mut self, data: &[u8], base: u64, start: usize) -> (Vec<Sequence>, Vec<u8>) {
+        let end = data.len();
+        let mut sequences = Vec::new();
+        let mut literals = Vec::new();
+        let mut anchor = start;
+        let mut pos = start;
+        while pos + MIN_MATCH <= end {
+            let (mut length, mut offset) = self.find_match(data, base, pos, end);
+            self.insert(data, base, pos);
+            if length < MIN_MATCH {
+                // Skip faster through data without matches
+                pos += 1 + ((pos - anchor) >> 8).min(31) * (self.depth < 8) as usize;
+                continue;
+            }
+            if self.lazy && pos + 1 + MIN_MATCH <= end {
+                let (next_length, next_offset) = self.find_match(data, base, pos + 1, end);
+                if next_length > length + 1 {
+                    self.insert(data, base, pos + 1);
+                    pos += 1;
+                    (length, offset) = (next_length, next_offset);
+                }
+            }
+            literals.extend_from_slice(&data[anchor..pos]);
+            sequences.push(Sequence { literals: (pos - anchor) as u32, offset: offset as u32, match_length: length as u32 });
+            let match_end = pos + length;
+            let step = if self.depth < 8 { 4 } else { 1 };
+            for p in (pos + 1..match_end.min(end.saturating_sub(MIN_MATCH - 1))).step_by(step) {
+                self.insert(data, base, p);
+            }
+            pos = match_end;
+            anchor = pos;
+        }
+        literals.extend_from_slice(&data[anchor..]);
+        (sequences, literals)
+    }
+
+    /// Content of a compressed block for `data[start..]` (see `parse`), `None` when
+    /// it is not smaller than the data
+    pub(super) fn compress(&mut self, data: &[u8], base: u64, start: usize) -> Option<Vec<u8>> {
+        let size = data.len() - start;
+        let (sequences, literals) = self.parse(data, base, start);
+        let mut out = Vec::new();
+        write_literals(&literals, &mut out);
+        write_sequences(&sequences, &mut out);
+        (out.len() < size).then_some(out)
+    }
+}
+
+/// Header of a raw or RLE literals section
+fn write_literals_header(kind: u8, size: usize, out: &mut Vec<u8>) {
+    match size {
+        0..32 => out.push(kind | (size << 3) as u8),
+        32..4096 => out.extend_from_slice(&((kind as u16) | 1 << 2 | (size << 4) as u16).to_le_bytes()),
+        _ => out.extend_from_slice(&((kind as u32) | 3 << 2 | (size << 4) as u32).to_le_bytes()[..3]),
+    }
+}
+
+fn write_literals(literals: &[u8], out: &mut Vec<u8>) {
+    if !literals.is_empty() && literals.iter().all(|&b| b == literals[0]) {
+        write_literals_header(1, literals.len(), out);
+        out.push(literals[0]);
+        return;
+    }
+    if literals.len() >= MIN_HUFFMAN_LITERALS
+        && let Some(compressed) = huffman_literals(literals)
+        && compressed.len() < literals.len()
+    {
+        out.extend(compressed);
+        return;
+    }
+    write_literals_header(0, literals.len(), out);
+    out.extend_from_slice(literals);
+}
+
+/// Huffman coded literals section, `None` when the table cannot be described
+fn huffman_literals(literals: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0u32; 256];
+    for &b in literals {
+        counts[b as usize] += 1;
+    }
+    let code = HuffmanCode::build(&counts)?;
+    if code.cost(&counts) / 8 >= literals.len() as u64 {
+        return None;
+    }
+    let mut body = code.describe()?;
+    let four_streams = literals.len() >= 256;
+    if four_streams {
+        let segment = literals.len().div_ceil(4);
+        let streams: Vec<Vec<u8>> = literals.chunks(segment).map(|chunk| code.encode(chunk)).collect();
+        for stream in &streams[..3] {
+            body.extend_from_slice(&u16::try_from(stream.len()).ok()?.to_le_bytes());
+        }
+        streams.iter().for_each(|stream| body.extend_from_slice(stream));
+    } else {
+        body.extend(code.encode(literals));
+    }
+
+    let (regenerated, compressed) = (literals.len() as u64, body.len() as u64);
+    let largest = regenerated.max(compressed);
+    let (size_format, header, bits) = match (four_streams, largest) {
+        (false, _) => (0, 3, 10),
+        (true, 0..1024) => (1, 3, 10),
+        (true, 1024..16384) => (2, 4, 14),
+        (true, _) => (3, 5, 18),
+    };
+    let value = 2 | size_format << 2 | regenerated << 4 | compressed << (4 + bits);
+    let mut out = value.to_le_bytes()[..header].to_vec();
+    out.extend(body);
+    Some(out)
+}
+
+/// How one of the three sequence codes is encoded
+enum CodeEncoder {
+    Rle,
+    Fse(EncodeTable),
+}
+
+impl CodeEncoder {
+    /// Picks RLE, the predefined or a table fitted to `codes`, writes the mode's
+    /// table description and returns the mode number with the encoder
+    fn choose(codes: &[u8], symbols: usize, default: &[i16], default_log: u32, max_log: u32, out: &mut Vec<u8>) -> (u8, Self) {
+        let mut counts = vec![0u32; symbols];
+        for &c in codes {
+            counts[c as usize] += 1;
+        }
+        let distinct = counts.iter().filter(|&&c| c > 0).count();
+        if distinct == 1 {
+            out.push(codes[0]);
+            return (1, CodeEncoder::Rle);
+        }
+        let default_cost = EncodeTable::cost(default, default_log, &counts);
+        if default_cost.is_some() && codes.len() < MIN_CUSTOM_TABLE_SEQUENCES {
+            return (0, CodeEncoder::Fse(EncodeTable::new(default, default_log)));
+        }
+        let used = counts.iter().rposition(|&c| c > 0).unwrap_or(0) + 1;
+        let log = fse::table_log(codes.len(), distinct, max_log);
+        let norm = fse::normalize(&counts[..used], log);
+        let mut description = Vec::new();
+        fse::write_distribution(&norm, log, &mut description);
+        let custom_cost = EncodeTable::cost(&norm, log, &counts).unwrap_or(f64::MAX) + description.len() as f64 * 8.0;
+        match default_cost {
+            Some(cost) if cost <= custom_cost => (0, CodeEncoder::Fse(EncodeTable::new(default, default_log))),
+            _ => {
+                out.extend(description);
+                (2, CodeEncoder::Fse(EncodeTable::new(&norm, log)))
+            }
+        }
+    }
+
+    fn initial_state(&self, symbol: u8) -> u32 {
+        match self {
+            CodeEncoder::Rle => 0,
+            CodeEncoder::Fse(table) => table.initial_state(symbol),
+        }
+    }
+
+    fn encode(&self, bits: &mut BitWriter, state: &mut u32, symbol: u8) {
+        if let CodeEncoder::Fse(table) = self {
+            table.encode(bits, state, symbol);
+        }
+    }
+
+    fn flush(&self, bits: &mut BitWriter, state: u32) {
+        if let CodeEncoder::Fse(table) = self {
+            table.flush(bits, state);
+        }
+    }
+}
+
+fn write_sequences(sequences: &[Sequence], out: &mut Vec<u8>) {
+    let count = sequences.len();
+    match count {
+        0..128 => out.push(count as u8),
+        128..0x7f00 => out.extend_from_slice(&[(count >> 8) as u8 + 128, count as u8]),
+        _ => {
+            out.push(255);
+            out.extend_from_slice(&((count - 0x7f00) as u16).to_le_bytes());
+        }
+    }
+    if count == 0 {
+        return;
+    }
+
+    let mut ll_codes = Vec::with_capacity(count);
+    let mut of_codes = Vec::with_capacity(count);
+    let mut ml_codes = Vec::with_capacity(count);
+    let mut extra = Vec::with_capacity(count);
+    for sequence in sequences {
+        let (ll, ll_extra) = length_code(&LITERAL_LENGTH_CODES, sequence.literals);
+        let (of, of_extra) = offset_code(sequence.offset + 3);
+        let (ml, ml_extra) = length_code(&MATCH_LENGTH_CODES, sequence.match_length);
+        ll_codes.push(ll);
+        of_codes.push(of);
+        ml_codes.push(ml);
+        extra.push((ll_extra, of_extra, ml_extra));
+    }
+
+    let modes_at = out.len();
+    out.push(0);
+    let (ll_mode, ll_enc) = CodeEncoder::choose(
+        &ll_codes,
+        LITERAL_LENGTH_CODES.len(),
+        &LITERAL_LENGTH_DEFAULT,
+        LITERAL_LENGTH_DEFAULT_LOG,
+        LITERAL_LENGTH_MAX_LOG,
+        out,
+    );
+    let (of_mode, of_enc) =
+        CodeEncoder::choose(&of_codes, MAX_OFFSET_CODE + 1, &OFFSET_DEFAULT, OFFSET_DEFAULT_LOG, OFFSET_MAX_LOG, out);
+    let (ml_mode, ml_enc) = CodeEncoder::choose(
+        &ml_codes,
+        MATCH_LENGTH_CODES.len(),
+        &MATCH_LENGTH_DEFAULT,
+        MATCH_LENGTH_DEFAULT_LOG,
+        MATCH_LENGTH_MAX_LOG,
+        out,
+    );
+    out[modes_at] = ll_mode << 6 | of_mode << 4 | ml_mode << 2;
+
+    // Written last to first, the decoder reads them first to last
+    let mut bits = BitWriter::new();
+    let last = count - 1;
+    let mut ml_state = ml_enc.initial_state(ml_codes[last]);
+    let mut of_state = of_enc.initial_state(of_codes[last]);
+    let mut ll_state = ll_enc.initial_state(ll_codes[last]);
+    for i in (0..count).rev() {
+        if i != last {
+            of_enc.encode(&mut bits, &mut of_state, of_codes[i]);
+            ml_enc.encode(&mut bits, &mut ml_state, ml_codes[i]);
+            ll_enc.encode(&mut bits, &mut ll_state, ll_codes[i]);
+        }
+        let (ll_extra, of_extra, ml_extra) = extra[i];
+        bits.add(ll_extra as u64, LITERAL_LENGTH_CODES[ll_codes[i] as usize].1);
+        bits.add(ml_extra as u64, MATCH_LENGTH_CODES[ml_codes[i] as usize].1);
+        bits.add(of_extra as u64, of_codes[i] as u32);
+    }
+    ml_enc.flush(&mut bits, ml_state);
+    of_enc.flush(&mut bits, of_state);
+    ll_enc.flush(&mut bits, ll_state);
+    out.extend(bits.close());
+}
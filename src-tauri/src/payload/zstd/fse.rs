@@ -0,0 +1,292 @@
+//! Finite State Entropy, the tANS coder zstd uses for sequence codes and Huffman
+//! weights. A distribution is a list of normalized counts that sum to the table
+//! size, `-1` marking symbols rarer than one table slot.
+
+use super::bits::{BackwardReader, BitWriter, ForwardReader};
+use super::invalid;
+use std::io;
+
+pub(super) const MIN_TABLE_LOG: u32 = 5;
+
+fn highbit(value: u32) -> u32 {
+    31 - value.leading_zeros()
+}
+
+/// Symbol of every table slot, the same spread for encoder and decoder
+fn spread(norm: &[i16], log: u32) -> Vec<u8> {
+    let size = 1usize << log;
+    let mut symbols = vec![0u8; size];
+    let mut high = size - 1;
+    for (s, &n) in norm.iter().enumerate() {
+        if n == -1 {
+            symbols[high] = s as u8;
+            high = high.wrapping_sub(1);
+        }
+    }
+    let step = (size >> 1) + (size >> 3) + 3;
+    let mask = size - 1;
+    let mut position = 0;
+    for (s, &n) in norm.iter().enumerate() {
+        for _ in 0..n.max(0) {
+            symbols[position] = s as u8;
+            position = (position + step) & mask;
+            while position > high {
+                position = (position + step) & mask;
+            }
+        }
+    }
+    symbols
+}
+
+/// Table log for `total` occurrences of `distinct` symbols, at most `max_log`
+pub(super) fn table_log(total: usize, distinct: usize, max_log: u32) -> u32 {
+    let wanted = usize::BITS - total.saturating_sub(1).leading_zeros();
+    let needed = usize::BITS - distinct.saturating_sub(1).leading_zeros() + 1;
+    wanted.max(needed).clamp(MIN_TABLE_LOG, max_log)
+}
+
+/// Scales `counts` to sum to `1 << log`, every symbol that occurs keeping at least
+/// one slot. `log` must leave a slot for each.
+pub(super) fn normalize(counts: &[u32], log: u32) -> Vec<i16> {
+    let size = 1i64 << log;
+    let total: i64 = counts.iter().map(|&c| c as i64).sum();
+    let mut norm: Vec<i16> = counts
+        .iter()
+        .map(|&c| match c {
+            0 => 0,
+            c => ((c as i64 * size + total / 2) / total).max(1) as i16,
+        })
+        .collect();
+    let mut excess = norm.iter().map(|&n| n as i64).sum::<i64>() - size;
+    let mut order: Vec<usize> = (0..norm.len()).filter(|&s| norm[s] > 0).collect();
+    order.sort_by_key(|&s| std::cmp::Reverse(norm[s]));
+    if excess < 0 {
+        norm[order[0]] -= excess as i16;
+    }
+    for &s in &order {
+        if excess <= 0 {
+            break;
+        }
+        let taken = excess.min(norm[s] as i64 - 1);
+        norm[s] -= taken as i16;
+        excess -= taken;
+    }
+    norm
+}
+
+/// Appends the table description of a distribution
+pub(super) fn write_distribution(norm: &[i16], log: u32, out: &mut Vec<u8>) {
+    let mut bits = BitWriter::new();
+    bits.add((log - MIN_TABLE_LOG) as u64, 4);
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let mut symbol = 0;
+    let mut previous_zero = false;
+    while symbol < norm.len() && remaining > 1 {
+        if previous_zero {
+            let mut start = symbol;
+            while symbol < norm.len() && norm[symbol] == 0 {
+                symbol += 1;
+            }
+            while symbol >= start + 3 {
+                bits.add(3, 2);
+                start += 3;
+            }
+            bits.add((symbol - start) as u64, 2);
+        }
+        let count = norm[symbol] as i32;
+        symbol += 1;
+        let max = 2 * threshold - 1 - remaining;
+        remaining -= count.abs();
+        let mut value = count + 1;
+        if value >= threshold {
+            value += max;
+        }
+        bits.add(value as u64, if value < max { nb_bits - 1 } else { nb_bits });
+        previous_zero = value == 1;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    out.extend(bits.finish());
+}
+
+/// Reads a table description of at most `max_symbols` symbols, returns the
+/// distribution, its log and the bytes the description took
+pub(super) fn read_distribution(data: &[u8], max_symbols: usize, max_log: u32) -> io::Result<(Vec<i16>, u32, usize)> {
+    let mut bits = ForwardReader::new(data);
+    let log = bits.read(4) as u32 + MIN_TABLE_LOG;
+    if log > max_log {
+        return Err(invalid("zstd table log too large"));
+    }
+    let mut norm = Vec::new();
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let mut previous_zero = false;
+    while remaining > 1 {
+        if previous_zero {
+            loop {
+                let repeat = bits.read(2) as usize;
+                norm.resize(norm.len() + repeat, 0);
+                if repeat != 3 {
+                    break;
+                }
+            }
+        }
+        if norm.len() >= max_symbols {
+            return Err(invalid("zstd table has too many symbols"));
+        }
+        let max = 2 * threshold - 1 - remaining;
+        let peek = bits.peek(nb_bits) as i32;
+        let mut value;
+        if peek & (threshold - 1) < max {
+            value = peek & (threshold - 1);
+            bits.consume(nb_bits - 1);
+        } else {
+            value = peek & (2 * threshold - 1);
+            if value >= threshold {
+                value -= max;
+            }
+            bits.consume(nb_bits);
+        }
+        let count = value - 1;
+        remaining -= count.abs();
+        norm.push(count as i16);
+        previous_zero = count == 0;
+        while remaining < threshold && nb_bits > 1 {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    if remaining != 1 {
+        return Err(invalid("zstd table counts do not add up"));
+    }
+    Ok((norm, log, bits.bytes_consumed()?))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DecodeEntry {
+    symbol: u8,
+    bits: u8,
+    baseline: u16,
+}
+
+/// Decoding table of a distribution, or of a single symbol (RLE mode)
+#[derive(Debug, Clone)]
+pub(super) struct DecodeTable {
+    log: u32,
+    entries: Vec<DecodeEntry>,
+}
+
+impl DecodeTable {
+    pub(super) fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1u32 << log;
+        let mut next: Vec<u32> = norm.iter().map(|&n| n.unsigned_abs() as u32).collect();
+        let entries = spread(norm, log)
+            .into_iter()
+            .map(|symbol| {
+                let state = next[symbol as usize];
+                next[symbol as usize] += 1;
+                let bits = log - highbit(state);
+                DecodeEntry { symbol, bits: bits as u8, baseline: ((state << bits) - size) as u16 }
+            })
+            .collect();
+        DecodeTable { log, entries }
+    }
+
+    pub(super) fn rle(symbol: u8) -> Self {
+        DecodeTable { log: 0, entries: vec![DecodeEntry { symbol, bits: 0, baseline: 0 }] }
+    }
+
+    pub(super) fn initial_state(&self, bits: &mut BackwardReader) -> usize {
+        bits.read(self.log) as usize
+    }
+
+    pub(super) fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    pub(super) fn next_state(&self, state: usize, bits: &mut BackwardReader) -> usize {
+        let entry = self.entries[state];
+        entry.baseline as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+/// Encoding table of a distribution
+pub(super) struct EncodeTable {
+    log: u32,
+    /// Next state for each (symbol, state range) slot
+    states: Vec<u16>,
+    /// Per symbol (delta_nb_bits, delta_find_state)
+    transforms: Vec<(i64, i64)>,
+}
+
+impl EncodeTable {
+    pub(super) fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1i64 << log;
+        let mut cumul = Vec::with_capacity(norm.len());
+        let mut total = 0i64;
+        let mut transforms = Vec::with_capacity(norm.len());
+        for &n in norm {
+            cumul.push(total);
+            transforms.push(match n {
+                0 => (0, 0),
+                -1 | 1 => {
+                    total += 1;
+                    (((log as i64) << 16) - size, total - 2)
+                }
+                n => {
+                    let n = n as i64;
+                    let max_bits_out = log as i64 - highbit(n as u32 - 1) as i64;
+                    total += n;
+                    ((max_bits_out << 16) - (n << max_bits_out), total - 2 * n)
+                }
+            });
+        }
+        let mut states = vec![0u16; size as usize];
+        for (u, symbol) in spread(norm, log).into_iter().enumerate() {
+            let slot = &mut cumul[symbol as usize];
+            states[*slot as usize] = (size + u as i64) as u16;
+            *slot += 1;
+        }
+        EncodeTable { log, states, transforms }
+    }
+
+    /// State that encodes `symbol` first, without writing bits
+    pub(super) fn initial_state(&self, symbol: u8) -> u32 {
+        let (delta_nb_bits, delta_find_state) = self.transforms[symbol as usize];
+        let bits_out = (delta_nb_bits + (1 << 15)) >> 16;
+        let value = (bits_out << 16) - delta_nb_bits;
+        self.states[((value >> bits_out) + delta_find_state) as usize] as u32
+    }
+
+    pub(super) fn encode(&self, bits: &mut BitWriter, state: &mut u32, symbol: u8) {
+        let (delta_nb_bits, delta_find_state) = self.transforms[symbol as usize];
+        let bits_out = ((*state as i64 + delta_nb_bits) >> 16) as u32;
+        bits.add(*state as u64, bits_out);
+        *state = self.states[((*state >> bits_out) as i64 + delta_find_state) as usize] as u32;
+    }
+
+    pub(super) fn flush(&self, bits: &mut BitWriter, state: u32) {
+        bits.add(state as u64, self.log);
+    }
+
+    /// Estimated bits to encode `counts` with this table
+    pub(super) fn cost(norm: &[i16], log: u32, counts: &[u32]) -> Option<f64> {
+        let mut bits = 0.0;
+        for (s, &count) in counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let n = match norm.get(s) {
+                Some(&n) if n != 0 => n.unsigned_abs() as f64,
+                _ => return None,
+            };
+            bits += count as f64 * (log as f64 - n.log2());
+        }
+        Some(bits)
+    }
+}
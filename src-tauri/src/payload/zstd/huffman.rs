@@ -0,0 +1,298 @@
+//! Huffman coding of literals. A table is described by a weight per byte value
+//! (code length `max_bits + 1 - weight`, 0 for unused bytes), the last weight left
+//! out since it follows from the others. Codes are canonical: the longest first,
+//! equal lengths in byte order.
+
+use super::bits::{BackwardReader, BitWriter};
+use super::fse::{self, DecodeTable, EncodeTable};
+use super::invalid;
+use std::io;
+
+/// Longest code zstd allows
+pub(super) const MAX_BITS: u32 = 11;
+const MAX_WEIGHT_LOG: u32 = 6;
+/// Most weights the direct (4 bit) representation can hold
+const MAX_DIRECT_WEIGHTS: usize = 128;
+
+/// Code of every byte value from its code length (0 for unused bytes)
+fn canonical_codes(lengths: &[u8; 256]) -> [u16; 256] {
+    let mut order: Vec<usize> = (0..256).filter(|&s| lengths[s] > 0).collect();
+    order.sort_by_key(|&s| (std::cmp::Reverse(lengths[s]), s));
+    let mut codes = [0u16; 256];
+    let mut code = 0u32;
+    let mut previous: Option<u8> = None;
+    for s in order {
+        if let Some(previous) = previous {
+            code = (code + 1) >> (previous - lengths[s]);
+        }
+        codes[s] = code as u16;
+        previous = Some(lengths[s]);
+    }
+    codes
+}
+
+/// Decoding table, indexed by the next `max_bits` bits of a stream
+pub(super) struct HuffmanTable {
+    max_bits: u32,
+    /// (byte, code length)
+    entries: Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    /// Reads a table description, returns the table and the bytes it took
+    pub(super) fn read(data: &[u8]) -> io::Result<(Self, usize)> {
+        let header = *data.first().ok_or_else(|| invalid("zstd Huffman table truncated"))? as usize;
+        let (weights, consumed) = if header >= 128 {
+            let count = header - 127;
+            let bytes = data.get(1..1 + count.div_ceil(2)).ok_or_else(|| invalid("zstd Huffman table truncated"))?;
+            let weights = (0..count).map(|i| (bytes[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0xf).collect();
+            (weights, 1 + count.div_ceil(2))
+        } else {
+            let compressed = data.get(1..1 + header).ok_or_else(|| invalid("zstd Huffman table truncated"))?;
+            (read_fse_weights(compressed)?, 1 + header)
+        };
+        Ok((Self::from_weights(&weights)?, consumed))
+    }
+
+    fn from_weights(weights: &[u8]) -> io::Result<Self> {
+        if weights.len() > 255 || weights.iter().any(|&w| w > MAX_BITS as u8) {
+            return Err(invalid("invalid zstd Huffman weights"));
+        }
+        let total: u32 = weights.iter().filter(|&&w| w > 0).map(|&w| 1u32 << (w - 1)).sum();
+        if total == 0 {
+            return Err(invalid("invalid zstd Huffman weights"));
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1u32 << max_bits) - total;
+        if max_bits > MAX_BITS || !rest.is_power_of_two() {
+            return Err(invalid("invalid zstd Huffman weights"));
+        }
+        let mut lengths = [0u8; 256];
+        for (s, &w) in weights.iter().enumerate() {
+            if w > 0 {
+                lengths[s] = (max_bits + 1 - w as u32) as u8;
+            }
+        }
+        let last_weight = rest.trailing_zeros() + 1;
+        lengths[weights.len()] = (max_bits + 1 - last_weight) as u8;
+
+        let codes = canonical_codes(&lengths);
+        let mut entries = vec![(0u8, 0u8); 1 << max_bits];
+        for s in 0..256 {
+            let length = lengths[s] as u32;
+            if length == 0 {
+                continue;
+            }
+            let start = (codes[s] as usize) << (max_bits - length);
+            entries[start..start + (1 << (max_bits - length))].fill((s as u8, length as u8));
+        }
+        Ok(HuffmanTable { max_bits, entries })
+    }
+
+    /// Decodes `count` bytes from one stream
+    fn decode_stream(&self, data: &[u8], count: usize, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut bits = BackwardReader::new(data)?;
+        for _ in 0..count {
+            let (symbol, length) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.consume(length as u32);
+            out.push(symbol);
+        }
+        match bits.is_finished() {
+            true => Ok(()),
+            false => Err(invalid("corrupt zstd literals")),
+        }
+    }
+
+    /// Decodes `count` literals from one stream, or from four behind a jump table
+    pub(super) fn decode(&self, data: &[u8], count: usize, four_streams: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        if !four_streams {
+            return self.decode_stream(data, count, out);
+        }
+        if data.len() < 6 {
+            return Err(invalid("zstd literals jump table truncated"));
+        }
+        let sizes = [0, 2, 4].map(|i| u16::from_le_bytes([data[i], data[i + 1]]) as usize);
+        let segment = count.div_ceil(4);
+        let mut start = 6;
+        for i in 0..4 {
+            let size = match i {
+                3 => data.len().checked_sub(start).ok_or_else(|| invalid("zstd literals jump table invalid"))?,
+                i => sizes[i],
+            };
+            let stream = data.get(start..start + size).ok_or_else(|| invalid("zstd literals jump table invalid"))?;
+            let stream_count = match i {
+                3 => count.checked_sub(3 * segment).ok_or_else(|| invalid("zstd literals too short for four streams"))?,
+                _ => segment,
+            };
+            self.decode_stream(stream, stream_count, out)?;
+            start += size;
+        }
+        Ok(())
+    }
+}
+
+/// Huffman weights compressed with FSE: two interleaved states, decoded until the
+/// stream runs out
+fn read_fse_weights(data: &[u8]) -> io::Result<Vec<u8>> {
+    let (norm, log, consumed) = fse::read_distribution(data, MAX_BITS as usize + 1, MAX_WEIGHT_LOG)?;
+    let table = DecodeTable::new(&norm, log);
+    let mut bits = BackwardReader::new(&data[consumed..])?;
+    let mut states = [table.initial_state(&mut bits), table.initial_state(&mut bits)];
+    let mut weights = Vec::new();
+    loop {
+        for i in 0..2 {
+            weights.push(table.symbol(states[i]));
+            states[i] = table.next_state(states[i], &mut bits);
+            if bits.overflowed() {
+                weights.push(table.symbol(states[1 - i]));
+                return Ok(weights);
+            }
+        }
+        if weights.len() > 255 {
+            return Err(invalid("too many zstd Huffman weights"));
+        }
+    }
+}
+
+/// A Huffman code for a block's literals
+pub(super) struct HuffmanCode {
+    lengths: [u8; 256],
+    codes: [u16; 256],
+}
+
+impl HuffmanCode {
+    /// Optimal code of at most `MAX_BITS` bits for byte frequencies `counts`, `None`
+    /// when fewer than two byte values occur
+    pub(super) fn build(counts: &[u32; 256]) -> Option<Self> {
+        let lengths = limited_lengths(counts, MAX_BITS)?;
+        Some(HuffmanCode { lengths, codes: canonical_codes(&lengths) })
+    }
+
+    /// Encoded size of the literals in bits
+    pub(super) fn cost(&self, counts: &[u32; 256]) -> u64 {
+        (0..256).map(|s| counts[s] as u64 * self.lengths[s] as u64).sum()
+    }
+
+    /// The table description, `None` when neither representation can hold it
+    pub(super) fn describe(&self) -> Option<Vec<u8>> {
+        let max_bits = *self.lengths.iter().max()? as u32;
+        let last = (0..256).rev().find(|&s| self.lengths[s] > 0)?;
+        let weights: Vec<u8> = self.lengths[..last]
+            .iter()
+            .map(|&l| match l {
+                0 => 0,
+                l => (max_bits + 1 - l as u32) as u8,
+            })
+            .collect();
+        let direct = (weights.len() <= MAX_DIRECT_WEIGHTS).then(|| {
+            let mut out = vec![(127 + weights.len()) as u8];
+            out.extend(weights.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)));
+            out
+        });
+        let compressed = compress_weights(&weights).filter(|c| c.len() < 128).map(|c| {
+            let mut out = vec![c.len() as u8];
+            out.extend(c);
+            out
+        });
+        match (direct, compressed) {
+            (Some(direct), Some(compressed)) => Some(if compressed.len() < direct.len() { compressed } else { direct }),
+            (direct, compressed) => direct.or(compressed),
+        }
+    }
+
+    /// One closed stream with `literals`, the first of them read first
+    pub(super) fn encode(&self, literals: &[u8]) -> Vec<u8> {
+        let mut bits = BitWriter::new();
+        for &byte in literals.iter().rev() {
+            bits.add(self.codes[byte as usize] as u64, self.lengths[byte as usize] as u32);
+        }
+        bits.close()
+    }
+}
+
+/// Weights as `read_fse_weights` reads them, `None` for a single distinct weight
+fn compress_weights(weights: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0u32; MAX_BITS as usize + 1];
+    for &w in weights {
+        counts[w as usize] += 1;
+    }
+    let distinct = counts.iter().filter(|&&c| c > 0).count();
+    if distinct < 2 {
+        return None;
+    }
+    let used = counts.iter().rposition(|&c| c > 0)? + 1;
+    let log = fse::table_log(weights.len(), distinct, MAX_WEIGHT_LOG);
+    let norm = fse::normalize(&counts[..used], log);
+    let table = EncodeTable::new(&norm, log);
+    let mut out = Vec::new();
+    fse::write_distribution(&norm, log, &mut out);
+
+    // Even positions belong to the first state, odd ones to the second; the last
+    // weight of each state sets its initial state
+    let mut bits = BitWriter::new();
+    let n = weights.len();
+    let mut states = [0u32; 2];
+    for i in (0..n).rev() {
+        let state = &mut states[i % 2];
+        match i + 2 >= n {
+            true => *state = table.initial_state(weights[i]),
+            false => table.encode(&mut bits, state, weights[i]),
+        }
+    }
+    table.flush(&mut bits, states[1]);
+    table.flush(&mut bits, states[0]);
+    out.extend(bits.close());
+    Some(out)
+}
+
+/// Code lengths of at most `limit` bits by package-merge, `None` for fewer than two
+/// symbols
+fn limited_lengths(counts: &[u32; 256], limit: u32) -> Option<[u8; 256]> {
+    enum Node {
+        Leaf(usize),
+        Package(usize, usize),
+    }
+    let mut nodes = Vec::new();
+    let mut leaves: Vec<(u64, usize)> = Vec::new();
+    for (s, &count) in counts.iter().enumerate() {
+        if count > 0 {
+            nodes.push(Node::Leaf(s));
+            leaves.push((count as u64, nodes.len() - 1));
+        }
+    }
+    if leaves.len() < 2 {
+        return None;
+    }
+    leaves.sort_by_key(|&(weight, _)| weight);
+
+    let mut list = leaves.clone();
+    for _ in 1..limit {
+        let mut packages = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks_exact(2) {
+            nodes.push(Node::Package(pair[0].1, pair[1].1));
+            packages.push((pair[0].0 + pair[1].0, nodes.len() - 1));
+        }
+        let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut a, mut b) = (0, 0);
+        while a < leaves.len() || b < packages.len() {
+            if b == packages.len() || (a < leaves.len() && leaves[a].0 <= packages[b].0) {
+                merged.push(leaves[a]);
+                a += 1;
+            } else {
+                merged.push(packages[b]);
+                b += 1;
+            }
+        }
+        list = merged;
+    }
+
+    let mut lengths = [0u8; 256];
+    let mut stack: Vec<usize> = list[..2 * leaves.len() - 2].iter().map(|&(_, node)| node).collect();
+    while let Some(node) = stack.pop() {
+        match nodes[node] {
+            Node::Leaf(s) => lengths[s] += 1,
+            Node::Package(a, b) => stack.extend([a, b]),
+        }
+    }
+    Some(lengths)
+}
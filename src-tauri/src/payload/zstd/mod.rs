@@ -0,0 +1,640 @@
+//! zstd compression (RFC 8878), streamed both ways so whole super images can be
+//! packed and unpacked without holding them. `ZstdReader` decodes everything the
+//! format allows except dictionaries, concatenated and skippable frames included,
+//! and checks the content checksum. `ZstdWriter` writes one frame with the content
+//! size (when known up front) and a checksum; its match finder is simpler than the
+//! `zstd` tool's, so files come out a little larger at the same level, but any zstd
+//! decoder reads them.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+mod bits;
+mod decode;
+mod encode;
+mod fse;
+mod huffman;
+mod sequences;
+
+use decode::BlockDecoder;
+use encode::BlockEncoder;
+
+pub(crate) const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Skippable frames use the magic numbers 0x184D2A50 to 0x184D2A5F
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+pub(crate) const MAX_BLOCK_SIZE: usize = 128 * 1024;
+/// Largest window `ZstdReader` accepts, the `zstd` tool's default limit
+const MAX_WINDOW_SIZE: u64 = 1 << 27;
+/// Window of `ZstdWriter` frames
+const WINDOW_LOG: u32 = 21;
+
+/// Level `ZstdWriter` uses for level 0, as the `zstd` tool does
+pub(crate) const DEFAULT_LEVEL: i32 = 3;
+pub(crate) const MAX_LEVEL: i32 = 19;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// What a frame header says about the frame
+struct FrameHeader {
+    window_size: u64,
+    content_size: Option<u64>,
+    checksum: bool,
+}
+
+/// Reads a frame header after its magic number
+fn read_frame_header<R: Read>(input: &mut R) -> io::Result<FrameHeader> {
+    let mut byte = [0u8];
+    input.read_exact(&mut byte)?;
+    let descriptor = byte[0];
+    if descriptor & 0x08 != 0 {
+        return Err(invalid("zstd frame header reserved bit set"));
+    }
+    let single_segment = descriptor & 0x20 != 0;
+    let window_size = match single_segment {
+        true => None,
+        false => {
+            input.read_exact(&mut byte)?;
+            let exponent = (byte[0] >> 3) as u32;
+            let base = 1u64 << (10 + exponent);
+            Some(base + base / 8 * (byte[0] & 7) as u64)
+        }
+    };
+    let dictionary_bytes = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let mut dictionary = [0u8; 4];
+    input.read_exact(&mut dictionary[..dictionary_bytes])?;
+    if u32::from_le_bytes(dictionary) != 0 {
+        return Err(invalid("zstd frames with a dictionary are not supported"));
+    }
+    let size_bytes = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let mut size = [0u8; 8];
+    input.read_exact(&mut size[..size_bytes])?;
+    let content_size = match size_bytes {
+        0 => None,
+        2 => Some(u64::from_le_bytes(size) + 256),
+        _ => Some(u64::from_le_bytes(size)),
+    };
+    let window_size = window_size.or(content_size).unwrap_or(0);
+    if window_size > MAX_WINDOW_SIZE {
+        return Err(invalid("zstd window larger than 128 MiB"));
+    }
+    Ok(FrameHeader { window_size, content_size, checksum: descriptor & 0x04 != 0 })
+}
+
+/// Block header as (last block, type, size)
+fn read_block_header<R: Read>(input: &mut R) -> io::Result<(bool, u32, usize)> {
+    let mut header = [0u8; 4];
+    input.read_exact(&mut header[..3])?;
+    let header = u32::from_le_bytes(header);
+    Ok((header & 1 != 0, (header >> 1) & 3, (header >> 3) as usize))
+}
+
+/// Reads up to 4 bytes of a magic number, `None` at the end of the input
+fn read_magic<R: Read>(input: &mut R) -> io::Result<Option<u32>> {
+    let mut magic = [0u8; 4];
+    let mut filled = 0;
+    while filled < magic.len() {
+        match input.read(&mut magic[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(u32::from_le_bytes(magic)))
+}
+
+fn skip<R: Read>(input: &mut R, bytes: u64) -> io::Result<()> {
+    match io::copy(&mut input.take(bytes), &mut io::sink())? == bytes {
+        true => Ok(()),
+        false => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// The frame `ZstdReader` is in
+struct Frame {
+    header: FrameHeader,
+    checksum: Xxh64,
+    decoded: u64,
+    decoder: BlockDecoder,
+}
+
+enum State {
+    /// Before a frame, `first` until one was read
+    Frame { first: bool },
+    Blocks(Box<Frame>),
+    Done,
+}
+
+/// Streaming decoder of concatenated zstd frames
+pub(crate) struct ZstdReader<R: Read> {
+    input: R,
+    state: State,
+    /// Decoded output, of which the last window size bytes are the history
+    window: Vec<u8>,
+    /// Start of the bytes in `window` that `read` has not returned yet
+    unread: usize,
+    /// Start of the current frame in `window`, matches do not reach before it
+    frame_start: usize,
+    block: Vec<u8>,
+}
+
+impl<R: Read> ZstdReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        ZstdReader { input, state: State::Frame { first: true }, window: Vec::new(), unread: 0, frame_start: 0, block: Vec::new() }
+    }
+
+    /// Decodes until new output is in `window`, `false` at the end of the data
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            match &mut self.state {
+                State::Done => return Ok(false),
+                State::Frame { first } => {
+                    let first = *first;
+                    let magic = match read_magic(&mut self.input)? {
+                        Some(magic) => magic,
+                        None if first => return Err(invalid("empty zstd file")),
+                        None => {
+                            self.state = State::Done;
+                            return Ok(false);
+                        }
+                    };
+                    if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+                        let mut size = [0u8; 4];
+                        self.input.read_exact(&mut size)?;
+                        skip(&mut self.input, u32::from_le_bytes(size) as u64)?;
+                        self.state = State::Frame { first: false };
+                        continue;
+                    }
+                    if magic.to_le_bytes() != MAGIC {
+                        return Err(invalid("not zstd data"));
+                    }
+                    let header = read_frame_header(&mut self.input)?;
+                    self.window.drain(..self.unread);
+                    self.unread = 0;
+                    self.frame_start = self.window.len();
+                    let frame = Frame { header, checksum: Xxh64::new(), decoded: 0, decoder: BlockDecoder::new() };
+                    self.state = State::Blocks(Box::new(frame));
+                }
+                State::Blocks(frame) => {
+                    let window_size = frame.header.window_size as usize;
+                    // Drop output that was read and left the window
+                    let droppable = self.unread.min(self.window.len().saturating_sub(window_size));
+                    if droppable >= window_size.max(MAX_BLOCK_SIZE * 8) {
+                        self.window.drain(..droppable);
+                        self.unread -= droppable;
+                        self.frame_start = self.frame_start.saturating_sub(droppable);
+                    }
+
+                    let (last, kind, size) = read_block_header(&mut self.input)?;
+                    let max_block = MAX_BLOCK_SIZE.min(window_size.max(1));
+                    let start = self.window.len();
+                    match kind {
+                        BLOCK_RAW | BLOCK_COMPRESSED if size > max_block => return Err(invalid("zstd block too large")),
+                        BLOCK_RAW => {
+                            self.window.resize(start + size, 0);
+                            self.input.read_exact(&mut self.window[start..])?;
+                        }
+                        BLOCK_RLE if size > max_block => return Err(invalid("zstd block too large")),
+                        BLOCK_RLE => {
+                            let mut byte = [0u8];
+                            self.input.read_exact(&mut byte)?;
+                            self.window.resize(start + size, byte[0]);
+                        }
+                        BLOCK_COMPRESSED => {
+                            self.block.resize(size, 0);
+                            self.input.read_exact(&mut self.block)?;
+                            let history = (start - self.frame_start).min(window_size);
+                            frame.decoder.decode(&self.block, &mut self.window, history)?;
+                            if self.window.len() - start > max_block {
+                                return Err(invalid("zstd block too large"));
+                            }
+                        }
+                        _ => return Err(invalid("reserved zstd block type")),
+                    }
+                    let produced = &self.window[start..];
+                    frame.checksum.update(produced);
+                    frame.decoded += produced.len() as u64;
+                    if frame.header.content_size.is_some_and(|size| frame.decoded > size) {
+                        return Err(invalid("zstd frame larger than its content size"));
+                    }
+                    let produced = !produced.is_empty();
+                    if last {
+                        if frame.header.content_size.is_some_and(|size| frame.decoded != size) {
+                            return Err(invalid("zstd frame smaller than its content size"));
+                        }
+                        if frame.header.checksum {
+                            let mut stored = [0u8; 4];
+                            self.input.read_exact(&mut stored)?;
+                            if u32::from_le_bytes(stored) != frame.checksum.digest() as u32 {
+                                return Err(invalid("zstd content checksum mismatch"));
+                            }
+                        }
+                        self.state = State::Frame { first: false };
+                    }
+                    if produced {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.unread == self.window.len() {
+            let more = self.fill().map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, "zstd data truncated"),
+                _ => e,
+            })?;
+            if !more {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.window.len() - self.unread);
+        buf[..n].copy_from_slice(&self.window[self.unread..self.unread + n]);
+        self.unread += n;
+        Ok(n)
+    }
+}
+
+/// Decompressed size of a zstd file: the sum of the content sizes of its frames,
+/// which are walked block header by block header without decoding. When a frame
+/// does not store its size (`zstd` reading from a pipe), the file is decompressed
+/// once to count it.
+pub(crate) fn uncompressed_size<R: Read + Seek>(file: &mut R) -> io::Result<u64> {
+    let start = file.stream_position()?;
+    let mut total = 0u64;
+    while let Some(magic) = read_magic(file)? {
+        if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+            let mut size = [0u8; 4];
+            file.read_exact(&mut size)?;
+            file.seek(SeekFrom::Current(u32::from_le_bytes(size) as i64))?;
+            continue;
+        }
+        if magic.to_le_bytes() != MAGIC {
+            return Err(invalid("not zstd data"));
+        }
+        let header = read_frame_header(file)?;
+        let Some(size) = header.content_size else {
+            file.seek(SeekFrom::Start(start))?;
+            return io::copy(&mut ZstdReader::new(file), &mut io::sink());
+        };
+        total += size;
+        loop {
+            let (last, kind, size) = read_block_header(file)?;
+            file.seek(SeekFrom::Current(if kind == BLOCK_RLE { 1 } else { size as i64 }))?;
+            if last {
+                break;
+            }
+        }
+        if header.checksum {
+            file.seek(SeekFrom::Current(4))?;
+        }
+    }
+    Ok(total)
+}
+
+/// Streaming encoder writing one zstd frame. Data is compressed in blocks of
+/// 128 KiB as it is written, only the match window (2 MiB) is kept; `finish`
+/// writes the last block and the checksum.
+pub(crate) struct ZstdWriter<W: Write> {
+    inner: W,
+    encoder: BlockEncoder,
+    /// Data written, of which the bytes from `pending` on are not compressed yet
+    window: Vec<u8>,
+    pending: usize,
+    /// Stream position of `window[0]`
+    base: u64,
+    content_size: Option<u64>,
+    started: bool,
+    checksum: Xxh64,
+    bytes_in: u64,
+}
+
+impl<W: Write> ZstdWriter<W> {
+    /// Encoder at `level`, 1 (fastest) to `MAX_LEVEL`; 0 is `DEFAULT_LEVEL`
+    pub(crate) fn new(inner: W, level: i32) -> Self {
+        let level = match level {
+            0 => DEFAULT_LEVEL,
+            level => level.clamp(1, MAX_LEVEL),
+        };
+        ZstdWriter {
+            inner,
+            encoder: BlockEncoder::new(level as u32, WINDOW_LOG),
+            window: Vec::new(),
+            pending: 0,
+            base: 0,
+            content_size: None,
+            started: false,
+            checksum: Xxh64::new(),
+            bytes_in: 0,
+        }
+    }
+
+    /// Stores `size` in the frame header; `finish` fails unless exactly that much
+    /// was written. Only has an effect before the first block is out.
+    pub(crate) fn with_content_size(mut self, size: u64) -> Self {
+        self.content_size = Some(size);
+        self
+    }
+
+    fn write_block(&mut self, last: bool) -> io::Result<()> {
+        if !self.started {
+            let mut header = MAGIC.to_vec();
+            let size_flag = if self.content_size.is_some() { 0xc0 } else { 0 };
+            header.push(size_flag | 0x04);
+            header.push(((WINDOW_LOG - 10) << 3) as u8);
+            if let Some(size) = self.content_size {
+                header.extend_from_slice(&size.to_le_bytes());
+            }
+            self.inner.write_all(&header)?;
+            self.started = true;
+        }
+
+        let data = &self.window[self.pending..];
+        let size = data.len();
+        let (kind, content) = if size > 0 && data.iter().all(|&b| b == data[0]) {
+            (BLOCK_RLE, vec![data[0]])
+        } else {
+            match self.encoder.compress(&self.window, self.base, self.pending) {
+                Some(compressed) => (BLOCK_COMPRESSED, compressed),
+                None => (BLOCK_RAW, data.to_vec()),
+            }
+        };
+        let header_size = if kind == BLOCK_RLE { size } else { content.len() };
+        let header = last as u32 | kind << 1 | (header_size as u32) << 3;
+        self.inner.write_all(&header.to_le_bytes()[..3])?;
+        self.inner.write_all(&content)?;
+
+        self.pending = self.window.len();
+        let window_size = 1usize << WINDOW_LOG;
+        if self.window.len() >= 2 * window_size {
+            let dropped = self.window.len() - window_size;
+            self.window.drain(..dropped);
+            self.pending -= dropped;
+            self.base += dropped as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the last block and the checksum, returns the inner writer
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if let Some(size) = self.content_size
+            && size != self.bytes_in
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("zstd frame got {} bytes, its header says {size}", self.bytes_in),
+            ));
+        }
+        self.write_block(true)?;
+        let checksum = (self.checksum.digest() as u32).to_le_bytes();
+        self.inner.write_all(&checksum)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.checksum.update(buf);
+        self.bytes_in += buf.len() as u64;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let room = MAX_BLOCK_SIZE - (self.window.len() - self.pending);
+            let (now, later) = rest.split_at(room.min(rest.len()));
+            self.window.extend_from_slice(now);
+            rest = later;
+            if self.window.len() - self.pending == MAX_BLOCK_SIZE {
+                self.write_block(false)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+const PRIME_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+/// XXH64 with seed 0, of which zstd stores the low 32 bits as content checksum
+struct Xxh64 {
+    lanes: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+    total: u64,
+}
+
+fn xxh_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME_2)).rotate_left(31).wrapping_mul(PRIME_1)
+}
+
+fn xxh_merge(acc: u64, lane: u64) -> u64 {
+    (acc ^ xxh_round(0, lane)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl Xxh64 {
+    fn new() -> Self {
+        Xxh64 {
+            lanes: [PRIME_1.wrapping_add(PRIME_2), PRIME_2, 0, 0u64.wrapping_sub(PRIME_1)],
+            buffer: [0; 32],
+            buffered: 0,
+            total: 0,
+        }
+    }
+
+    fn consume_stripe(lanes: &mut [u64; 4], stripe: &[u8]) {
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            *lane = xxh_round(*lane, le64(&stripe[i * 8..]));
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        if self.buffered > 0 {
+            let take = (32 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 32 {
+                return;
+            }
+            let stripe = self.buffer;
+            Self::consume_stripe(&mut self.lanes, &stripe);
+            self.buffered = 0;
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            Self::consume_stripe(&mut self.lanes, stripe);
+        }
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    fn digest(&self) -> u64 {
+        let [v1, v2, v3, v4] = self.lanes;
+        let mut h = match self.total >= 32 {
+            true => {
+                let h = v1.rotate_left(1).wrapping_add(v2.rotate_left(7)).wrapping_add(v3.rotate_left(12)).wrapping_add(v4.rotate_left(18));
+                [v1, v2, v3, v4].into_iter().fold(h, xxh_merge)
+            }
+            false => PRIME_5,
+        };
+        h = h.wrapping_add(self.total);
+        let mut rest = &self.buffer[..self.buffered];
+        while rest.len() >= 8 {
+            h = (h ^ xxh_round(0, le64(rest))).rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let word = u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64;
+            h = (h ^ word.wrapping_mul(PRIME_1)).rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            h = (h ^ (byte as u64).wrapping_mul(PRIME_5)).rotate_left(11).wrapping_mul(PRIME_1);
+        }
+        h ^= h >> 33;
+        h = h.wrapping_mul(PRIME_2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(PRIME_3);
+        h ^ (h >> 32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the files of `testdata/mixed_*.zst` decompress to: 200000 bytes of numbered
+    /// lines, 300000 zero bytes (an RLE block in every file) and 4000 noisy ones
+    fn mixed() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..20000).flat_map(|i| format!("{:05} {:03}\n", i, i * 37 % 1000).into_bytes()).collect();
+        data.resize(data.len() + 300_000, 0);
+        data.extend((0..4000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8));
+        data
+    }
+
+    fn decompress(zst: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ZstdReader::new(zst).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// `zstd -3` of `mixed()`
+    const LEVEL_3: &[u8] = include_bytes!("../testdata/mixed_3.zst");
+
+    #[test]
+    fn decodes_frames_of_the_zstd_tool() {
+        let mixed = mixed();
+        // `zstd` 1.5.7 with `-3`, `-19`, `--fast=1` and `-9 --long=24`, and `-3 --no-check`
+        // from a pipe, which leaves out the content size and the checksum
+        for zst in [
+            LEVEL_3,
+            include_bytes!("../testdata/mixed_19.zst"),
+            include_bytes!("../testdata/mixed_fast1.zst"),
+            include_bytes!("../testdata/mixed_long24.zst"),
+            include_bytes!("../testdata/mixed_piped.zst"),
+        ] {
+            assert!(decompress(zst).unwrap() == mixed);
+            assert_eq!(uncompressed_size(&mut io::Cursor::new(zst)).unwrap(), mixed.len() as u64);
+        }
+
+        // Frames follow each other, skippable frames are skipped
+        let skippable = [&0x184d_2a53u32.to_le_bytes()[..], &3u32.to_le_bytes(), b"abc"].concat();
+        let file = [LEVEL_3, &skippable, LEVEL_3].concat();
+        assert!(decompress(&file).unwrap() == [mixed.clone(), mixed].concat());
+        assert_eq!(uncompressed_size(&mut io::Cursor::new(&file)).unwrap(), 2 * 504_000);
+    }
+
+    #[test]
+    fn decodes_raw_and_rle_blocks() {
+        // Single segment frames with a 1 byte content size, no checksum
+        let raw = [&MAGIC[..], &[0x20, 5], &(1u32 | BLOCK_RAW << 1 | 5 << 3).to_le_bytes()[..3], b"hello"].concat();
+        assert_eq!(decompress(&raw).unwrap(), b"hello");
+        let rle = [&MAGIC[..], &[0x20, 200], &(1u32 | BLOCK_RLE << 1 | 200 << 3).to_le_bytes()[..3], b"z"].concat();
+        assert_eq!(decompress(&rle).unwrap(), [b'z'; 200]);
+    }
+
+    #[test]
+    fn damaged_frames_are_rejected() {
+        let error = |zst: &[u8]| decompress(zst).unwrap_err().to_string();
+        let mut checksum = LEVEL_3.to_vec();
+        *checksum.last_mut().unwrap() ^= 1;
+        assert_eq!(error(&checksum), "zstd content checksum mismatch");
+        assert_eq!(error(&LEVEL_3[..LEVEL_3.len() / 2]), "zstd data truncated");
+        assert_eq!(error(b""), "empty zstd file");
+        assert_eq!(error(b"\x28\xb5\x2f\xfe...."), "not zstd data");
+        // The content size says one byte more than the blocks hold
+        let mut size = LEVEL_3.to_vec();
+        size[6] += 1;
+        assert_eq!(error(&size), "zstd frame smaller than its content size");
+    }
+
+    #[test]
+    fn xxh64_matches_the_reference() {
+        let digest = |data: &[u8], chunk: usize| {
+            let mut hash = Xxh64::new();
+            data.chunks(chunk.max(1)).for_each(|c| hash.update(c));
+            hash.digest()
+        };
+        assert_eq!(digest(b"", 1), 0xef46_db37_51d8_e999);
+        // As `zstd -lv` shows for the files of `testdata`
+        let mixed = mixed();
+        for chunk in [1, 31, 32, 4096, mixed.len()] {
+            assert_eq!(digest(&mixed, chunk) as u32, 0xd4bc_11ad, "{}", chunk);
+        }
+    }
+
+    #[test]
+    fn written_frames_decode_back_and_match_the_reference() {
+        let mixed = mixed();
+        for level in [0, 1, 3, 9, 19] {
+            let mut writer = ZstdWriter::new(Vec::new(), level).with_content_size(mixed.len() as u64);
+            for chunk in mixed.chunks(77777) {
+                writer.write_all(chunk).unwrap();
+            }
+            let zst = writer.finish().unwrap();
+            assert!(zst.len() < mixed.len() / 5, "level {}: {} bytes", level, zst.len());
+            assert!(decompress(&zst).unwrap() == mixed, "level {}", level);
+            assert_eq!(uncompressed_size(&mut io::Cursor::new(&zst)).unwrap(), mixed.len() as u64);
+        }
+        // `mixed_written_3.zst` is what level 3 wrote when `zstd -d` was checked to
+        // decode it to `mixed()`; after a change to the encoder, check the new output
+        // with `zstd -d` again before replacing the file
+        let mut writer = ZstdWriter::new(Vec::new(), 3).with_content_size(mixed.len() as u64);
+        writer.write_all(&mixed).unwrap();
+        assert!(writer.finish().unwrap() == include_bytes!("../testdata/mixed_written_3.zst"));
+
+        let writer = ZstdWriter::new(Vec::new(), 3).with_content_size(10);
+        assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(decompress(&ZstdWriter::new(Vec::new(), 3).finish().unwrap()).unwrap(), b"");
+    }
+}
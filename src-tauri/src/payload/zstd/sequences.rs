@@ -0,0 +1,67 @@
+//! Sequence codes: literal and match lengths as a code plus extra bits, offsets as
+//! their bit length plus the bits below it, and the predefined distributions of
+//! the three codes.
+
+/// One LZ77 step of a block: copy `literals` literals, then `match_length` bytes
+/// from `offset` bytes back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Sequence {
+    pub literals: u32,
+    pub offset: u32,
+    pub match_length: u32,
+}
+
+/// (baseline, extra bits) of each literal length code
+pub(super) const LITERAL_LENGTH_CODES: [(u32, u32); 36] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0),
+    (8, 0), (9, 0), (10, 0), (11, 0), (12, 0), (13, 0), (14, 0), (15, 0),
+    (16, 1), (18, 1), (20, 1), (22, 1), (24, 2), (28, 2), (32, 3), (40, 3),
+    (48, 4), (64, 6), (128, 7), (256, 8), (512, 9), (1024, 10), (2048, 11), (4096, 12),
+    (8192, 13), (16384, 14), (32768, 15), (65536, 16),
+];
+
+/// (baseline, extra bits) of each match length code
+pub(super) const MATCH_LENGTH_CODES: [(u32, u32); 53] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 0), (12, 0), (13, 0), (14, 0), (15, 0), (16, 0), (17, 0), (18, 0),
+    (19, 0), (20, 0), (21, 0), (22, 0), (23, 0), (24, 0), (25, 0), (26, 0),
+    (27, 0), (28, 0), (29, 0), (30, 0), (31, 0), (32, 0), (33, 0), (34, 0),
+    (35, 1), (37, 1), (39, 1), (41, 1), (43, 2), (47, 2), (51, 3), (59, 3),
+    (67, 4), (83, 4), (99, 5), (131, 7), (259, 8), (515, 9), (1027, 10), (2051, 11),
+    (4099, 12), (8195, 13), (16387, 14), (32771, 15), (65539, 16),
+];
+
+/// Largest offset code a decoder accepts
+pub(super) const MAX_OFFSET_CODE: usize = 31;
+
+pub(super) const LITERAL_LENGTH_MAX_LOG: u32 = 9;
+pub(super) const MATCH_LENGTH_MAX_LOG: u32 = 9;
+pub(super) const OFFSET_MAX_LOG: u32 = 8;
+
+pub(super) const LITERAL_LENGTH_DEFAULT_LOG: u32 = 6;
+pub(super) const LITERAL_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1,
+];
+
+pub(super) const MATCH_LENGTH_DEFAULT_LOG: u32 = 6;
+pub(super) const MATCH_LENGTH_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+
+pub(super) const OFFSET_DEFAULT_LOG: u32 = 5;
+pub(super) const OFFSET_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+/// Code of a length in `codes` and the extra bits that go with it
+pub(super) fn length_code(codes: &[(u32, u32)], length: u32) -> (u8, u32) {
+    let code = codes.partition_point(|&(baseline, _)| baseline <= length) - 1;
+    (code as u8, length - codes[code].0)
+}
+
+/// Offset code of an offset value and the bits below it
+pub(super) fn offset_code(value: u32) -> (u8, u32) {
+    let code = 31 - value.leading_zeros();
+    (code as u8, value - (1 << code))
+}
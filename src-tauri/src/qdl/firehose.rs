@@ -2,15 +2,29 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 use anyhow::bail;
 use indexmap::IndexMap;
+use log::{debug, info, trace};
 use owo_colors::OwoColorize;
 use pbr::{ProgressBar, Units};
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use std::str::{self, FromStr};
+use thiserror::Error;
 use xmltree::{Element, XMLNode};
 
-use crate::qdl::types::{QdlChan, FirehoseResetMode, FirehoseStatus, FirehoseStorageType, QdlBackend};
+use crate::qdl::types::{
+    QdlChan, FirehoseResetMode, FirehoseStatus, FirehoseStorageType, QdlBackend, TimeoutError,
+};
 use crate::qdl::parsers::firehose_parser_ack_nak;
+use crate::gpt_parser::{GptError, GptPartition, GptTable, parse_gpt};
+use crate::super_image_creater::{
+    BuildError, BuildProgress, BuildReport, PatchEntry, PatchExprError, PhysicalPartition, ProgramEntry, RawProgram,
+    SuperImageBuilder, XmlParseError, evaluate_patch_expr, open_raw_or_sparse, parse_patch_xml,
+};
 
 /// Reboot or power off the Device
 pub fn firehose_reset<T: QdlChan>(
@@ -37,6 +51,18 @@ pub fn firehose_reset<T: QdlChan>(
 }
 
 
+/// Longest XML document the debug log shows in full
+const MAX_LOGGED_XML: usize = 1024;
+
+/// An XML document for the debug log, cut short after `MAX_LOGGED_XML` bytes
+fn xml_for_log(xml: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&xml[..xml.len().min(MAX_LOGGED_XML)]);
+    match xml.len() > MAX_LOGGED_XML {
+        true => format!("{}... ({} bytes)", text.trim(), xml.len()),
+        false => text.trim().to_string(),
+    }
+}
+
 /// Wrapper for easily creating Firehose-y XML packets
 fn firehose_xml_setup(op: &str, kvps: &[(&str, &str)]) -> anyhow::Result<Vec<u8>> {
     let mut xml = Element::new("data");
@@ -81,6 +107,17 @@ pub fn firehose_write_getack<T: QdlChan>(
 pub fn firehose_read<T: QdlChan>(
     channel: &mut T,
     response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+) -> Result<FirehoseStatus, anyhow::Error> {
+    let mut logs = Vec::new();
+    firehose_read_with_log(channel, response_parser, &mut logs)
+}
+
+/// Like `firehose_read`, also collecting the \<log\> messages received before the
+/// response (e.g. the reason for a NAK) into `logs`
+pub fn firehose_read_with_log<T: QdlChan>(
+    channel: &mut T,
+    response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+    logs: &mut Vec<String>,
 ) -> Result<FirehoseStatus, anyhow::Error> {
     let mut got_any_data = false;
     let mut pending: Vec<u8> = Vec::new();
@@ -130,6 +167,7 @@ pub fn firehose_read<T: QdlChan>(
             let xml = match xmltree::Element::parse(xml_chunk) {
                 Ok(x) => x,
                 Err(e) => {
+                    debug!("Firehose <- unparsable XML ({}): {}", e, xml_for_log(xml_chunk));
                     // Consume the bad data and continue
                     bail!("Failed to parse XML: {}", e);
                 }
@@ -152,6 +190,10 @@ pub fn firehose_read<T: QdlChan>(
             if let Some(XMLNode::Element(e)) = xml.children.first() {
                 // Check for a 'log' node and print out the message
                 if e.name == "log" {
+                    if let Some(value) = e.attributes.get("value") {
+                        debug!("Firehose <- log: {}", value);
+                        logs.push(value.clone());
+                    }
                     if channel.fh_config().skip_firehose_log {
                         continue;
                     }
@@ -174,9 +216,11 @@ pub fn firehose_read<T: QdlChan>(
                     println!("RECV: {}", format!("{e:?}").magenta());
                 }
 
+                debug!("Firehose <- <{}> {:?}", e.name, e.attributes);
+
                 // TODO: Use std::intrinsics::unlikely after it exits nightly
                 if e.attributes.get("AttemptRetry").is_some() {
-                    return firehose_read::<T>(channel, response_parser);
+                    return firehose_read_with_log::<T>(channel, response_parser, logs);
                 } else if e.attributes.get("AttemptRestart").is_some() {
                     // TODO: handle this automagically
                     firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
@@ -206,6 +250,7 @@ pub fn firehose_write<T: QdlChan>(channel: &mut T, buf: &mut [u8]) -> anyhow::Re
         b.push(b'\n');
     }
 
+    debug!("Firehose -> {}", xml_for_log(&b));
     match channel.write_all(&b) {
         Ok(_) => Ok(()),
         // Assume FH will hang after NAK..
@@ -458,6 +503,860 @@ pub fn firehose_program_storage<T: QdlChan>(
     Ok(())
 }
 
+/// Write `num_sectors` sectors read from `data` to Device storage, starting at
+/// `start_sector` of `phys_part_idx`.
+///
+/// Data is sent in chunks of the negotiated MaxPayloadSizeToTargetInBytes (in whole
+/// sectors), and `progress` gets the bytes sent and the total after each chunk. If
+/// `data` ends early, the last sectors are padded with zeros. A NAK fails with the
+/// \<log\> messages the Device sent before it.
+pub fn firehose_flash_partition<T: QdlChan>(
+    channel: &mut T,
+    phys_part_idx: u8,
+    start_sector: u64,
+    sector_size: usize,
+    data: &mut impl Read,
+    num_sectors: u64,
+    mut progress: impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    Ok(program_sectors(channel, phys_part_idx, start_sector, sector_size, data, num_sectors, &mut progress)?)
+}
+
+/// Most bytes one `<program>` of `FirehoseSession::flash_partition_with_cancel` and
+/// `flash_resumable` covers. The Device only acknowledges a `<program>` at its end
+/// and cannot be interrupted before, so this bounds how long a cancel waits.
+pub const PROGRAM_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Why a Firehose operation failed, told apart so the UI can advise the user
+#[derive(Error, Debug)]
+pub enum FirehoseError {
+    /// The Device rejected the command, `message` holds its \<log\> output
+    #[error("The Device rejected {command}: {message}")]
+    Nak { command: String, message: String },
+    /// No answer in time, even after retrying: the programmer may have crashed, or
+    /// the cable come loose
+    #[error("{0}")]
+    Timeout(TimeoutError),
+    #[error("Transport error: {0}")]
+    Io(std::io::Error),
+    #[error("Firehose protocol error: {0}")]
+    Protocol(String),
+    /// The Device stopped sending before the requested number of bytes
+    #[error("Received {received} of {expected} bytes")]
+    ShortRead { expected: u64, received: u64 },
+    #[error("{0}")]
+    Gpt(#[from] GptError),
+    #[error("No partition '{0}' on the Device")]
+    PartitionNotFound(String),
+    /// Streaming a super image failed on the host side
+    #[error("{0}")]
+    Build(#[from] BuildError),
+    /// A patch XML could not be read
+    #[error("{0}")]
+    Xml(#[from] XmlParseError),
+    #[error("{0}")]
+    PatchExpr(#[from] PatchExprError),
+    /// The cancel flag was set; every `<program>` sent so far was completed
+    #[error("Flash cancelled by user")]
+    Cancelled,
+}
+
+impl From<std::io::Error> for FirehoseError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => FirehoseError::Timeout(TimeoutError::from_io(&e, "waiting for the Device")),
+            _ => FirehoseError::Io(e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for FirehoseError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<std::io::Error>() {
+            Ok(io) => io.into(),
+            Err(e) => FirehoseError::Protocol(e.to_string()),
+        }
+    }
+}
+
+/// Reads a response, turning a NAK into `FirehoseError::Nak` for `command`
+fn read_ack<T: QdlChan>(channel: &mut T, command: &str) -> Result<(), FirehoseError> {
+    read_ack_with_log(channel, command, &mut Vec::new())
+}
+
+/// `read_ack`, also collecting the \<log\> messages received before the response
+fn read_ack_with_log<T: QdlChan>(channel: &mut T, command: &str, logs: &mut Vec<String>) -> Result<(), FirehoseError> {
+    let status = firehose_read_with_log(channel, firehose_parser_ack_nak, logs).map_err(|e| match FirehoseError::from(e) {
+        FirehoseError::Timeout(timeout) => {
+            FirehoseError::Timeout(TimeoutError { operation: format!("waiting for the answer to {command}"), ..timeout })
+        }
+        other => other,
+    })?;
+    match status {
+        FirehoseStatus::Ack => Ok(()),
+        FirehoseStatus::Nak => Err(FirehoseError::Nak {
+            command: command.to_owned(),
+            message: describe_logs(logs),
+        }),
+    }
+}
+
+fn program_sectors<T: QdlChan>(
+    channel: &mut T,
+    phys_part_idx: u8,
+    start_sector: u64,
+    sector_size: usize,
+    data: &mut dyn Read,
+    num_sectors: u64,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<(), FirehoseError> {
+    let mut xml = firehose_xml_setup(
+        "program",
+        &[
+            ("SECTOR_SIZE_IN_BYTES", &sector_size.to_string()),
+            ("num_partition_sectors", &num_sectors.to_string()),
+            ("physical_partition_number", &phys_part_idx.to_string()),
+            ("start_sector", &start_sector.to_string()),
+            (
+                "read_back_verify",
+                &(channel.fh_config().read_back_verify as u32).to_string(),
+            ),
+        ],
+    )?;
+
+    firehose_write(channel, &mut xml)?;
+    read_ack(channel, "<program>")?;
+
+    let started = Instant::now();
+    let total = num_sectors * sector_size as u64;
+    let chunk_size = (channel.fh_config().send_buffer_size / sector_size).max(1) * sector_size;
+    let mut buf = vec![0u8; chunk_size];
+    let mut sent = 0u64;
+    while sent < total {
+        let chunk = &mut buf[..min(chunk_size as u64, total - sent) as usize];
+        let filled = read_up_to(data, chunk)?;
+        chunk[filled..].fill(0);
+        channel.write_all(chunk)?;
+        sent += chunk.len() as u64;
+        trace!("Firehose program: sent {} of {} bytes", sent, total);
+        progress(sent, total);
+    }
+    debug!("Sent {} bytes for LUN {} sector {} in {:?}", total, phys_part_idx, start_sector, started.elapsed());
+
+    // Send a Zero-Length Packet to indicate end of stream
+    if channel.fh_config().backend == QdlBackend::Usb && !channel.fh_config().skip_usb_zlp {
+        let _ = channel.write(&[])?;
+    }
+
+    read_ack(channel, &format!("writing {num_sectors} sectors at {start_sector}"))
+}
+
+/// Reads past the first `file_sector_offset` sectors of an entry's image
+fn skip_file_offset(entry: &ProgramEntry, data: &mut dyn Read) -> Result<(), FirehoseError> {
+    let skip = entry.file_sector_offset * entry.sector_size;
+    let skipped = std::io::copy(&mut (&mut *data).take(skip), &mut std::io::sink())?;
+    if skipped < skip {
+        return Err(FirehoseError::Protocol(format!(
+            "{} ends before its file_sector_offset {}",
+            entry.filename, entry.file_sector_offset
+        )));
+    }
+    Ok(())
+}
+
+/// Zero length reads in a row after which `read_sectors` gives up; a single one is
+/// a USB ZLP
+const MAX_EMPTY_READS: u32 = 3;
+
+/// Reads `num_sectors` sectors in raw mode into `out`, returns the bytes received
+fn read_sectors<T: QdlChan>(
+    channel: &mut T,
+    phys_part_idx: u8,
+    start_sector: u64,
+    sector_size: usize,
+    num_sectors: u64,
+    out: &mut dyn Write,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, FirehoseError> {
+    let mut xml = firehose_xml_setup(
+        "read",
+        &[
+            ("SECTOR_SIZE_IN_BYTES", &sector_size.to_string()),
+            ("num_partition_sectors", &num_sectors.to_string()),
+            ("physical_partition_number", &phys_part_idx.to_string()),
+            ("start_sector", &start_sector.to_string()),
+        ],
+    )?;
+
+    firehose_write(channel, &mut xml)?;
+    read_ack(channel, "<read>")?;
+    let started = Instant::now();
+
+    let total = num_sectors * sector_size as u64;
+    let mut buf = vec![0u8; channel.fh_config().recv_buffer_size.max(sector_size)];
+    let mut received = 0u64;
+    let mut empty_reads = 0;
+    while received < total {
+        let len = min(buf.len() as u64, total - received) as usize;
+        let n = match channel.read(&mut buf[..len]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            empty_reads += 1;
+            if empty_reads >= MAX_EMPTY_READS {
+                debug!("Firehose read stalled after {} of {} bytes", received, total);
+                return Err(FirehoseError::ShortRead { expected: total, received });
+            }
+            continue;
+        }
+        empty_reads = 0;
+        out.write_all(&buf[..n])?;
+        received += n as u64;
+        trace!("Firehose read: received {} of {} bytes", received, total);
+        progress(received, total);
+    }
+    debug!("Received {} bytes from LUN {} sector {} in {:?}", total, phys_part_idx, start_sector, started.elapsed());
+
+    if empty_reads == 0 && channel.fh_config().backend == QdlBackend::Usb {
+        // Issue a dummy read to drain the queue
+        let _ = channel.read(&mut [])?;
+    }
+
+    read_ack(channel, &format!("reading {num_sectors} sectors at {start_sector}"))?;
+    Ok(received)
+}
+
+/// Progress of a partition dump, with the average rate so far
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DumpProgress {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub bytes_per_second: f64,
+}
+
+/// Geometry and identity of one physical partition (LUN) of the device's storage,
+/// see `FirehoseSession::get_storage_info`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StorageInfo {
+    /// `UFS`, `eMMC`..., only newer programmers tell
+    pub memory_type: Option<String>,
+    /// Bytes per sector (logical block)
+    pub sector_size: u64,
+    /// Sectors of the LUN
+    pub total_blocks: u64,
+    /// Physical partitions (LUNs) of the storage
+    pub num_physical: Option<u8>,
+    /// JEDEC manufacturer id, e.g. 0x1ce for Samsung UFS
+    pub manufacturer_id: Option<u32>,
+    pub serial_number: Option<u64>,
+    pub product_name: Option<String>,
+    pub fw_version: Option<String>,
+}
+
+impl StorageInfo {
+    pub fn size_bytes(&self) -> u64 {
+        self.sector_size * self.total_blocks
+    }
+}
+
+/// The `storage_info` object newer programmers log as JSON
+#[derive(Deserialize)]
+struct StorageInfoJson {
+    storage_info: StorageInfoFields,
+}
+
+#[derive(Deserialize)]
+struct StorageInfoFields {
+    total_blocks: Option<u64>,
+    block_size: Option<u64>,
+    num_physical: Option<u8>,
+    manufacturer_id: Option<u32>,
+    serial_num: Option<u64>,
+    fw_version: Option<String>,
+    mem_type: Option<String>,
+    prod_name: Option<String>,
+}
+
+/// Parses the \<log\> lines of a `<getstorageinfo>` answer.
+///
+/// Newer programmers log a `{"storage_info": {...}}` JSON object, sometimes split
+/// over several lines; older ones one `key: value` line per field, named after
+/// the storage (`Device Block Size in Bytes: 0x200` on eMMC, `UFS Logical Block
+/// Size: 0xc` on UFS, where the size is a power of two). Fields of either form are
+/// taken, the JSON wins where both have one.
+pub fn parse_storage_info(logs: &[String]) -> Result<StorageInfo, FirehoseError> {
+    let mut info = StorageInfo::default();
+    let mut sector_size = None;
+    let mut total_blocks = None;
+    let mut json: Option<String> = None;
+    for log in logs {
+        let line = log.trim();
+        let line = line.strip_prefix("INFO:").map(str::trim).unwrap_or(line);
+        if let Some(pending) = &mut json {
+            pending.push_str(line);
+        } else if line.starts_with('{') && line.contains("storage_info") {
+            json = Some(line.to_string());
+        } else if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim().trim_start_matches('=').trim();
+            match key.trim() {
+                "Device Block Size in Bytes" => sector_size = sector_size.or(parse_log_number(value)),
+                "UFS Logical Block Size" => {
+                    // bLogicalBlockSize of the unit descriptor, log2 of the size
+                    sector_size = sector_size.or(parse_log_number(value).map(|n| if n < 32 { 1 << n } else { n }))
+                }
+                "Device Total Logical Blocks" | "UFS LU Total Blocks" | "UFS Logical Block Count" => {
+                    total_blocks = total_blocks.or(parse_log_number(value))
+                }
+                "Device Total Physical Partitions" | "UFS Total Active LU" => {
+                    info.num_physical = info.num_physical.or(parse_log_number(value).and_then(|n| n.try_into().ok()))
+                }
+                "Device Manufacturer ID" | "UFS wManufacturerID" => {
+                    info.manufacturer_id = info.manufacturer_id.or(parse_log_number(value).and_then(|n| n.try_into().ok()))
+                }
+                "Device Serial Number" => info.serial_number = info.serial_number.or(parse_log_number(value)),
+                "UFS iProductName String" | "Device Product Name" => info.product_name = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if let Some(Ok(parsed)) = json.as_deref().map(serde_json::from_str::<StorageInfoJson>) {
+            let fields = parsed.storage_info;
+            sector_size = fields.block_size.or(sector_size);
+            total_blocks = fields.total_blocks.or(total_blocks);
+            info.num_physical = fields.num_physical.or(info.num_physical);
+            info.manufacturer_id = fields.manufacturer_id.or(info.manufacturer_id);
+            info.serial_number = fields.serial_num.or(info.serial_number);
+            info.fw_version = fields.fw_version.or(info.fw_version);
+            info.memory_type = fields.mem_type.or(info.memory_type);
+            info.product_name = fields.prod_name.or(info.product_name);
+            json = None;
+        }
+    }
+    let missing = |field: &str| {
+        FirehoseError::Protocol(format!("The getstorageinfo answer has no {field}: {}", describe_logs(logs)))
+    };
+    info.sector_size = sector_size.filter(|&n| n > 0).ok_or_else(|| missing("sector size"))?;
+    info.total_blocks = total_blocks.ok_or_else(|| missing("sector count"))?;
+    Ok(info)
+}
+
+/// A number of a getstorageinfo log line, hexadecimal with `0x`
+fn parse_log_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// What a \<power\> command of `FirehoseSession::power` does to the Device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    /// Reboot into the system
+    Reset,
+    PowerOff,
+    /// Reboot back into EDL mode, to load a programmer again
+    ResetToEdl,
+}
+
+impl PowerAction {
+    /// `value` of the \<power\> command
+    pub fn value(self) -> &'static str {
+        match self {
+            PowerAction::Reset => "reset",
+            PowerAction::PowerOff => "off",
+            PowerAction::ResetToEdl => "reset_to_edl",
+        }
+    }
+}
+
+/// What `FirehoseSession::read_partition` reads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadTarget {
+    /// The whole partition with this GPT label, see `find_partition`
+    Label(String),
+    /// Sectors of `lun`, in the storage sector size of the channel
+    Sectors { lun: u8, start_sector: u64, num_sectors: u64 },
+}
+
+impl From<&str> for ReadTarget {
+    fn from(label: &str) -> Self {
+        ReadTarget::Label(label.to_string())
+    }
+}
+
+/// Physical partitions searched by `find_partition`, UFS devices have up to six
+const MAX_LUNS: u8 = 6;
+
+/// Firehose operations on a channel whose programmer is running and configured
+/// (see `open_firehose`), with typed errors
+pub struct FirehoseSession<T: QdlChan> {
+    channel: T,
+}
+
+impl<T: QdlChan> FirehoseSession<T> {
+    pub fn new(channel: T) -> Self {
+        FirehoseSession { channel }
+    }
+
+    pub fn channel_mut(&mut self) -> &mut T {
+        &mut self.channel
+    }
+
+    /// Writes `data` to the sectors described by a rawprogram entry.
+    ///
+    /// `data` is the image file from its start (e.g. the reader of
+    /// `open_raw_or_sparse`, which expands sparse images), the first
+    /// `file_sector_offset` sectors are skipped. Exactly `num_partition_sectors`
+    /// sectors are written; an image that ends before that, including a final
+    /// partial sector, is padded with zeros.
+    pub fn flash_partition(&mut self, entry: &ProgramEntry, data: &mut dyn Read) -> Result<(), FirehoseError> {
+        self.flash_partition_with_progress(entry, data, |_, _| {})
+    }
+
+    /// `flash_partition`, calling `progress` with the bytes sent and the total after
+    /// each chunk
+    pub fn flash_partition_with_progress(
+        &mut self,
+        entry: &ProgramEntry,
+        data: &mut dyn Read,
+        progress: impl FnMut(u64, u64),
+    ) -> Result<(), FirehoseError> {
+        skip_file_offset(entry, data)?;
+        self.flash_streamed(entry, data, progress)
+    }
+
+    /// `flash_partition_with_progress`, stopping when `cancel` is set.
+    ///
+    /// The sectors are sent as consecutive `<program>`s of at most
+    /// `PROGRAM_SEGMENT_BYTES`, and `cancel` is checked before each. A cancelled flash
+    /// returns `FirehoseError::Cancelled` with the Device back in command mode, so it
+    /// takes further commands (a reset, another flash). The sectors of the segments
+    /// already sent are written, the rest of the entry keeps its old contents.
+    pub fn flash_partition_with_cancel(
+        &mut self,
+        entry: &ProgramEntry,
+        data: &mut dyn Read,
+        cancel: &AtomicBool,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), FirehoseError> {
+        skip_file_offset(entry, data)?;
+        let start_sector = entry.start_sector_number().ok_or_else(|| {
+            FirehoseError::Protocol(format!("Cannot resolve start_sector '{}' of {}", entry.start_sector, entry.label))
+        })?;
+        let sector_size = entry.sector_size.max(1);
+        let segment_sectors = (PROGRAM_SEGMENT_BYTES / sector_size).max(1);
+        let total = entry.num_partition_sectors * sector_size;
+        info!(
+            "Flashing {}: {} sectors at LUN {} sector {}",
+            entry.label, entry.num_partition_sectors, entry.physical_partition_number, start_sector
+        );
+        let _span = tracing::debug_span!("flash", %entry.label).entered();
+        let mut sectors_done = 0;
+        while sectors_done < entry.num_partition_sectors {
+            if cancel.load(Ordering::SeqCst) {
+                info!("Flash of {} cancelled after {} of {} sectors", entry.label, sectors_done, entry.num_partition_sectors);
+                return Err(FirehoseError::Cancelled);
+            }
+            let sectors = segment_sectors.min(entry.num_partition_sectors - sectors_done);
+            let offset = sectors_done * sector_size;
+            program_sectors(
+                &mut self.channel,
+                entry.physical_partition_number,
+                start_sector + sectors_done,
+                sector_size as usize,
+                data,
+                sectors,
+                &mut |sent, _| progress(offset + sent, total),
+            )?;
+            sectors_done += sectors;
+        }
+        Ok(())
+    }
+
+    /// Writes the sectors of a rawprogram entry with data that already starts at its
+    /// `file_sector_offset`, like the chunks of `SuperImageBuilder::stream`
+    pub fn flash_streamed(
+        &mut self,
+        entry: &ProgramEntry,
+        data: &mut dyn Read,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), FirehoseError> {
+        let start_sector = entry.start_sector_number().ok_or_else(|| {
+            FirehoseError::Protocol(format!("Cannot resolve start_sector '{}' of {}", entry.start_sector, entry.label))
+        })?;
+        program_sectors(
+            &mut self.channel,
+            entry.physical_partition_number,
+            start_sector,
+            entry.sector_size as usize,
+            data,
+            entry.num_partition_sectors,
+            &mut progress,
+        )
+    }
+
+    /// Builds a super image straight onto `target` (the `super` partition), without an
+    /// image file: every chunk of `SuperImageBuilder::stream` is written with its own
+    /// `<program>`, see `ImageChunk::program_entry`. The zero gaps between chunks are
+    /// not sent and keep their old contents.
+    ///
+    /// The image must fit `target` and its block size be a whole number of sectors.
+    /// `progress` gets the build progress as the data is sent.
+    pub fn flash_super_stream(
+        &mut self,
+        builder: &SuperImageBuilder,
+        target: &PhysicalPartition,
+        cancel: &AtomicBool,
+        progress: impl FnMut(BuildProgress),
+    ) -> Result<BuildReport, FirehoseError> {
+        let layout = builder.plan()?;
+        let partition_size = target.num_sectors * target.sector_size;
+        if layout.device_size > partition_size {
+            return Err(FirehoseError::Protocol(format!(
+                "Super image is {} bytes, the partition only {}",
+                layout.device_size, partition_size
+            )));
+        }
+        if target.sector_size == 0 || !layout.block_size.is_multiple_of(target.sector_size) {
+            return Err(FirehoseError::Protocol(format!(
+                "Block size {} is not a multiple of the sector size {}",
+                layout.block_size, target.sector_size
+            )));
+        }
+        let label = builder.config().block_devices.first().map(|d| d.name.clone()).unwrap_or_else(|| "super".to_string());
+
+        // Device errors are kept as they are, the builder only sees an io::Error
+        let mut device_error = None;
+        let result = builder.stream(cancel, progress, |chunk| {
+            let entry = chunk.program_entry(target, &label);
+            self.flash_streamed(&entry, chunk.data, |_, _| {}).map_err(|e| {
+                let message = e.to_string();
+                device_error = Some(e);
+                std::io::Error::other(message)
+            })
+        });
+        match device_error {
+            // A cancelled read surfaces as a device error too
+            Some(e) if !cancel.load(Ordering::SeqCst) => Err(e),
+            _ => Ok(result?),
+        }
+    }
+
+    /// Reads sectors of `lun` into the file `output_path`, streaming them to disk as
+    /// they arrive, and returns the number of bytes written. The sector count
+    /// received must match the request, otherwise the partial file is removed and
+    /// `FirehoseError::ShortRead` returned.
+    pub fn dump_partition(
+        &mut self,
+        lun: u8,
+        start_sector: u64,
+        num_sectors: u64,
+        sector_size: usize,
+        output_path: &Path,
+        mut progress: impl FnMut(DumpProgress),
+    ) -> Result<u64, FirehoseError> {
+        let mut out = BufWriter::with_capacity(1024 * 1024, File::create(output_path)?);
+        let started = Instant::now();
+        let mut report = |bytes_done, bytes_total| {
+            let seconds = started.elapsed().as_secs_f64();
+            let bytes_per_second = if seconds > 0.0 { bytes_done as f64 / seconds } else { 0.0 };
+            progress(DumpProgress { bytes_done, bytes_total, bytes_per_second });
+        };
+        let result = read_sectors(&mut self.channel, lun, start_sector, sector_size, num_sectors, &mut out, &mut report)
+            .and_then(|received| {
+                out.flush()?;
+                Ok(received)
+            });
+        if result.is_err() {
+            drop(out);
+            let _ = fs::remove_file(output_path);
+        }
+        result
+    }
+
+    /// Reads a partition (or any sector range) off the Device into `out`, the
+    /// reverse of `flash_partition`, and returns the number of bytes written.
+    ///
+    /// One \<read\> command covers the whole target, it is not split at
+    /// `MaxPayloadSizeFromTargetInBytes`: the data is taken in reads of the read
+    /// buffer of the channel, which \<configure\> grows to that size when the
+    /// programmer offers a larger one. Fewer bytes than requested are
+    /// `FirehoseError::ShortRead`, with `out` holding what arrived.
+    pub fn read_partition(&mut self, target: ReadTarget, out: &mut dyn Write) -> Result<u64, FirehoseError> {
+        let (lun, start_sector, num_sectors, sector_size) = match target {
+            ReadTarget::Label(name) => {
+                let (lun, partition, sector_size) = self.find_partition(&name)?;
+                (lun, partition.first_lba, partition.size_in_sectors(), sector_size as usize)
+            }
+            ReadTarget::Sectors { lun, start_sector, num_sectors } => {
+                (lun, start_sector, num_sectors, self.channel.fh_config().storage_sector_size)
+            }
+        };
+        let received = read_sectors(&mut self.channel, lun, start_sector, sector_size, num_sectors, out, &mut |_, _| {})?;
+        out.flush()?;
+        Ok(received)
+    }
+
+    /// Looks up a partition by name in the GPT of each LUN, returns the LUN, the
+    /// partition and the sector size. LUNs after the first that cannot be read end
+    /// the search, as eMMC has only one.
+    pub fn find_partition(&mut self, name: &str) -> Result<(u8, GptPartition, u32), FirehoseError> {
+        for lun in 0..MAX_LUNS {
+            let table = match read_gpt(self, lun) {
+                Ok(table) => table,
+                Err(e) if lun == 0 => return Err(e.into()),
+                Err(_) => break,
+            };
+            if let Some(partition) = table.find(name) {
+                return Ok((lun, partition.clone(), table.sector_size));
+            }
+        }
+        Err(FirehoseError::PartitionNotFound(name.to_string()))
+    }
+
+    /// Sector size, capacity and identity of the storage behind `lun`, from the
+    /// \<log\> lines the programmer answers `<getstorageinfo>` with (see
+    /// `parse_storage_info`)
+    pub fn get_storage_info(&mut self, lun: u8) -> Result<StorageInfo, FirehoseError> {
+        let mut xml = firehose_xml_setup("getstorageinfo", &[("physical_partition_number", &lun.to_string())])?;
+        firehose_write(&mut self.channel, &mut xml)?;
+        let mut logs = Vec::new();
+        read_ack_with_log(&mut self.channel, &format!("getstorageinfo of LUN {lun}"), &mut logs)?;
+        parse_storage_info(&logs)
+    }
+
+    /// `dump_partition` of the whole partition called `name`, see `find_partition`
+    pub fn dump_by_name(&mut self, name: &str, output_path: &Path, progress: impl FnMut(DumpProgress)) -> Result<u64, FirehoseError> {
+        let (lun, partition, sector_size) = self.find_partition(name)?;
+        self.dump_partition(lun, partition.first_lba, partition.size_in_sectors(), sector_size as usize, output_path, progress)
+    }
+
+    /// Sends a \<power\> command, `delay_in_sec` seconds before the Device acts on it.
+    ///
+    /// Programmers often drop off the bus right after the command, before or instead
+    /// of acknowledging it, so a read that fails on the transport or times out counts
+    /// as done; only a NAK is an error. The session takes no further commands
+    /// afterwards, and the channel no longer resets the Device when dropped.
+    pub fn power(&mut self, action: PowerAction, delay_in_sec: u32) -> Result<(), FirehoseError> {
+        let command = format!("power {}", action.value());
+        let mut xml = firehose_xml_setup("power", &[("value", action.value()), ("DelayInSeconds", &delay_in_sec.to_string())])?;
+        firehose_write(&mut self.channel, &mut xml)?;
+        self.channel.disarm_reset_on_drop();
+        match read_ack(&mut self.channel, &command) {
+            Ok(()) => info!("Device acknowledged {}", command),
+            Err(FirehoseError::Timeout(_) | FirehoseError::Io(_)) => info!("Device disconnected without acknowledging {}", command),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Reboots the Device into the system, the usual end of a flash
+    pub fn reset(&mut self) -> Result<(), FirehoseError> {
+        self.power(PowerAction::Reset, 0)
+    }
+
+    pub fn power_off(&mut self) -> Result<(), FirehoseError> {
+        self.power(PowerAction::PowerOff, 0)
+    }
+
+    /// Reboots the Device back into EDL mode
+    pub fn edl_reboot(&mut self) -> Result<(), FirehoseError> {
+        self.power(PowerAction::ResetToEdl, 0)
+    }
+
+    /// Flashes the image of `entry`, looked up in `image_dir` like fh_loader's
+    /// `--search_path`. Sparse images are expanded while they are sent.
+    pub fn flash_image(&mut self, entry: &ProgramEntry, image_dir: &Path) -> Result<(), FirehoseError> {
+        let (mut reader, _) = open_raw_or_sparse(&image_dir.join(&entry.filename))?;
+        self.flash_partition(entry, &mut reader)
+    }
+}
+
+/// Applies the `DISK` entries of a `patch*.xml` file to the device, like fh_loader.
+///
+/// Patches fix up the GPT once it is on the device (backup header location, last
+/// usable LBA, CRCs), so this runs after every rawprogram file of the firmware is
+/// flashed. Entries for host files (`gpt_main0.bin` and such) were meant for the
+/// images before flashing and are skipped. Every `start_sector` and `value` is
+/// checked before the first patch is sent, plain arithmetic is evaluated on the
+/// host and `NUM_DISK_SECTORS` / `CRC32(..)` terms are left to the programmer.
+pub fn apply_patch<T: QdlChan, P: AsRef<Path>>(session: &mut FirehoseSession<T>, patch_xml: P) -> Result<(), FirehoseError> {
+    let patches = parse_patch_xml(patch_xml)?;
+    let mut commands = Vec::new();
+    for patch in patches.iter().filter(|p| p.is_disk()) {
+        let start_sector = evaluate_patch_expr(&patch.start_sector)?.to_attribute();
+        let value = evaluate_patch_expr(&patch.value)?.to_attribute();
+        commands.push((patch, start_sector, value));
+    }
+    for (patch, start_sector, value) in commands {
+        send_patch(session.channel_mut(), patch, &start_sector, &value)?;
+    }
+    Ok(())
+}
+
+fn send_patch<T: QdlChan>(channel: &mut T, patch: &PatchEntry, start_sector: &str, value: &str) -> Result<(), FirehoseError> {
+    let mut xml = firehose_xml_setup(
+        "patch",
+        &[
+            ("SECTOR_SIZE_IN_BYTES", &patch.sector_size.to_string()),
+            ("byte_offset", &patch.byte_offset.to_string()),
+            ("filename", &patch.filename),
+            ("physical_partition_number", &patch.physical_partition_number.to_string()),
+            ("size_in_bytes", &patch.size_in_bytes.to_string()),
+            ("start_sector", start_sector),
+            ("value", value),
+        ],
+    )?;
+    firehose_write(channel, &mut xml)?;
+    read_ack(channel, &format!("<patch> ({})", patch.what))
+}
+
+/// Largest partition entry array `read_gpt` reads, 128 entries of 128 bytes are
+/// the norm
+const MAX_GPT_ENTRY_ARRAY: u64 = 1024 * 1024;
+
+/// Reads and validates the primary GPT of `lun` from the device.
+///
+/// The protective MBR and the header come first, the header tells how many sectors
+/// the entry array after them needs. Sectors are `storage_sector_size` large, so
+/// the configured storage type decides between 4096 (UFS) and 512 (eMMC).
+pub fn read_gpt<T: QdlChan>(session: &mut FirehoseSession<T>, lun: u8) -> Result<GptTable, GptError> {
+    let channel = session.channel_mut();
+    let sector_size = channel.fh_config().storage_sector_size;
+    let mut data = read_gpt_sectors(channel, lun, 0, 2)?;
+    let header = &data[sector_size..];
+    if &header[0..8] != b"EFI PART" {
+        return Err(GptError::InvalidSignature);
+    }
+    let entry_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let num_entries = u32::from_le_bytes(header[80..84].try_into().unwrap()) as u64;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as u64;
+    if entry_lba < 2 || num_entries * entry_size > MAX_GPT_ENTRY_ARRAY {
+        return Err(GptError::EntryOutOfBounds);
+    }
+    let end_sector = entry_lba + (num_entries * entry_size).div_ceil(sector_size as u64);
+    data.extend(read_gpt_sectors(channel, lun, 2, end_sector - 2)?);
+    parse_gpt(&data, sector_size as u32)
+}
+
+fn read_gpt_sectors<T: QdlChan>(channel: &mut T, lun: u8, start_sector: u64, num_sectors: u64) -> Result<Vec<u8>, GptError> {
+    let sector_size = channel.fh_config().storage_sector_size;
+    let mut data = Vec::with_capacity(num_sectors as usize * sector_size);
+    if num_sectors > 0 {
+        read_sectors(channel, lun, start_sector, sector_size, num_sectors, &mut data, &mut |_, _| {})
+            .map_err(|e| GptError::DeviceRead(e.to_string()))?;
+    }
+    Ok(data)
+}
+
+/// Difference between a rawprogram entry and the partition table of the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutMismatch {
+    /// No partition with the label on the LUN
+    MissingPartition { label: String, lun: u8 },
+    /// The entry writes outside of the partition with its label
+    OutOfRange { label: String, start_sector: u64, num_sectors: u64, first_lba: u64, last_lba: u64 },
+    /// The entry is for another sector size than the device uses
+    SectorSize { label: String, expected: u64, actual: u64 },
+}
+
+impl std::fmt::Display for LayoutMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutMismatch::MissingPartition { label, lun } => write!(f, "No partition '{}' on LUN {}", label, lun),
+            LayoutMismatch::OutOfRange { label, start_sector, num_sectors, first_lba, last_lba } => write!(
+                f,
+                "'{}' writes sectors {}..{}, outside of the partition ({}..={})",
+                label, start_sector, start_sector + num_sectors, first_lba, last_lba
+            ),
+            LayoutMismatch::SectorSize { label, expected, actual } => {
+                write!(f, "'{}' uses {} byte sectors, the device {}", label, actual, expected)
+            }
+        }
+    }
+}
+
+/// Compares the rawprogram entries of `lun` that have an image against its GPT.
+///
+/// GPT entries themselves (`PrimaryGPT`, `BackupGPT`) and entries placed relative to
+/// the end of the disk are not checked.
+pub fn check_layout(table: &GptTable, lun: u8, rawprogram: &RawProgram) -> Vec<LayoutMismatch> {
+    let mut mismatches = Vec::new();
+    for entry in rawprogram.images().filter(|p| p.physical_partition_number == lun) {
+        if entry.label.is_empty() || entry.label.ends_with("GPT") {
+            continue;
+        }
+        let Some(start_sector) = entry.start_sector_number() else {
+            continue;
+        };
+        let label = entry.label.clone();
+        if entry.sector_size != table.sector_size as u64 {
+            mismatches.push(LayoutMismatch::SectorSize { label, expected: table.sector_size as u64, actual: entry.sector_size });
+            continue;
+        }
+        let Some(partition) = table.find(&entry.label) else {
+            mismatches.push(LayoutMismatch::MissingPartition { label, lun });
+            continue;
+        };
+        if start_sector < partition.first_lba || start_sector + entry.num_partition_sectors > partition.last_lba + 1 {
+            mismatches.push(LayoutMismatch::OutOfRange {
+                label,
+                start_sector,
+                num_sectors: entry.num_partition_sectors,
+                first_lba: partition.first_lba,
+                last_lba: partition.last_lba,
+            });
+        }
+    }
+    mismatches
+}
+
+/// The rawprogram entries of `lun` that have an image but another sector size than
+/// the storage, e.g. a 512 byte eMMC rawprogram against a 4096 byte UFS device
+pub fn check_sector_size(info: &StorageInfo, lun: u8, rawprogram: &RawProgram) -> Vec<LayoutMismatch> {
+    rawprogram
+        .images()
+        .filter(|p| p.physical_partition_number == lun && p.sector_size != info.sector_size)
+        .map(|p| LayoutMismatch::SectorSize { label: p.label.clone(), expected: info.sector_size, actual: p.sector_size })
+        .collect()
+}
+
+/// Reads the GPT of every LUN `rawprogram` writes to and compares it with the entries
+pub fn verify_layout<T: QdlChan>(session: &mut FirehoseSession<T>, rawprogram: &RawProgram) -> Result<Vec<LayoutMismatch>, GptError> {
+    let mut luns: Vec<u8> = rawprogram.images().map(|p| p.physical_partition_number).collect();
+    luns.sort_unstable();
+    luns.dedup();
+    let mut mismatches = Vec::new();
+    for lun in luns {
+        let table = read_gpt(session, lun)?;
+        mismatches.extend(check_layout(&table, lun, rawprogram));
+    }
+    Ok(mismatches)
+}
+
+/// Fills as much of `buf` as `data` has left, returns the number of bytes read
+fn read_up_to(data: &mut dyn Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match data.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn describe_logs(logs: &[String]) -> String {
+    if logs.is_empty() {
+        "the Device sent no error message".to_owned()
+    } else {
+        logs.join("; ")
+    }
+}
+
 /// Get a SHA256 digest of a portion of Device storage
 pub fn firehose_checksum_storage<T: QdlChan>(
     channel: &mut T,
@@ -575,3 +1474,394 @@ pub fn firehose_get_default_sector_size(t: &str) -> Option<usize> {
         FirehoseStorageType::Spinor => Some(4096),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt_parser::crc32;
+    use crate::qdl::types::FirehoseConfiguration;
+    use std::io::BufRead;
+
+    const ACK: &[u8] = b"<?xml version=\"1.0\" ?><data><response value=\"ACK\" rawmode=\"true\"/></data>";
+    const NAK: &[u8] = b"<?xml version=\"1.0\" ?><data><response value=\"NAK\"/></data>";
+    const SECTOR_SIZE: usize = 512;
+
+    /// Byte `i` of sector `sector` on `lun`, outside of the GPT of LUN 0
+    fn sector_byte(lun: u8, sector: u64, i: usize) -> u8 {
+        (sector as u8).wrapping_mul(31) ^ i as u8 ^ lun
+    }
+
+    /// Protective MBR, GPT header and a 32 entry array (sectors 0..34) with `boot_a`
+    /// at sectors 40..=44 and `super` at 45..=2092
+    fn gpt() -> Vec<u8> {
+        let array_sectors = (32 * 128usize).div_ceil(SECTOR_SIZE);
+        let mut data = vec![0u8; SECTOR_SIZE * (2 + array_sectors)];
+        data[510] = 0x55;
+        data[511] = 0xaa;
+        data[446 + 4] = 0xee;
+        for (i, (first, last, name)) in [(40u64, 44u64, "boot_a"), (45, 2092, "super")].into_iter().enumerate() {
+            let entry = 2 * SECTOR_SIZE + i * 128;
+            data[entry..entry + 4].copy_from_slice(&0xebd0a0a2u32.to_le_bytes());
+            data[entry + 32..entry + 40].copy_from_slice(&first.to_le_bytes());
+            data[entry + 40..entry + 48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                data[entry + 56 + 2 * j..entry + 58 + 2 * j].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let header = SECTOR_SIZE;
+        data[header..header + 8].copy_from_slice(b"EFI PART");
+        data[header + 12..header + 16].copy_from_slice(&92u32.to_le_bytes());
+        data[header + 72..header + 80].copy_from_slice(&2u64.to_le_bytes());
+        data[header + 80..header + 84].copy_from_slice(&32u32.to_le_bytes());
+        data[header + 84..header + 88].copy_from_slice(&128u32.to_le_bytes());
+        let array_crc = crc32(&data[2 * SECTOR_SIZE..2 * SECTOR_SIZE + 32 * 128]);
+        data[header + 88..header + 92].copy_from_slice(&array_crc.to_le_bytes());
+        let header_crc = crc32(&data[header..header + 92]);
+        data[header + 16..header + 20].copy_from_slice(&header_crc.to_le_bytes());
+        data
+    }
+
+    /// A programmer behind a `QdlChan`: answers \<read\> of LUN 0 with `sector_byte`
+    /// data (and the GPT of `gpt()` at its start) between two ACKs, of other LUNs
+    /// with a NAK, and \<patch\> with an ACK. Reads hand out at most `transfer`
+    /// bytes, as USB transfers do. With `truncate` set the data stops that many bytes
+    /// short, as a stalled read would.
+    struct FakeDevice {
+        config: FirehoseConfiguration,
+        gpt: Vec<u8>,
+        transfer: usize,
+        /// Responses and data, handed out up to `position`
+        pending: Vec<u8>,
+        position: usize,
+        truncate: usize,
+        command: Vec<u8>,
+        /// LUN, start sector and sector count of every \<read\>
+        reads: Vec<(u8, u64, u64)>,
+        /// Start sector and value of every \<patch\>, which is ACKed
+        patches: Vec<(String, String)>,
+        /// Largest buffer passed to `read`
+        largest_read: usize,
+    }
+
+    impl FakeDevice {
+        fn new(transfer: usize) -> Self {
+            FakeDevice::with_gpt(gpt(), SECTOR_SIZE, transfer)
+        }
+
+        /// A device of `sector_size` byte sectors starting with `gpt`
+        fn with_gpt(gpt: Vec<u8>, sector_size: usize, transfer: usize) -> Self {
+            FakeDevice {
+                config: FirehoseConfiguration { storage_sector_size: sector_size, backend: QdlBackend::Serial, ..Default::default() },
+                gpt,
+                transfer,
+                pending: Vec::new(),
+                position: 0,
+                truncate: 0,
+                command: Vec::new(),
+                reads: Vec::new(),
+                patches: Vec::new(),
+                largest_read: 0,
+            }
+        }
+
+        fn answer(&mut self, command: &Element) {
+            let attribute = |name: &str| command.attributes.get(name).and_then(|v| v.parse::<u64>().ok()).unwrap();
+            let (lun, start, count) = (attribute("physical_partition_number") as u8, attribute("start_sector"), attribute("num_partition_sectors"));
+            self.reads.push((lun, start, count));
+            if lun != 0 {
+                self.pending.extend(NAK);
+                return;
+            }
+            self.pending.extend(ACK);
+            let sector_size = self.config.storage_sector_size;
+            let mut data = Vec::new();
+            for sector in start..start + count {
+                let offset = sector as usize * sector_size;
+                match self.gpt.get(offset..offset + sector_size) {
+                    Some(gpt) => data.extend(gpt),
+                    None => data.extend((0..sector_size).map(|i| sector_byte(lun, sector, i))),
+                }
+            }
+            self.pending.extend(&data[..data.len() - self.truncate]);
+            if self.truncate == 0 {
+                self.pending.extend(ACK);
+            }
+        }
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.largest_read = self.largest_read.max(buf.len());
+            let available = self.fill_buf()?;
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for FakeDevice {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            let end = self.pending.len().min(self.position.saturating_add(self.transfer));
+            Ok(&self.pending[self.position..end])
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.position += amount;
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.command.extend_from_slice(buf);
+            if let Ok(data) = Element::parse(&self.command[..]) {
+                self.command.clear();
+                if let Some(read) = data.get_child("read") {
+                    self.answer(&read.clone());
+                }
+                if let Some(patch) = data.get_child("patch") {
+                    let attribute = |name: &str| patch.attributes.get(name).cloned().unwrap_or_default();
+                    self.patches.push((attribute("start_sector"), attribute("value")));
+                    self.pending.extend(ACK);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl QdlChan for FakeDevice {
+        fn fh_config(&self) -> &FirehoseConfiguration {
+            &self.config
+        }
+
+        fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
+            &mut self.config
+        }
+    }
+
+    fn expected(lun: u8, start: u64, count: u64) -> Vec<u8> {
+        (start..start + count).flat_map(|sector| (0..SECTOR_SIZE).map(move |i| sector_byte(lun, sector, i))).collect()
+    }
+
+    #[test]
+    fn reads_a_sector_range() {
+        let mut session = FirehoseSession::new(FakeDevice::new(1000));
+        let mut out = Vec::new();
+        let read = session.read_partition(ReadTarget::Sectors { lun: 0, start_sector: 100, num_sectors: 20 }, &mut out).unwrap();
+        assert_eq!(read, 20 * SECTOR_SIZE as u64);
+        assert!(out == expected(0, 100, 20));
+        assert_eq!(session.channel_mut().reads, [(0, 100, 20)]);
+    }
+
+    #[test]
+    fn reads_in_pieces_of_the_read_buffer() {
+        let mut device = FakeDevice::new(usize::MAX);
+        // As a \<configure\> response with MaxPayloadSizeFromTargetInBytes="8192" leaves it
+        device.config.recv_buffer_size = 8192;
+        let mut session = FirehoseSession::new(device);
+        let mut out = Vec::new();
+        session.read_partition(ReadTarget::Sectors { lun: 0, start_sector: 3000, num_sectors: 64 }, &mut out).unwrap();
+        assert!(out == expected(0, 3000, 64));
+        // One \<read\> command, its data taken in pieces of the read buffer
+        assert_eq!(session.channel_mut().reads.len(), 1);
+        assert_eq!(session.channel_mut().largest_read, 8192);
+    }
+
+    #[test]
+    fn reads_a_partition_by_label() {
+        let mut session = FirehoseSession::new(FakeDevice::new(700));
+        let mut out = Vec::new();
+        assert_eq!(session.read_partition("boot_a".into(), &mut out).unwrap(), 5 * SECTOR_SIZE as u64);
+        assert!(out == expected(0, 40, 5));
+        // The two GPT reads, then the partition
+        assert_eq!(session.channel_mut().reads, [(0, 0, 2), (0, 2, 8), (0, 40, 5)]);
+    }
+
+    #[test]
+    fn missing_label_and_short_reads_fail() {
+        let mut session = FirehoseSession::new(FakeDevice::new(4096));
+        let e = session.read_partition("vendor_boot".into(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(&e, FirehoseError::PartitionNotFound(name) if name == "vendor_boot"), "{}", e);
+
+        let mut device = FakeDevice::new(4096);
+        device.truncate = 100;
+        let mut out = Vec::new();
+        let e = FirehoseSession::new(device).read_partition(ReadTarget::Sectors { lun: 0, start_sector: 50, num_sectors: 4 }, &mut out).unwrap_err();
+        assert!(matches!(e, FirehoseError::ShortRead { expected: 2048, received: 1948 }), "{}", e);
+        assert!(out == expected(0, 50, 4)[..1948]);
+    }
+
+    /// `testdata/gpt_main0.bin`, the GPT of LUN 0 of a UFS device (4096 byte sectors)
+    fn ufs_gpt() -> Vec<u8> {
+        std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata/gpt_main0.bin")).unwrap()
+    }
+
+    #[test]
+    fn reads_the_gpt_of_ufs_and_emmc_devices() {
+        let mut session = FirehoseSession::new(FakeDevice::with_gpt(ufs_gpt(), 4096, 4096));
+        let table = read_gpt(&mut session, 0).unwrap();
+        assert_eq!(table.sector_size, 4096);
+        assert_eq!((table.first_usable_lba, table.last_usable_lba, table.backup_lba), (6, 62499994, 62499999));
+        let names: Vec<&str> = table.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["ssd", "persist", "misc", "metadata", "super", "userdata"]);
+        assert_eq!(table.find("super").map(|p| (p.first_lba, p.last_lba)), Some((12552, 2633991)));
+        // MBR and header, then the 4 sectors of the 128 entry array
+        assert_eq!(session.channel_mut().reads, [(0, 0, 2), (0, 2, 4)]);
+
+        let mut session = FirehoseSession::new(FakeDevice::new(512));
+        let table = read_gpt(&mut session, 0).unwrap();
+        assert_eq!(table.sector_size, 512);
+        assert_eq!(table.find("boot_a").map(|p| (p.first_lba, p.last_lba)), Some((40, 44)));
+    }
+
+    #[test]
+    fn damaged_or_unreadable_gpts_fail() {
+        let mut gpt = ufs_gpt();
+        gpt[4096 + 40] ^= 1; // first usable LBA in the header
+        let error = read_gpt(&mut FirehoseSession::new(FakeDevice::with_gpt(gpt, 4096, 4096)), 0).unwrap_err();
+        assert!(matches!(error, GptError::HeaderCrcMismatch { .. }), "{}", error);
+
+        let mut gpt = ufs_gpt();
+        gpt[2 * 4096 + 56] ^= 1; // name of the first entry
+        let error = read_gpt(&mut FirehoseSession::new(FakeDevice::with_gpt(gpt, 4096, 4096)), 0).unwrap_err();
+        assert!(matches!(error, GptError::EntryArrayCrcMismatch { .. }), "{}", error);
+
+        let error = read_gpt(&mut FirehoseSession::new(FakeDevice::new(512)), 1).unwrap_err();
+        assert!(matches!(error, GptError::DeviceRead(_)), "{}", error);
+    }
+
+    #[test]
+    fn rawprogram_entries_are_checked_against_the_gpt() {
+        let table = read_gpt(&mut FirehoseSession::new(FakeDevice::with_gpt(ufs_gpt(), 4096, 4096)), 0).unwrap();
+        let program = |label: &str, start: u64, count: u64, sector_size: u64| {
+            format!(r#"<program SECTOR_SIZE_IN_BYTES="{}" filename="{}.img" label="{}" num_partition_sectors="{}" physical_partition_number="0" start_sector="{}"/>"#, sector_size, label, label, count, start)
+        };
+        let xml = [
+            program("super", 12552, 2621440, 4096),
+            program("metadata", 8456, 4097, 4096),
+            program("vendor_boot", 20, 10, 4096),
+            program("persist", 8, 8192, 512),
+            program("PrimaryGPT", 0, 6, 4096),
+            program("userdata", 2633992, 1, 4096),
+        ]
+        .concat();
+        let rawprogram = crate::super_image_creater::parse_rawprogram_str(&format!("<data>{}</data>", xml)).unwrap();
+        let mismatches = check_layout(&table, 0, &rawprogram);
+        assert_eq!(
+            mismatches,
+            [
+                LayoutMismatch::OutOfRange { label: "metadata".into(), start_sector: 8456, num_sectors: 4097, first_lba: 8456, last_lba: 12551 },
+                LayoutMismatch::MissingPartition { label: "vendor_boot".into(), lun: 0 },
+                LayoutMismatch::SectorSize { label: "persist".into(), expected: 4096, actual: 512 },
+            ]
+        );
+        assert!(check_layout(&table, 1, &rawprogram).is_empty());
+    }
+
+    #[test]
+    fn applies_the_disk_patches_of_a_patch_xml() {
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        let mut session = FirehoseSession::new(FakeDevice::new(4096));
+        apply_patch(&mut session, testdata.join("patch0.xml")).unwrap();
+        let patches = &session.channel_mut().patches;
+        // Only the DISK half, in file order
+        assert_eq!(patches.len(), 13);
+        assert_eq!(patches[0], ("2".to_string(), "NUM_DISK_SECTORS-6.".to_string()));
+        assert_eq!(patches[12], ("NUM_DISK_SECTORS-1.".to_string(), "CRC32(NUM_DISK_SECTORS-1.,92)".to_string()));
+
+        let dir = std::env::temp_dir().join(format!("apply-patch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let arithmetic = r#"<patches><patch SECTOR_SIZE_IN_BYTES="512" byte_offset="0" filename="DISK" physical_partition_number="0" size_in_bytes="8" start_sector="(2+3)*2" value="0x10+34." what="Arithmetic."/></patches>"#;
+        std::fs::write(dir.join("patch1.xml"), arithmetic).unwrap();
+        apply_patch(&mut session, dir.join("patch1.xml")).unwrap();
+        assert_eq!(session.channel_mut().patches.last(), Some(&("10".to_string(), "50".to_string())));
+
+        // Nothing is sent when any expression is invalid
+        std::fs::write(dir.join("patch2.xml"), format!("{}{}", arithmetic.replace("</patches>", ""), arithmetic.replace("<patches>", "").replace("(2+3)*2", "(2+3"))).unwrap();
+        let error = apply_patch(&mut session, dir.join("patch2.xml")).unwrap_err();
+        assert!(error.to_string().contains("(2+3"), "{}", error);
+        assert_eq!(session.channel_mut().patches.len(), 14);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// The \<log\> lines of a captured \<getstorageinfo\> answer, one per line
+    fn logs(text: &str) -> Vec<String> {
+        text.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_the_storage_info_of_ufs_and_emmc_devices() {
+        // A Samsung UFS 3.1 programmer, key: value lines only
+        let ufs = logs(
+            "UFS fInitialized: 0x1
+            UFS Current LUN Number: = 0x0
+            UFS Total Active LU: 0x6
+            UFS wManufacturerID: 0x1ce
+            UFS Boot Partition Enabled: 0x1
+            UFS Raw Device Capacity: = 0x3b9e000
+            UFS Min Block Size: 0x8
+            UFS Erase Block Size: 0x2000
+            UFS Logical Block Size: 0xc
+            UFS LU Total Blocks: 0x1d1b436
+            UFS iManufacturerName String: = SAMSUNG
+            UFS iProductName String: = KLUDG4UHDB-B2D1
+            UFS Inquiry Command Output: SAMSUNG  KLUDG4UHDB-B2D1  0500",
+        );
+        let info = parse_storage_info(&ufs).unwrap();
+        assert_eq!(
+            info,
+            StorageInfo {
+                memory_type: None,
+                sector_size: 4096,
+                total_blocks: 0x1d1b436,
+                num_physical: Some(6),
+                manufacturer_id: Some(0x1ce),
+                serial_number: None,
+                product_name: Some("KLUDG4UHDB-B2D1".into()),
+                fw_version: None,
+            }
+        );
+
+        // An eMMC programmer logging both forms, the JSON split over two lines
+        let emmc = logs(
+            r#"Device Total Logical Blocks: 0x747c000
+            Device Block Size in Bytes: 0x200
+            Device Total Physical Partitions: 0x4
+            Device Manufacturer ID: 0x15
+            Device Serial Number: 0x1b9d4e2a
+            INFO: {"storage_info": {"total_blocks":122142720, "block_size":512, "page_size":512,
+            "num_physical":4, "manufacturer_id":21, "serial_num":463294000,
+            "fw_version":"0x5","mem_type":"eMMC","prod_name":"QE63MB"}}"#,
+        );
+        let info = parse_storage_info(&emmc).unwrap();
+        assert_eq!((info.sector_size, info.total_blocks, info.num_physical), (512, 122142720, Some(4)));
+        assert_eq!((info.manufacturer_id, info.serial_number), (Some(21), Some(463294000)));
+        assert_eq!((info.memory_type.as_deref(), info.product_name.as_deref(), info.fw_version.as_deref()), (Some("eMMC"), Some("QE63MB"), Some("0x5")));
+        assert_eq!(info.size_bytes(), 122142720 * 512);
+
+        let error = parse_storage_info(&ufs[..3]).unwrap_err();
+        assert!(error.to_string().contains("no sector size"), "{}", error);
+        assert!(parse_storage_info(&[]).unwrap_err().to_string().contains("the Device sent no error message"));
+    }
+
+    #[test]
+    fn rawprograms_of_another_sector_size_are_reported() {
+        let info = StorageInfo { sector_size: 4096, total_blocks: 0x1d1b436, ..Default::default() };
+        let rawprogram = crate::super_image_creater::parse_rawprogram_str(
+            r#"<data>
+            <program SECTOR_SIZE_IN_BYTES="512" filename="boot.img" label="boot_a" num_partition_sectors="10" physical_partition_number="0" start_sector="100"/>
+            <program SECTOR_SIZE_IN_BYTES="4096" filename="vbmeta.img" label="vbmeta_a" num_partition_sectors="10" physical_partition_number="0" start_sector="200"/>
+            <program SECTOR_SIZE_IN_BYTES="512" filename="xbl.elf" label="xbl_a" num_partition_sectors="10" physical_partition_number="1" start_sector="6"/>
+            </data>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            check_sector_size(&info, 0, &rawprogram),
+            [LayoutMismatch::SectorSize { label: "boot_a".into(), expected: 4096, actual: 512 }]
+        );
+    }
+}
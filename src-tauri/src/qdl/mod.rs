@@ -1,6 +1,7 @@
 pub mod types;
 pub mod parsers;
 pub mod serial;
+pub mod usb;
 pub mod firehose;
 pub mod sahara;
 use itertools::Itertools;
@@ -9,12 +10,15 @@ use std::fs;
 use std::io;
 use crate::qdl::serial::setup_serial_device;
 use crate::qdl::types::FirehoseConfiguration;
-use crate::qdl::firehose::{firehose_read, firehose_configure};
+use crate::qdl::firehose::{DumpProgress, FirehoseError, FirehoseSession, firehose_read, firehose_configure};
+use crate::qdl::types::{QdlBackend, QdlReadWrite};
+use crate::qdl::usb::list_edl_devices;
 use crate::qdl::parsers::{firehose_parser_ack_nak, firehose_parser_configure_response};
 use crate::qdl::serial::QdlSerialConfig;
 use crate::qdl::sahara::SaharaMode;
 use crate::qdl::sahara::sahara_run;
 use crate::qdl::sahara::SaharaCmdModeCmd;
+use crate::qdl::sahara::{DeviceIds, SaharaError, SaharaSession};
 
 pub struct SaharaClient {
     chip_sn: String,
@@ -115,3 +119,63 @@ impl SaharaClient {
     }
 }
 
+
+/// Connects to the Firehose programmer already running on the Device (see
+/// `SaharaClient::send_loader`) and configures it for writing to storage. Uses USB
+/// where that is the default backend, the QDLoader serial port `dev_path` otherwise.
+pub fn open_firehose(dev_path: Option<String>) -> anyhow::Result<QdlDevice<dyn QdlReadWrite>> {
+    let backend = QdlBackend::default();
+    let rw: Box<dyn QdlReadWrite> = match backend {
+        QdlBackend::Usb => {
+            let device = list_edl_devices()?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("No EDL device found on USB"))?;
+            Box::new(device.open()?)
+        }
+        QdlBackend::Serial => Box::new(setup_serial_device(dev_path)?),
+    };
+    let mut qdl_dev = QdlDevice {
+        rw,
+        fh_cfg: FirehoseConfiguration { backend, bypass_storage: false, ..Default::default() },
+        reset_on_drop: false,
+    };
+
+    firehose_configure(&mut qdl_dev, false)?;
+    firehose_read(&mut qdl_dev, firehose_parser_configure_response)?;
+    Ok(qdl_dev)
+}
+
+/// Reads the Sahara device ids, then uploads the Firehose programmer at `loader_path`.
+/// Uses the first EDL device on USB, or the serial port `port` where the backend is
+/// serial (Windows).
+pub fn upload_loader(port: Option<String>, loader_path: &str) -> Result<DeviceIds, SaharaError> {
+    let loader = fs::read(loader_path)?;
+    let rw: Box<dyn QdlReadWrite> = match QdlBackend::default() {
+        QdlBackend::Usb => {
+            let device = list_edl_devices()
+                .map_err(|e| SaharaError::Open(e.to_string()))?
+                .into_iter()
+                .next()
+                .ok_or_else(|| SaharaError::Open("No EDL device found on USB".to_string()))?;
+            Box::new(device.open().map_err(|e| SaharaError::Open(e.to_string()))?)
+        }
+        QdlBackend::Serial => Box::new(setup_serial_device(port).map_err(|e| SaharaError::Open(e.to_string()))?),
+    };
+    SaharaSession::identify_and_upload(rw, &loader)
+}
+
+/// Opens the device like `open_firehose` and dumps sectors of `lun` to `output_path`,
+/// see `FirehoseSession::dump_partition`
+pub fn dump_partition(
+    port: Option<String>,
+    lun: u8,
+    start_sector: u64,
+    num_sectors: u64,
+    sector_size: usize,
+    output_path: &std::path::Path,
+    progress: impl FnMut(DumpProgress),
+) -> Result<u64, FirehoseError> {
+    let mut session = FirehoseSession::new(open_firehose(port)?);
+    session.dump_partition(lun, start_sector, num_sectors, sector_size, output_path, progress)
+}
@@ -69,8 +69,16 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
         );
     }
 
-    // TODO: MaxPayloadSizeFromTargetInBytes seems useless when xfers are abstracted through libusb
-    // TODO: ^ is usually 1kiB (reaaally small), newer (citation needed) devices don't advertise it
+    // MaxPayloadSizeFromTargetInBytes is the largest piece the programmer sends <read>
+    // data in. This is the intended chunking: one <read> streams its whole range and
+    // the host takes it in reads of recv_buffer_size (see read_sectors), so pieces
+    // only need to fit the buffer. It is usually 1kiB (reaaally small) and newer
+    // devices don't always advertise it, so it only ever grows the read buffer
+    if let Some(val) = attrs.get("MaxPayloadSizeFromTargetInBytes").and_then(|v| v.parse::<usize>().ok())
+        && val > channel.fh_config().recv_buffer_size
+    {
+        channel.mut_fh_config().recv_buffer_size = val;
+    }
 
     channel.mut_fh_config().xml_buf_size = attrs
         .get("MaxXMLSizeInBytes")
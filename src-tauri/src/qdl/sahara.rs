@@ -7,17 +7,20 @@ use std::{
     cmp::min,
     ffi::CStr,
     fs::File,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
     mem::{self, size_of_val},
+    time::Instant,
 };
 
 use anyhow::{Result, anyhow, bail};
 
 use bincode::serialize;
+use log::{debug, trace};
 use serde::{self, Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use thiserror::Error;
 
-use crate::qdl::types::{QdlBackend, QdlChan};
+use crate::qdl::types::{QdlBackend, QdlChan, TimeoutError, TransportConfig};
 
 const SAHARA_STATUS_SUCCESS: u32 = 0;
 
@@ -489,6 +492,7 @@ pub fn sahara_run<T: QdlChan>(
     loop {
         let bytes_read = channel.read(&mut buf[..])?;
         let pkt = sahara_parse_packet(&buf[..bytes_read], verbose)?;
+        trace!("Sahara <- {:?}, {} bytes", pkt.cmd, pkt.len);
         let pktsize = size_of_val(&pkt.cmd) + size_of_val(&pkt.len);
 
         match pkt.cmd {
@@ -657,3 +661,467 @@ fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
 
     Ok(ret)
 }
+
+#[derive(Debug, Error)]
+pub enum SaharaError {
+    #[error("Transport error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed Sahara packet: {0}")]
+    Malformed(String),
+    #[error("Unexpected Sahara command 0x{cmd:x} while {state:?}")]
+    Unexpected { cmd: u32, state: SaharaState },
+    #[error("Device requested {len} bytes at offset {offset}, the programmer has {size}")]
+    OutOfBounds { offset: u64, len: u64, size: u64 },
+    #[error("Device reported a failed image transfer (status 0x{0:x})")]
+    TransferFailed(u32),
+    #[error("{0}")]
+    Timeout(TimeoutError),
+    #[error("Cannot open the device: {0}")]
+    Open(String),
+}
+
+/// Identifiers read in Sahara command mode, `None` where the device does not offer
+/// them (Sahara v3 devices refuse command mode)
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DeviceIds {
+    /// Chip serial number, e.g. `0x1a2b3c4d`
+    pub serial_number: Option<String>,
+    /// MSM HWID, e.g. `0x0013b0e100000000`
+    pub hw_id: Option<String>,
+    /// Hash of the OEM root (PK) certificate, lowercase hex
+    pub oem_pk_hash: Option<String>,
+}
+
+/// Where a `SaharaSession` is in the image transfer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaharaState {
+    /// Waiting for the device's HELLO
+    WaitingForHello,
+    /// HELLO answered, serving READ_DATA requests until END_IMAGE_TX
+    Transferring,
+    /// DONE sent, waiting for DONE_RESP
+    WaitingForDone,
+    Finished,
+}
+
+/// Host side of the Sahara image transfer, for any byte stream (serial port, USB,
+/// or a mock in tests). Unlike `sahara_run` it never panics on unexpected input and
+/// reads each packet by its length field, so packets may arrive split or merged.
+///
+/// Reads give up once `TransportConfig::total_read_timeout` (20 s by default) has
+/// passed without data, so the transport must return from `read` now and then (a
+/// read timeout, as serial ports and USB have) for a stuck device not to block
+/// forever.
+pub struct SaharaSession<T: Read + Write> {
+    transport: T,
+    state: SaharaState,
+    bytes_sent: u64,
+    config: TransportConfig,
+    /// When the image transfer started, for the timing in the debug log
+    transfer_started: Option<Instant>,
+}
+
+impl<T: Read + Write> SaharaSession<T> {
+    pub fn new(transport: T) -> Self {
+        SaharaSession {
+            transport,
+            state: SaharaState::WaitingForHello,
+            bytes_sent: 0,
+            config: TransportConfig::default(),
+            transfer_started: None,
+        }
+    }
+
+    /// Uploads a Firehose programmer: answers HELLO, serves READ_DATA and
+    /// READ_DATA_64 requests from `programmer`, and sends DONE after a successful
+    /// END_IMAGE_TX. Returns once the device acknowledges with DONE_RESP, or right
+    /// away if the device already talks Firehose XML (the programmer is running).
+    pub fn upload_programmer(transport: T, programmer: &[u8]) -> Result<(), SaharaError> {
+        SaharaSession::new(transport).run(programmer)
+    }
+
+    /// Reads the device identifiers in command mode, then uploads `programmer`
+    pub fn identify_and_upload(transport: T, programmer: &[u8]) -> Result<DeviceIds, SaharaError> {
+        let mut session = SaharaSession::new(transport);
+        let ids = session.read_device_ids()?;
+        session.run(programmer)?;
+        Ok(ids)
+    }
+
+    pub fn state(&self) -> SaharaState {
+        self.state
+    }
+
+    /// Reads serial number, HWID and OEM PK hash in command mode, before any image
+    /// is transferred, and leaves the session ready for `run`.
+    ///
+    /// Each command is a round of HELLO (answered in command mode), COMMAND_READY,
+    /// EXECUTE, EXECUTE_RESP and EXECUTE_DATA with the raw response, after which the
+    /// host switches back to image transfer mode and the device says HELLO again.
+    /// Devices with Sahara v3 or newer get no commands and all ids are `None`.
+    pub fn read_device_ids(&mut self) -> Result<DeviceIds, SaharaError> {
+        let mut ids = DeviceIds::default();
+        let Some(version) = self.wait_hello()? else {
+            return Ok(ids);
+        };
+        if version < 3 {
+            let serial = self.execute(SaharaCmdModeCmd::ReadSerialNum)?;
+            ids.serial_number = Some(format!("0x{:08x}", field(&serial, 0, 4)?));
+            if self.wait_hello()?.is_none() {
+                return Ok(ids);
+            }
+            let hw_id = self.execute(SaharaCmdModeCmd::ReadHwId)?;
+            ids.hw_id = Some(format!("0x{:016x}", field(&hw_id, 0, 8)?));
+            if self.wait_hello()?.is_none() {
+                return Ok(ids);
+            }
+            let key_hash = self.execute(SaharaCmdModeCmd::ReadOemKeyHash)?;
+            ids.oem_pk_hash = Some(key_hash_hex(&key_hash));
+            // HELLO again, this time for the image transfer
+            if self.wait_hello()?.is_none() {
+                return Ok(ids);
+            }
+        }
+        self.send_hello_resp(SaharaMode::WaitingForImage)?;
+        self.start_transfer();
+        Ok(ids)
+    }
+
+    /// Waits for HELLO and returns the device's protocol version, `None` if the
+    /// device already talks Firehose (the session is then finished)
+    fn wait_hello(&mut self) -> Result<Option<u32>, SaharaError> {
+        let (cmd, body) = self.read_packet()?;
+        if cmd == SaharaCmd::SaharaXML as u32 {
+            debug!("Device already runs the Firehose programmer, no Sahara transfer");
+            self.state = SaharaState::Finished;
+            return Ok(None);
+        }
+        if cmd != SaharaCmd::SaharaHello as u32 {
+            debug!("Sahara expected HELLO, got command 0x{:x}", cmd);
+            return Err(SaharaError::Unexpected { cmd, state: self.state });
+        }
+        let version = field(&body, 0, 4)? as u32;
+        debug!("Sahara HELLO, protocol version {}", version);
+        Ok(Some(version))
+    }
+
+    /// Reads a packet that must be `expected`, returns its body
+    fn expect(&mut self, expected: SaharaCmd) -> Result<Vec<u8>, SaharaError> {
+        let (cmd, body) = self.read_packet()?;
+        if cmd != expected as u32 {
+            debug!("Sahara expected {:?}, got command 0x{:x}", expected, cmd);
+            return Err(SaharaError::Unexpected { cmd, state: self.state });
+        }
+        Ok(body)
+    }
+
+    /// One command mode round after HELLO, returns the response data
+    fn execute(&mut self, command: SaharaCmdModeCmd) -> Result<Vec<u8>, SaharaError> {
+        self.send_hello_resp(SaharaMode::Command)?;
+        self.expect(SaharaCmd::SaharaCommandReady)?;
+        self.send_packet(SaharaCmd::SaharaExecute, &(command as u32).to_le_bytes())?;
+        let resp = self.expect(SaharaCmd::SaharaExecuteResp)?;
+        let len = field(&resp, 4, 4)? as usize;
+        self.send_packet(SaharaCmd::SaharaExecuteData, &(command as u32).to_le_bytes())?;
+        let mut data = vec![0u8; len];
+        self.read_exact(&mut data)?;
+        debug!("Sahara command {:?} returned {} bytes", command, len);
+        self.send_packet(SaharaCmd::SaharaSwitchMode, &(SaharaMode::WaitingForImage as u32).to_le_bytes())?;
+        Ok(data)
+    }
+
+    /// `read_exact` that keeps waiting through transport timeouts until the total
+    /// read timeout has passed without the buffer being filled
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), SaharaError> {
+        let deadline = Instant::now() + self.config.total_read_timeout();
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.transport.read(&mut buf[filled..]) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e.into()),
+            }
+            if filled < buf.len() && Instant::now() >= deadline {
+                debug!("Sahara read timed out while {:?}, {} of {} bytes received", self.state, filled, buf.len());
+                return Err(SaharaError::Timeout(TimeoutError {
+                    operation: format!("waiting for a Sahara packet while {:?}", self.state),
+                    attempts: self.config.max_retries + 1,
+                    timeout: self.config.read_timeout,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Programmer bytes sent so far (bytes requested twice count twice)
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn run(&mut self, programmer: &[u8]) -> Result<(), SaharaError> {
+        while self.state != SaharaState::Finished {
+            let (cmd, body) = self.read_packet()?;
+            self.handle(cmd, &body, programmer)?;
+        }
+        Ok(())
+    }
+
+    fn read_packet(&mut self) -> Result<(u32, Vec<u8>), SaharaError> {
+        let mut header = [0u8; 8];
+        self.read_exact(&mut header)?;
+        let cmd = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        if cmd == SaharaCmd::SaharaXML as u32 {
+            return Ok((cmd, Vec::new()));
+        }
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        trace!("Sahara <- command 0x{:x}, {} bytes", cmd, len);
+        if !(header.len()..=4096).contains(&len) {
+            return Err(SaharaError::Malformed(format!("command 0x{:x} with length {}", cmd, len)));
+        }
+        let mut body = vec![0u8; len - header.len()];
+        self.read_exact(&mut body)?;
+        Ok((cmd, body))
+    }
+
+    fn handle(&mut self, cmd: u32, body: &[u8], programmer: &[u8]) -> Result<(), SaharaError> {
+        let unexpected = SaharaError::Unexpected { cmd, state: self.state };
+        match (self.state, cmd) {
+            (SaharaState::WaitingForHello, c) if c == SaharaCmd::SaharaHello as u32 => {
+                let version = field(body, 0, 4)?;
+                debug!("Sahara HELLO, protocol version {}, sending the programmer ({} bytes)", version, programmer.len());
+                self.send_hello_resp(SaharaMode::WaitingForImage)?;
+                self.start_transfer();
+            }
+            (SaharaState::Transferring, c) if c == SaharaCmd::SaharaReadData as u32 => {
+                let (offset, len) = (field(body, 4, 4)?, field(body, 8, 4)?);
+                trace!("Sahara READ_DATA offset {} length {}", offset, len);
+                self.send_image(programmer, offset, len)?;
+            }
+            (SaharaState::Transferring, c) if c == SaharaCmd::SaharaReadData64 as u32 => {
+                let (offset, len) = (field(body, 8, 8)?, field(body, 16, 8)?);
+                trace!("Sahara READ_DATA_64 offset {} length {}", offset, len);
+                self.send_image(programmer, offset, len)?;
+            }
+            (SaharaState::Transferring, c) if c == SaharaCmd::SaharaEndOfImage as u32 => {
+                let status = field(body, 4, 4)? as u32;
+                debug!(
+                    "Sahara END_IMAGE_TX, status {}, {} bytes sent in {:?}",
+                    status,
+                    self.bytes_sent,
+                    self.transfer_started.map(|t| t.elapsed()).unwrap_or_default()
+                );
+                if status != SAHARA_STATUS_SUCCESS {
+                    return Err(SaharaError::TransferFailed(status));
+                }
+                self.send_packet(SaharaCmd::SaharaDone, &[])?;
+                self.state = SaharaState::WaitingForDone;
+            }
+            (SaharaState::WaitingForDone, c) if c == SaharaCmd::SaharaDoneResp as u32 => {
+                // Status 1 means no more images are needed; a single programmer is all
+                // this session has to offer either way
+                debug!("Sahara DONE_RESP, status {}", field(body, 0, 4).unwrap_or_default());
+                self.state = SaharaState::Finished;
+            }
+            (_, c) if c == SaharaCmd::SaharaXML as u32 => {
+                debug!("Device already runs the Firehose programmer, no Sahara transfer");
+                self.state = SaharaState::Finished;
+            }
+            (_, c) if c == SaharaCmd::SaharaResetResp as u32 => {}
+            _ => {
+                debug!("Sahara got unexpected command 0x{:x} while {:?}", cmd, self.state);
+                return Err(unexpected);
+            }
+        }
+        Ok(())
+    }
+
+    fn start_transfer(&mut self) {
+        self.state = SaharaState::Transferring;
+        self.transfer_started = Some(Instant::now());
+    }
+
+    fn send_packet(&mut self, cmd: SaharaCmd, body: &[u8]) -> Result<(), SaharaError> {
+        let len = (2 * size_of::<u32>() + body.len()) as u32;
+        trace!("Sahara -> {:?}, {} bytes", cmd, len);
+        let mut pkt = Vec::with_capacity(len as usize);
+        pkt.extend_from_slice(&(cmd as u32).to_le_bytes());
+        pkt.extend_from_slice(&len.to_le_bytes());
+        pkt.extend_from_slice(body);
+        self.transport.write_all(&pkt)?;
+        self.transport.flush()?;
+        Ok(())
+    }
+
+    fn send_hello_resp(&mut self, mode: SaharaMode) -> Result<(), SaharaError> {
+        let data = HelloResp {
+            ver: SAHARA_VERSION,
+            compatible: 1,
+            status: SAHARA_STATUS_SUCCESS,
+            mode,
+            unk0: 0,
+            unk1: 0,
+            unk2: 0,
+            unk3: 0,
+            unk4: 0,
+            unk5: 0,
+        };
+        let body = serialize(&data).map_err(|e| SaharaError::Malformed(e.to_string()))?;
+        self.send_packet(SaharaCmd::SaharaHelloResp, &body)
+    }
+
+    fn send_image(&mut self, programmer: &[u8], offset: u64, len: u64) -> Result<(), SaharaError> {
+        let size = programmer.len() as u64;
+        let end = offset.checked_add(len).filter(|&end| end <= size);
+        let Some(end) = end else {
+            return Err(SaharaError::OutOfBounds { offset, len, size });
+        };
+        self.transport.write_all(&programmer[offset as usize..end as usize])?;
+        self.transport.flush()?;
+        self.bytes_sent += len;
+        Ok(())
+    }
+}
+
+/// Some devices send the PK hash three times in a row, keep one copy then
+fn key_hash_hex(data: &[u8]) -> String {
+    let third = data.len() / 3;
+    let hash = if third > 0 && data.len().is_multiple_of(3) && data[..third] == data[third..2 * third] && data[..third] == data[2 * third..] {
+        &data[..third]
+    } else {
+        data
+    };
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Little endian field of `size` (4 or 8) bytes at `offset` in a packet body
+fn field(body: &[u8], offset: usize, size: usize) -> Result<u64, SaharaError> {
+    let bytes = body
+        .get(offset..offset + size)
+        .ok_or_else(|| SaharaError::Malformed(format!("body of {} bytes is too short", body.len())))?;
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Replays recorded device packets and records what the host sends back
+    struct MockTransport {
+        device: Cursor<Vec<u8>>,
+        host: Vec<u8>,
+    }
+
+    impl MockTransport {
+        fn new(packets: &[Vec<u8>]) -> Self {
+            MockTransport { device: Cursor::new(packets.concat()), host: Vec::new() }
+        }
+    }
+
+    impl Read for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.device.read(buf)
+        }
+    }
+
+    impl Write for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.host.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A packet of `cmd` with 32-bit fields
+    fn packet(cmd: u32, fields: &[u32]) -> Vec<u8> {
+        let mut packet = cmd.to_le_bytes().to_vec();
+        packet.extend_from_slice(&(8 + 4 * fields.len() as u32).to_le_bytes());
+        fields.iter().for_each(|f| packet.extend_from_slice(&f.to_le_bytes()));
+        packet
+    }
+
+    /// A packet of `cmd` with 64-bit fields (READ_DATA_64)
+    fn packet64(cmd: u32, fields: &[u64]) -> Vec<u8> {
+        let mut packet = cmd.to_le_bytes().to_vec();
+        packet.extend_from_slice(&(8 + 8 * fields.len() as u32).to_le_bytes());
+        fields.iter().for_each(|f| packet.extend_from_slice(&f.to_le_bytes()));
+        packet
+    }
+
+    /// HELLO of a Sahara v2 device in image transfer mode
+    fn hello() -> Vec<u8> {
+        packet(0x01, &[2, 1, 1024, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    #[test]
+    fn uploads_the_programmer_through_read_data_and_read_data_64() {
+        let programmer: Vec<u8> = (0..100u8).collect();
+        let mut transport = MockTransport::new(&[
+            hello(),
+            packet(0x03, &[13, 0, 50]),
+            packet64(0x12, &[13, 50, 50]),
+            packet(0x04, &[13, 0]),
+            packet(0x06, &[1]),
+        ]);
+        let mut session = SaharaSession::new(&mut transport);
+        session.run(&programmer).unwrap();
+        assert_eq!(session.bytes_sent(), 100);
+        assert_eq!(session.state(), SaharaState::Finished);
+
+        let host = &transport.host;
+        // HELLO_RESP (48 bytes, version 2, image transfer mode), the data, then DONE
+        assert_eq!(host[0..8], [0x02, 0, 0, 0, 48, 0, 0, 0]);
+        assert_eq!(host[8..12], 2u32.to_le_bytes());
+        assert_eq!(host[48..148], programmer[..]);
+        assert_eq!(host[148..], [0x05, 0, 0, 0, 8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_requests_and_failed_transfers() {
+        let programmer = vec![0u8; 100];
+        let transport = MockTransport::new(&[hello(), packet(0x03, &[13, 90, 50])]);
+        let error = SaharaSession::upload_programmer(transport, &programmer).unwrap_err();
+        assert!(matches!(error, SaharaError::OutOfBounds { offset: 90, len: 50, size: 100 }), "{}", error);
+
+        let transport = MockTransport::new(&[packet(0x03, &[13, 0, 1])]);
+        let error = SaharaSession::upload_programmer(transport, &programmer).unwrap_err();
+        assert!(matches!(error, SaharaError::Unexpected { cmd: 0x03, state: SaharaState::WaitingForHello }), "{}", error);
+
+        let transport = MockTransport::new(&[hello(), packet(0x04, &[13, 5])]);
+        let error = SaharaSession::upload_programmer(transport, &programmer).unwrap_err();
+        assert!(matches!(error, SaharaError::TransferFailed(5)), "{}", error);
+
+        // The device goes away in the middle of a packet
+        let mut truncated = hello();
+        truncated.truncate(20);
+        let error = SaharaSession::upload_programmer(MockTransport::new(&[truncated]), &programmer).unwrap_err();
+        assert!(matches!(error, SaharaError::Io(_) | SaharaError::Malformed(_)), "{}", error);
+    }
+
+    #[test]
+    fn reads_device_ids_in_command_mode_before_the_upload() {
+        let pk_hash: Vec<u8> = (0..32u8).collect();
+        let mut packets = Vec::new();
+        for (command, data) in [(1u32, 0x1a2b3c4du32.to_le_bytes().to_vec()), (2, 0x0013b0e100000000u64.to_le_bytes().to_vec()), (3, pk_hash.repeat(3))] {
+            packets.push(hello());
+            packets.push(packet(0x0b, &[]));
+            packets.push(packet(0x0e, &[command, data.len() as u32]));
+            packets.push(data);
+        }
+        packets.extend([hello(), packet(0x03, &[13, 0, 50]), packet(0x04, &[13, 0]), packet(0x06, &[1])]);
+        let mut transport = MockTransport::new(&packets);
+        let ids = SaharaSession::identify_and_upload(&mut transport, &[7u8; 50]).unwrap();
+        assert_eq!(ids.serial_number.as_deref(), Some("0x1a2b3c4d"));
+        assert_eq!(ids.hw_id.as_deref(), Some("0x0013b0e100000000"));
+        assert_eq!(ids.oem_pk_hash, Some(pk_hash.iter().map(|b| format!("{:02x}", b)).collect()));
+        // HELLO_RESP in command mode, then EXECUTE of the serial number command
+        assert_eq!(transport.host[20..24], 3u32.to_le_bytes());
+        assert_eq!(transport.host[48..60], [0x0d, 0, 0, 0, 12, 0, 0, 0, 1, 0, 0, 0]);
+    }
+}
@@ -4,19 +4,28 @@ use anyhow::{Result, bail};
 use serial2::{self, SerialPort};
 use std::io::{BufRead, Read, Write};
 
-use crate::qdl::types::QdlReadWrite;
+use crate::qdl::types::{QdlReadWrite, TransportConfig};
 
 pub struct QdlSerialConfig {
     serport: SerialPort,
+    /// Timeouts and retries of every read and write, also set on the port itself
+    transport: TransportConfig,
     buf: Vec<u8>,
     pos: usize,
     cap: usize,
 }
 
-// TODO: timeouts?
+impl QdlSerialConfig {
+    fn read_port(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        let serport = &mut self.serport;
+        self.transport.retry("reading from the serial port", self.transport.read_timeout, || serport.read(out))
+    }
+}
+
 impl Write for QdlSerialConfig {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        self.serport.write(buf)
+        let serport = &mut self.serport;
+        self.transport.retry("writing to the serial port", self.transport.write_timeout, || serport.write(buf))
     }
     fn flush(&mut self) -> Result<(), std::io::Error> {
         self.serport.flush()
@@ -33,7 +42,7 @@ impl Read for QdlSerialConfig {
             return Ok(n);
         }
         // Otherwise, read directly from serial port
-        self.serport.read(out)
+        self.read_port(out)
     }
 }
 
@@ -45,12 +54,10 @@ impl BufRead for QdlSerialConfig {
             if self.buf.is_empty() {
                 self.buf.resize(4096, 0);
             }
-            match self.serport.read(&mut self.buf) {
-                Ok(n) => {
-                    self.cap = n;
-                }
-                Err(e) => return Err(e),
-            }
+            let mut buf = std::mem::take(&mut self.buf);
+            let result = self.read_port(&mut buf);
+            self.buf = buf;
+            self.cap = result?;
         }
         Ok(&self.buf[self.pos..self.cap])
     }
@@ -60,7 +67,14 @@ impl BufRead for QdlSerialConfig {
     }
 }
 
-impl QdlReadWrite for QdlSerialConfig {}
+impl QdlReadWrite for QdlSerialConfig {
+    fn set_transport_config(&mut self, config: TransportConfig) {
+        // Ports that refuse a timeout keep their own, the retries still apply
+        let _ = self.serport.set_read_timeout(config.read_timeout);
+        let _ = self.serport.set_write_timeout(config.write_timeout);
+        self.transport = config;
+    }
+}
 
 pub fn setup_serial_device(dev_path: Option<String>) -> Result<QdlSerialConfig> {
     if dev_path.is_none() {
@@ -73,10 +87,14 @@ pub fn setup_serial_device(dev_path: Option<String>) -> Result<QdlSerialConfig>
         Ok(settings)
     })?;
 
-    Ok(QdlSerialConfig {
+    let mut config = QdlSerialConfig {
         serport,
+        transport: TransportConfig::default(),
         buf: Vec::new(),
         pos: 0,
         cap: 0,
-    })
+    };
+    // The same 5 s timeouts and retries as USB
+    config.set_transport_config(TransportConfig::default());
+    Ok(config)
 }
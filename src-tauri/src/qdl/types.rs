@@ -5,6 +5,7 @@ use std::{
     fmt::Display,
     io::{BufRead, ErrorKind, Read, Write},
     str::FromStr,
+    time::Duration,
 };
 
 use anyhow::{Error, bail};
@@ -85,13 +86,107 @@ impl Default for FirehoseConfiguration {
         }
     }
 }
+/// Timeouts and retries of the single USB or serial transfers under Sahara and
+/// Firehose, so a stalled Device fails the operation instead of hanging it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportConfig {
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    /// Further attempts of a transfer that timed out, 0 gives up at the first timeout
+    pub max_retries: u32,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self { read_timeout: Duration::from_secs(5), write_timeout: Duration::from_secs(5), max_retries: 3 }
+    }
+}
+
+impl TransportConfig {
+    /// Longest a read can take before failing: every attempt timing out
+    pub fn total_read_timeout(&self) -> Duration {
+        self.read_timeout * (self.max_retries + 1)
+    }
+
+    /// Runs `transfer` again while it times out, up to `max_retries` more times.
+    ///
+    /// The final timeout is returned as an `ErrorKind::TimedOut` error wrapping a
+    /// `TimeoutError` for `operation`; other errors are returned right away.
+    pub(crate) fn retry<R>(
+        &self,
+        operation: &str,
+        timeout: Duration,
+        mut transfer: impl FnMut() -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match transfer() {
+                Err(e) if e.kind() == ErrorKind::TimedOut && attempts <= self.max_retries => continue,
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    let error = TimeoutError { operation: operation.to_string(), attempts, timeout };
+                    return Err(std::io::Error::new(ErrorKind::TimedOut, error));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A transfer that still timed out after all retries of its `TransportConfig`
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("Timed out {operation}{}", describe_attempts(*attempts, *timeout))]
+pub struct TimeoutError {
+    /// What was waited for, e.g. `reading from USB`
+    pub operation: String,
+    /// 0 when the transport did not say how often it tried
+    pub attempts: u32,
+    /// Timeout of each attempt
+    pub timeout: Duration,
+}
+
+impl TimeoutError {
+    /// The `TimeoutError` a `TransportConfig::retry` error carries, or one for
+    /// `operation` when the timeout came from elsewhere (e.g. a serial port read)
+    pub fn from_io(e: &std::io::Error, operation: &str) -> Self {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<TimeoutError>()) {
+            Some(timeout) => timeout.clone(),
+            None => TimeoutError { operation: operation.to_string(), attempts: 0, timeout: Duration::ZERO },
+        }
+    }
+}
+
+fn describe_attempts(attempts: u32, timeout: Duration) -> String {
+    match attempts {
+        0 => String::new(),
+        _ => format!(" ({attempts} attempt(s) of {timeout:?})"),
+    }
+}
+
 pub trait QdlChan: BufRead + Write {
     fn fh_config(&self) -> &FirehoseConfiguration;
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration;
+
+    /// Applies new timeouts and retries to the underlying transport
+    fn set_transport_config(&mut self, _config: TransportConfig) {}
+
+    /// Stops the channel from resetting the Device to EDL when dropped, once a
+    /// \<power\> command has left nothing to reset
+    fn disarm_reset_on_drop(&mut self) {}
 }
 
-pub trait QdlReadWrite: BufRead + Write + Send + Sync {}
-impl<T> QdlReadWrite for &mut T where T: QdlReadWrite + ?Sized {}
+pub trait QdlReadWrite: BufRead + Write + Send + Sync {
+    /// Applies new timeouts and retries; transports without timeouts ignore them
+    fn set_transport_config(&mut self, _config: TransportConfig) {}
+}
+impl<T> QdlReadWrite for &mut T
+where
+    T: QdlReadWrite + ?Sized,
+{
+    fn set_transport_config(&mut self, config: TransportConfig) {
+        (**self).set_transport_config(config);
+    }
+}
 
 pub struct QdlDevice<T>
 where
@@ -148,6 +243,14 @@ where
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
         &mut self.fh_cfg
     }
+
+    fn set_transport_config(&mut self, config: TransportConfig) {
+        self.rw.set_transport_config(config);
+    }
+
+    fn disarm_reset_on_drop(&mut self) {
+        self.reset_on_drop = false;
+    }
 }
 
 impl<T> Drop for QdlDevice<T>
@@ -236,3 +339,102 @@ impl Display for FirehoseResetMode {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qdl::firehose::FirehoseError;
+
+    /// A port whose first `timeouts` reads time out, retried like the serial and
+    /// USB transports retry theirs
+    struct StalledPort {
+        transport: TransportConfig,
+        timeouts: u32,
+        attempts: u32,
+    }
+
+    impl StalledPort {
+        fn new(timeouts: u32) -> Self {
+            StalledPort { transport: TransportConfig::default(), timeouts, attempts: 0 }
+        }
+    }
+
+    impl Read for StalledPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let transport = self.transport;
+            transport.retry("reading from the port", transport.read_timeout, || {
+                self.attempts += 1;
+                if self.timeouts > 0 {
+                    self.timeouts -= 1;
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                buf[0] = 0x5a;
+                Ok(1)
+            })
+        }
+    }
+
+    impl BufRead for StalledPort {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            unimplemented!()
+        }
+
+        fn consume(&mut self, _amt: usize) {}
+    }
+
+    impl Write for StalledPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl QdlReadWrite for StalledPort {
+        fn set_transport_config(&mut self, config: TransportConfig) {
+            self.transport = config;
+        }
+    }
+
+    #[test]
+    fn timeouts_are_retried_until_max_retries() {
+        let mut buf = [0u8; 4];
+        // The default three retries ride out three timeouts
+        let mut port = StalledPort::new(3);
+        assert_eq!(port.read(&mut buf).unwrap(), 1);
+        assert_eq!((port.attempts, buf[0]), (4, 0x5a));
+
+        // A fourth one fails the read with the attempts made
+        let mut port = StalledPort::new(4);
+        let error = port.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(port.attempts, 4);
+        let timeout = TimeoutError::from_io(&error, "waiting for the Device");
+        assert_eq!(timeout, TimeoutError { operation: "reading from the port".into(), attempts: 4, timeout: Duration::from_secs(5) });
+        assert_eq!(timeout.to_string(), "Timed out reading from the port (4 attempt(s) of 5s)");
+        assert!(matches!(FirehoseError::from(error), FirehoseError::Timeout(t) if t == timeout));
+
+        let mut port = StalledPort::new(1);
+        port.set_transport_config(TransportConfig { max_retries: 0, read_timeout: Duration::from_millis(100), ..Default::default() });
+        let timeout = TimeoutError::from_io(&port.read(&mut buf).unwrap_err(), "");
+        assert_eq!((timeout.attempts, timeout.timeout, port.attempts), (1, Duration::from_millis(100), 1));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let config = TransportConfig::default();
+        let mut attempts = 0;
+        let error = config
+            .retry("writing to the port", config.write_timeout, || -> std::io::Result<()> {
+                attempts += 1;
+                Err(ErrorKind::BrokenPipe.into())
+            })
+            .unwrap_err();
+        assert_eq!((error.kind(), attempts), (ErrorKind::BrokenPipe, 1));
+        // Not a retry timeout, so it only gets the operation
+        let timeout = TimeoutError::from_io(&ErrorKind::TimedOut.into(), "reading from the serial port");
+        assert_eq!(timeout.to_string(), "Timed out reading from the serial port");
+    }
+}
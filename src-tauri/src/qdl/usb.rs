@@ -0,0 +1,219 @@
+use log::warn;
+use rusb::{Context, Device, DeviceHandle, Direction, TransferType, UsbContext};
+use serde::Serialize;
+use std::io::{BufRead, Read, Write};
+use thiserror::Error;
+
+use crate::qdl::types::{QdlReadWrite, TransportConfig};
+
+/// Qualcomm VID. OPlus devices enumerate with it in EDL mode too.
+pub const QUALCOMM_VID: u16 = 0x05c6;
+/// PIDs of the Qualcomm emergency download interfaces: 9008 (QDLoader, Sahara and
+/// Firehose) and 900E (Sahara memory dump after a crash)
+pub const EDL_PIDS: [u16; 2] = [0x9008, 0x900e];
+
+const VENDOR_SPECIFIC_CLASS: u8 = 0xff;
+
+#[derive(Error, Debug)]
+pub enum UsbError {
+    #[error("USB error: {0}")]
+    Usb(#[from] rusb::Error),
+    #[error("No bulk IN/OUT interface found on the EDL device")]
+    NoBulkInterface,
+}
+
+/// A device in emergency download mode
+#[derive(Debug, Clone, Serialize)]
+pub struct EdlDevice {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Chip serial number, from the serial descriptor or the `_SN:` part of the
+    /// product string (e.g. `QUSB__BULK_CID:0412_SN:1A2B3C4D`)
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+    #[serde(skip)]
+    device: Device<Context>,
+}
+
+/// A matching device that could not be opened, usually for lack of permissions
+/// (missing udev rule on Linux, WinUSB/libusb driver not installed on Windows)
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedDevice {
+    pub bus: u8,
+    pub address: u8,
+    pub product_id: u16,
+    pub reason: String,
+}
+
+/// Result of `scan_edl_devices`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EdlScan {
+    pub devices: Vec<EdlDevice>,
+    pub skipped: Vec<SkippedDevice>,
+}
+
+/// Finds connected EDL devices, also reporting the ones that cannot be opened. Fails
+/// only if USB itself is unavailable.
+pub fn scan_edl_devices() -> Result<EdlScan, UsbError> {
+    let mut scan = EdlScan::default();
+    for device in Context::new()?.devices()?.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => continue,
+        };
+        if descriptor.vendor_id() != QUALCOMM_VID || !EDL_PIDS.contains(&descriptor.product_id()) {
+            continue;
+        }
+        let (bus, address) = (device.bus_number(), device.address());
+        let handle = match device.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                scan.skipped.push(SkippedDevice { bus, address, product_id: descriptor.product_id(), reason: e.to_string() });
+                continue;
+            }
+        };
+        let product = handle.read_product_string_ascii(&descriptor).ok();
+        let serial_number = handle
+            .read_serial_number_string_ascii(&descriptor)
+            .ok()
+            .filter(|sn| !sn.is_empty())
+            .or_else(|| product.as_deref().and_then(serial_from_product));
+        scan.devices.push(EdlDevice {
+            bus,
+            address,
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            serial_number,
+            product,
+            device,
+        });
+    }
+    Ok(scan)
+}
+
+/// Connected EDL devices that can be opened; the others are logged with the reason
+pub fn list_edl_devices() -> Result<Vec<EdlDevice>, UsbError> {
+    let scan = scan_edl_devices()?;
+    for skipped in &scan.skipped {
+        warn!("Skipping EDL device on bus {} address {}: {}", skipped.bus, skipped.address, skipped.reason);
+    }
+    Ok(scan.devices)
+}
+
+pub(crate) fn serial_from_product(product: &str) -> Option<String> {
+    let (_, serial) = product.split_once("_SN:")?;
+    Some(serial.trim().to_string())
+}
+
+impl EdlDevice {
+    /// Opens the bulk interface, for use with `SaharaSession` or as the `rw` of a
+    /// `QdlDevice` (set the backend to `QdlBackend::Usb` so ZLPs are handled).
+    /// Transfers use the default `TransportConfig` until `set_transport_config`.
+    pub fn open(&self) -> Result<QdlUsbConfig, UsbError> {
+        let handle = self.device.open()?;
+        let config = self.device.active_config_descriptor()?;
+        for interface in config.interfaces() {
+            for setting in interface.descriptors() {
+                if setting.class_code() != VENDOR_SPECIFIC_CLASS {
+                    continue;
+                }
+                let bulk = |direction| {
+                    setting
+                        .endpoint_descriptors()
+                        .find(|ep| ep.transfer_type() == TransferType::Bulk && ep.direction() == direction)
+                        .map(|ep| ep.address())
+                };
+                let (Some(in_ep), Some(out_ep)) = (bulk(Direction::In), bulk(Direction::Out)) else {
+                    continue;
+                };
+                // Not supported on Windows and macOS, where no kernel driver is bound
+                let _ = handle.set_auto_detach_kernel_driver(true);
+                handle.claim_interface(interface.number())?;
+                return Ok(QdlUsbConfig {
+                    handle,
+                    in_ep,
+                    out_ep,
+                    transport: TransportConfig::default(),
+                    buf: Vec::new(),
+                    pos: 0,
+                    cap: 0,
+                });
+            }
+        }
+        Err(UsbError::NoBulkInterface)
+    }
+}
+
+/// Bulk transport of an opened EDL device
+pub struct QdlUsbConfig {
+    handle: DeviceHandle<Context>,
+    in_ep: u8,
+    out_ep: u8,
+    transport: TransportConfig,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+/// Keeps timeouts recognizable as `ErrorKind::TimedOut`, which `firehose_read` and
+/// `TransportConfig::retry` rely on
+fn to_io_error(e: rusb::Error) -> std::io::Error {
+    match e {
+        rusb::Error::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, e),
+        _ => std::io::Error::other(e),
+    }
+}
+
+impl Write for QdlUsbConfig {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let (handle, timeout) = (&self.handle, self.transport.write_timeout);
+        self.transport
+            .retry("writing to USB", timeout, || handle.write_bulk(self.out_ep, buf, timeout).map_err(to_io_error))
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Read for QdlUsbConfig {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        // Drain internal buffer first
+        if self.pos < self.cap {
+            let n = std::cmp::min(out.len(), self.cap - self.pos);
+            out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        let (handle, timeout) = (&self.handle, self.transport.read_timeout);
+        self.transport.retry("reading from USB", timeout, || handle.read_bulk(self.in_ep, out, timeout).map_err(to_io_error))
+    }
+}
+
+impl BufRead for QdlUsbConfig {
+    fn fill_buf(&mut self) -> Result<&[u8], std::io::Error> {
+        if self.pos >= self.cap {
+            self.pos = 0;
+            self.cap = 0;
+            if self.buf.is_empty() {
+                self.buf.resize(4096, 0);
+            }
+            let (handle, buf, timeout) = (&self.handle, &mut self.buf, self.transport.read_timeout);
+            self.cap = self
+                .transport
+                .retry("reading from USB", timeout, || handle.read_bulk(self.in_ep, buf, timeout).map_err(to_io_error))?;
+        }
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl QdlReadWrite for QdlUsbConfig {
+    fn set_transport_config(&mut self, config: TransportConfig) {
+        self.transport = config;
+    }
+}
@@ -0,0 +1,2123 @@
+use super::firehose::ProgramEntry;
+use super::inputs::{AutoSizeError, ResolvedSize, resolve_auto_sizes};
+use super::link::LinkError;
+use super::lp_metadata::*;
+use super::manifest::{ImageHasher, ManifestError, manifest_path};
+use super::nv::{AppliedNv, NvError, NvMapping};
+use super::rawprogram::PhysicalPartition;
+use super::space::{SizeEstimate, SpaceVerdict};
+use super::sparse::{CHUNK_HEADER_SIZE, SPARSE_HEADER_SIZE, SparseStats, SparseWriter, open_raw_or_sparse};
+use super::split::{ChunkWriter, SplitError, SplitIndex};
+use super::slots::SlotMode;
+use super::validate::{BlockDeviceError, ValidationError, usable_space, validate_with_slot_count};
+use super::PartitionConfig;
+use crate::logger::Sensitive;
+use crate::payload::zstd::{MAX_BLOCK_SIZE as ZSTD_BLOCK_SIZE, ZstdWriter};
+use log::{debug, info, trace, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use thiserror::Error;
+
+/// Read buffer used when streaming partition images into the output
+const COPY_BUFFER_SIZE: u64 = 1024 * 1024;
+
+/// Largest zstd frame header plus the content checksum
+const ZSTD_FRAME_OVERHEAD: u64 = 18 + 4;
+
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Config failed validation with {} error(s)", .0.len())]
+    Invalid(Vec<ValidationError>),
+    #[error("{0}")]
+    BlockDevice(#[from] BlockDeviceError),
+    #[error("Only a single block device is supported, config has {0}")]
+    MultipleBlockDevices(usize),
+    #[error("Name '{0}' is longer than 36 bytes")]
+    NameTooLong(String),
+    #[error("Metadata needs {required} bytes, but super_meta.size is only {max_size}")]
+    MetadataTooLarge { required: u64, max_size: u64 },
+    #[error("The image needs at least one metadata slot")]
+    NoMetadataSlots,
+    #[error(
+        "{slot_count} metadata slots of {max_size} bytes need a {metadata_region} byte metadata region, but block device '{device}' is only {device_size} bytes"
+    )]
+    TooManyMetadataSlots { slot_count: u32, max_size: u64, metadata_region: u64, device: String, device_size: u64 },
+    #[error("Image for '{partition}' is {image_size} bytes, larger than the partition size of {size} bytes")]
+    ImageTooLarge { partition: String, image_size: u64, size: u64 },
+    #[error("Partitions do not fit on block device '{0}'")]
+    OutOfSpace(String),
+    #[error("The output needs at least {required} bytes, but only {available} are free in {}", dir.display())]
+    InsufficientSpace { dir: PathBuf, required: u64, available: u64 },
+    #[error("Device size {size} is not a multiple of the block size {block_size}, cannot write a sparse image")]
+    SparseUnaligned { size: u64, block_size: u64 },
+    #[error("Name '{0}' already has a slot suffix, A/B slot mode expects un-suffixed names")]
+    AlreadySlotted(String),
+    #[error("{0}")]
+    Manifest(#[from] ManifestError),
+    #[error("{0}")]
+    Split(#[from] SplitError),
+    #[error("{0}")]
+    AutoSize(#[from] AutoSizeError),
+    #[error("{0}")]
+    Link(#[from] LinkError),
+    #[error("{0}")]
+    Nv(#[from] NvError),
+    #[error("The layout changed since it was planned: {0}")]
+    LayoutChanged(String),
+    #[error("Build cancelled by user")]
+    Cancelled,
+}
+
+/// File format of the built super image
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Plain image of the full block device size (free space is left as holes on disk)
+    #[default]
+    Raw,
+    /// Android sparse image, like `lpmake --sparse`. Gaps between extents become
+    /// Don't-Care chunks; `max_blob_size` caps the data size of a single Raw chunk
+    /// (64 MiB if `None`).
+    Sparse { max_blob_size: Option<u64> },
+    /// The raw image compressed as one zstd frame (`super.img.zst`), streamed so the
+    /// uncompressed image never touches the disk. `level` goes from 1 (fastest) to
+    /// 19, 0 picks the zstd default of 3.
+    Zstd { level: i32 },
+}
+
+/// What a zstd build compressed, see `BuildReport::zstd`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ZstdStats {
+    pub level: i32,
+    /// Size of the raw image inside, the block device size
+    pub uncompressed_size: u64,
+    pub compressed_size: u64,
+    /// SHA-256 of the raw image inside, as `sha256sum` prints it after
+    /// decompressing
+    pub uncompressed_sha256: String,
+}
+
+/// Where a partition ended up in the built image
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PartitionExtent {
+    pub name: String,
+    pub group_name: String,
+    /// Byte offset of the extent from the start of the super image (0 for empty partitions)
+    pub offset: u64,
+    /// Extent length in bytes (the partition size rounded up to the block size)
+    pub size: u64,
+    /// Bytes copied from the partition image
+    pub image_bytes: u64,
+}
+
+/// Summary of a successful `build_super_image` run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuildReport {
+    /// Size of the output file: the block device size for a raw image, the size of
+    /// the sparse or compressed file otherwise
+    pub image_size: u64,
+    pub partitions: Vec<PartitionExtent>,
+    /// Bytes actually written: geometry, all metadata copies and image data for a raw
+    /// image, the whole file for a sparse or compressed one
+    pub bytes_written: u64,
+    /// Header counts of a sparse image, `None` for raw output
+    pub sparse: Option<SparseStats>,
+    /// Sizes and hash of a zstd image, `None` for other formats
+    pub zstd: Option<ZstdStats>,
+    /// Chunks the output was split into with `SuperImageBuilder::split`, also
+    /// written as their index
+    pub chunks: Option<SplitIndex>,
+    /// Slot the partition data was written to (`_a` or `_b`), `None` for a build in
+    /// `SlotMode::Single`
+    pub slot_suffix: Option<String>,
+    /// Manifest written next to the image, if requested
+    pub manifest: Option<PathBuf>,
+    /// `SuperLayout` written next to the image with `SuperImageBuilder::layout_file`
+    pub layout_file: Option<PathBuf>,
+    /// Partition sizes that were `"auto"` in the config, as measured for this build
+    pub resolved_sizes: Vec<ResolvedSize>,
+    /// NV variant whose images were packed, with `SuperImageBuilder::nv_mapping`
+    pub nv: Option<AppliedNv>,
+}
+
+/// Partition of a `SuperLayout`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedPartition {
+    pub name: String,
+    pub group_name: String,
+    /// Byte offset of the extent from the start of the super image (0 for empty partitions)
+    pub offset: u64,
+    /// Extent length in bytes (the partition size rounded up to the block size)
+    pub size: u64,
+    /// Whether the image gets copied; other extents are reserved and zero-filled. For
+    /// an image read by `inspect_super`, whether the partition has extents.
+    pub copied: bool,
+    /// `LP_PARTITION_ATTR_*` bits as stored: 1 read-only, 2 slot suffixed
+    pub attributes: u32,
+    /// Extents as stored in the metadata, none for an empty partition
+    pub extents: Vec<PlannedExtent>,
+}
+
+/// Extent of a `PlannedPartition` on a block device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PlannedExtent {
+    /// Index into `SuperLayout::block_devices`
+    pub device_index: u32,
+    /// In 512-byte sectors
+    pub start_sector: u64,
+    pub num_sectors: u64,
+    /// The same in bytes: on the first block device, the position in the raw image
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Space a group of a `SuperLayout` takes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupUsage {
+    pub name: String,
+    /// `None` for a group without a limit
+    pub maximum_size: Option<u64>,
+    /// Sum of the extents of its partitions
+    pub used: u64,
+    /// Room left below `maximum_size`, `None` without a limit
+    pub free: Option<u64>,
+    /// Bytes kept free for OTA snapshots, see `Group.cow_reserve`; 0 for an existing image
+    pub reserved: u64,
+    /// `free` less `reserved`, what the partitions could still grow by
+    pub free_after_reserve: Option<u64>,
+}
+
+/// Space a block device of a `SuperLayout` takes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceUsage {
+    pub name: String,
+    pub size: u64,
+    /// Bytes before the first usable offset: reserved bytes, geometry and metadata
+    pub metadata: u64,
+    /// Sum of the partition extents on the device
+    pub used: u64,
+    /// Everything else, alignment gaps included
+    pub free: u64,
+}
+
+/// Layout a build would write, from `SuperImageBuilder::plan` or `plan_super_image`.
+/// The build lays the image out the same way; given this layout with
+/// `SuperImageBuilder::expect_layout`, it refuses to write any other.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SuperLayout {
+    /// Size of the block device, and of a raw image
+    pub device_size: u64,
+    pub block_size: u64,
+    pub alignment: u64,
+    /// `super_meta.size`, the room for one metadata copy
+    pub metadata_max_size: u64,
+    pub metadata_slot_count: u64,
+    /// Size of the serialized metadata, at most `metadata_max_size`
+    pub metadata_size: u64,
+    /// First byte after the (aligned) metadata region, where partition data may start
+    pub first_usable_offset: u64,
+    /// In config order, with slot suffixes applied
+    pub partitions: Vec<PlannedPartition>,
+    /// Slot the partitions are placed in, as in `BuildReport::slot_suffix`
+    pub slot_suffix: Option<String>,
+    /// `"auto"` partition sizes as measured for this plan
+    pub resolved_sizes: Vec<ResolvedSize>,
+    /// In config order, with slot suffixes applied
+    pub groups: Vec<GroupUsage>,
+    pub block_devices: Vec<DeviceUsage>,
+    /// Free bytes of all block devices, the room an OTA has for its COW snapshots
+    pub snapshot_space: u64,
+}
+
+/// What a `Segment` of the address map holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    /// Reserved bytes, geometry, metadata slots and the padding up to the first usable offset
+    Metadata,
+    /// Extent of a partition
+    PartitionData,
+    /// Alignment gap between two partitions of the same group
+    GroupFree,
+    /// Space outside every group: before, between and after the groups' partitions
+    ImageFree,
+}
+
+/// Byte range of the super image in `SuperLayout::address_map`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Segment {
+    /// Region, partition or group name; `"free"` for image free space
+    pub name: String,
+    pub start: u64,
+    pub length: u64,
+    pub kind: SegmentKind,
+}
+
+impl SuperLayout {
+    /// The whole image as contiguous segments sorted by start, from 0 to `device_size`.
+    /// Groups have no region of their own: their partitions are packed one after
+    /// another, so a group only owns the gaps between its partitions
+    pub fn address_map(&self) -> Vec<Segment> {
+        let max = self.metadata_max_size;
+        let slots = self.metadata_slot_count;
+        let mut segments = Vec::new();
+        let push = |segments: &mut Vec<Segment>, name: &str, start: u64, end: u64, kind| {
+            if end > start {
+                segments.push(Segment { name: name.to_string(), start, length: end - start, kind });
+            }
+        };
+        let metadata = [
+            ("reserved", 0, LP_PARTITION_RESERVED_BYTES),
+            ("geometry", LP_PARTITION_RESERVED_BYTES, primary_metadata_offset(max, 0)),
+            ("metadata", primary_metadata_offset(max, 0), backup_metadata_offset(max, slots, 0)),
+            ("metadata_backup", backup_metadata_offset(max, slots, 0), metadata_region_end(max, slots)),
+            ("padding", metadata_region_end(max, slots), self.first_usable_offset),
+        ];
+        for (name, start, end) in metadata {
+            push(&mut segments, name, start, end, SegmentKind::Metadata);
+        }
+
+        let mut partitions: Vec<&PlannedPartition> = self.partitions.iter().filter(|p| p.size > 0).collect();
+        partitions.sort_by_key(|p| p.offset);
+        let mut end = self.first_usable_offset;
+        let mut group: Option<&str> = None;
+        for partition in partitions {
+            match group {
+                Some(group) if group == partition.group_name => push(&mut segments, group, end, partition.offset, SegmentKind::GroupFree),
+                _ => push(&mut segments, "free", end, partition.offset, SegmentKind::ImageFree),
+            }
+            push(&mut segments, &partition.name, partition.offset, partition.offset + partition.size, SegmentKind::PartitionData);
+            end = partition.offset + partition.size;
+            group = Some(&partition.group_name);
+        }
+        push(&mut segments, "free", end, self.device_size, SegmentKind::ImageFree);
+        segments
+    }
+}
+
+/// Piece of a super image produced by `SuperImageBuilder::stream`
+pub struct ImageChunk<'a> {
+    /// Byte offset into the image, a multiple of the block size
+    pub offset: u64,
+    /// Length in bytes, a multiple of the block size
+    pub len: u64,
+    /// Partition whose image this is, `None` for the geometry and metadata region
+    pub partition: Option<&'a str>,
+    /// Yields exactly `len` bytes, reading it advances the build progress
+    pub data: &'a mut dyn Read,
+}
+
+impl ImageChunk<'_> {
+    /// `<program>` entry that writes this chunk to `target`, the partition the image
+    /// is flashed to. `file_sector_offset` is the position of the chunk in the image,
+    /// as in the split entries of `rawprogram_entries`; `data` already starts there.
+    pub fn program_entry(&self, target: &PhysicalPartition, label: &str) -> ProgramEntry {
+        let sector_size = target.sector_size.max(1);
+        ProgramEntry {
+            filename: format!("{}.img", label),
+            start_sector: (target.start_sector + self.offset / sector_size).to_string(),
+            num_partition_sectors: self.len.div_ceil(sector_size),
+            sector_size: target.sector_size,
+            physical_partition_number: target.lun,
+            label: label.to_string(),
+            file_sector_offset: self.offset / sector_size,
+            sparse: false,
+        }
+    }
+}
+
+/// Stage of a build reported through `BuildProgress`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildPhase {
+    /// Geometry and metadata slots are being written
+    Metadata,
+    /// A partition image is being copied
+    Partition,
+    /// The output is being flushed (and, for sparse output, its header patched)
+    Finalize,
+}
+
+/// Progress update passed to the callback of `build_super_image_with_progress`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuildProgress {
+    pub phase: BuildPhase,
+    /// Partition being copied, `None` outside the `Partition` phase
+    pub partition: Option<String>,
+    /// Bytes of the current phase (or partition) done so far
+    pub bytes_done: u64,
+    /// Total bytes of the current phase (or partition)
+    pub bytes_total: u64,
+    /// Bytes of the whole build done so far (metadata plus copied image data)
+    pub overall_bytes_done: u64,
+    /// Total bytes of the whole build
+    pub overall_bytes_total: u64,
+    /// Progress of the whole build, 0 to 100
+    pub overall_percent: u8,
+}
+
+/// Turns per-phase byte counts into `BuildProgress` updates with an overall percentage,
+/// and carries the cancellation flag into the copy loops
+struct ProgressTracker<'a> {
+    callback: &'a mut dyn FnMut(BuildProgress),
+    cancel: &'a AtomicBool,
+    overall_total: u64,
+    overall_done: u64,
+}
+
+impl ProgressTracker<'_> {
+    fn check_cancelled(&self) -> Result<(), BuildError> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(BuildError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, phase: BuildPhase, partition: Option<&str>, bytes_done: u64, bytes_total: u64) {
+        let done = self.overall_done + bytes_done;
+        let overall_percent = (done.min(self.overall_total) * 100)
+            .checked_div(self.overall_total)
+            .unwrap_or(100) as u8;
+        (self.callback)(BuildProgress {
+            phase,
+            partition: partition.map(str::to_string),
+            bytes_done,
+            bytes_total,
+            overall_bytes_done: done.min(self.overall_total),
+            overall_bytes_total: self.overall_total,
+            overall_percent,
+        });
+    }
+
+    /// Marks a phase (or partition) of `bytes` as done
+    fn complete(&mut self, bytes: u64) {
+        self.overall_done += bytes;
+    }
+}
+
+/// Builds the super image described by a config into a file, by default the
+/// config's own `super_meta.path`.
+///
+/// Metadata slot count: the image gets `PartitionConfig::metadata_slot_count()`
+/// slots, i.e. `metadata_slots` or two, as lpmake builds with `--metadata-slots`.
+/// Each slot is `super_meta.size` bytes, and the image itself is
+/// `block_devices[0].size` bytes.
+/// With `SlotMode::AB` the image always gets two slots instead, and
+/// `metadata_slot_count` overrides both.
+#[derive(Debug, Clone)]
+pub struct SuperImageBuilder {
+    config: PartitionConfig,
+    output: PathBuf,
+    format: OutputFormat,
+    slot_mode: SlotMode,
+    metadata_slot_count: Option<u32>,
+    manifest: bool,
+    layout_file: bool,
+    nv_mapping: Option<NvMapping>,
+    split: Option<u64>,
+    check_space: bool,
+    expected_layout: Option<SuperLayout>,
+}
+
+impl SuperImageBuilder {
+    pub fn new(config: PartitionConfig) -> Self {
+        let output = PathBuf::from(&config.super_meta.path);
+        Self {
+            config,
+            output,
+            format: OutputFormat::Raw,
+            slot_mode: SlotMode::Single,
+            metadata_slot_count: None,
+            manifest: false,
+            layout_file: false,
+            nv_mapping: None,
+            split: None,
+            check_space: true,
+            expected_layout: None,
+        }
+    }
+
+    /// Writes the image to `output` instead of `super_meta.path`
+    pub fn output<P: Into<PathBuf>>(mut self, output: P) -> Self {
+        self.output = output.into();
+        self
+    }
+
+    /// Selects raw (the default) or sparse output
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Expands un-suffixed names into `_a` / `_b` slots, see `SlotMode`
+    pub fn slot_mode(mut self, slot_mode: SlotMode) -> Self {
+        self.slot_mode = slot_mode;
+        self
+    }
+
+    /// Builds the image with `count` metadata slots instead of the default of the slot
+    /// mode (two for stock configs), for devices that allocate more, like Virtual A/B
+    /// devices with three. Every slot gets a primary and a backup copy of the
+    /// metadata, so the metadata region grows by `2 * super_meta.size` per slot; a
+    /// count whose region does not fit the block device fails the build with
+    /// `TooManyMetadataSlots`, and 0 with `NoMetadataSlots`.
+    pub fn metadata_slot_count(mut self, count: u32) -> Self {
+        self.metadata_slot_count = Some(count);
+        self
+    }
+
+    /// Also writes `<output>.manifest.json` with the SHA-256 of every copied partition
+    /// image and of the whole image, see `BuildManifest`
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Also writes the `SuperLayout` of the image as `<output>.layout.json` (see
+    /// `layout_path`): the extents of every partition as byte offsets, their groups
+    /// and attributes, and the geometry, for scripts that patch the image.
+    /// `inspect_super` reads the same from any super image.
+    pub fn layout_file(mut self, layout_file: bool) -> Self {
+        self.layout_file = layout_file;
+        self
+    }
+
+    /// Packs the region-specific images of the config's `nv_id` from the firmware's
+    /// NV mapping (see `NvMapping::apply`) instead of the config's own paths. An
+    /// `nv_id` the mapping does not have fails the build with `BuildError::Nv`.
+    pub fn nv_mapping(mut self, mapping: NvMapping) -> Self {
+        self.nv_mapping = Some(mapping);
+        self
+    }
+
+    /// Writes the output as chunks of `chunk_size` bytes (`super.img.0`, `super.img.1`,
+    /// ...) next to `output` instead of one file, with a `split_index_path` index, e.g.
+    /// `FAT32_CHUNK_SIZE` for a FAT32 card. The chunks are cut while the image is
+    /// written, in any format; `join_image` puts them back together.
+    pub fn split(mut self, chunk_size: Option<u64>) -> Self {
+        self.split = chunk_size;
+        self
+    }
+
+    /// Whether a build refuses to start when the output directory has less free space
+    /// than the output takes at the least (`SpaceVerdict::Insufficient`, see
+    /// `estimate`), with `BuildError::InsufficientSpace`. On by default.
+    pub fn check_free_space(mut self, check: bool) -> Self {
+        self.check_space = check;
+        self
+    }
+
+    /// Makes the build fail with `BuildError::LayoutChanged` before writing anything
+    /// unless it lays the image out as `layout`, a `plan` shown to the user, e.g. when
+    /// an `"auto"` sized image changed in between
+    pub fn expect_layout(mut self, layout: SuperLayout) -> Self {
+        self.expected_layout = Some(layout);
+        self
+    }
+
+    pub fn config(&self) -> &PartitionConfig {
+        &self.config
+    }
+
+    /// Sizes the output of `build` and checks it against the free space of the output
+    /// directory, without writing anything. The images are opened to learn their
+    /// sizes, but not read.
+    pub fn estimate(&self) -> Result<SizeEstimate, BuildError> {
+        let mut progress = |_| {};
+        let cancel = AtomicBool::new(false);
+        let build = start_build(&self.config, self.slot_mode, self.metadata_slot_count, self.nv_mapping.as_ref(), &cancel, &mut progress)?;
+        Ok(build.size_estimate(self.format, &self.output))
+    }
+
+    /// Computes the layout `build` would write, without touching the output.
+    ///
+    /// The config is validated and laid out exactly as for a build, so the same
+    /// errors come back early, e.g. `MetadataTooLarge` when the metadata does not
+    /// fit `super_meta.size` or `OutOfSpace` when the partitions do not fit the
+    /// block device. No partition data is read: only the size of images with an
+    /// `"auto"` size is measured.
+    pub fn plan(&self) -> Result<SuperLayout, BuildError> {
+        let plan = prepare(&self.config, self.slot_mode, self.metadata_slot_count, self.nv_mapping.as_ref())?;
+        Ok(plan.super_layout(self.slot_mode))
+    }
+
+    /// Builds the image, see `build_super_image` for the layout
+    pub fn build(&self) -> Result<(), BuildError> {
+        self.build_with_progress(|_| {})
+    }
+
+    /// Same as `build`, calling `cb` at the start of every phase and partition and
+    /// after every copied MiB. A panic in `cb` propagates and aborts the build,
+    /// leaving the partial file (see `partial_path`) behind, but never `output`.
+    pub fn build_with_progress<F: FnMut(BuildProgress)>(&self, cb: F) -> Result<(), BuildError> {
+        self.build_with_cancel(&AtomicBool::new(false), cb).map(|_| ())
+    }
+
+    /// Same as `build_with_progress`, stopping when `cancel` is set (see
+    /// `build_super_image_with_progress`) and returning the build report
+    pub fn build_with_cancel<F: FnMut(BuildProgress)>(&self, cancel: &AtomicBool, cb: F) -> Result<BuildReport, BuildError> {
+        let options = BuildOptions {
+            format: self.format,
+            slot_mode: self.slot_mode,
+            metadata_slot_count: self.metadata_slot_count,
+            manifest: self.manifest,
+            layout_file: self.layout_file,
+            nv_mapping: self.nv_mapping.as_ref(),
+            split: self.split,
+            check_space: self.check_space,
+            expected_layout: self.expected_layout.as_ref(),
+        };
+        build_with_options(&self.config, &self.output, options, cancel, cb)
+    }
+
+    /// Same as `build_with_cancel`, writing the image to `sink` instead of a file.
+    ///
+    /// The image is written from offset 0 of `sink` on. Raw output is written with
+    /// seeks over the regions it leaves zero (free space, extent tails), so those must
+    /// read back as zeros, as in a new file or an empty `Cursor`; a raw image is then
+    /// extended to the device size. No manifest is written, there is no output path
+    /// to put it next to. A sink that cannot seek (a pipe, a compressor) takes the
+    /// image from `stream` instead, which produces it strictly in offset order.
+    pub fn build_to<W: Write + Seek, F: FnMut(BuildProgress)>(
+        &self,
+        sink: &mut W,
+        cancel: &AtomicBool,
+        mut cb: F,
+    ) -> Result<BuildReport, BuildError> {
+        let mut build = start_build(&self.config, self.slot_mode, self.metadata_slot_count, self.nv_mapping.as_ref(), cancel, &mut cb)?;
+        build.check_layout(self.expected_layout.as_ref())?;
+        build.write(self.format, sink, &mut None)
+    }
+
+    /// Produces the image as chunks in offset order instead of writing it, so it can
+    /// go straight to the device (see `FirehoseSession::flash_super_stream`) without
+    /// an image file.
+    ///
+    /// `sink` gets the metadata region first, then one chunk per copied partition
+    /// image, padded with zeros to the block size. The rest of a raw image is zeros
+    /// and not part of any chunk: like the Don't-Care chunks of a sparse image, it
+    /// keeps whatever the target held before. Progress follows the bytes `sink` reads
+    /// from the chunks, and `cancel` is checked on every read. The output format and
+    /// manifest settings do not apply. An error from `sink` ends the stream and is
+    /// returned as `BuildError::Io`; `BuildReport::bytes_written` counts the bytes
+    /// of all chunks.
+    pub fn stream<F, S>(&self, cancel: &AtomicBool, mut progress: F, mut sink: S) -> Result<BuildReport, BuildError>
+    where
+        F: FnMut(BuildProgress),
+        S: FnMut(ImageChunk) -> io::Result<()>,
+    {
+        info!("Streaming super image for NV {} ({})", self.config.nv_id, Sensitive(&self.config.nv_text));
+        let _span = tracing::debug_span!("stream").entered();
+        let mut build = start_build(&self.config, self.slot_mode, self.metadata_slot_count, self.nv_mapping.as_ref(), cancel, &mut progress)?;
+        build.check_layout(self.expected_layout.as_ref())?;
+        let mut report = stream_image(&mut build, &mut sink)?;
+        report.slot_suffix = build.slot_mode.active_suffix().map(str::to_string);
+        report.resolved_sizes = build.resolved_sizes;
+        report.nv = build.nv;
+        Ok(report)
+    }
+
+    /// Async variant of `build`, running the build on tokio's blocking thread pool.
+    ///
+    /// Not cancellation-safe: dropping the future does not stop the build, which runs
+    /// to completion in the background. Use `build_async_with_cancel` to stop it.
+    pub async fn build_async(&self) -> Result<(), BuildError> {
+        self.build_async_with_cancel(Arc::new(AtomicBool::new(false)), |_| {}).await.map(|_| ())
+    }
+
+    /// Async variant of `build_with_cancel`, running the build on tokio's blocking
+    /// thread pool. `cb` is called from that thread.
+    ///
+    /// Not cancellation-safe either: dropping the future leaves the build running,
+    /// set `cancel` to stop it (the partial output is then removed). A panic in `cb`
+    /// is resumed in the awaiting task.
+    pub async fn build_async_with_cancel<F>(&self, cancel: Arc<AtomicBool>, cb: F) -> Result<BuildReport, BuildError>
+    where
+        F: FnMut(BuildProgress) + Send + 'static,
+    {
+        let builder = self.clone();
+        join_build(tokio::task::spawn_blocking(move || builder.build_with_cancel(&cancel, cb)).await)
+    }
+
+    /// Starts the build on tokio's blocking thread pool and returns its handle right
+    /// away, for callers that keep the build around (a Tauri job per build) instead
+    /// of awaiting it in place. `cb` is called from the build thread.
+    ///
+    /// Each job has its own cancel flag, so builds to different outputs run side by
+    /// side without affecting each other. Must be called from within a tokio runtime.
+    pub fn spawn<F>(&self, cb: F) -> BuildJob
+    where
+        F: FnMut(BuildProgress) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let builder = self.clone();
+        let flag = cancel.clone();
+        let task = tokio::task::spawn_blocking(move || builder.build_with_cancel(&flag, cb));
+        BuildJob { cancel, task: Some(task) }
+    }
+}
+
+/// A running build, see `SuperImageBuilder::spawn`.
+///
+/// Dropping the handle before the build finished cancels it, like setting the flag
+/// of `build_with_cancel`: the build stops at the next buffer and removes its
+/// partial output. This includes dropping the future of `wait`.
+pub struct BuildJob {
+    cancel: Arc<AtomicBool>,
+    /// `None` once `wait` has the result
+    task: Option<tokio::task::JoinHandle<Result<BuildReport, BuildError>>>,
+}
+
+impl BuildJob {
+    /// Asks the build to stop, `wait` then returns `BuildError::Cancelled`
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// The flag `cancel` sets, for stopping the build while another task waits for it
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    /// Whether the build has stopped, successfully or not, so `wait` returns at once
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().is_none_or(|task| task.is_finished())
+    }
+
+    /// The result of the build. A panic in the progress callback is resumed here.
+    pub async fn wait(mut self) -> Result<BuildReport, BuildError> {
+        let Some(task) = self.task.as_mut() else {
+            return Err(BuildError::Io(io::Error::other("Build result was already taken")));
+        };
+        let result = task.await;
+        self.task = None;
+        join_build(result)
+    }
+}
+
+impl Drop for BuildJob {
+    fn drop(&mut self) {
+        if self.task.is_some() {
+            self.cancel();
+        }
+    }
+}
+
+/// Result of a build run with `spawn_blocking`
+fn join_build(
+    joined: Result<Result<BuildReport, BuildError>, tokio::task::JoinError>,
+) -> Result<BuildReport, BuildError> {
+    match joined {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(BuildError::Io(io::Error::other(e))),
+    }
+}
+
+/// Builds a raw (non-sparse) super image from a config, without calling lpmake.
+///
+/// The output matches what lpmake builds from the same config: Virtual A/B
+/// metadata (v10.2, v10.0 with `virtual_ab: false`) with `metadata_slot_count()` slots of `super_meta.size` bytes,
+/// partitions with a size marked readonly, every extent starting on the block device
+/// `alignment` and rounded up to `block_size`. Partitions are laid out group by
+/// group (in `groups` order), each group's partitions in config order. Partitions
+/// that are not dynamic or have no `path` get their extent reserved (zero-filled)
+/// without copying any data. Images may be raw or Android sparse, sparse ones are
+/// expanded while copying. Relative image paths are resolved against the current
+/// working directory. `"auto"` partition sizes are measured from their images first
+/// (see `resolve_auto_sizes`) and reported in `BuildReport::resolved_sizes`.
+///
+/// # Arguments
+/// * `config` - Parsed configuration, checked with `validate` first (after resolving
+///   `"auto"` sizes)
+/// * `output` - Destination image file (overwritten if it exists)
+pub fn build_super_image(config: &PartitionConfig, output: &Path) -> Result<BuildReport, BuildError> {
+    build_super_image_as(config, output, OutputFormat::Raw)
+}
+
+/// Same as `build_super_image_as`, writing the image to `sink` instead of a file,
+/// see `SuperImageBuilder::build_to`
+pub fn build_super_image_to<W: Write + Seek>(config: &PartitionConfig, sink: &mut W, format: OutputFormat) -> Result<BuildReport, BuildError> {
+    SuperImageBuilder::new(config.clone()).format(format).build_to(sink, &AtomicBool::new(false), |_| {})
+}
+
+/// Same as `build_super_image`, writing the image in the given format.
+///
+/// A sparse image expands (e.g. with AOSP `simg2img`) to exactly the raw image.
+pub fn build_super_image_as(config: &PartitionConfig, output: &Path, format: OutputFormat) -> Result<BuildReport, BuildError> {
+    build_super_image_with_progress(config, output, format, &AtomicBool::new(false), |_| {})
+}
+
+/// Same as `build_super_image_as`, calling `progress` as the build advances and
+/// stopping when `cancel` is set.
+///
+/// The callback is called once per copied buffer (1 MiB), so callers that forward
+/// updates to a UI should throttle them. It is not called again after an error.
+/// `cancel` is checked before every buffer; a cancelled build returns
+/// `BuildError::Cancelled`.
+///
+/// The image is written to `partial_path(output)` and renamed to `output` once it is
+/// complete and synced. On any error, cancellation included, the partial file is
+/// removed and whatever was at `output` before is left as it was. The manifest is
+/// written after the rename.
+pub fn build_super_image_with_progress<F: FnMut(BuildProgress)>(
+    config: &PartitionConfig,
+    output: &Path,
+    format: OutputFormat,
+    cancel: &AtomicBool,
+    progress: F,
+) -> Result<BuildReport, BuildError> {
+    let options =
+        BuildOptions { format, slot_mode: SlotMode::Single, metadata_slot_count: None, manifest: false, layout_file: false, nv_mapping: None, split: None, check_space: true, expected_layout: None };
+    build_with_options(config, output, options, cancel, progress)
+}
+
+/// Settings `plan_super_image` and `estimate_output_size` lay out a build with, as
+/// set on a `SuperImageBuilder`
+#[derive(Debug, Clone, Default)]
+pub struct PlanOptions {
+    /// Where the image goes, `super_meta.path` if `None`; does not change the layout
+    pub output: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub slot_mode: SlotMode,
+    pub metadata_slot_count: Option<u32>,
+}
+
+/// Same as `SuperImageBuilder::estimate` for a builder with `options`
+pub fn estimate_output_size(config: &PartitionConfig, options: &PlanOptions) -> Result<SizeEstimate, BuildError> {
+    options.builder(config).estimate()
+}
+
+/// Same as `SuperImageBuilder::plan` for a builder with `options`: the extents of
+/// every partition and the space used and left in every group and block device,
+/// without reading or writing any partition data
+pub fn plan_super_image(config: &PartitionConfig, options: &PlanOptions) -> Result<SuperLayout, BuildError> {
+    options.builder(config).plan()
+}
+
+impl PlanOptions {
+    fn builder(&self, config: &PartitionConfig) -> SuperImageBuilder {
+        let mut builder = SuperImageBuilder::new(config.clone()).format(self.format).slot_mode(self.slot_mode);
+        if let Some(output) = &self.output {
+            builder = builder.output(output);
+        }
+        if let Some(count) = self.metadata_slot_count {
+            builder = builder.metadata_slot_count(count);
+        }
+        builder
+    }
+}
+
+/// Path of the layout written for `output` with `SuperImageBuilder::layout_file`,
+/// e.g. `super.img.layout.json`
+pub fn layout_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".layout.json");
+    PathBuf::from(path)
+}
+
+/// File a build writes before it is complete, `output` with `.partial` appended.
+///
+/// Only a finished image is renamed to `output`, so an existing image there is kept
+/// until the new one replaces it, and a build that fails, is cancelled or is killed
+/// never leaves an incomplete image under the output name.
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".partial");
+    PathBuf::from(path)
+}
+
+/// Settings of a build besides the config, output and callbacks
+#[derive(Debug, Clone, Copy)]
+struct BuildOptions<'a> {
+    format: OutputFormat,
+    slot_mode: SlotMode,
+    /// Overrides the slot mode's metadata slot count
+    metadata_slot_count: Option<u32>,
+    /// Hash the data while writing it and write a manifest next to the output
+    manifest: bool,
+    /// Write the `SuperLayout` next to the output
+    layout_file: bool,
+    nv_mapping: Option<&'a NvMapping>,
+    /// Chunk size of a split output
+    split: Option<u64>,
+    /// Refuse to start without the free space the output needs
+    check_space: bool,
+    /// Refuse to write a layout other than this one
+    expected_layout: Option<&'a SuperLayout>,
+}
+
+fn build_with_options<F: FnMut(BuildProgress)>(
+    config: &PartitionConfig,
+    output: &Path,
+    options: BuildOptions<'_>,
+    cancel: &AtomicBool,
+    mut progress: F,
+) -> Result<BuildReport, BuildError> {
+    let started = Instant::now();
+    info!(
+        "Building super image {} ({:?}, {:?}) for NV {} ({})",
+        output.display(),
+        options.format,
+        options.slot_mode,
+        config.nv_id,
+        Sensitive(&config.nv_text)
+    );
+    let _span = tracing::debug_span!("build").entered();
+    let mut build = start_build(config, options.slot_mode, options.metadata_slot_count, options.nv_mapping, cancel, &mut progress)?;
+    build.check_layout(options.expected_layout)?;
+    if options.check_space {
+        let estimate = build.size_estimate(options.format, output);
+        debug!("Output needs {} to {} bytes, {:?} free", estimate.minimum_size, estimate.output_size, estimate.available);
+        if let (SpaceVerdict::Insufficient, Some(available)) = (estimate.verdict, estimate.available) {
+            return Err(BuildError::InsufficientSpace { dir: estimate.output_dir, required: estimate.minimum_size, available });
+        }
+    }
+    let mut hasher = options.manifest.then(ImageHasher::new);
+    let partial = partial_path(output);
+    let result = (|| -> Result<BuildReport, BuildError> {
+        if let Some(chunk_size) = options.split {
+            return write_chunks(&mut build, output, options.format, chunk_size, &mut hasher);
+        }
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&partial)?;
+        let report = match options.format {
+            OutputFormat::Raw => {
+                // Unwritten regions (reserved bytes, free space) stay zero and sparse on disk
+                file.set_len(build.layout.device_size)?;
+                build.write(OutputFormat::Raw, &mut file, &mut hasher)?
+            }
+            format => {
+                let mut out = BufWriter::new(file);
+                let report = build.write(format, &mut out, &mut hasher)?;
+                file = out.into_inner().map_err(|e| e.into_error())?;
+                report
+            }
+        };
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&partial, output)?;
+        Ok(report)
+    })();
+    if let Err(e) = &result {
+        warn!("Super image build to {} failed after {:?}: {}", output.display(), started.elapsed(), e);
+        let _ = std::fs::remove_file(&partial);
+    }
+    let mut report = result?;
+    info!("Built super image {} ({:?}, {} bytes) in {:?}", output.display(), options.format, report.image_size, started.elapsed());
+    if let Some(hasher) = hasher {
+        let path = manifest_path(output);
+        hasher.finish(build.layout.device_size).write(&path)?;
+        report.manifest = Some(path);
+    }
+    if options.layout_file {
+        let path = layout_path(output);
+        let layout = super_layout(&build.config, &build.layout, &build.resolved_sizes, build.slot_mode);
+        std::fs::write(&path, serde_json::to_vec_pretty(&layout).map_err(io::Error::from)?)?;
+        report.layout_file = Some(path);
+    }
+    Ok(report)
+}
+
+/// The image as chunks named after `output` in its directory. The chunks are only
+/// renamed into place once all of them are written, see `ChunkWriter`.
+fn write_chunks(
+    build: &mut Build,
+    output: &Path,
+    format: OutputFormat,
+    chunk_size: u64,
+    hasher: &mut Option<ImageHasher>,
+) -> Result<BuildReport, BuildError> {
+    let image = output
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "output is not a file"))?
+        .to_string_lossy();
+    let mut chunks = ChunkWriter::new(output.parent().unwrap_or(Path::new("")), &image, chunk_size)?;
+    let report = match format {
+        OutputFormat::Raw => build.write(format, &mut chunks, hasher)?,
+        format => {
+            let mut out = BufWriter::new(&mut chunks);
+            let report = build.write(format, &mut out, hasher)?;
+            out.flush()?;
+            drop(out);
+            report
+        }
+    };
+    let index = chunks.finish()?;
+    Ok(BuildReport { chunks: Some(index), ..report })
+}
+
+/// A build about to write: the prepared plan, the placements whose image gets
+/// copied and the progress of the whole build
+struct Build<'a> {
+    config: PartitionConfig,
+    layout: Layout,
+    resolved_sizes: Vec<ResolvedSize>,
+    nv: Option<AppliedNv>,
+    slot_mode: SlotMode,
+    copied: Vec<usize>,
+    /// Size of the image of each of `copied`
+    image_sizes: Vec<u64>,
+    tracker: ProgressTracker<'a>,
+}
+
+fn start_build<'a>(
+    config: &PartitionConfig,
+    slot_mode: SlotMode,
+    metadata_slot_count: Option<u32>,
+    nv_mapping: Option<&NvMapping>,
+    cancel: &'a AtomicBool,
+    progress: &'a mut dyn FnMut(BuildProgress),
+) -> Result<Build<'a>, BuildError> {
+    let _span = tracing::debug_span!("plan").entered();
+    let Plan { config, layout, resolved_sizes, nv } = prepare(config, slot_mode, metadata_slot_count, nv_mapping)?;
+    let copied = copied_partitions(&config, &layout);
+    debug!(
+        "Super layout: {} bytes, block size {}, alignment {}, {} metadata slots of {} bytes ({} used)",
+        layout.device_size,
+        layout.block_size,
+        layout.alignment,
+        layout.slot_count,
+        layout.metadata_max_size,
+        layout.metadata.len()
+    );
+    for (i, placement) in layout.placements.iter().enumerate() {
+        debug!(
+            "  {} ({}) at offset {}, {} bytes{}",
+            placement.name,
+            placement.group_name,
+            placement.offset,
+            placement.size,
+            if copied.contains(&i) { "" } else { ", not copied" }
+        );
+    }
+
+    // Open every image once up front, so size errors surface before writing and the
+    // overall total is known
+    let mut image_sizes = Vec::with_capacity(copied.len());
+    for &i in &copied {
+        let (_, image_size) = open_image(config.partitions[i].path.trim(), &layout.placements[i])?;
+        image_sizes.push(image_size);
+    }
+    let tracker = ProgressTracker {
+        callback: progress,
+        cancel,
+        overall_total: layout.metadata_bytes() + image_sizes.iter().sum::<u64>(),
+        overall_done: 0,
+    };
+    tracker.check_cancelled()?;
+    Ok(Build { config, layout, resolved_sizes, nv, slot_mode, copied, image_sizes, tracker })
+}
+
+impl Build<'_> {
+    /// Fails with `LayoutChanged` if the build does not lay the image out as `expected`
+    fn check_layout(&self, expected: Option<&SuperLayout>) -> Result<(), BuildError> {
+        let Some(expected) = expected else { return Ok(()) };
+        let actual = super_layout(&self.config, &self.layout, &self.resolved_sizes, self.slot_mode);
+        match layout_difference(expected, &actual) {
+            Some(difference) => Err(BuildError::LayoutChanged(difference)),
+            None => Ok(()),
+        }
+    }
+
+    /// Size of the output in `format` at `output`, see `SizeEstimate`
+    fn size_estimate(&self, format: OutputFormat, output: &Path) -> SizeEstimate {
+        let layout = &self.layout;
+        let block_size = layout.block_size.max(1);
+        let region = align_up(metadata_region_end(layout.metadata_max_size, layout.slot_count), block_size);
+        // (offset, length) of the block aligned regions with data, in offset order
+        let mut extents = vec![(0, region)];
+        extents.extend(self.copied.iter().zip(&self.image_sizes).map(|(&i, &size)| (layout.placements[i].offset, align_up(size, block_size))));
+        let data_size = layout.metadata_bytes() + extents[1..].iter().map(|&(_, len)| len).sum::<u64>();
+
+        let (output_size, exact, minimum_size) = match format {
+            OutputFormat::Raw => (layout.device_size, true, data_size),
+            OutputFormat::Sparse { .. } => {
+                // At worst every data block is its own chunk, plus a Don't-Care chunk
+                // before every image and at the end
+                let blocks = extents.iter().map(|&(_, len)| len / block_size).sum::<u64>();
+                let chunk_header = CHUNK_HEADER_SIZE as u64;
+                let size = SPARSE_HEADER_SIZE as u64 + blocks * (block_size + chunk_header) + extents.len() as u64 * chunk_header;
+                // At best everything but the geometry block is of one value and
+                // shrinks to Fill chunks
+                (size, false, SPARSE_HEADER_SIZE as u64 + chunk_header + block_size)
+            }
+            OutputFormat::Zstd { .. } => {
+                // Blocks with data stay at most 3 bytes over their size, all-zero ones
+                // become 4 byte RLE blocks
+                let zstd_block = ZSTD_BLOCK_SIZE as u64;
+                let mut touched = 0u64;
+                let mut next = 0u64;
+                for &(offset, len) in extents.iter().filter(|&&(_, len)| len > 0) {
+                    let first = (offset / zstd_block).max(next);
+                    let end = (offset + len).div_ceil(zstd_block);
+                    touched += end.saturating_sub(first);
+                    next = next.max(end);
+                }
+                let blocks = layout.device_size.div_ceil(zstd_block);
+                (ZSTD_FRAME_OVERHEAD + touched * (zstd_block + 3) + (blocks - touched.min(blocks)) * 4, false, 0)
+            }
+        };
+        SizeEstimate::at(output, layout.device_size, data_size, output_size, exact, minimum_size)
+    }
+
+    fn write<W: Write + Seek>(
+        &mut self,
+        format: OutputFormat,
+        out: &mut W,
+        hasher: &mut Option<ImageHasher>,
+    ) -> Result<BuildReport, BuildError> {
+        let _span = tracing::debug_span!("write").entered();
+        let mut report = match format {
+            OutputFormat::Raw => write_raw(&self.config, &mut self.layout, &self.copied, out, &mut self.tracker, hasher),
+            OutputFormat::Sparse { max_blob_size } => {
+                write_sparse(&self.config, &mut self.layout, &self.copied, out, max_blob_size, &mut self.tracker, hasher)
+            }
+            OutputFormat::Zstd { level } => {
+                write_zstd(&self.config, &mut self.layout, &self.copied, out, level, &mut self.tracker, hasher)
+            }
+        }?;
+        report.slot_suffix = self.slot_mode.active_suffix().map(str::to_string);
+        report.resolved_sizes = self.resolved_sizes.clone();
+        report.nv = self.nv.clone();
+        Ok(report)
+    }
+}
+
+/// The config as it gets written (NV images selected, `"auto"` sizes resolved,
+/// slots applied) and its layout, shared by `plan` and the build so both always agree
+struct Plan {
+    config: PartitionConfig,
+    layout: Layout,
+    resolved_sizes: Vec<ResolvedSize>,
+    nv: Option<AppliedNv>,
+}
+
+fn prepare(
+    config: &PartitionConfig,
+    slot_mode: SlotMode,
+    metadata_slot_count: Option<u32>,
+    nv_mapping: Option<&NvMapping>,
+) -> Result<Plan, BuildError> {
+    let mut config = config.clone();
+    // Before measuring, so "auto" sizes come from the region's images
+    let nv = nv_mapping.map(|mapping| mapping.apply(&mut config)).transpose()?;
+    let resolved_sizes = resolve_auto_sizes(&mut config)?;
+    let slot_count = metadata_slot_count.unwrap_or_else(|| slot_mode.metadata_slot_count(&config));
+    check_slot_count(&config, slot_count)?;
+    let config = slot_mode.apply(&config).map_err(BuildError::AlreadySlotted)?;
+    let layout = plan_layout(&config, slot_count)?;
+    Ok(Plan { config, layout, resolved_sizes, nv })
+}
+
+impl Plan {
+    fn super_layout(&self, slot_mode: SlotMode) -> SuperLayout {
+        super_layout(&self.config, &self.layout, &self.resolved_sizes, slot_mode)
+    }
+}
+
+fn super_layout(config: &PartitionConfig, layout: &Layout, resolved_sizes: &[ResolvedSize], slot_mode: SlotMode) -> SuperLayout {
+    let copied = copied_partitions(config, layout);
+    // Names come from the config, as the metadata of a retrofit image stores them
+    // without the slot suffix; the metadata partitions are in config order
+    let partitions: Vec<PlannedPartition> = layout
+        .placements
+        .iter()
+        .zip(&layout.tables.partitions)
+        .enumerate()
+        .map(|(i, (placement, partition))| PlannedPartition {
+            name: placement.name.clone(),
+            group_name: placement.group_name.clone(),
+            offset: placement.offset,
+            size: placement.size,
+            copied: copied.contains(&i),
+            attributes: partition.attributes,
+            extents: planned_extents(&layout.tables, partition),
+        })
+        .collect();
+    let usable = usable_space(config, layout.slot_count as u32);
+    let groups = config
+        .groups
+        .iter()
+        .map(|group| group_usage(&group.name, group.maximum_size_bytes(), config.cow_reserve_bytes(group, usable), &partitions))
+        .collect();
+    let first_usable_offset = first_usable_offset(layout.metadata_max_size, layout.slot_count, layout.alignment);
+    let block_devices: Vec<DeviceUsage> = config
+        .block_devices
+        .iter()
+        .enumerate()
+        .map(|(i, device)| device_usage(i, &device.name, device.size_bytes(), first_usable_offset, &partitions))
+        .collect();
+    SuperLayout {
+        device_size: layout.device_size,
+        block_size: layout.block_size,
+        alignment: layout.alignment,
+        metadata_max_size: layout.metadata_max_size,
+        metadata_slot_count: layout.slot_count,
+        metadata_size: layout.metadata.len() as u64,
+        first_usable_offset,
+        partitions,
+        slot_suffix: slot_mode.active_suffix().map(str::to_string),
+        resolved_sizes: resolved_sizes.to_vec(),
+        groups,
+        snapshot_space: block_devices.iter().map(|d| d.free).sum(),
+        block_devices,
+    }
+}
+
+impl SuperLayout {
+    /// Layout of an existing image from its geometry and metadata, see `inspect_super`
+    pub(super) fn from_metadata(geometry: &LpGeometry, metadata: &LpMetadata) -> SuperLayout {
+        let partitions: Vec<PlannedPartition> = metadata
+            .partitions
+            .iter()
+            .map(|partition| {
+                let extents = planned_extents(metadata, partition);
+                PlannedPartition {
+                    name: partition.name.clone(),
+                    group_name: metadata.groups.get(partition.group_index as usize).map(|g| g.name.clone()).unwrap_or_default(),
+                    offset: extents.first().map_or(0, |e| e.offset),
+                    size: extents.iter().map(|e| e.length).sum(),
+                    copied: !extents.is_empty(),
+                    attributes: partition.attributes,
+                    extents,
+                }
+            })
+            .collect();
+        // A maximum_size of 0 is no limit
+        let groups = metadata
+            .groups
+            .iter()
+            .map(|group| group_usage(&group.name, Some(group.maximum_size).filter(|&max| max > 0), 0, &partitions))
+            .collect();
+        let block_devices: Vec<DeviceUsage> = metadata
+            .block_devices
+            .iter()
+            .enumerate()
+            .map(|(i, device)| device_usage(i, &device.partition_name, device.size, device.first_logical_sector * LP_SECTOR_SIZE, &partitions))
+            .collect();
+        let first = metadata.block_devices.first();
+        SuperLayout {
+            device_size: first.map_or(0, |d| d.size),
+            block_size: geometry.logical_block_size as u64,
+            alignment: first.map_or(0, |d| d.alignment as u64),
+            metadata_max_size: geometry.metadata_max_size as u64,
+            metadata_slot_count: geometry.metadata_slot_count as u64,
+            metadata_size: metadata.to_bytes().len() as u64,
+            first_usable_offset: block_devices.first().map_or(0, |d| d.metadata),
+            partitions,
+            slot_suffix: None,
+            resolved_sizes: Vec::new(),
+            groups,
+            snapshot_space: block_devices.iter().map(|d| d.free).sum(),
+            block_devices,
+        }
+    }
+}
+
+/// The linear extents of `partition` in `metadata`
+fn planned_extents(metadata: &LpMetadata, partition: &LpPartition) -> Vec<PlannedExtent> {
+    let start = partition.first_extent_index as usize;
+    metadata
+        .extents
+        .get(start..start + partition.num_extents as usize)
+        .unwrap_or_default()
+        .iter()
+        .map(|extent| PlannedExtent {
+            device_index: extent.target_source,
+            start_sector: extent.target_data,
+            num_sectors: extent.num_sectors,
+            offset: extent.target_data * LP_SECTOR_SIZE,
+            length: extent.num_sectors * LP_SECTOR_SIZE,
+        })
+        .collect()
+}
+
+fn group_usage(name: &str, maximum_size: Option<u64>, reserved: u64, partitions: &[PlannedPartition]) -> GroupUsage {
+    let used = partitions.iter().filter(|p| p.group_name == name).map(|p| p.size).sum();
+    let free = maximum_size.map(|max| max.saturating_sub(used));
+    GroupUsage { name: name.to_string(), maximum_size, used, free, reserved, free_after_reserve: free.map(|f| f.saturating_sub(reserved)) }
+}
+
+/// Usage of block device `index`, whose first `metadata` bytes are not for partitions
+fn device_usage(index: usize, name: &str, size: u64, metadata: u64, partitions: &[PlannedPartition]) -> DeviceUsage {
+    let used = partitions
+        .iter()
+        .flat_map(|p| &p.extents)
+        .filter(|e| e.device_index as usize == index)
+        .map(|e| e.length)
+        .sum::<u64>();
+    DeviceUsage { name: name.to_string(), size, metadata, used, free: size.saturating_sub(metadata + used) }
+}
+
+/// The first difference of `actual` from `expected`, by partition name where it is
+/// in a partition
+fn layout_difference(expected: &SuperLayout, actual: &SuperLayout) -> Option<String> {
+    let fields = [
+        ("device size", expected.device_size, actual.device_size),
+        ("block size", expected.block_size, actual.block_size),
+        ("alignment", expected.alignment, actual.alignment),
+        ("metadata size", expected.metadata_max_size, actual.metadata_max_size),
+        ("metadata slot count", expected.metadata_slot_count, actual.metadata_slot_count),
+    ];
+    if let Some((field, expected, actual)) = fields.into_iter().find(|(_, expected, actual)| expected != actual) {
+        return Some(format!("{} is {}, the plan has {}", field, actual, expected));
+    }
+    let names: Vec<&str> = expected.partitions.iter().map(|p| p.name.as_str()).collect();
+    let actual_names: Vec<&str> = actual.partitions.iter().map(|p| p.name.as_str()).collect();
+    if names != actual_names {
+        return Some(format!("partitions are {:?}, the plan has {:?}", actual_names, names));
+    }
+    for (e, a) in expected.partitions.iter().zip(&actual.partitions) {
+        if e.size != a.size {
+            return Some(format!("partition '{}' is {} bytes, the plan has {}", e.name, a.size, e.size));
+        }
+        if e != a {
+            return Some(format!("partition '{}' is {:?}, the plan has {:?}", e.name, a, e));
+        }
+    }
+    (expected != actual).then(|| "groups, slots or measured sizes differ from the plan".to_string())
+}
+
+/// What a `SlotMode::Single` build of a config writes, see `verify_super`
+pub(super) struct ExpectedImage {
+    pub device_size: u64,
+    pub slot_count: u64,
+    pub geometry: Vec<u8>,
+    pub metadata: Vec<u8>,
+    /// (name, image path, offset) of every copied partition, in offset order
+    pub images: Vec<(String, String, u64)>,
+}
+
+pub(super) fn expected_image(config: &PartitionConfig) -> Result<ExpectedImage, BuildError> {
+    let Plan { config, layout, .. } = prepare(config, SlotMode::Single, None, None)?;
+    let images = copied_partitions(&config, &layout)
+        .into_iter()
+        .map(|i| (layout.placements[i].name.clone(), config.partitions[i].path.trim().to_string(), layout.placements[i].offset))
+        .collect();
+    Ok(ExpectedImage {
+        device_size: layout.device_size,
+        slot_count: layout.slot_count,
+        geometry: layout.geometry,
+        metadata: layout.metadata,
+        images,
+    })
+}
+
+/// Checks that `slot_count` metadata slots of `super_meta.size` bytes, each with its
+/// backup, fit the first block device
+fn check_slot_count(config: &PartitionConfig, slot_count: u32) -> Result<(), BuildError> {
+    if slot_count == 0 {
+        return Err(BuildError::NoMetadataSlots);
+    }
+    let Some(device) = config.block_devices.first() else {
+        // Left to validation, which reports the missing block device
+        return Ok(());
+    };
+    let max_size = config.super_meta.size_bytes();
+    let metadata_region = metadata_region_end(max_size, slot_count as u64);
+    if metadata_region > device.size_bytes() {
+        return Err(BuildError::TooManyMetadataSlots {
+            slot_count,
+            max_size,
+            metadata_region,
+            device: device.name.clone(),
+            device_size: device.size_bytes(),
+        });
+    }
+    Ok(())
+}
+
+/// Everything needed to write the image, independent of the output format
+struct Layout {
+    device_size: u64,
+    block_size: u64,
+    alignment: u64,
+    metadata_max_size: u64,
+    slot_count: u64,
+    geometry: Vec<u8>,
+    metadata: Vec<u8>,
+    /// What `metadata` serializes
+    tables: LpMetadata,
+    placements: Vec<PartitionExtent>,
+}
+
+fn plan_layout(config: &PartitionConfig, slot_count: u32) -> Result<Layout, BuildError> {
+    validate_with_slot_count(config, slot_count).map_err(BuildError::Invalid)?;
+    if let Err(errors) = config.validate_block_devices() {
+        return Err(errors[0].clone().into());
+    }
+    if config.block_devices.len() > 1 {
+        return Err(BuildError::MultipleBlockDevices(config.block_devices.len()));
+    }
+    let device = &config.block_devices[0];
+    let block_size = device.block_size_bytes();
+    let alignment = device.alignment_bytes();
+    let device_size = device.size_bytes();
+    let metadata_max_size = config.super_meta.size_bytes();
+    let slot_count = slot_count as u64;
+
+    check_name(&device.name)?;
+    let linked = config.clone().link()?;
+
+    // Retrofit metadata stores names without the slot suffix, the device appends
+    // that of the slot it boots from (validation made sure the device has one)
+    let retrofit_suffix = if config.retrofit { config.retrofit_suffix() } else { None };
+    let stored_name = |name: &str| match retrofit_suffix {
+        Some(suffix) => name.strip_suffix(suffix).unwrap_or(name).to_string(),
+        None => name.to_string(),
+    };
+    let (group_flags, partition_flags, device_flags) = match retrofit_suffix {
+        Some(_) => (LP_GROUP_SLOT_SUFFIXED, LP_PARTITION_ATTR_SLOT_SUFFIXED, LP_BLOCK_DEVICE_SLOT_SUFFIXED),
+        None => (0, 0, 0),
+    };
+
+    // Group 0 is the implicit "default" group, as in liblp
+    let mut groups = vec![LpGroup { name: "default".to_string(), flags: 0, maximum_size: 0 }];
+    // Metadata group index of each config group
+    let mut group_indexes = Vec::with_capacity(config.groups.len());
+    for (id, group) in linked.groups() {
+        check_name(&group.name)?;
+        let maximum_size = linked.group_limit(id).unwrap_or(0);
+        if group.name == "default" {
+            // Configs produced by unpack_super_image may list it explicitly
+            groups[0].maximum_size = maximum_size;
+            group_indexes.push(0);
+            continue;
+        }
+        group_indexes.push(groups.len() as u32);
+        groups.push(LpGroup { name: stored_name(&group.name), flags: group_flags, maximum_size });
+    }
+
+    // Assign offsets group by group, so the partitions of each group share one
+    // contiguous region, in config order within the group
+    let first_usable = first_usable_offset(metadata_max_size, slot_count, alignment);
+    let mut next_offset = first_usable;
+    let mut offsets = vec![0u64; config.partitions.len()];
+    for (id, _) in linked.groups() {
+        for (i, partition) in linked.partitions_in(id) {
+            let size = align_up(partition.size_bytes().unwrap_or(0), block_size);
+            if size == 0 {
+                continue;
+            }
+            // Block aligned as well, in case alignment is smaller than the block size
+            let offset = align_up(align_up(next_offset, alignment), block_size);
+            if offset + size > device_size {
+                return Err(BuildError::OutOfSpace(device.name.clone()));
+            }
+            offsets[i] = offset;
+            next_offset = offset + size;
+        }
+    }
+
+    let mut partitions = Vec::new();
+    let mut extents = Vec::new();
+    let mut placements = Vec::new();
+    for (i, (partition, &offset)) in config.partitions.iter().zip(&offsets).enumerate() {
+        check_name(&partition.name)?;
+        let group_index = group_indexes[linked.group_of(i).index()];
+        let size = align_up(partition.size_bytes().unwrap_or(0), block_size);
+
+        let mut placement = PartitionExtent {
+            name: partition.name.clone(),
+            group_name: partition.group_name.clone(),
+            offset: 0,
+            size,
+            image_bytes: 0,
+        };
+        let mut lp_partition = LpPartition {
+            name: stored_name(&partition.name),
+            attributes: LP_PARTITION_ATTR_NONE | partition_flags,
+            first_extent_index: extents.len() as u32,
+            num_extents: 0,
+            group_index,
+        };
+        if partition.size.is_some() {
+            lp_partition.attributes |= LP_PARTITION_ATTR_READONLY;
+        }
+        if size > 0 {
+            extents.push(LpExtent {
+                num_sectors: size / LP_SECTOR_SIZE,
+                target_type: LP_TARGET_TYPE_LINEAR,
+                target_data: offset / LP_SECTOR_SIZE,
+                target_source: 0,
+            });
+            lp_partition.num_extents = 1;
+            placement.offset = offset;
+        }
+        partitions.push(lp_partition);
+        placements.push(placement);
+    }
+
+    // The header flags field only exists from minor version 2 on
+    let (minor_version, header_flags) = if config.is_virtual_ab() {
+        (LP_METADATA_VERSION_FOR_VIRTUAL_AB, LP_HEADER_FLAG_VIRTUAL_AB_DEVICE)
+    } else {
+        (0, 0)
+    };
+    let tables = LpMetadata {
+        minor_version,
+        header_flags,
+        partitions,
+        extents,
+        groups,
+        block_devices: vec![LpBlockDevice {
+            first_logical_sector: first_usable / LP_SECTOR_SIZE,
+            alignment: alignment as u32,
+            alignment_offset: 0,
+            size: device_size,
+            partition_name: stored_name(&device.name),
+            flags: device_flags,
+        }],
+    };
+    let metadata = tables.to_bytes();
+    if metadata.len() as u64 > metadata_max_size {
+        return Err(BuildError::MetadataTooLarge { required: metadata.len() as u64, max_size: metadata_max_size });
+    }
+    let geometry = LpGeometry {
+        metadata_max_size: metadata_max_size as u32,
+        metadata_slot_count: slot_count as u32,
+        logical_block_size: block_size as u32,
+    }
+    .to_bytes();
+
+    Ok(Layout { device_size, block_size, alignment, metadata_max_size, slot_count, geometry, metadata, tables, placements })
+}
+
+impl Layout {
+    /// (offset, bytes) of both geometry copies and every metadata copy
+    fn metadata_writes(&self) -> Vec<(u64, &[u8])> {
+        let mut writes = vec![
+            (LP_PARTITION_RESERVED_BYTES, self.geometry.as_slice()),
+            (LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE, self.geometry.as_slice()),
+        ];
+        for slot in 0..self.slot_count {
+            writes.push((primary_metadata_offset(self.metadata_max_size, slot), self.metadata.as_slice()));
+            writes.push((backup_metadata_offset(self.metadata_max_size, self.slot_count, slot), self.metadata.as_slice()));
+        }
+        writes
+    }
+
+    /// Image contents from offset 0 up to the end of the metadata region, rounded up to
+    /// the block size
+    fn metadata_region(&self) -> Vec<u8> {
+        let region_end = metadata_region_end(self.metadata_max_size, self.slot_count);
+        let mut region = vec![0u8; align_up(region_end, self.block_size) as usize];
+        for (offset, data) in self.metadata_writes() {
+            region[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        }
+        region
+    }
+
+    /// Total size of the geometry and metadata copies
+    fn metadata_bytes(&self) -> u64 {
+        self.metadata_writes().iter().map(|(_, data)| data.len() as u64).sum()
+    }
+}
+
+/// Indices of the placements whose image gets copied, i.e. dynamic partitions with a
+/// path, in offset order so the output is written front to back
+fn copied_partitions(config: &PartitionConfig, layout: &Layout) -> Vec<usize> {
+    let mut copied: Vec<usize> = config
+        .partitions
+        .iter()
+        .zip(&layout.placements)
+        .enumerate()
+        .filter(|(_, (partition, placement))| placement.size > 0 && partition.has_data())
+        .map(|(i, _)| i)
+        .collect();
+    copied.sort_by_key(|&i| layout.placements[i].offset);
+    copied
+}
+
+fn write_raw<W: Write + Seek>(
+    config: &PartitionConfig,
+    layout: &mut Layout,
+    copied: &[usize],
+    file: &mut W,
+    tracker: &mut ProgressTracker,
+    hasher: &mut Option<ImageHasher>,
+) -> Result<BuildReport, BuildError> {
+    let metadata_bytes = layout.metadata_bytes();
+    tracker.report(BuildPhase::Metadata, None, 0, metadata_bytes);
+    let region = layout.metadata_region();
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&region)?;
+    if let Some(hasher) = hasher {
+        hasher.update(&region);
+    }
+    tracker.report(BuildPhase::Metadata, None, metadata_bytes, metadata_bytes);
+    tracker.complete(metadata_bytes);
+    let mut bytes_written = metadata_bytes;
+
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE as usize];
+    for &i in copied {
+        let placement = &mut layout.placements[i];
+        let (mut reader, image_size) = open_image(config.partitions[i].path.trim(), placement)?;
+        file.seek(SeekFrom::Start(placement.offset))?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.begin_partition(&placement.name, config.partitions[i].size_bytes(), placement.offset);
+        }
+        copy_image(&mut reader, image_size, &mut buf, &placement.name, tracker, |data| {
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(data);
+            }
+            file.write_all(data)
+        })?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.end_partition();
+        }
+        placement.image_bytes = image_size;
+        bytes_written += image_size;
+    }
+
+    tracker.report(BuildPhase::Finalize, None, 0, 0);
+    // A file already has the device size, other sinks end after the last write
+    if file.seek(SeekFrom::End(0))? < layout.device_size {
+        file.seek(SeekFrom::Start(layout.device_size - 1))?;
+        file.write_all(&[0])?;
+    }
+    file.flush()?;
+
+    Ok(BuildReport {
+        image_size: layout.device_size,
+        partitions: layout.placements.clone(),
+        bytes_written,
+        sparse: None,
+        zstd: None,
+        chunks: None,
+        slot_suffix: None,
+        manifest: None,
+        layout_file: None,
+        resolved_sizes: Vec::new(),
+        nv: None,
+    })
+}
+
+/// Raw image going into a stream that cannot seek: seeking forward writes zeros,
+/// seeking back is an error. Hashes everything that goes through.
+struct ForwardWriter<W: Write> {
+    inner: W,
+    position: u64,
+    sha256: Sha256,
+}
+
+impl<W: Write> Write for ForwardWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.sha256.update(&buf[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for ForwardWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) | SeekFrom::End(delta) => self.position.checked_add_signed(delta),
+        };
+        let target = target
+            .filter(|&target| target >= self.position)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "cannot seek back in a compressed image"))?;
+        let zeros = [0u8; 64 * 1024];
+        while self.position < target {
+            let n = (target - self.position).min(zeros.len() as u64) as usize;
+            self.write_all(&zeros[..n])?;
+        }
+        Ok(self.position)
+    }
+}
+
+/// The raw image through `ForwardWriter` into a zstd frame of the device size
+fn write_zstd<W: Write + Seek>(
+    config: &PartitionConfig,
+    layout: &mut Layout,
+    copied: &[usize],
+    out: &mut W,
+    level: i32,
+    tracker: &mut ProgressTracker,
+    hasher: &mut Option<ImageHasher>,
+) -> Result<BuildReport, BuildError> {
+    let start = out.stream_position()?;
+    let encoder = ZstdWriter::new(&mut *out, level).with_content_size(layout.device_size);
+    let mut raw = ForwardWriter { inner: encoder, position: 0, sha256: Sha256::new() };
+    let report = write_raw(config, layout, copied, &mut raw, tracker, hasher)?;
+    let ForwardWriter { inner: encoder, position: uncompressed_size, sha256 } = raw;
+    encoder.finish()?;
+    let compressed_size = out.stream_position()? - start;
+    Ok(BuildReport {
+        image_size: compressed_size,
+        bytes_written: compressed_size,
+        zstd: Some(ZstdStats {
+            level,
+            uncompressed_size,
+            compressed_size,
+            uncompressed_sha256: format!("{:x}", sha256.finalize()),
+        }),
+        ..report
+    })
+}
+
+fn write_sparse<W: Write + Seek>(
+    config: &PartitionConfig,
+    layout: &mut Layout,
+    copied: &[usize],
+    out: &mut W,
+    max_blob_size: Option<u64>,
+    tracker: &mut ProgressTracker,
+    hasher: &mut Option<ImageHasher>,
+) -> Result<BuildReport, BuildError> {
+    let block_size = layout.block_size;
+    if block_size == 0 || !layout.device_size.is_multiple_of(block_size) {
+        return Err(BuildError::SparseUnaligned { size: layout.device_size, block_size });
+    }
+    let total_blocks = (layout.device_size / block_size) as u32;
+
+    let start = out.stream_position()?;
+    let mut sparse = SparseWriter::new(&mut *out, block_size as u32, max_blob_size)?;
+
+    // The metadata region is small, assemble it in memory and add it as data
+    let metadata_bytes = layout.metadata_bytes();
+    tracker.report(BuildPhase::Metadata, None, 0, metadata_bytes);
+    let region = layout.metadata_region();
+    sparse.write_blocks(&region)?;
+    if let Some(hasher) = hasher {
+        hasher.update(&region);
+    }
+    tracker.report(BuildPhase::Metadata, None, metadata_bytes, metadata_bytes);
+    tracker.complete(metadata_bytes);
+
+    let mut buf = vec![0u8; align_up(COPY_BUFFER_SIZE, block_size) as usize];
+    for &i in copied {
+        let placement = &mut layout.placements[i];
+        let (mut reader, image_size) = open_image(config.partitions[i].path.trim(), placement)?;
+        sparse.skip_blocks((placement.offset / block_size) as u32 - sparse.blocks_written())?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.begin_partition(&placement.name, config.partitions[i].size_bytes(), placement.offset);
+        }
+        copy_image(&mut reader, image_size, &mut buf, &placement.name, tracker, |data| {
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(data);
+            }
+            // Zero-pad the last partial block
+            if (data.len() as u64).is_multiple_of(block_size) {
+                sparse.write_blocks(data)
+            } else {
+                let mut padded = data.to_vec();
+                padded.resize(align_up(data.len() as u64, block_size) as usize, 0);
+                sparse.write_blocks(&padded)
+            }
+        })?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.end_partition();
+        }
+        placement.image_bytes = image_size;
+    }
+
+    tracker.report(BuildPhase::Finalize, None, 0, 0);
+    sparse.skip_blocks(total_blocks - sparse.blocks_written())?;
+    let stats = sparse.finish()?;
+
+    let image_size = out.stream_position()? - start;
+    Ok(BuildReport {
+        image_size,
+        partitions: layout.placements.clone(),
+        bytes_written: image_size,
+        sparse: Some(stats),
+        zstd: None,
+        chunks: None,
+        slot_suffix: None,
+        manifest: None,
+        layout_file: None,
+        resolved_sizes: Vec::new(),
+        nv: None,
+    })
+}
+
+/// The chunks of `SuperImageBuilder::stream`: the metadata region, then every copied
+/// image padded to the block size
+fn stream_image(build: &mut Build, sink: &mut dyn FnMut(ImageChunk) -> io::Result<()>) -> Result<BuildReport, BuildError> {
+    let Build { config, layout, copied, tracker, .. } = build;
+    let block_size = layout.block_size;
+
+    let metadata_bytes = layout.metadata_bytes();
+    tracker.report(BuildPhase::Metadata, None, 0, metadata_bytes);
+    let region = layout.metadata_region();
+    sink(ImageChunk { offset: 0, len: region.len() as u64, partition: None, data: &mut region.as_slice() })?;
+    tracker.report(BuildPhase::Metadata, None, metadata_bytes, metadata_bytes);
+    tracker.complete(metadata_bytes);
+    let mut bytes_written = region.len() as u64;
+
+    for &i in copied.iter() {
+        let (reader, image_size) = open_image(config.partitions[i].path.trim(), &layout.placements[i])?;
+        let (name, offset) = (layout.placements[i].name.clone(), layout.placements[i].offset);
+        let len = align_up(image_size, block_size);
+        debug!("Streaming {} as a chunk of {} bytes at offset {}", name, len, offset);
+        let _span = tracing::debug_span!("stream", %name).entered();
+        tracker.report(BuildPhase::Partition, Some(&name), 0, image_size);
+        let mut data = TrackedReader {
+            inner: reader.take(image_size).chain(io::repeat(0).take(len - image_size)),
+            tracker,
+            name: &name,
+            done: 0,
+            total: image_size,
+        };
+        let result = sink(ImageChunk { offset, len, partition: Some(&name), data: &mut data });
+        tracker.check_cancelled()?;
+        result?;
+        tracker.complete(image_size);
+        layout.placements[i].image_bytes = image_size;
+        bytes_written += len;
+    }
+    tracker.report(BuildPhase::Finalize, None, 0, 0);
+
+    Ok(BuildReport {
+        image_size: layout.device_size,
+        partitions: layout.placements.clone(),
+        bytes_written,
+        sparse: None,
+        zstd: None,
+        chunks: None,
+        slot_suffix: None,
+        manifest: None,
+        layout_file: None,
+        resolved_sizes: Vec::new(),
+        nv: None,
+    })
+}
+
+/// Data of a streamed partition chunk, reporting the bytes read as its progress
+struct TrackedReader<'a, 'b, R> {
+    inner: R,
+    tracker: &'a mut ProgressTracker<'b>,
+    name: &'a str,
+    done: u64,
+    total: u64,
+}
+
+impl<R: Read> Read for TrackedReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tracker.cancel.load(Ordering::SeqCst) {
+            return Err(io::Error::other(BuildError::Cancelled));
+        }
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        self.tracker.report(BuildPhase::Partition, Some(self.name), self.done.min(self.total), self.total);
+        Ok(n)
+    }
+}
+
+/// Streams `image_size` bytes from `reader` to `write` through `buf`, reporting progress
+fn copy_image<W: FnMut(&[u8]) -> io::Result<()>>(
+    reader: &mut dyn Read,
+    image_size: u64,
+    buf: &mut [u8],
+    name: &str,
+    tracker: &mut ProgressTracker,
+    mut write: W,
+) -> Result<(), BuildError> {
+    let _span = tracing::debug_span!("copy", %name).entered();
+    let mut done = 0u64;
+    tracker.report(BuildPhase::Partition, Some(name), 0, image_size);
+    while done < image_size {
+        tracker.check_cancelled()?;
+        let len = (image_size - done).min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..len])?;
+        write(&buf[..len])?;
+        done += len as u64;
+        trace!("{}: copied {} of {} bytes", name, done, image_size);
+        tracker.report(BuildPhase::Partition, Some(name), done, image_size);
+    }
+    tracker.complete(image_size);
+    debug!("Copied {} bytes", image_size);
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), BuildError> {
+    if name.len() > LP_NAME_LEN {
+        return Err(BuildError::NameTooLong(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Opens a partition image after checking that it fits its extent, and returns a
+/// reader of its raw contents together with the raw size.
+///
+/// Android sparse images are expanded while reading, and checked by their expanded
+/// size (`blk_sz * total_blks`) rather than the file size.
+fn open_image(path: &str, placement: &PartitionExtent) -> Result<(Box<dyn Read>, u64), BuildError> {
+    let (reader, image_size) = open_raw_or_sparse(Path::new(path))?;
+    if image_size > placement.size {
+        return Err(BuildError::ImageTooLarge {
+            partition: placement.name.clone(),
+            image_size,
+            size: placement.size,
+        });
+    }
+    Ok((reader, image_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::sparse::expand_sparse;
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+    use crate::super_image_creater::unpack::read_super_metadata;
+    use crate::super_image_creater::{ByteSize, Group};
+
+    #[test]
+    fn images_larger_than_the_copy_buffer_land_at_their_offsets() {
+        let dir = test_dir("copy-buffer");
+        let system = pattern(2 * COPY_BUFFER_SIZE as usize + 12345, 0x5a);
+        let vendor = pattern(5000, 0xa5);
+        let config = config_with_images(&dir, &[("system", &system, 3 << 20), ("vendor", &vendor, 1 << 20)]);
+        let output = dir.join("super.img");
+        let report = build_super_image(&config, &output).unwrap();
+        let image = std::fs::read(&output).unwrap();
+        assert_eq!(image.len() as u64, 16 << 20);
+        assert_eq!(report.image_size, 16 << 20);
+
+        for (extent, data) in report.partitions.iter().zip([&system, &vendor]) {
+            assert_eq!(extent.image_bytes, data.len() as u64);
+            assert!(extent.offset.is_multiple_of(1 << 20), "{} at {}", extent.name, extent.offset);
+            let (start, end) = (extent.offset as usize, (extent.offset + extent.size) as usize);
+            assert!(image[start..start + data.len()] == data[..], "{}", extent.name);
+            // The rest of the extent is left zero
+            assert!(image[start + data.len()..end].iter().all(|&b| b == 0), "{}", extent.name);
+        }
+        let system_extent = &report.partitions[0];
+        let vendor_extent = &report.partitions[1];
+        assert!(system_extent.offset + system_extent.size <= vendor_extent.offset);
+        // The geometry follows the 4096 reserved bytes
+        assert_eq!(image[4096..4100], LP_METADATA_GEOMETRY_MAGIC.to_le_bytes());
+
+        // The sparse writer streams through the same buffer and expands to the same bytes
+        let sparse = dir.join("super.simg");
+        build_super_image_as(&config, &sparse, OutputFormat::Sparse { max_blob_size: Some(COPY_BUFFER_SIZE) }).unwrap();
+        let mut expanded = Vec::new();
+        crate::super_image_creater::sparse::expand_sparse(std::fs::File::open(&sparse).unwrap(), &mut expanded).unwrap();
+        assert!(expanded == image);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn metadata_parses_back_to_the_config() {
+        let dir = test_dir("metadata-back");
+        let mut config = config_with_images(&dir, &[("system", &pattern(8192, 1), 2 << 20), ("vendor", &pattern(100, 2), 4096)]);
+        config.groups[0].maximum_size = Some(ByteSize::new(8 << 20));
+        config.add_group(Group { name: "spare".into(), ..Default::default() });
+        config.partitions[1].group_name = "spare".into();
+        let output = dir.join("super.img");
+        let report = build_super_image(&config, &output).unwrap();
+
+        let (geometry, metadata) = read_super_metadata(&output).unwrap();
+        assert_eq!(geometry.metadata_max_size as u64, config.super_meta.size_bytes());
+        assert_eq!(geometry.metadata_slot_count, config.metadata_slot_count());
+        assert_eq!(geometry.logical_block_size as u64, config.block_devices[0].block_size_bytes());
+
+        let groups: Vec<(&str, u64)> = metadata.groups.iter().map(|g| (g.name.as_str(), g.maximum_size)).collect();
+        assert!(groups.ends_with(&[("main", 8 << 20), ("spare", 0)]), "{:?}", groups);
+        assert_eq!(metadata.block_devices.len(), 1);
+        assert_eq!(metadata.block_devices[0].partition_name, "super");
+        assert_eq!(metadata.block_devices[0].size, 16 << 20);
+        assert_eq!(metadata.block_devices[0].alignment as u64, config.block_devices[0].alignment_bytes());
+
+        assert_eq!(metadata.partitions.len(), config.partitions.len());
+        for ((partition, lp), extent) in config.partitions.iter().zip(&metadata.partitions).zip(&report.partitions) {
+            assert_eq!(lp.name, partition.name);
+            assert_eq!(metadata.groups[lp.group_index as usize].name, partition.group_name);
+            let extents = metadata.partition_extents(lp);
+            assert_eq!(extents.len(), 1, "{}", lp.name);
+            assert_eq!(extents[0].target_type, LP_TARGET_TYPE_LINEAR);
+            assert_eq!(extents[0].target_data * LP_SECTOR_SIZE, extent.offset);
+            assert_eq!(extents[0].num_sectors * LP_SECTOR_SIZE, partition.size_bytes().unwrap());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn virtual_ab_and_retrofit_set_the_metadata_flags() {
+        let dir = test_dir("flags");
+        let build = |config: &PartitionConfig| {
+            let output = dir.join("super.img");
+            build_super_image(config, &output).map(|_| read_super_metadata(&output).unwrap().1)
+        };
+        let config = config_with_images(&dir, &[("system", b"system", 1 << 20)]);
+        // Virtual A/B unless turned off
+        let metadata = build(&config).unwrap();
+        assert_eq!((metadata.minor_version, metadata.header_flags), (LP_METADATA_VERSION_FOR_VIRTUAL_AB, LP_HEADER_FLAG_VIRTUAL_AB_DEVICE));
+        assert_eq!(metadata.block_devices[0].flags, 0);
+        let metadata = build(&PartitionConfig { virtual_ab: Some(false), ..config.clone() }).unwrap();
+        assert_eq!((metadata.minor_version, metadata.header_flags), (0, 0));
+
+        // Retrofit: every name is stored without the slot suffix, and flagged
+        let mut retrofit = config_with_images(&dir, &[("system_a", b"system", 1 << 20), ("vendor_a", b"vendor", 1 << 20)]);
+        retrofit.retrofit = true;
+        retrofit.block_devices[0].name = "system_a".into();
+        retrofit.groups[0].name = "main_a".into();
+        for partition in &mut retrofit.partitions {
+            partition.group_name = "main_a".into();
+        }
+        let metadata = build(&retrofit).unwrap();
+        assert_eq!((metadata.minor_version, metadata.header_flags), (0, 0));
+        assert_eq!(metadata.block_devices[0].partition_name, "system");
+        assert_eq!(metadata.block_devices[0].flags, LP_BLOCK_DEVICE_SLOT_SUFFIXED);
+        assert_eq!((metadata.groups[1].name.as_str(), metadata.groups[1].flags), ("main", LP_GROUP_SLOT_SUFFIXED));
+        let partitions: Vec<(&str, u32)> = metadata.partitions.iter().map(|p| (p.name.as_str(), p.attributes & LP_PARTITION_ATTR_SLOT_SUFFIXED)).collect();
+        assert_eq!(partitions, [("system", LP_PARTITION_ATTR_SLOT_SUFFIXED), ("vendor", LP_PARTITION_ATTR_SLOT_SUFFIXED)]);
+
+        // A retrofit image needs a slot to be built for, and is never Virtual A/B
+        let errors = |config: &PartitionConfig| match build(config) {
+            Err(BuildError::Invalid(errors)) => errors,
+            other => panic!("{:?}", other.map(|_| ())),
+        };
+        retrofit.block_devices[0].name = "super".into();
+        assert_eq!(errors(&retrofit), [ValidationError::RetrofitUnsuffixedDevice { device: "super".into() }]);
+        retrofit.block_devices[0].name = "system_b".into();
+        retrofit.virtual_ab = Some(true);
+        let found = errors(&retrofit);
+        assert_eq!(found[0], ValidationError::RetrofitVirtualAb);
+        assert!(found[1..].iter().all(|e| matches!(e, ValidationError::RetrofitOtherSlot { suffix, expected, .. } if suffix == "_a" && expected == "_b")), "{:?}", found);
+        assert_eq!(found.len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_cancelled_build_leaves_no_partial_output_and_keeps_the_old_image() {
+        let dir = test_dir("cancel");
+        let system = pattern(3 * COPY_BUFFER_SIZE as usize + 5, 0x3c);
+        let config = config_with_images(&dir, &[("system", &system, 4 << 20)]);
+        let output = dir.join("super.img");
+        let cancel_mid_copy = |format| {
+            let cancel = AtomicBool::new(false);
+            let mut updates = 0;
+            let result = build_super_image_with_progress(&config, &output, format, &cancel, |p| {
+                if p.partition.is_some() && p.bytes_done > 0 {
+                    updates += 1;
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            });
+            assert!(matches!(result, Err(BuildError::Cancelled)), "{:?}", result.map(|_| ()));
+            // Stopped at the next buffer
+            assert_eq!(updates, 1);
+            assert!(!partial_path(&output).exists());
+        };
+
+        cancel_mid_copy(OutputFormat::Raw);
+        assert!(!output.exists());
+
+        build_super_image(&config, &output).unwrap();
+        let before = std::fs::read(&output).unwrap();
+        cancel_mid_copy(OutputFormat::Sparse { max_blob_size: None });
+        assert!(std::fs::read(&output).unwrap() == before);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn address_map_segments_tile_the_whole_image() {
+        let dir = test_dir("address-map");
+        let mut config = config_with_images(
+            &dir,
+            &[("system", b"system", 3 << 19), ("vendor", b"vendor", 1 << 20), ("odm", b"", 0), ("product", b"product", 1 << 20)],
+        );
+        config.add_group(Group { name: "other".into(), ..Default::default() });
+        config.partitions[3].group_name = "other".into();
+        let layout = SuperImageBuilder::new(config).plan().unwrap();
+        let map = layout.address_map();
+
+        let mut end = 0;
+        for segment in &map {
+            assert_eq!(segment.start, end, "{:?}", segment);
+            assert!(segment.length > 0, "{:?}", segment);
+            end += segment.length;
+        }
+        assert_eq!(end, layout.device_size);
+
+        let summary: Vec<(&str, SegmentKind)> = map.iter().map(|s| (s.name.as_str(), s.kind)).collect();
+        let metadata = summary.iter().take_while(|(_, kind)| *kind == SegmentKind::Metadata).count();
+        assert_eq!(map[metadata].start, layout.first_usable_offset);
+        assert_eq!(
+            summary[metadata..],
+            [
+                ("system", SegmentKind::PartitionData),
+                ("main", SegmentKind::GroupFree),
+                ("vendor", SegmentKind::PartitionData),
+                ("product", SegmentKind::PartitionData),
+                ("free", SegmentKind::ImageFree),
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn three_metadata_slots_are_written_and_read_back() {
+        let dir = test_dir("three-slots");
+        let system = pattern(300_000, 0x3c);
+        let config = config_with_images(&dir, &[("system", &system, 1 << 20)]);
+        let output = dir.join("super.img");
+        let builder = SuperImageBuilder::new(config.clone()).output(&output).metadata_slot_count(3);
+        assert_eq!(builder.plan().unwrap().metadata_slot_count, 3);
+        builder.build().unwrap();
+
+        let image = std::fs::read(&output).unwrap();
+        let max = config.super_meta.size_bytes();
+        for geometry in [LP_PARTITION_RESERVED_BYTES, LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE] {
+            let geometry = LpGeometry::from_bytes(&image[geometry as usize..(geometry + LP_METADATA_GEOMETRY_SIZE) as usize]).unwrap();
+            assert_eq!(geometry.metadata_slot_count, 3);
+        }
+        let (geometry, metadata) = read_super_metadata(&output).unwrap();
+        assert_eq!(geometry.metadata_slot_count, 3);
+        // Three primary and three backup copies, then nothing up to the first partition
+        for slot in 0..3 {
+            for offset in [primary_metadata_offset(max, slot), backup_metadata_offset(max, 3, slot)] {
+                let copy = LpMetadata::from_bytes(&image[offset as usize..(offset + max) as usize]).unwrap();
+                assert_eq!(copy, metadata, "slot {} at {}", slot, offset);
+            }
+        }
+        assert!(image[metadata_region_end(max, 3) as usize..1 << 20].iter().all(|&b| b == 0));
+
+        let error = SuperImageBuilder::new(config.clone()).output(&output).metadata_slot_count(0).build().unwrap_err();
+        assert!(matches!(error, BuildError::NoMetadataSlots), "{}", error);
+        let error = SuperImageBuilder::new(config).output(&output).metadata_slot_count(128).plan().unwrap_err();
+        assert!(matches!(error, BuildError::TooManyMetadataSlots { slot_count: 128, .. }), "{}", error);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn builds_into_an_in_memory_buffer() {
+        let dir = test_dir("build-to");
+        let system = pattern(COPY_BUFFER_SIZE as usize + 77, 0x21);
+        let vendor = pattern(5000, 0x12);
+        let config = config_with_images(&dir, &[("system", &system, 2 << 20), ("vendor", &vendor, 1 << 20)]);
+        let file = dir.join("file.img");
+        build_super_image(&config, &file).unwrap();
+        let raw = std::fs::read(&file).unwrap();
+
+        let mut sink = io::Cursor::new(Vec::new());
+        let report = build_super_image_to(&config, &mut sink, OutputFormat::Raw).unwrap();
+        assert_eq!(report.image_size, 16 << 20);
+        assert!(sink.get_ref() == &raw);
+        // Nothing is written at `super_meta.path`
+        assert!(!dir.join("super.img").exists());
+
+        let mut sink = io::Cursor::new(Vec::new());
+        let report = build_super_image_to(&config, &mut sink, OutputFormat::Sparse { max_blob_size: None }).unwrap();
+        assert_eq!(report.image_size, sink.get_ref().len() as u64);
+        let mut expanded = Vec::new();
+        expand_sparse(sink.get_ref().as_slice(), &mut expanded).unwrap();
+        assert!(expanded == raw);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
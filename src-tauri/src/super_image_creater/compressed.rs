@@ -0,0 +1,173 @@
+use super::builder::partial_path;
+use crate::payload::xz::{self, XzReader};
+use crate::payload::zstd::{self, ZstdReader};
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+/// Granularity of the holes `decompress_super` leaves for zero data
+const HOLE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Compression of a partition image file, as firmware downloads ship them
+/// (`system.img.gz`, `vendor.img.xz`) and zstd builds write them (`super.img.zst`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Compression of a file by its magic bytes. The extension is not looked at, so
+/// a renamed or unsuffixed file is still recognised.
+pub fn detect_compression(path: &Path) -> io::Result<Compression> {
+    let mut magic = [0u8; 6];
+    let filled = read_head(&mut File::open(path)?, &mut magic)?;
+    Ok(if filled >= 2 && magic[..2] == GZIP_MAGIC {
+        Compression::Gzip
+    } else if filled == 6 && magic == XZ_MAGIC {
+        Compression::Xz
+    } else if filled >= 4 && magic[..4] == zstd::MAGIC {
+        Compression::Zstd
+    } else {
+        Compression::None
+    })
+}
+
+/// Reads up to `buf.len()` bytes, fewer only at the end of the data
+pub(super) fn read_head<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Reader of the decompressed contents of a gzip, xz or zstd file, or of the file
+/// itself when it is not compressed. Decoding is streamed, memory use does not
+/// grow with the image (xz keeps its dictionary, at most 64 MiB for `xz -9`, zstd
+/// its window, at most 128 MiB).
+pub fn decompress_reader(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(match detect_compression(path)? {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Xz => Box::new(XzReader::new(file)),
+        Compression::Zstd => Box::new(ZstdReader::new(file)),
+    })
+}
+
+/// Size of the contents `decompress_reader` yields.
+///
+/// xz files store it in their index, which is read from the end of the file, zstd
+/// files in their frame headers. gzip only stores it modulo 4 GiB, so the file is
+/// decompressed once to count it.
+pub fn decompressed_size(path: &Path) -> io::Result<u64> {
+    match detect_compression(path)? {
+        Compression::None => Ok(std::fs::metadata(path)?.len()),
+        Compression::Gzip => io::copy(&mut MultiGzDecoder::new(BufReader::new(File::open(path)?)), &mut io::sink()),
+        Compression::Xz => xz::uncompressed_size(&mut BufReader::new(File::open(path)?)),
+        Compression::Zstd => zstd::uncompressed_size(&mut BufReader::new(File::open(path)?)),
+    }
+}
+
+/// Decompresses an image, e.g. the `super.img.zst` of an `OutputFormat::Zstd`
+/// build, to `output` and returns its size. Any compression `detect_compression`
+/// recognises works.
+///
+/// Zero data is skipped instead of written, so the free space of a super image
+/// stays a hole on disk as with a raw build. As in a build, the image goes to
+/// `partial_path(output)` first and only a complete one is renamed to `output`.
+pub fn decompress_super(input: &Path, output: &Path) -> io::Result<u64> {
+    let partial = partial_path(output);
+    let result = (|| {
+        let mut reader = decompress_reader(input)?;
+        let mut file = File::create(&partial)?;
+        let mut buf = vec![0u8; HOLE_BLOCK_SIZE];
+        let mut size = 0u64;
+        loop {
+            let filled = read_head(&mut reader, &mut buf)?;
+            let data = &buf[..filled];
+            if data.iter().all(|&b| b == 0) {
+                file.seek(SeekFrom::Current(filled as i64))?;
+            } else {
+                file.write_all(data)?;
+            }
+            size += filled as u64;
+            if filled < buf.len() {
+                break;
+            }
+        }
+        file.set_len(size)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&partial, output)?;
+        Ok(size)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{fixture, lines};
+    use crate::super_image_creater::{BlockDevice, BuildError, ByteSize, Group, Partition, PartitionConfig, build_super_image};
+
+    #[test]
+    fn gz_and_xz_fixtures_decompress() {
+        let lines = lines();
+        for (name, compression) in [("lines.img.gz", Compression::Gzip), ("lines.img.xz", Compression::Xz)] {
+            let path = fixture(name);
+            assert_eq!(detect_compression(&path).unwrap(), compression);
+            assert_eq!(decompressed_size(&path).unwrap(), lines.len() as u64);
+            let mut data = Vec::new();
+            decompress_reader(&path).unwrap().read_to_end(&mut data).unwrap();
+            assert!(data == lines, "{}", name);
+        }
+    }
+
+    #[test]
+    fn declared_size_is_checked_against_the_decompressed_length() {
+        let lines = lines();
+        let mut config = PartitionConfig::new("super.img", 65536);
+        config
+            .add_block_device(BlockDevice { size: ByteSize::new(8 << 20), ..Default::default() })
+            .add_group(Group { name: "main".into(), ..Default::default() });
+        for (name, file) in [("system", "lines.img.gz"), ("vendor", "lines.img.xz")] {
+            config.add_partition(Partition {
+                name: name.into(),
+                group_name: "main".into(),
+                path: fixture(file).to_str().unwrap().into(),
+                // Larger than the compressed files, smaller than their contents
+                size: Some(ByteSize::new(64 << 10)),
+                ..Default::default()
+            });
+        }
+        let output = std::env::temp_dir().join(format!("compressed-test-{}.img", std::process::id()));
+        let e = build_super_image(&config, &output).unwrap_err();
+        assert!(matches!(e, BuildError::ImageTooLarge { image_size: 80000, size: 65536, .. }), "{}", e);
+
+        for partition in &mut config.partitions {
+            partition.size = Some(ByteSize::auto());
+        }
+        let report = build_super_image(&config, &output).unwrap();
+        let image = fs::read(&output).unwrap();
+        for partition in &report.partitions {
+            assert_eq!(partition.image_bytes, lines.len() as u64);
+            let offset = partition.offset as usize;
+            assert!(image[offset..offset + lines.len()] == lines[..], "{}", partition.name);
+        }
+        let _ = fs::remove_file(&output);
+    }
+}
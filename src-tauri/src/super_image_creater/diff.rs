@@ -0,0 +1,308 @@
+use super::{BlockDevice, ByteSize, Group, Partition, PartitionConfig};
+use crate::logger::Sensitive;
+use serde::Serialize;
+use std::fmt;
+
+/// One difference between two configs, old value first.
+///
+/// Serialized with a `kind` tag like `ValidationError`, e.g.
+/// `{"kind": "PartitionResized", "partition": "system_a", "old": "0x1000", "new": "8192"}`.
+/// Sizes keep their spelling from the configs, but only count as changed when the
+/// byte counts differ.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum ConfigChange {
+    NvText { old: String, new: String },
+    NvId { old: String, new: String },
+    /// `super_meta.size`, the room for one metadata copy
+    MetadataSize { old: ByteSize, new: ByteSize },
+    /// `is_virtual_ab`, so a missing `virtual_ab` and `true` are the same
+    VirtualAb { old: bool, new: bool },
+    Retrofit { old: bool, new: bool },
+    /// `metadata_slot_count`, so dropping `metadata_slots` that matched the
+    /// default is no change
+    MetadataSlots { old: u32, new: u32 },
+    BlockDeviceAdded { device: BlockDevice },
+    BlockDeviceRemoved { device: BlockDevice },
+    BlockDeviceResized { device: String, old: ByteSize, new: ByteSize },
+    GroupAdded { group: Group },
+    GroupRemoved { group: Group },
+    /// `None` is no limit
+    GroupLimitChanged { group: String, old: Option<ByteSize>, new: Option<ByteSize> },
+    PartitionAdded { partition: Partition },
+    PartitionRemoved { partition: Partition },
+    PartitionResized { partition: String, old: Option<ByteSize>, new: Option<ByteSize> },
+    PartitionRegrouped { partition: String, old: String, new: String },
+    /// The image file name of `path` changed, see `diff_configs`
+    PartitionImageChanged { partition: String, old: String, new: String },
+}
+
+fn describe_size(size: &Option<ByteSize>) -> String {
+    match size {
+        Some(size) => size.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::NvText { old, new } => write!(f, "nv_text changed from '{}' to '{}'", Sensitive(old), Sensitive(new)),
+            ConfigChange::NvId { old, new } => write!(f, "nv_id changed from '{old}' to '{new}'"),
+            ConfigChange::MetadataSize { old, new } => write!(f, "Metadata size changed from {old} to {new}"),
+            ConfigChange::VirtualAb { old, new } => write!(f, "Virtual A/B flag changed from {old} to {new}"),
+            ConfigChange::Retrofit { old, new } => write!(f, "retrofit changed from {old} to {new}"),
+            ConfigChange::MetadataSlots { old, new } => write!(f, "Metadata slot count changed from {old} to {new}"),
+            ConfigChange::BlockDeviceAdded { device } => write!(f, "Block device '{}' added ({} bytes)", device.name, device.size),
+            ConfigChange::BlockDeviceRemoved { device } => write!(f, "Block device '{}' removed", device.name),
+            ConfigChange::BlockDeviceResized { device, old, new } => {
+                write!(f, "Block device '{device}' resized from {old} to {new}")
+            }
+            ConfigChange::GroupAdded { group } => write!(f, "Group '{}' added", group.name),
+            ConfigChange::GroupRemoved { group } => write!(f, "Group '{}' removed", group.name),
+            ConfigChange::GroupLimitChanged { group, old, new } => {
+                write!(f, "Group '{group}' maximum_size changed from {} to {}", describe_size(old), describe_size(new))
+            }
+            ConfigChange::PartitionAdded { partition } => {
+                write!(f, "Partition '{}' added to group '{}'", partition.name, partition.group_name)
+            }
+            ConfigChange::PartitionRemoved { partition } => write!(f, "Partition '{}' removed", partition.name),
+            ConfigChange::PartitionResized { partition, old, new } => {
+                write!(f, "Partition '{partition}' resized from {} to {}", describe_size(old), describe_size(new))
+            }
+            ConfigChange::PartitionRegrouped { partition, old, new } => {
+                write!(f, "Partition '{partition}' moved from group '{old}' to '{new}'")
+            }
+            ConfigChange::PartitionImageChanged { partition, old, new } => {
+                write!(f, "Partition '{partition}' image changed from '{old}' to '{new}'")
+            }
+        }
+    }
+}
+
+/// Result of `diff_configs`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    /// Top-level fields first, then block devices, groups and partitions. Within
+    /// each, removals in the order of the old config, then additions and changes
+    /// in the order of the new one.
+    pub changes: Vec<ConfigChange>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Differences between two configs, e.g. the same device in two firmware versions.
+///
+/// Block devices, groups and partitions are matched by name, so reordering them
+/// is not a change. Of an image path only the file name is compared (a
+/// `payload.bin#system` entry as a whole), the directories differ between any two
+/// unpacked firmwares; hashes are left out.
+pub fn diff_configs(a: &PartitionConfig, b: &PartitionConfig) -> ConfigDiff {
+    let mut changes = Vec::new();
+    if a.nv_text != b.nv_text {
+        changes.push(ConfigChange::NvText { old: a.nv_text.clone(), new: b.nv_text.clone() });
+    }
+    if a.nv_id != b.nv_id {
+        changes.push(ConfigChange::NvId { old: a.nv_id.clone(), new: b.nv_id.clone() });
+    }
+    if a.super_meta.size != b.super_meta.size {
+        changes.push(ConfigChange::MetadataSize { old: a.super_meta.size.clone(), new: b.super_meta.size.clone() });
+    }
+    if a.is_virtual_ab() != b.is_virtual_ab() {
+        changes.push(ConfigChange::VirtualAb { old: a.is_virtual_ab(), new: b.is_virtual_ab() });
+    }
+    if a.retrofit != b.retrofit {
+        changes.push(ConfigChange::Retrofit { old: a.retrofit, new: b.retrofit });
+    }
+    if a.metadata_slot_count() != b.metadata_slot_count() {
+        changes.push(ConfigChange::MetadataSlots { old: a.metadata_slot_count(), new: b.metadata_slot_count() });
+    }
+
+    diff_by_name(
+        &a.block_devices,
+        &b.block_devices,
+        |d| &d.name,
+        |device| ConfigChange::BlockDeviceRemoved { device: device.clone() },
+        |device| ConfigChange::BlockDeviceAdded { device: device.clone() },
+        |old, new, changes| {
+            if old.size != new.size {
+                changes.push(ConfigChange::BlockDeviceResized {
+                    device: new.name.clone(),
+                    old: old.size.clone(),
+                    new: new.size.clone(),
+                });
+            }
+        },
+        &mut changes,
+    );
+    diff_by_name(
+        &a.groups,
+        &b.groups,
+        |g| &g.name,
+        |group| ConfigChange::GroupRemoved { group: group.clone() },
+        |group| ConfigChange::GroupAdded { group: group.clone() },
+        |old, new, changes| {
+            if old.maximum_size != new.maximum_size {
+                changes.push(ConfigChange::GroupLimitChanged {
+                    group: new.name.clone(),
+                    old: old.maximum_size.clone(),
+                    new: new.maximum_size.clone(),
+                });
+            }
+        },
+        &mut changes,
+    );
+    diff_by_name(
+        &a.partitions,
+        &b.partitions,
+        |p| &p.name,
+        |partition| ConfigChange::PartitionRemoved { partition: partition.clone() },
+        |partition| ConfigChange::PartitionAdded { partition: partition.clone() },
+        |old, new, changes| {
+            if old.group_name != new.group_name {
+                changes.push(ConfigChange::PartitionRegrouped {
+                    partition: new.name.clone(),
+                    old: old.group_name.clone(),
+                    new: new.group_name.clone(),
+                });
+            }
+            if old.size != new.size {
+                changes.push(ConfigChange::PartitionResized {
+                    partition: new.name.clone(),
+                    old: old.size.clone(),
+                    new: new.size.clone(),
+                });
+            }
+            if image_name(&old.path) != image_name(&new.path) {
+                changes.push(ConfigChange::PartitionImageChanged {
+                    partition: new.name.clone(),
+                    old: image_name(&old.path).to_string(),
+                    new: image_name(&new.path).to_string(),
+                });
+            }
+        },
+        &mut changes,
+    );
+    ConfigDiff { changes }
+}
+
+impl PartitionConfig {
+    /// Changes from `self` to `other`, see `diff_configs`
+    pub fn diff(&self, other: &PartitionConfig) -> ConfigDiff {
+        diff_configs(self, other)
+    }
+}
+
+/// Last component of an image path, with either separator; empty without an image
+fn image_name(path: &str) -> &str {
+    let path = path.trim();
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// Pairs up `old` and `new` by name. With duplicate names (which `validate`
+/// reports) only the first of each is compared.
+fn diff_by_name<T>(
+    old: &[T],
+    new: &[T],
+    name: impl Fn(&T) -> &String,
+    removed: impl Fn(&T) -> ConfigChange,
+    added: impl Fn(&T) -> ConfigChange,
+    compare: impl Fn(&T, &T, &mut Vec<ConfigChange>),
+    changes: &mut Vec<ConfigChange>,
+) {
+    let find = |items: &'_ [T], wanted: &String| items.iter().position(|item| name(item) == wanted);
+    for (i, item) in old.iter().enumerate() {
+        if find(old, name(item)) == Some(i) && find(new, name(item)).is_none() {
+            changes.push(removed(item));
+        }
+    }
+    for (i, item) in new.iter().enumerate() {
+        if find(new, name(item)) != Some(i) {
+            continue;
+        }
+        match find(old, name(item)) {
+            Some(j) => compare(&old[j], item, changes),
+            None => changes.push(added(item)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::parse_partition_config_str;
+    use crate::super_image_creater::test_support::SAMPLE;
+
+    #[test]
+    fn reordering_and_respelling_is_no_change() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        let mut other = config.clone();
+        other.partitions.reverse();
+        other.groups.reverse();
+        other.block_devices[0].size = "8704MiB".parse().unwrap();
+        other.partitions[2].path = "/home/user/firmware/IMAGES/system.img".into();
+        let diff = config.diff(&other);
+        assert!(diff.is_empty(), "{:?}", diff);
+        assert_eq!(serde_json::to_string(&diff).unwrap(), r#"{"changes":[]}"#);
+    }
+
+    #[test]
+    fn added_partition_removed_group_and_resized_super() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        let mut other = config.clone();
+        let removed = other.groups.remove(1);
+        other.partitions.retain(|p| p.group_name != removed.name);
+        let vendor = Partition {
+            name: "vendor_a".into(),
+            group_name: "qti_dynamic_partitions_a".into(),
+            path: "IMAGES/vendor.img".into(),
+            is_dynamic: true,
+            ..Default::default()
+        };
+        other.add_partition(vendor.clone());
+        other.super_meta.size = ByteSize::new(131072);
+        other.block_devices[0].size = "0x300000000".parse().unwrap();
+
+        // Still two metadata slots without the _b group
+        assert_eq!(other.metadata_slot_count(), 2);
+        let diff = config.diff(&other);
+        assert_eq!(
+            diff.changes,
+            [
+                ConfigChange::MetadataSize { old: ByteSize::new(65536), new: ByteSize::new(131072) },
+                ConfigChange::BlockDeviceResized {
+                    device: "super".into(),
+                    old: "0x220000000".parse().unwrap(),
+                    new: "0x300000000".parse().unwrap(),
+                },
+                ConfigChange::GroupRemoved { group: removed },
+                ConfigChange::PartitionRemoved { partition: config.partitions[1].clone() },
+                ConfigChange::PartitionRemoved { partition: config.partitions[2].clone() },
+                ConfigChange::PartitionAdded { partition: vendor },
+            ]
+        );
+        let messages: Vec<String> = diff.changes.iter().map(ToString::to_string).collect();
+        assert_eq!(messages[1], "Block device 'super' resized from 0x220000000 to 0x300000000");
+        assert_eq!(messages[2], "Group 'qti_dynamic_partitions_b' removed");
+        assert_eq!(messages[5], "Partition 'vendor_a' added to group 'qti_dynamic_partitions_a'");
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["changes"][1], serde_json::json!({"kind": "BlockDeviceResized", "device": "super", "old": "0x220000000", "new": "0x300000000"}));
+
+        // The other way round everything is undone
+        let back = other.diff(&config);
+        assert_eq!(back.changes.len(), 6);
+        assert!(matches!(&back.changes[2], ConfigChange::GroupAdded { group } if group.name == "qti_dynamic_partitions_b"));
+        assert!(matches!(&back.changes[3], ConfigChange::PartitionRemoved { partition } if partition.name == "vendor_a"));
+
+        // The NV text is only logged in full at debug, the JSON keeps it
+        let mut renamed = config.clone();
+        renamed.nv_text = "India".into();
+        let diff = config.diff(&renamed);
+        assert_eq!(diff.changes[0].to_string(), "nv_text changed from '<redacted, 6 bytes>' to '<redacted, 5 bytes>'");
+        assert_eq!(serde_json::to_value(&diff).unwrap()["changes"][0]["new"], "India");
+    }
+}
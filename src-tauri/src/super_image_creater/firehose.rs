@@ -0,0 +1,422 @@
+use quick_xml::de::from_str;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum XmlParseError {
+    #[error("File operation error: {0}")]
+    FileError(#[from] std::io::Error),
+    /// `path` is set when the XML was read from a file
+    #[error("XML parsing error{}: {source}", describe_path(path))]
+    XmlError {
+        source: quick_xml::DeError,
+        path: Option<PathBuf>,
+    },
+}
+
+impl From<quick_xml::DeError> for XmlParseError {
+    fn from(source: quick_xml::DeError) -> Self {
+        XmlParseError::XmlError { source, path: None }
+    }
+}
+
+fn describe_path(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!(" in '{}'", path.display()),
+        None => String::new(),
+    }
+}
+
+/// One `<program>` entry of a rawprogram XML file
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ProgramEntry {
+    /// Image to write, relative to the XML file. Empty for partitions that are only
+    /// described (or zeroed), not flashed.
+    #[serde(rename = "@filename", default)]
+    pub filename: String,
+    /// First sector, kept as written. Usually a number, but GPT backup entries use
+    /// expressions like `NUM_DISK_SECTORS-5.`, see `start_sector_number`.
+    #[serde(rename = "@start_sector")]
+    pub start_sector: String,
+    #[serde(rename = "@num_partition_sectors")]
+    pub num_partition_sectors: u64,
+    #[serde(rename = "@SECTOR_SIZE_IN_BYTES")]
+    pub sector_size: u64,
+    /// LUN of the partition
+    #[serde(rename = "@physical_partition_number")]
+    pub physical_partition_number: u8,
+    #[serde(rename = "@label", default)]
+    pub label: String,
+    /// Sectors to skip in `filename` before the data of this entry starts
+    #[serde(rename = "@file_sector_offset", default)]
+    pub file_sector_offset: u64,
+    #[serde(rename = "@sparse", default)]
+    pub sparse: bool,
+}
+
+impl ProgramEntry {
+    /// Entries without a filename have nothing to flash and are skipped
+    pub fn has_image(&self) -> bool {
+        !self.filename.trim().is_empty()
+    }
+
+    /// `start_sector` as a number, `None` if it is an expression relative to the end
+    /// of the disk. A trailing `.` (as in `"0."`) is accepted.
+    pub fn start_sector_number(&self) -> Option<u64> {
+        self.start_sector.trim().trim_end_matches('.').parse().ok()
+    }
+
+    /// Partition size in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.num_partition_sectors * self.sector_size
+    }
+}
+
+/// Any child of `<data>`: rawprogram files may mix in `<read>`, `<patch>` or other
+/// tags, which are not needed here
+#[derive(Debug, Deserialize)]
+enum DataEntry {
+    #[serde(rename = "program")]
+    Program(ProgramEntry),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawProgramXml {
+    #[serde(rename = "$value", default)]
+    entries: Vec<DataEntry>,
+}
+
+/// Contents of a Qualcomm `rawprogram*.xml` file, the Firehose counterpart of the
+/// JSON partition config
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RawProgram {
+    /// `<program>` entries in file order, including those without a filename
+    pub programs: Vec<ProgramEntry>,
+}
+
+impl RawProgram {
+    /// Entries that have an image to flash
+    pub fn images(&self) -> impl Iterator<Item = &ProgramEntry> {
+        self.programs.iter().filter(|p| p.has_image())
+    }
+
+    /// First entry with the given label
+    pub fn find(&self, label: &str) -> Option<&ProgramEntry> {
+        self.programs.iter().find(|p| p.label == label)
+    }
+}
+
+/// Reads a rawprogram XML file
+pub fn parse_rawprogram<P: AsRef<Path>>(path: P) -> Result<RawProgram, XmlParseError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+    parse_rawprogram_str(&content).map_err(|e| match e {
+        XmlParseError::XmlError { source, .. } => XmlParseError::XmlError { source, path: Some(path.to_path_buf()) },
+        other => other,
+    })
+}
+
+/// Parses rawprogram XML from a string, entries other than `<program>` are ignored
+pub fn parse_rawprogram_str(content: &str) -> Result<RawProgram, XmlParseError> {
+    let xml: RawProgramXml = from_str(content)?;
+    let programs = xml
+        .entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            DataEntry::Program(program) => Some(program),
+            DataEntry::Other => None,
+        })
+        .collect();
+    Ok(RawProgram { programs })
+}
+
+/// `filename` of patches applied to the device's storage, anything else names a
+/// file patched on the host before it is flashed
+const DISK: &str = "DISK";
+
+/// One `<patch>` entry of a Qualcomm `patch*.xml` file
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PatchEntry {
+    #[serde(rename = "@filename")]
+    pub filename: String,
+    #[serde(rename = "@SECTOR_SIZE_IN_BYTES")]
+    pub sector_size: u64,
+    /// Offset of the patched bytes within `start_sector`
+    #[serde(rename = "@byte_offset")]
+    pub byte_offset: u64,
+    #[serde(rename = "@physical_partition_number")]
+    pub physical_partition_number: u8,
+    #[serde(rename = "@size_in_bytes")]
+    pub size_in_bytes: u64,
+    /// Patched sector, kept as written, see `evaluate_patch_expr`
+    #[serde(rename = "@start_sector")]
+    pub start_sector: String,
+    /// Value written, kept as written, e.g. `NUM_DISK_SECTORS-5.` or `CRC32(2,4096)`
+    #[serde(rename = "@value")]
+    pub value: String,
+    /// Description of the patch, as ptool writes it
+    #[serde(rename = "@what", default)]
+    pub what: String,
+}
+
+impl PatchEntry {
+    /// Whether the patch targets the device instead of a host file
+    pub fn is_disk(&self) -> bool {
+        self.filename.trim() == DISK
+    }
+}
+
+#[derive(Debug, Deserialize)]
+enum PatchesEntry {
+    #[serde(rename = "patch")]
+    Patch(PatchEntry),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchXml {
+    #[serde(rename = "$value", default)]
+    entries: Vec<PatchesEntry>,
+}
+
+/// Reads a patch XML file, entries in file order
+pub fn parse_patch_xml<P: AsRef<Path>>(path: P) -> Result<Vec<PatchEntry>, XmlParseError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+    parse_patch_str(&content).map_err(|e| match e {
+        XmlParseError::XmlError { source, .. } => XmlParseError::XmlError { source, path: Some(path.to_path_buf()) },
+        other => other,
+    })
+}
+
+/// Parses patch XML from a string, entries other than `<patch>` are ignored
+pub fn parse_patch_str(content: &str) -> Result<Vec<PatchEntry>, XmlParseError> {
+    let xml: PatchXml = from_str(content)?;
+    Ok(xml
+        .entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            PatchesEntry::Patch(patch) => Some(patch),
+            PatchesEntry::Other => None,
+        })
+        .collect())
+}
+
+/// Size of the storage in sectors, only known to the device
+const NUM_DISK_SECTORS: &str = "NUM_DISK_SECTORS";
+/// `CRC32(sector,len)`: checksum of `len` bytes from `sector`, computed by the device
+const CRC32: &str = "CRC32";
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Invalid patch expression '{expr}': {reason}")]
+pub struct PatchExprError {
+    pub expr: String,
+    pub reason: &'static str,
+}
+
+/// A `start_sector` or `value` of a patch, once checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchValue {
+    /// Plain arithmetic, already evaluated
+    Number(u64),
+    /// Depends on `NUM_DISK_SECTORS` or `CRC32(..)`, left to the device as written
+    Device(String),
+}
+
+impl PatchValue {
+    /// The attribute value to send to the Firehose programmer
+    pub fn to_attribute(&self) -> String {
+        match self {
+            PatchValue::Number(n) => n.to_string(),
+            PatchValue::Device(expr) => expr.clone(),
+        }
+    }
+}
+
+/// Checks a patch expression: decimal numbers (a trailing `.` is allowed) or `0x`
+/// hex, `+ - * /`, parentheses, `NUM_DISK_SECTORS` and `CRC32(a,b)`. Expressions of
+/// numbers only are evaluated here, so that only the terms fh_loader documents for
+/// the device reach it.
+pub fn evaluate_patch_expr(expr: &str) -> Result<PatchValue, PatchExprError> {
+    let mut parser = ExprParser { expr, pos: 0 };
+    let value = parser.sum().map_err(|reason| PatchExprError { expr: expr.to_string(), reason })?;
+    parser.skip_spaces();
+    if parser.pos < expr.len() {
+        return Err(PatchExprError { expr: expr.to_string(), reason: "unexpected character" });
+    }
+    Ok(match value {
+        Some(n) => PatchValue::Number(n),
+        None => PatchValue::Device(expr.trim().to_string()),
+    })
+}
+
+/// Recursive descent over a patch expression. `None` stands for a value only the
+/// device knows; arithmetic on it stays unevaluated.
+struct ExprParser<'a> {
+    expr: &'a str,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.expr[self.pos..].starts_with(' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.expr[self.pos..].starts_with(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<Option<u64>, &'static str> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                let rhs = self.product()?;
+                value = combine(value, rhs, u64::checked_add, "overflow")?;
+            } else if self.eat('-') {
+                let rhs = self.product()?;
+                value = combine(value, rhs, u64::checked_sub, "negative result")?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Option<u64>, &'static str> {
+        let mut value = self.atom()?;
+        loop {
+            if self.eat('*') {
+                let rhs = self.atom()?;
+                value = combine(value, rhs, u64::checked_mul, "overflow")?;
+            } else if self.eat('/') {
+                let rhs = self.atom()?;
+                value = combine(value, rhs, u64::checked_div, "division by zero")?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Option<u64>, &'static str> {
+        self.skip_spaces();
+        let rest = &self.expr[self.pos..];
+        if self.eat('(') {
+            let value = self.sum()?;
+            return if self.eat(')') { Ok(value) } else { Err("missing ')'") };
+        }
+        if rest.starts_with(NUM_DISK_SECTORS) {
+            self.pos += NUM_DISK_SECTORS.len();
+            return Ok(None);
+        }
+        if rest.starts_with(CRC32) {
+            self.pos += CRC32.len();
+            if !self.eat('(') {
+                return Err("CRC32 needs (sector,length)");
+            }
+            self.sum()?;
+            if !self.eat(',') {
+                return Err("CRC32 needs (sector,length)");
+            }
+            self.sum()?;
+            return if self.eat(')') { Ok(None) } else { Err("missing ')'") };
+        }
+        self.number().map(Some)
+    }
+
+    fn number(&mut self) -> Result<u64, &'static str> {
+        let rest = &self.expr[self.pos..];
+        let (digits, radix, prefix) = match rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+            Some(hex) => (hex, 16, 2),
+            None => (rest, 10, 0),
+        };
+        let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+        if len == 0 {
+            return Err("expected a number");
+        }
+        let value = u64::from_str_radix(&digits[..len], radix).map_err(|_| "number too large")?;
+        self.pos += prefix + len;
+        // ptool marks decimal numbers with a trailing '.'
+        if radix == 10 && self.expr[self.pos..].starts_with('.') {
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+fn combine(
+    lhs: Option<u64>,
+    rhs: Option<u64>,
+    op: fn(u64, u64) -> Option<u64>,
+    error: &'static str,
+) -> Result<Option<u64>, &'static str> {
+    match (lhs, rhs) {
+        (Some(a), Some(b)) => op(a, b).map(Some).ok_or(error),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `src/testdata/patch0.xml`, the patch0.xml of a UFS LUN 0 as ptool writes it
+    fn patch0_xml() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata/patch0.xml")
+    }
+
+    #[test]
+    fn parses_a_patch0_xml() {
+        let patches = parse_patch_xml(patch0_xml()).unwrap();
+        assert_eq!(patches.len(), 26);
+        assert_eq!(patches.iter().filter(|p| p.is_disk()).count(), 13);
+        assert_eq!(
+            patches[4],
+            PatchEntry {
+                filename: "gpt_main0.bin".into(),
+                sector_size: 4096,
+                byte_offset: 48,
+                physical_partition_number: 0,
+                size_in_bytes: 8,
+                start_sector: "1".into(),
+                value: "NUM_DISK_SECTORS-6.".into(),
+                what: "Update Primary Header with LastUseableLBA.".into(),
+            }
+        );
+        for patch in &patches {
+            assert!(evaluate_patch_expr(&patch.start_sector).is_ok(), "{}", patch.start_sector);
+            assert!(evaluate_patch_expr(&patch.value).is_ok(), "{}", patch.value);
+        }
+        // The comment and XML declaration are skipped, other tags too
+        assert_eq!(parse_patch_str("<patches><!-- x --><read/></patches>").unwrap(), []);
+    }
+
+    #[test]
+    fn patch_expressions_are_evaluated_or_left_to_the_device() {
+        let number = |expr: &str| evaluate_patch_expr(expr).unwrap();
+        assert_eq!(number("(2+3)*2"), PatchValue::Number(10));
+        assert_eq!(number(" 0x10 + 34. "), PatchValue::Number(50));
+        assert_eq!(number("100-2*3-4/2"), PatchValue::Number(92));
+        assert_eq!(number("NUM_DISK_SECTORS-5."), PatchValue::Device("NUM_DISK_SECTORS-5.".into()));
+        assert_eq!(number(" CRC32(NUM_DISK_SECTORS-5.,16384)"), PatchValue::Device("CRC32(NUM_DISK_SECTORS-5.,16384)".into()));
+        assert_eq!(PatchValue::Number(10).to_attribute(), "10");
+
+        for (expr, reason) in [("1-2", "negative result"), ("4/0", "division by zero"), ("(1+2", "missing ')'"), ("CRC32(1)", "CRC32 needs (sector,length)"), ("1 2", "unexpected character")] {
+            assert_eq!(evaluate_patch_expr(expr).unwrap_err().reason, reason, "{}", expr);
+        }
+        for expr in ["", "FOO", "99999999999999999999"] {
+            assert!(evaluate_patch_expr(expr).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::sparse::open_raw_or_sparse;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Bytes read from the start of an image, enough for every superblock below
+const SNIFF_BYTES: u64 = 4096;
+/// ext2/3/4, EROFS and f2fs keep their superblock 1 KiB into the image
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT4_MAGIC: u16 = 0xef53;
+/// Offset of `s_magic` in the ext superblock
+const EXT4_MAGIC_OFFSET: usize = 0x38;
+const EROFS_MAGIC: u32 = 0xe0f5_e1e2;
+const F2FS_MAGIC: u32 = 0xf2f5_2010;
+/// `hsqs`, at the very start of the image
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+
+/// Filesystem of a partition image, by superblock magic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsType {
+    /// Any ext2/3/4, they share the magic
+    Ext4,
+    Erofs,
+    F2fs,
+    Squashfs,
+    /// Raw data or a filesystem not listed here
+    Unknown,
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsType::Ext4 => "ext4",
+            FsType::Erofs => "EROFS",
+            FsType::F2fs => "f2fs",
+            FsType::Squashfs => "squashfs",
+            FsType::Unknown => "raw/unknown",
+        })
+    }
+}
+
+/// Identifies the filesystem from the first 4 KiB `reader` yields
+pub fn detect_fs_type<R: Read>(reader: R) -> io::Result<FsType> {
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    reader.take(SNIFF_BYTES).read_to_end(&mut head)?;
+    let u32_at = |offset: usize| head.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let u16_at = |offset: usize| head.get(offset..offset + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()));
+
+    Ok(if u32_at(SUPERBLOCK_OFFSET) == Some(EROFS_MAGIC) {
+        FsType::Erofs
+    } else if u32_at(SUPERBLOCK_OFFSET) == Some(F2FS_MAGIC) {
+        FsType::F2fs
+    } else if u16_at(SUPERBLOCK_OFFSET + EXT4_MAGIC_OFFSET) == Some(EXT4_MAGIC) {
+        FsType::Ext4
+    } else if u32_at(0) == Some(SQUASHFS_MAGIC) {
+        FsType::Squashfs
+    } else {
+        FsType::Unknown
+    })
+}
+
+/// Filesystem of an image file, raw or sparse (sniffed after expanding it)
+pub fn detect_image_fs(path: &Path) -> io::Result<FsType> {
+    let (reader, _) = open_raw_or_sparse(path)?;
+    detect_fs_type(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{fixture, fs_head};
+
+    /// 4 KiB of zeros with `magic` at `offset`
+    fn with_magic(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut head = vec![0u8; SNIFF_BYTES as usize];
+        head[offset..offset + magic.len()].copy_from_slice(magic);
+        head
+    }
+
+    #[test]
+    fn filesystems_are_told_apart_by_their_superblock_magic() {
+        // The first 4 KiB of a 1 MiB mkfs.ext4 image
+        assert_eq!(detect_image_fs(&fixture("ext4_head.img")).unwrap(), FsType::Ext4);
+        for fs_type in [FsType::Ext4, FsType::Erofs, FsType::F2fs, FsType::Squashfs] {
+            assert_eq!(detect_fs_type(&fs_head(fs_type)[..]).unwrap(), fs_type);
+        }
+
+        // The magic at the wrong offset, a short image or plain data is unknown
+        assert_eq!(detect_fs_type(&with_magic(0, &EROFS_MAGIC.to_le_bytes())[..]).unwrap(), FsType::Unknown);
+        assert_eq!(detect_fs_type(&with_magic(EXT4_MAGIC_OFFSET, &EXT4_MAGIC.to_le_bytes())[..]).unwrap(), FsType::Unknown);
+        assert_eq!(detect_fs_type(&fs_head(FsType::Erofs)[..SUPERBLOCK_OFFSET + 2]).unwrap(), FsType::Unknown);
+        assert_eq!(detect_fs_type(&[][..]).unwrap(), FsType::Unknown);
+        assert_eq!(detect_image_fs(&fixture("lines.simg")).unwrap(), FsType::Unknown);
+    }
+}
@@ -0,0 +1,181 @@
+use super::PartitionConfig;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Read buffer of `sha256_file`
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Lowercase hex SHA-256 of a file, read in 1 MiB chunks. Sparse images are hashed
+/// as they are on disk, like the hashes firmware packages ship.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String, io::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A partition image that does not have its `expected_sha256`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HashMismatch {
+    pub partition: String,
+    pub path: String,
+    pub expected: String,
+    /// Hash of the image, `None` when it could not be read (see `error`)
+    pub actual: Option<String>,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.actual, &self.error) {
+            (Some(actual), _) => write!(
+                f,
+                "Image '{}' of partition '{}' has SHA-256 {}, expected {}",
+                self.path, self.partition, actual, self.expected
+            ),
+            (None, error) => write!(
+                f,
+                "Cannot hash image '{}' of partition '{}': {}",
+                self.path,
+                self.partition,
+                error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+}
+
+/// Images `verify_hashes_parallel` reads at once when the caller has no preference.
+/// More only makes a hard disk seek between files, NVMe drives keep up with 8.
+pub const DEFAULT_HASH_PARALLELISM: usize = 2;
+
+/// A partition image to check against its `expected_sha256`
+struct HashJob<'a> {
+    partition: &'a str,
+    path: &'a str,
+    expected: &'a str,
+}
+
+impl HashJob<'_> {
+    fn check(&self) -> Option<HashMismatch> {
+        let mismatch = |actual, error| HashMismatch {
+            partition: self.partition.to_string(),
+            path: self.path.to_string(),
+            expected: self.expected.to_string(),
+            actual,
+            error,
+        };
+        match sha256_file(self.path) {
+            Ok(actual) if actual.eq_ignore_ascii_case(self.expected) => None,
+            Ok(actual) => Some(mismatch(Some(actual), None)),
+            Err(e) => Some(mismatch(None, Some(e.to_string()))),
+        }
+    }
+}
+
+impl PartitionConfig {
+    /// Hashes the image of every partition that has both a `path` and an
+    /// `expected_sha256`, and returns all that differ or cannot be read. Hashes are
+    /// compared ignoring case.
+    pub fn verify_hashes(&self) -> Result<(), Vec<HashMismatch>> {
+        let mismatches: Vec<HashMismatch> = self.hash_jobs().iter().filter_map(HashJob::check).collect();
+        if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+    }
+
+    /// Same as `verify_hashes`, hashing up to `parallelism` images at once (at least
+    /// one, see `DEFAULT_HASH_PARALLELISM`).
+    ///
+    /// Each image is read start to end by a single thread, only different images are
+    /// read concurrently. An image that cannot be read does not stop the others, and
+    /// the mismatches come back in partition order, exactly as `verify_hashes`
+    /// returns them.
+    pub fn verify_hashes_parallel(&self, parallelism: usize) -> Result<(), Vec<HashMismatch>> {
+        let jobs = self.hash_jobs();
+        let next = AtomicUsize::new(0);
+        let mut found: Vec<(usize, HashMismatch)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallelism.clamp(1, jobs.len().max(1)))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut found = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(job) = jobs.get(i) else { break };
+                            if let Some(mismatch) = job.check() {
+                                found.push((i, mismatch));
+                            }
+                        }
+                        found
+                    })
+                })
+                .collect();
+            // A panicking worker only loses its own share, like a failed read
+            workers.into_iter().flat_map(|worker| worker.join().unwrap_or_default()).collect()
+        });
+        found.sort_by_key(|(i, _)| *i);
+        let mismatches: Vec<HashMismatch> = found.into_iter().map(|(_, mismatch)| mismatch).collect();
+        if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+    }
+
+    /// The images `verify_hashes` checks, in partition order
+    fn hash_jobs(&self) -> Vec<HashJob<'_>> {
+        self.partitions
+            .iter()
+            .map(|p| HashJob {
+                partition: &p.name,
+                path: p.path.trim(),
+                expected: p.expected_sha256.as_deref().unwrap_or("").trim(),
+            })
+            .filter(|job| !job.path.is_empty() && !job.expected.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+
+    #[test]
+    fn parallel_hashing_finds_the_same_mismatches_as_serial() {
+        let dir = test_dir("hash");
+        let images: Vec<Vec<u8>> = (0..12).map(|i| pattern(HASH_BUFFER_SIZE + i * 4096 + 7, i as u8)).collect();
+        let names: Vec<String> = (0..12).map(|i| format!("p{}", i)).collect();
+        let entries: Vec<(&str, &[u8], u64)> = names.iter().zip(&images).map(|(n, d)| (n.as_str(), d.as_slice(), 4 << 20)).collect();
+        let mut config = config_with_images(&dir, &entries);
+        for (i, partition) in config.partitions.iter_mut().enumerate() {
+            // Right (in upper case), wrong, or not checked
+            partition.expected_sha256 = Some(match i % 3 {
+                0 => sha256_file(&partition.path).unwrap().to_uppercase(),
+                1 => "00".into(),
+                _ => String::new(),
+            });
+        }
+        config.partitions[7].path = dir.join("missing.img").to_str().unwrap().into();
+        config.partitions[7].expected_sha256 = Some("11".into());
+
+        let serial = config.verify_hashes().unwrap_err();
+        let failed: Vec<(&str, bool)> = serial.iter().map(|m| (m.partition.as_str(), m.error.is_some())).collect();
+        assert_eq!(failed, [("p1", false), ("p4", false), ("p7", true), ("p10", false)]);
+        assert_eq!(serial[0].actual, Some(format!("{:x}", Sha256::digest(&images[1]))));
+        for parallelism in [0, 1, 2, DEFAULT_HASH_PARALLELISM, 8, 100] {
+            assert_eq!(config.verify_hashes_parallel(parallelism).unwrap_err(), serial, "{}", parallelism);
+        }
+
+        config.partitions.retain(|p| p.expected_sha256.as_deref().is_some_and(|h| h.len() == 64));
+        assert_eq!(config.verify_hashes(), Ok(()));
+        assert_eq!(config.verify_hashes_parallel(8), Ok(()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
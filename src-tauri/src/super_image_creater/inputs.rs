@@ -0,0 +1,399 @@
+use super::fs_type::{FsType, detect_fs_type};
+use super::{ByteSize, PartitionConfig};
+use super::lp_metadata::{LP_SECTOR_SIZE, align_up};
+use super::sparse::open_raw_or_sparse;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+/// How serious an `InputIssue` or validation entry is: errors make the build fail,
+/// warnings do not
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueSeverity {
+    Error,
+    Warning,
+}
+
+/// What is wrong with a partition image, serialized with a `kind` tag like `ValidationError`
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum InputProblem {
+    #[error("Image '{path}' does not exist")]
+    Missing { path: String },
+    #[error("Image '{path}' cannot be read: {message}")]
+    Unreadable { path: String, message: String },
+    #[error("Image '{path}' is {image_size} bytes, larger than the declared size of {size} bytes")]
+    TooLarge { path: String, image_size: u64, size: u64 },
+    #[error("Image '{path}' is {image_size} bytes, smaller than the declared size of {size} bytes, the rest is zero-filled")]
+    Smaller { path: String, image_size: u64, size: u64 },
+    #[error("Dynamic partition has a size of {size} bytes but no image path")]
+    NoImagePath { size: u64 },
+    #[error("Dynamic partition has an \"auto\" size but no image path to measure")]
+    NoImageForAutoSize,
+    #[error("Image '{path}' is ignored because the partition has no size (use \"auto\" to measure it)")]
+    NoDeclaredSize { path: String },
+    #[error("Image '{path}' is {detected}, but the partition expects {expected}")]
+    FsMismatch { path: String, detected: FsType, expected: FsType },
+    #[error("Image '{path}' is {detected}, while the other images are {common}")]
+    FsUnlikeOthers { path: String, detected: FsType, common: FsType },
+}
+
+/// A problem with one partition's image file, found by `check_inputs`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputIssue {
+    pub partition: String,
+    pub severity: IssueSeverity,
+    #[serde(flatten)]
+    pub problem: InputProblem,
+    /// Human readable description of `problem`
+    pub message: String,
+}
+
+impl InputIssue {
+    fn new(partition: &str, severity: IssueSeverity, problem: InputProblem) -> Self {
+        InputIssue { partition: partition.to_string(), severity, message: problem.to_string(), problem }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == IssueSeverity::Error
+    }
+}
+
+/// A readable partition image, as found by `inspect_inputs`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputImage {
+    pub partition: String,
+    pub path: String,
+    /// Raw size (the expanded size for sparse images)
+    pub image_size: u64,
+    /// `None` if the start of the image could not be read
+    pub fs_type: Option<FsType>,
+}
+
+/// Result of `inspect_inputs`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InputReport {
+    /// Every readable image, in partition order
+    pub images: Vec<InputImage>,
+    pub issues: Vec<InputIssue>,
+}
+
+/// Checks the image files of the dynamic partitions before a build, without writing
+/// anything; the issues of `inspect_inputs`.
+pub fn check_inputs(config: &PartitionConfig) -> Vec<InputIssue> {
+    inspect_inputs(config).issues
+}
+
+/// Checks the image files of the dynamic partitions before a build and detects
+/// their filesystems, without writing anything.
+///
+/// Every file must exist, be readable and fit into its partition. Sparse images are
+/// measured by their expanded size, and sizes are compared the way the builder does,
+/// after rounding the declared size up to the block size. Images smaller than their
+/// partition and images of partitions without a size are only warnings, the build
+/// zero-fills the first and skips the second. `"auto"` sizes only need a readable
+/// image.
+///
+/// The filesystem of each image is sniffed from its superblock (see
+/// `detect_fs_type`). A partition with an `expected_fs` gets an error if its image
+/// differs; one without gets a warning if its image has a filesystem other than the
+/// one most images share, e.g. an ext4 `my_product` among EROFS images.
+///
+/// All issues are collected in partition order; no errors means the build will not
+/// fail because of its inputs.
+pub fn inspect_inputs(config: &PartitionConfig) -> InputReport {
+    let block_size = config.block_devices.first().map(|d| d.block_size_bytes()).unwrap_or(LP_SECTOR_SIZE);
+    let mut report = InputReport::default();
+    let issues = &mut report.issues;
+
+    for partition in config.partitions.iter().filter(|p| p.is_dynamic) {
+        let name = partition.name.as_str();
+        let path = partition.path.trim();
+        let size = partition.size_bytes().unwrap_or(0);
+
+        let auto = partition.has_auto_size();
+
+        if path.is_empty() {
+            if auto {
+                issues.push(InputIssue::new(name, IssueSeverity::Error, InputProblem::NoImageForAutoSize));
+            } else if size > 0 {
+                issues.push(InputIssue::new(name, IssueSeverity::Error, InputProblem::NoImagePath { size }));
+            }
+            continue;
+        }
+
+        let (reader, image_size) = match open_raw_or_sparse(Path::new(path)) {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                issues.push(InputIssue::new(name, IssueSeverity::Error, InputProblem::Missing { path: path.to_string() }));
+                continue;
+            }
+            Err(e) => {
+                let problem = InputProblem::Unreadable { path: path.to_string(), message: e.to_string() };
+                issues.push(InputIssue::new(name, IssueSeverity::Error, problem));
+                continue;
+            }
+        };
+        let fs_type = detect_fs_type(reader).ok();
+        report.images.push(InputImage { partition: name.to_string(), path: path.to_string(), image_size, fs_type });
+
+        let path = path.to_string();
+        if let (Some(expected), Some(detected)) = (partition.expected_fs, fs_type)
+            && detected != expected
+        {
+            let problem = InputProblem::FsMismatch { path: path.clone(), detected, expected };
+            issues.push(InputIssue::new(name, IssueSeverity::Error, problem));
+        }
+        if auto {
+            // The size is taken from the image, so it always fits
+        } else if size == 0 {
+            issues.push(InputIssue::new(name, IssueSeverity::Warning, InputProblem::NoDeclaredSize { path }));
+        } else if image_size > align_up(size, block_size) {
+            issues.push(InputIssue::new(name, IssueSeverity::Error, InputProblem::TooLarge { path, image_size, size }));
+        } else if image_size < size {
+            issues.push(InputIssue::new(name, IssueSeverity::Warning, InputProblem::Smaller { path, image_size, size }));
+        }
+    }
+
+    if let Some(common) = common_fs_type(&report.images) {
+        for image in &report.images {
+            let Some(detected) = image.fs_type.filter(|&t| t != common && t != FsType::Unknown) else { continue };
+            let hinted = config.partitions.iter().any(|p| p.name == image.partition && p.expected_fs.is_some());
+            if !hinted {
+                let problem = InputProblem::FsUnlikeOthers { path: image.path.clone(), detected, common };
+                report.issues.push(InputIssue::new(&image.partition, IssueSeverity::Warning, problem));
+            }
+        }
+        // Keep the issues in partition order
+        let order = |partition: &str| config.partitions.iter().position(|p| p.name == partition);
+        report.issues.sort_by_key(|issue| order(&issue.partition));
+    }
+    report
+}
+
+/// The filesystem most images have, if more than half of those with a filesystem
+/// have it
+fn common_fs_type(images: &[InputImage]) -> Option<FsType> {
+    let mut counts: HashMap<FsType, usize> = HashMap::new();
+    for fs_type in images.iter().filter_map(|i| i.fs_type).filter(|&t| t != FsType::Unknown) {
+        *counts.entry(fs_type).or_default() += 1;
+    }
+    let total: usize = counts.values().sum();
+    counts.into_iter().find(|&(_, count)| count * 2 > total).map(|(fs_type, _)| fs_type)
+}
+
+/// A partition `"auto"` size measured from its image file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolvedSize {
+    pub partition: String,
+    pub path: String,
+    /// Raw size of the image (the expanded size for sparse images)
+    pub image_size: u64,
+    /// Allocated size: `image_size` rounded up to the block size
+    pub size: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum AutoSizeError {
+    #[error("Partition '{0}' has an \"auto\" size but no image path")]
+    MissingPath(String),
+    #[error("Cannot measure image '{path}' of partition '{partition}': {source}")]
+    Image { partition: String, path: String, source: io::Error },
+}
+
+/// Replaces every `"auto"` partition size with the size of the partition's image,
+/// rounded up to the block size of the first block device, and returns what was
+/// resolved in partition order.
+///
+/// Explicit sizes are left alone, so both kinds can be mixed. The resolved sizes are
+/// spelled as plain bytes, so a config written back afterwards no longer says
+/// `"auto"`.
+pub fn resolve_auto_sizes(config: &mut PartitionConfig) -> Result<Vec<ResolvedSize>, AutoSizeError> {
+    let block_size = config.block_devices.first().map(|d| d.block_size_bytes()).unwrap_or(LP_SECTOR_SIZE);
+    let mut resolved = Vec::new();
+    for partition in config.partitions.iter_mut().filter(|p| p.has_auto_size()) {
+        let path = partition.path.trim().to_string();
+        if path.is_empty() {
+            return Err(AutoSizeError::MissingPath(partition.name.clone()));
+        }
+        let image_size = match open_raw_or_sparse(Path::new(&path)) {
+            Ok((_, image_size)) => image_size,
+            Err(source) => return Err(AutoSizeError::Image { partition: partition.name.clone(), path, source }),
+        };
+        let size = align_up(image_size, block_size);
+        partition.size = Some(ByteSize::new(size));
+        resolved.push(ResolvedSize { partition: partition.name.clone(), path, image_size, size });
+    }
+    Ok(resolved)
+}
+
+impl PartitionConfig {
+    /// Fills in the empty `size` of every dynamic partition with a `path` from its
+    /// image, as lpmake sizes a partition from its image. Relative paths are taken
+    /// from `base_dir`.
+    ///
+    /// Images are measured like `"auto"` sizes: sparse images by their expanded size,
+    /// compressed ones decompressed, and rounded up to the block size of the first
+    /// block device. Partitions with a size, `"auto"` included, are left alone.
+    pub fn fill_sizes_from_files(&mut self, base_dir: &Path) -> io::Result<()> {
+        let block_size = self.block_devices.first().map(|d| d.block_size_bytes()).unwrap_or(LP_SECTOR_SIZE);
+        for partition in self.partitions.iter_mut().filter(|p| p.size.is_none() && p.has_data()) {
+            let path = base_dir.join(partition.path.trim());
+            let (_, image_size) = open_raw_or_sparse(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("Image '{}' of partition '{}': {}", path.display(), partition.name, e)))?;
+            partition.size = Some(ByteSize::new(align_up(image_size, block_size)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{SAMPLE, config_with_images, fixture, fs_head, lines, pattern, test_dir};
+    use crate::super_image_creater::{Partition, parse_partition_config_str};
+
+    #[test]
+    fn empty_sizes_are_filled_from_the_fixture_images() {
+        let testdata = fixture("lines.simg").parent().unwrap().to_path_buf();
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.partitions[0].path = "lines.img.gz".into();
+        config.partitions[2].path = "lines.simg".into();
+        let partition = |name: &str, path: &str| Partition {
+            name: name.into(),
+            group_name: "qti_dynamic_partitions_a".into(),
+            path: path.into(),
+            is_dynamic: true,
+            ..Default::default()
+        };
+        config.add_partition(partition("vendor_a", "lines.img.xz"));
+        config.add_partition(partition("product_a", "lines.img.gz"));
+        config.add_partition(Partition { size: Some(ByteSize::auto()), ..partition("odm_a", "lines.simg") });
+        config.fill_sizes_from_files(&testdata).unwrap();
+
+        let sizes: Vec<(&str, Option<u64>)> = config.partitions.iter().map(|p| (p.name.as_str(), p.size.as_ref().map(ByteSize::as_u64))).collect();
+        let decompressed = (lines().len() as u64).div_ceil(4096) * 4096;
+        assert_eq!(
+            sizes,
+            [
+                // Explicit sizes are kept, sparse images count expanded
+                ("system_a", Some(4096 << 10)),
+                ("system_b", None),
+                ("odm_b", Some(36864)),
+                ("vendor_a", Some(decompressed)),
+                ("product_a", Some(decompressed)),
+                ("odm_a", Some(0)),
+            ]
+        );
+        // and so is "auto", which the build measures
+        assert!(config.partitions[5].has_auto_size());
+
+        config.add_partition(partition("my_product", "missing.img"));
+        let error = config.fill_sizes_from_files(&testdata).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("missing.img") && error.to_string().contains("'my_product'"), "{}", error);
+    }
+
+    #[test]
+    fn issues_are_split_into_errors_and_warnings() {
+        let dir = test_dir("check-inputs");
+        let mut config = config_with_images(
+            &dir,
+            &[
+                ("fits", &pattern(8192, 1), 8192),
+                // Larger than the size, but not than the size rounded up to the 4096 byte blocks
+                ("rounded", &pattern(4100, 2), 4097),
+                ("too_large", &pattern(10000, 3), 4096),
+                ("smaller", &pattern(1000, 4), 8192),
+                ("auto", &pattern(3000, 5), 0),
+                ("unsized", &pattern(3000, 6), 0),
+            ],
+        );
+        config.partitions[4].size = Some(ByteSize::auto());
+        config.partitions[5].size = None;
+        let partition = |name: &str, path: &str, size: Option<ByteSize>| Partition {
+            name: name.into(),
+            group_name: "main".into(),
+            path: path.into(),
+            size,
+            ..Default::default()
+        };
+        let missing = dir.join("missing.img").to_str().unwrap().to_string();
+        config.add_partition(partition("missing", &missing, Some(ByteSize::new(4096))));
+        config.add_partition(partition("no_path", "", Some(ByteSize::new(4096))));
+        config.add_partition(partition("auto_no_path", "", Some(ByteSize::auto())));
+        config.add_partition(partition("placeholder", "", None));
+        // Not dynamic, so not an input of the build
+        config.add_partition(Partition { is_dynamic: false, ..partition("modem", &missing, None) });
+
+        let issues = check_inputs(&config);
+        let of = |severity| -> Vec<(&str, InputProblem)> {
+            issues.iter().filter(|i| i.severity == severity).map(|i| (i.partition.as_str(), i.problem.clone())).collect()
+        };
+        let path = |name: &str| dir.join(format!("{}.img", name)).to_str().unwrap().to_string();
+        assert_eq!(
+            of(IssueSeverity::Error),
+            [
+                ("too_large", InputProblem::TooLarge { path: path("too_large"), image_size: 10000, size: 4096 }),
+                ("missing", InputProblem::Missing { path: missing.clone() }),
+                ("no_path", InputProblem::NoImagePath { size: 4096 }),
+                ("auto_no_path", InputProblem::NoImageForAutoSize),
+            ]
+        );
+        assert_eq!(
+            of(IssueSeverity::Warning),
+            [
+                ("smaller", InputProblem::Smaller { path: path("smaller"), image_size: 1000, size: 8192 }),
+                ("unsized", InputProblem::NoDeclaredSize { path: path("unsized") }),
+            ]
+        );
+        assert!(issues.iter().all(|i| i.is_error() == (i.severity == IssueSeverity::Error) && i.message == i.problem.to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn images_unlike_most_others_get_a_filesystem_warning() {
+        let dir = test_dir("fs-majority");
+        let (erofs, ext4, f2fs) = (fs_head(FsType::Erofs), fs_head(FsType::Ext4), fs_head(FsType::F2fs));
+        let mut config = config_with_images(
+            &dir,
+            &[
+                ("system", &erofs, 4096),
+                ("vendor", &erofs, 4096),
+                ("product", &erofs, 4096),
+                ("system_ext", &erofs, 4096),
+                ("my_product", &ext4, 4096),
+                ("odm", &ext4, 4096),
+                ("my_stock", &f2fs, 4096),
+                ("raw", &pattern(4096, 7), 4096),
+            ],
+        );
+        // Expected as ext4, so not compared with the others
+        config.partitions[5].expected_fs = Some(FsType::Ext4);
+        config.partitions[6].expected_fs = Some(FsType::Erofs);
+
+        let report = inspect_inputs(&config);
+        let fs_types: Vec<Option<FsType>> = report.images.iter().map(|i| i.fs_type).collect();
+        use FsType::*;
+        assert_eq!(fs_types, [Some(Erofs), Some(Erofs), Some(Erofs), Some(Erofs), Some(Ext4), Some(Ext4), Some(F2fs), Some(Unknown)]);
+        let path = |name: &str| dir.join(format!("{}.img", name)).to_str().unwrap().to_string();
+        let issues: Vec<(&str, IssueSeverity, InputProblem)> = report.issues.iter().map(|i| (i.partition.as_str(), i.severity, i.problem.clone())).collect();
+        assert_eq!(
+            issues,
+            [
+                ("my_product", IssueSeverity::Warning, InputProblem::FsUnlikeOthers { path: path("my_product"), detected: Ext4, common: Erofs }),
+                ("my_stock", IssueSeverity::Error, InputProblem::FsMismatch { path: path("my_stock"), detected: F2fs, expected: Erofs }),
+            ]
+        );
+
+        // Without a majority (more than half of the images with a filesystem) nothing
+        // is unlike the others
+        config.partitions.truncate(2);
+        config.partitions[1].path = path("my_product");
+        assert_eq!(inspect_inputs(&config).issues, []);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
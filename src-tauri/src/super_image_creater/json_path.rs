@@ -0,0 +1,121 @@
+//! Where in a JSON document a serde_json error happened, as a field path
+//! (`partitions[14].group_name`) and a snippet of the text around it. serde_json
+//! only gives a line and column, which say little for a minified one-line config.
+
+/// Characters of context `snippet_at` keeps on each side of the error
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Byte offset of a 1-based serde_json (line, column), just past the character
+/// the error was found at
+pub(super) fn offset_of(content: &str, line: usize, column: usize) -> usize {
+    let line_start = match line {
+        0 | 1 => 0,
+        _ => content.match_indices('\n').nth(line - 2).map_or(content.len(), |(i, _)| i + 1),
+    };
+    (line_start + column).min(content.len())
+}
+
+enum Frame {
+    /// `key` is the last key read, `None` until the first one and after a comma
+    Object { key: Option<String> },
+    Array { index: usize },
+}
+
+/// Field path of the value that ends at `offset` (see `offset_of`), `None` at the
+/// top level.
+///
+/// For a missing or unknown field the error points at the enclosing object, so the
+/// field named in `message` is appended to the object's path.
+pub(super) fn field_path_at(content: &str, offset: usize, message: &str) -> Option<String> {
+    let bytes = content.as_bytes();
+    // The character the error was found at is left out, otherwise the closing
+    // brace of an object with a missing field would already have left the object
+    let end = offset.saturating_sub(1).min(bytes.len());
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut string_start: Option<usize> = None;
+    let mut is_key = false;
+    let mut escaped = false;
+    for (i, &c) in bytes[..end].iter().enumerate() {
+        if let Some(start) = string_start {
+            match c {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => {
+                    string_start = None;
+                    if let (true, Some(Frame::Object { key })) = (is_key, stack.last_mut()) {
+                        *key = Some(serde_json::from_str(&content[start..=i]).unwrap_or_else(|_| content[start + 1..i].to_string()));
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            b'"' => {
+                string_start = Some(i);
+                is_key = matches!(stack.last(), Some(Frame::Object { key: None }));
+            }
+            b'{' => stack.push(Frame::Object { key: None }),
+            b'[' => stack.push(Frame::Array { index: 0 }),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => match stack.last_mut() {
+                Some(Frame::Object { key }) => *key = None,
+                Some(Frame::Array { index }) => *index += 1,
+                None => {}
+            },
+            _ => {}
+        }
+    }
+
+    let named_field = ["missing field `", "unknown field `"]
+        .iter()
+        .find_map(|prefix| message.split_once(prefix))
+        .and_then(|(_, rest)| rest.split_once('`'))
+        .map(|(field, _)| field);
+    if named_field.is_some()
+        && let Some(Frame::Object { key }) = stack.last_mut()
+    {
+        *key = None;
+    }
+
+    let mut path = String::new();
+    for frame in &stack {
+        match frame {
+            Frame::Object { key: Some(key) } => push_key(&mut path, key),
+            Frame::Object { key: None } => {}
+            Frame::Array { index } => path.push_str(&format!("[{index}]")),
+        }
+    }
+    if let Some(field) = named_field {
+        push_key(&mut path, field);
+    }
+    (!path.is_empty()).then_some(path)
+}
+
+fn push_key(path: &mut String, key: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(key);
+}
+
+/// The text of the line around `offset`, at most `SNIPPET_CONTEXT` characters to
+/// each side, with `...` where it was cut
+pub(super) fn snippet_at(content: &str, offset: usize) -> Option<String> {
+    let offset = (0..=offset.min(content.len())).rev().find(|&i| content.is_char_boundary(i))?;
+    let line_start = content[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[offset..].find('\n').map_or(content.len(), |i| offset + i);
+    let before: Vec<char> = content[line_start..offset].chars().collect();
+    let after: Vec<char> = content[offset..line_end].chars().collect();
+    let cut_before = before.len() > SNIPPET_CONTEXT;
+    let cut_after = after.len() > SNIPPET_CONTEXT;
+    let mut snippet: String = before[before.len().saturating_sub(SNIPPET_CONTEXT)..].iter().collect();
+    snippet.extend(&after[..after.len().min(SNIPPET_CONTEXT)]);
+    let snippet = snippet.trim();
+    if snippet.is_empty() {
+        return None;
+    }
+    Some(format!("{}{}{}", if cut_before { "..." } else { "" }, snippet, if cut_after { "..." } else { "" }))
+}
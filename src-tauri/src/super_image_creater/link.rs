@@ -0,0 +1,136 @@
+use super::{Group, Partition, PartitionConfig};
+use serde::Serialize;
+use thiserror::Error;
+
+/// Index of a group in a `LinkedConfig`. Only `PartitionConfig::link` creates
+/// them, so one always names an existing group of the config it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupId(usize);
+
+impl GroupId {
+    /// Position of the group in `groups`
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A reference `link` could not resolve
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum LinkError {
+    #[error("Partition '{partition}' references unknown group '{group}'")]
+    UnknownGroup { partition: String, group: String },
+    /// Partitions of this group could belong to either entry
+    #[error("Duplicate group name '{name}'")]
+    DuplicateGroup { name: String },
+}
+
+/// A config whose partitions are resolved to their groups, see `PartitionConfig::link`.
+///
+/// The config is only reachable read-only, so the links cannot go stale; use
+/// `into_config` to edit or save it again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedConfig {
+    config: PartitionConfig,
+    /// Group of each partition, by partition index
+    partition_groups: Vec<GroupId>,
+    /// `maximum_size` of each group in bytes, `None` for no limit
+    group_limits: Vec<Option<u64>>,
+}
+
+impl PartitionConfig {
+    /// Resolves every `group_name` to its group and every group limit to bytes.
+    ///
+    /// Fails on the first partition of an unknown group, or when two groups share a
+    /// name. Nothing else is checked, that is still `validate`'s job.
+    pub fn link(self) -> Result<LinkedConfig, LinkError> {
+        for (i, group) in self.groups.iter().enumerate() {
+            if self.groups[..i].iter().any(|g| g.name == group.name) {
+                return Err(LinkError::DuplicateGroup { name: group.name.clone() });
+            }
+        }
+        let partition_groups = self
+            .partitions
+            .iter()
+            .map(|partition| {
+                self.groups.iter().position(|g| g.name == partition.group_name).map(GroupId).ok_or_else(|| {
+                    LinkError::UnknownGroup { partition: partition.name.clone(), group: partition.group_name.clone() }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let group_limits = self.groups.iter().map(Group::maximum_size_bytes).collect();
+        Ok(LinkedConfig { config: self, partition_groups, group_limits })
+    }
+}
+
+impl LinkedConfig {
+    pub fn config(&self) -> &PartitionConfig {
+        &self.config
+    }
+
+    pub fn into_config(self) -> PartitionConfig {
+        self.config
+    }
+
+    /// Every group with its id, in config order
+    pub fn groups(&self) -> impl Iterator<Item = (GroupId, &Group)> {
+        self.config.groups.iter().enumerate().map(|(i, group)| (GroupId(i), group))
+    }
+
+    pub fn group(&self, id: GroupId) -> &Group {
+        &self.config.groups[id.0]
+    }
+
+    /// `maximum_size` of the group in bytes, `None` for no limit
+    pub fn group_limit(&self, id: GroupId) -> Option<u64> {
+        self.group_limits[id.0]
+    }
+
+    /// Group of the partition at `partition` in `partitions`
+    pub fn group_of(&self, partition: usize) -> GroupId {
+        self.partition_groups[partition]
+    }
+
+    /// The partitions of a group with their index in `partitions`, in config order
+    pub fn partitions_in(&self, id: GroupId) -> impl Iterator<Item = (usize, &Partition)> {
+        self.config.partitions.iter().enumerate().filter(move |(i, _)| self.partition_groups[*i] == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::parse_partition_config_str;
+    use crate::super_image_creater::test_support::SAMPLE;
+
+    #[test]
+    fn partitions_link_to_their_groups() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        let linked = config.clone().link().unwrap();
+        let groups: Vec<_> = linked.groups().map(|(id, group)| (group.name.as_str(), linked.group_limit(id))).collect();
+        assert_eq!(groups, [("qti_dynamic_partitions_a", Some(8_858_370_048)), ("qti_dynamic_partitions_b", None)]);
+        let (b, _) = linked.groups().nth(1).unwrap();
+        let names: Vec<_> = linked.partitions_in(b).map(|(i, p)| (i, p.name.as_str())).collect();
+        assert_eq!(names, [(1, "system_b"), (2, "odm_b")]);
+        assert_eq!(linked.group_of(0).index(), 0);
+        assert_eq!(linked.into_config(), config);
+    }
+
+    #[test]
+    fn dangling_references_and_duplicate_groups_fail_to_link() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        let mut dangling = config.clone();
+        dangling.partitions[2].group_name = "qti_dynamic_partitions".into();
+        let error = dangling.link().unwrap_err();
+        assert_eq!(error, LinkError::UnknownGroup { partition: "odm_b".into(), group: "qti_dynamic_partitions".into() });
+        assert_eq!(error.to_string(), "Partition 'odm_b' references unknown group 'qti_dynamic_partitions'");
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"kind":"UnknownGroup","partition":"odm_b","group":"qti_dynamic_partitions"}"#
+        );
+
+        let mut duplicate = config;
+        duplicate.groups.push(duplicate.groups[0].clone());
+        assert_eq!(duplicate.link().unwrap_err(), LinkError::DuplicateGroup { name: "qti_dynamic_partitions_a".into() });
+    }
+}
@@ -0,0 +1,404 @@
+// Layout of Android logical partition (LP) metadata, as defined by AOSP liblp
+// (system/core/fs_mgr/liblp/include/liblp/metadata_format.h).
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use thiserror::Error;
+
+/// Sector size used by all LP metadata offsets and extents
+pub const LP_SECTOR_SIZE: u64 = 512;
+/// Bytes reserved at the start of the super partition (kept zero, e.g. for a boot sector)
+pub const LP_PARTITION_RESERVED_BYTES: u64 = 4096;
+/// Size of one geometry block; a primary and a backup copy follow the reserved area
+pub const LP_METADATA_GEOMETRY_SIZE: u64 = 4096;
+
+pub const LP_METADATA_GEOMETRY_MAGIC: u32 = 0x616c4467;
+pub const LP_METADATA_HEADER_MAGIC: u32 = 0x414c5030;
+pub const LP_METADATA_MAJOR_VERSION: u16 = 10;
+/// Minor version 2 adds the header `flags` field (and grows the header to 256 bytes)
+pub const LP_METADATA_VERSION_FOR_VIRTUAL_AB: u16 = 2;
+
+pub const LP_PARTITION_ATTR_NONE: u32 = 0;
+pub const LP_PARTITION_ATTR_READONLY: u32 = 1 << 0;
+/// The slot suffix is appended to the name at runtime (retrofit devices)
+pub const LP_PARTITION_ATTR_SLOT_SUFFIXED: u32 = 1 << 1;
+
+pub const LP_TARGET_TYPE_LINEAR: u32 = 0;
+pub const LP_TARGET_TYPE_ZERO: u32 = 1;
+
+pub const LP_HEADER_FLAG_VIRTUAL_AB_DEVICE: u32 = 0x1;
+
+/// Group and block device counterparts of `LP_PARTITION_ATTR_SLOT_SUFFIXED`
+pub const LP_GROUP_SLOT_SUFFIXED: u32 = 1 << 0;
+pub const LP_BLOCK_DEVICE_SLOT_SUFFIXED: u32 = 1 << 0;
+
+/// Maximum length of partition, group and block device names
+pub const LP_NAME_LEN: usize = 36;
+
+const GEOMETRY_STRUCT_SIZE: u32 = 52;
+const HEADER_V1_0_SIZE: u32 = 128;
+const HEADER_V1_2_SIZE: u32 = 256;
+const PARTITION_ENTRY_SIZE: u32 = 52;
+const EXTENT_ENTRY_SIZE: u32 = 24;
+const GROUP_ENTRY_SIZE: u32 = 48;
+const BLOCK_DEVICE_ENTRY_SIZE: u32 = 64;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LpMetadataError {
+    #[error("Bad {0} magic")]
+    BadMagic(&'static str),
+    #[error("Bad {0} checksum")]
+    BadChecksum(&'static str),
+    #[error("Unsupported metadata version {major}.{minor}")]
+    UnsupportedVersion { major: u16, minor: u16 },
+    #[error("Truncated or malformed {0}")]
+    Malformed(&'static str),
+}
+
+/// Rounds `value` up to the next multiple of `alignment` (no-op for an alignment of 0)
+pub fn align_up(value: u64, alignment: u64) -> u64 {
+    if alignment == 0 {
+        return value;
+    }
+    value.div_ceil(alignment) * alignment
+}
+
+/// Byte offset of the end of the metadata region: reserved bytes, both geometry
+/// copies, then primary and backup copies of every metadata slot
+pub fn metadata_region_end(metadata_max_size: u64, metadata_slot_count: u64) -> u64 {
+    LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE * 2 + metadata_max_size * metadata_slot_count * 2
+}
+
+/// Byte offset of the first sector that can hold partition data
+pub fn first_usable_offset(metadata_max_size: u64, metadata_slot_count: u64, alignment: u64) -> u64 {
+    align_up(metadata_region_end(metadata_max_size, metadata_slot_count), alignment)
+}
+
+/// Byte offset of the primary copy of a metadata slot
+pub fn primary_metadata_offset(metadata_max_size: u64, slot: u64) -> u64 {
+    LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE * 2 + metadata_max_size * slot
+}
+
+/// Byte offset of the backup copy of a metadata slot (all backups follow all primaries)
+pub fn backup_metadata_offset(metadata_max_size: u64, metadata_slot_count: u64, slot: u64) -> u64 {
+    primary_metadata_offset(metadata_max_size, metadata_slot_count + slot)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpGeometry {
+    pub metadata_max_size: u32,
+    pub metadata_slot_count: u32,
+    pub logical_block_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpPartition {
+    pub name: String,
+    pub attributes: u32,
+    pub first_extent_index: u32,
+    pub num_extents: u32,
+    pub group_index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpExtent {
+    pub num_sectors: u64,
+    pub target_type: u32,
+    /// Start sector on the target block device (for linear extents)
+    pub target_data: u64,
+    /// Index of the target block device
+    pub target_source: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpGroup {
+    pub name: String,
+    pub flags: u32,
+    /// 0 means unlimited
+    pub maximum_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpBlockDevice {
+    pub first_logical_sector: u64,
+    pub alignment: u32,
+    pub alignment_offset: u32,
+    pub size: u64,
+    pub partition_name: String,
+    pub flags: u32,
+}
+
+/// One complete metadata slot: header flags plus the four tables
+#[derive(Debug, Clone, PartialEq)]
+pub struct LpMetadata {
+    pub minor_version: u16,
+    pub header_flags: u32,
+    pub partitions: Vec<LpPartition>,
+    pub extents: Vec<LpExtent>,
+    pub groups: Vec<LpGroup>,
+    pub block_devices: Vec<LpBlockDevice>,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    let mut raw = [0u8; LP_NAME_LEN];
+    let len = name.len().min(LP_NAME_LEN);
+    raw[..len].copy_from_slice(&name.as_bytes()[..len]);
+    buf.extend_from_slice(&raw);
+}
+
+impl LpGeometry {
+    /// Serializes the geometry into a full `LP_METADATA_GEOMETRY_SIZE` block
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(LP_METADATA_GEOMETRY_SIZE as usize);
+        buf.write_u32::<LittleEndian>(LP_METADATA_GEOMETRY_MAGIC).unwrap();
+        buf.write_u32::<LittleEndian>(GEOMETRY_STRUCT_SIZE).unwrap();
+        buf.extend_from_slice(&[0u8; 32]); // checksum, filled in below
+        buf.write_u32::<LittleEndian>(self.metadata_max_size).unwrap();
+        buf.write_u32::<LittleEndian>(self.metadata_slot_count).unwrap();
+        buf.write_u32::<LittleEndian>(self.logical_block_size).unwrap();
+
+        let checksum = sha256(&buf);
+        buf[8..40].copy_from_slice(&checksum);
+        buf.resize(LP_METADATA_GEOMETRY_SIZE as usize, 0);
+        buf
+    }
+}
+
+impl LpMetadata {
+    fn header_size(&self) -> u32 {
+        if self.minor_version >= LP_METADATA_VERSION_FOR_VIRTUAL_AB {
+            HEADER_V1_2_SIZE
+        } else {
+            HEADER_V1_0_SIZE
+        }
+    }
+
+    /// Serializes the header followed by the partition, extent, group and block
+    /// device tables, with both checksums filled in
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut tables = Vec::new();
+        for p in &self.partitions {
+            put_name(&mut tables, &p.name);
+            tables.write_u32::<LittleEndian>(p.attributes).unwrap();
+            tables.write_u32::<LittleEndian>(p.first_extent_index).unwrap();
+            tables.write_u32::<LittleEndian>(p.num_extents).unwrap();
+            tables.write_u32::<LittleEndian>(p.group_index).unwrap();
+        }
+        for e in &self.extents {
+            tables.write_u64::<LittleEndian>(e.num_sectors).unwrap();
+            tables.write_u32::<LittleEndian>(e.target_type).unwrap();
+            tables.write_u64::<LittleEndian>(e.target_data).unwrap();
+            tables.write_u32::<LittleEndian>(e.target_source).unwrap();
+        }
+        for g in &self.groups {
+            put_name(&mut tables, &g.name);
+            tables.write_u32::<LittleEndian>(g.flags).unwrap();
+            tables.write_u64::<LittleEndian>(g.maximum_size).unwrap();
+        }
+        for d in &self.block_devices {
+            tables.write_u64::<LittleEndian>(d.first_logical_sector).unwrap();
+            tables.write_u32::<LittleEndian>(d.alignment).unwrap();
+            tables.write_u32::<LittleEndian>(d.alignment_offset).unwrap();
+            tables.write_u64::<LittleEndian>(d.size).unwrap();
+            put_name(&mut tables, &d.partition_name);
+            tables.write_u32::<LittleEndian>(d.flags).unwrap();
+        }
+
+        let header_size = self.header_size();
+        let mut header = Vec::with_capacity(header_size as usize);
+        header.write_u32::<LittleEndian>(LP_METADATA_HEADER_MAGIC).unwrap();
+        header.write_u16::<LittleEndian>(LP_METADATA_MAJOR_VERSION).unwrap();
+        header.write_u16::<LittleEndian>(self.minor_version).unwrap();
+        header.write_u32::<LittleEndian>(header_size).unwrap();
+        header.extend_from_slice(&[0u8; 32]); // header checksum, filled in below
+        header.write_u32::<LittleEndian>(tables.len() as u32).unwrap();
+        header.extend_from_slice(&sha256(&tables));
+
+        // Table descriptors: offset (relative to the end of the header), count, entry size
+        let mut offset = 0u32;
+        for (count, entry_size) in [
+            (self.partitions.len() as u32, PARTITION_ENTRY_SIZE),
+            (self.extents.len() as u32, EXTENT_ENTRY_SIZE),
+            (self.groups.len() as u32, GROUP_ENTRY_SIZE),
+            (self.block_devices.len() as u32, BLOCK_DEVICE_ENTRY_SIZE),
+        ] {
+            header.write_u32::<LittleEndian>(offset).unwrap();
+            header.write_u32::<LittleEndian>(count).unwrap();
+            header.write_u32::<LittleEndian>(entry_size).unwrap();
+            offset += count * entry_size;
+        }
+        if header_size == HEADER_V1_2_SIZE {
+            header.write_u32::<LittleEndian>(self.header_flags).unwrap();
+        }
+        header.resize(header_size as usize, 0);
+
+        let checksum = sha256(&header);
+        header[12..44].copy_from_slice(&checksum);
+
+        header.extend_from_slice(&tables);
+        header
+    }
+}
+
+fn get_name(raw: &[u8]) -> String {
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).to_string()
+}
+
+impl LpGeometry {
+    /// Parses and checksums a geometry block (at least `GEOMETRY_STRUCT_SIZE` bytes)
+    pub fn from_bytes(data: &[u8]) -> Result<Self, LpMetadataError> {
+        let malformed = |_| LpMetadataError::Malformed("geometry");
+        let mut cursor = Cursor::new(data);
+        if cursor.read_u32::<LittleEndian>().map_err(malformed)? != LP_METADATA_GEOMETRY_MAGIC {
+            return Err(LpMetadataError::BadMagic("geometry"));
+        }
+        let struct_size = cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize;
+        if struct_size < GEOMETRY_STRUCT_SIZE as usize || data.len() < struct_size {
+            return Err(LpMetadataError::Malformed("geometry"));
+        }
+        let mut copy = data[..struct_size].to_vec();
+        copy[8..40].fill(0);
+        if sha256(&copy) != data[8..40] {
+            return Err(LpMetadataError::BadChecksum("geometry"));
+        }
+        cursor.set_position(40);
+        Ok(Self {
+            metadata_max_size: cursor.read_u32::<LittleEndian>().map_err(malformed)?,
+            metadata_slot_count: cursor.read_u32::<LittleEndian>().map_err(malformed)?,
+            logical_block_size: cursor.read_u32::<LittleEndian>().map_err(malformed)?,
+        })
+    }
+}
+
+impl LpMetadata {
+    /// Parses one metadata slot (header followed by the tables), verifying both checksums
+    pub fn from_bytes(data: &[u8]) -> Result<Self, LpMetadataError> {
+        let malformed = |_| LpMetadataError::Malformed("metadata header");
+        let mut cursor = Cursor::new(data);
+        if cursor.read_u32::<LittleEndian>().map_err(malformed)? != LP_METADATA_HEADER_MAGIC {
+            return Err(LpMetadataError::BadMagic("metadata header"));
+        }
+        let major = cursor.read_u16::<LittleEndian>().map_err(malformed)?;
+        let minor = cursor.read_u16::<LittleEndian>().map_err(malformed)?;
+        if major != LP_METADATA_MAJOR_VERSION || minor > LP_METADATA_VERSION_FOR_VIRTUAL_AB {
+            return Err(LpMetadataError::UnsupportedVersion { major, minor });
+        }
+        let header_size = cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize;
+        if header_size < HEADER_V1_0_SIZE as usize || data.len() < header_size {
+            return Err(LpMetadataError::Malformed("metadata header"));
+        }
+        let mut header = data[..header_size].to_vec();
+        header[12..44].fill(0);
+        if sha256(&header) != data[12..44] {
+            return Err(LpMetadataError::BadChecksum("metadata header"));
+        }
+
+        cursor.set_position(44);
+        let tables_size = cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize;
+        let tables = data
+            .get(header_size..header_size + tables_size)
+            .ok_or(LpMetadataError::Malformed("metadata tables"))?;
+        if sha256(tables) != data[48..80] {
+            return Err(LpMetadataError::BadChecksum("metadata tables"));
+        }
+
+        cursor.set_position(80);
+        let mut descriptors = [(0usize, 0usize, 0usize); 4];
+        for descriptor in descriptors.iter_mut() {
+            *descriptor = (
+                cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize,
+                cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize,
+                cursor.read_u32::<LittleEndian>().map_err(malformed)? as usize,
+            );
+        }
+        let header_flags = if header_size >= HEADER_V1_2_SIZE as usize {
+            cursor.read_u32::<LittleEndian>().map_err(malformed)?
+        } else {
+            0
+        };
+
+        // Returns each entry of a table as a slice
+        let entries = |index: usize, min_size: u32| -> Result<Vec<&[u8]>, LpMetadataError> {
+            let (offset, count, entry_size) = descriptors[index];
+            if entry_size < min_size as usize {
+                return Err(LpMetadataError::Malformed("metadata tables"));
+            }
+            (0..count)
+                .map(|i| {
+                    let start = offset + i * entry_size;
+                    tables.get(start..start + entry_size).ok_or(LpMetadataError::Malformed("metadata tables"))
+                })
+                .collect()
+        };
+        let table_err = |_| LpMetadataError::Malformed("metadata tables");
+
+        let mut partitions = Vec::new();
+        for entry in entries(0, PARTITION_ENTRY_SIZE)? {
+            let mut c = Cursor::new(&entry[LP_NAME_LEN..]);
+            partitions.push(LpPartition {
+                name: get_name(&entry[..LP_NAME_LEN]),
+                attributes: c.read_u32::<LittleEndian>().map_err(table_err)?,
+                first_extent_index: c.read_u32::<LittleEndian>().map_err(table_err)?,
+                num_extents: c.read_u32::<LittleEndian>().map_err(table_err)?,
+                group_index: c.read_u32::<LittleEndian>().map_err(table_err)?,
+            });
+        }
+        let mut extents = Vec::new();
+        for entry in entries(1, EXTENT_ENTRY_SIZE)? {
+            let mut c = Cursor::new(entry);
+            extents.push(LpExtent {
+                num_sectors: c.read_u64::<LittleEndian>().map_err(table_err)?,
+                target_type: c.read_u32::<LittleEndian>().map_err(table_err)?,
+                target_data: c.read_u64::<LittleEndian>().map_err(table_err)?,
+                target_source: c.read_u32::<LittleEndian>().map_err(table_err)?,
+            });
+        }
+        let mut groups = Vec::new();
+        for entry in entries(2, GROUP_ENTRY_SIZE)? {
+            let mut c = Cursor::new(&entry[LP_NAME_LEN..]);
+            groups.push(LpGroup {
+                name: get_name(&entry[..LP_NAME_LEN]),
+                flags: c.read_u32::<LittleEndian>().map_err(table_err)?,
+                maximum_size: c.read_u64::<LittleEndian>().map_err(table_err)?,
+            });
+        }
+        let mut block_devices = Vec::new();
+        for entry in entries(3, BLOCK_DEVICE_ENTRY_SIZE)? {
+            let mut c = Cursor::new(entry);
+            let first_logical_sector = c.read_u64::<LittleEndian>().map_err(table_err)?;
+            let alignment = c.read_u32::<LittleEndian>().map_err(table_err)?;
+            let alignment_offset = c.read_u32::<LittleEndian>().map_err(table_err)?;
+            let size = c.read_u64::<LittleEndian>().map_err(table_err)?;
+            let partition_name = get_name(&entry[24..24 + LP_NAME_LEN]);
+            c.set_position((24 + LP_NAME_LEN) as u64);
+            block_devices.push(LpBlockDevice {
+                first_logical_sector,
+                alignment,
+                alignment_offset,
+                size,
+                partition_name,
+                flags: c.read_u32::<LittleEndian>().map_err(table_err)?,
+            });
+        }
+
+        for partition in &partitions {
+            let end = partition.first_extent_index as usize + partition.num_extents as usize;
+            if end > extents.len() || partition.group_index as usize >= groups.len() {
+                return Err(LpMetadataError::Malformed("partition table"));
+            }
+        }
+
+        Ok(Self { minor_version: minor, header_flags, partitions, extents, groups, block_devices })
+    }
+
+    /// Extents belonging to a partition
+    pub fn partition_extents(&self, partition: &LpPartition) -> &[LpExtent] {
+        let start = partition.first_extent_index as usize;
+        &self.extents[start..start + partition.num_extents as usize]
+    }
+}
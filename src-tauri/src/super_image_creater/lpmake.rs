@@ -0,0 +1,371 @@
+use super::size::parse_size;
+use super::{BlockDevice, ByteSize, DEFAULT_METADATA_SLOTS, Group, Partition, PartitionConfig};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LpmakeParseError {
+    #[error("Unterminated quote in the command line")]
+    UnterminatedQuote,
+    #[error("Unsupported lpmake flag '{0}'")]
+    UnsupportedFlag(String),
+    #[error("Unexpected argument '{0}', lpmake only takes flags")]
+    UnexpectedArgument(String),
+    #[error("lpmake flag --{0} needs a value")]
+    MissingValue(String),
+    #[error("Invalid value '{value}' for --{flag}: {reason}")]
+    InvalidValue { flag: String, value: String, reason: String },
+    #[error("--image is for partition '{0}', which no --partition declares")]
+    UnknownImagePartition(String),
+    #[error("--device-size and --device cannot be used together")]
+    DeviceConflict,
+    #[error("No block device, --device-size or --device is required")]
+    NoDevice,
+    #[error("No --metadata-size given")]
+    NoMetadataSize,
+}
+
+/// lpmake flags understood by `config_from_lpmake_args`: short form, long form and
+/// whether a value follows
+const FLAGS: &[(Option<char>, &str, bool)] = &[
+    (Some('d'), "device-size", true),
+    (Some('m'), "metadata-size", true),
+    (Some('s'), "metadata-slots", true),
+    (Some('p'), "partition", true),
+    (Some('o'), "output", true),
+    (Some('i'), "image", true),
+    (Some('g'), "group", true),
+    (Some('S'), "sparse", false),
+    (Some('b'), "block-size", true),
+    (Some('a'), "alignment", true),
+    (Some('n'), "super-name", true),
+    (Some('D'), "device", true),
+    (Some('F'), "force-full-image", false),
+    (None, "virtual-ab", false),
+];
+
+/// Long name of a flag of `FLAGS`, whether it takes a value, and the value written
+/// into the argument
+type FlagArg<'a> = (&'static str, bool, Option<&'a str>);
+
+/// The flag `arg` with the value written into it (`--group=main:0`, `-gmain:0`),
+/// `None` for an argument that is no flag. A long name after a single dash
+/// (`-block-size=4096`) is taken as that flag, as the lpmake call this tool made before
+/// it built images itself wrote it.
+fn split_flag(arg: &str) -> Result<Option<FlagArg<'_>>, LpmakeParseError> {
+    let unsupported = || Err(LpmakeParseError::UnsupportedFlag(arg.to_string()));
+    let Some(rest) = arg.strip_prefix('-') else { return Ok(None) };
+    let long = rest.strip_prefix('-').or_else(|| {
+        let name = rest.split_once('=').map_or(rest, |(name, _)| name);
+        (name.len() > 1 && FLAGS.iter().any(|f| f.1 == name)).then_some(rest)
+    });
+    match long {
+        Some(long) => {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            match FLAGS.iter().find(|f| f.1 == name) {
+                Some(&(_, name, takes_value)) => Ok(Some((name, takes_value, value))),
+                None => unsupported(),
+            }
+        }
+        None => {
+            let mut chars = rest.chars();
+            let short = chars.next();
+            let attached = chars.as_str();
+            match FLAGS.iter().find(|f| f.0.is_some() && f.0 == short) {
+                Some(&(_, name, true)) => Ok(Some((name, true, (!attached.is_empty()).then_some(attached)))),
+                Some(&(_, name, false)) if attached.is_empty() => Ok(Some((name, false, None))),
+                _ => unsupported(),
+            }
+        }
+    }
+}
+
+fn invalid(flag: &str, value: &str, reason: impl ToString) -> LpmakeParseError {
+    LpmakeParseError::InvalidValue { flag: flag.to_string(), value: value.to_string(), reason: reason.to_string() }
+}
+
+fn size_value(flag: &str, value: &str) -> Result<u64, LpmakeParseError> {
+    parse_size(value).map_err(|e| invalid(flag, value, e))
+}
+
+/// Splits `value` at the first `:` into a name and a size
+fn name_and_size<'a>(flag: &str, value: &'a str) -> Result<(&'a str, u64), LpmakeParseError> {
+    match value.split_once(':') {
+        Some((name, size)) if !name.is_empty() => Ok((name, size_value(flag, size)?)),
+        _ => Err(invalid(flag, value, "expected <name>:<size>")),
+    }
+}
+
+/// Reads a super layout from the arguments of an lpmake call, as device trees and
+/// build docs often share it, e.g.
+/// `--metadata-size 65536 --device super:9126805504 --group main:9122611200
+/// --partition system:readonly:2147483648:main --image system=system.img`.
+///
+/// `--device-size`, `--device`, `--metadata-size`, `--metadata-slots`, `--group`,
+/// `--partition`, `--image`, `--output` (the `super_meta.path`), `--super-name`,
+/// `--block-size`, `--alignment` and `--virtual-ab` go into the config. `--sparse`
+/// and `--force-full-image` are accepted but are not part of a config, the output
+/// format is picked at build time. Any other flag is rejected. Without
+/// `--metadata-slots` the image gets two slots, as with lpmake. A leading `lpmake`
+/// (or a path to it) is skipped. A group of size 0 has no limit and a partition of
+/// size 0 gets no size, as in lpmake. Partitions without a group go into lpmake's
+/// implicit `default` group, which is added (without a limit) unless a `--group`
+/// declares it. Image paths are kept as written, relative to where lpmake would
+/// have run.
+pub fn config_from_lpmake_args(args: &[String]) -> Result<PartitionConfig, LpmakeParseError> {
+    let mut args = args.iter().map(String::as_str).peekable();
+    if let Some(first) = args.next_if(|a| !a.starts_with('-'))
+        && !Path::new(first).file_stem().is_some_and(|stem| stem.eq_ignore_ascii_case("lpmake"))
+    {
+        return Err(LpmakeParseError::UnexpectedArgument(first.to_string()));
+    }
+
+    let mut config = PartitionConfig::default();
+    let mut metadata_size = None;
+    let mut metadata_slots = DEFAULT_METADATA_SLOTS;
+    let mut device_size = None;
+    let mut super_name = None;
+    let mut block_size = None;
+    let mut alignment = None;
+    let mut images = Vec::new();
+    while let Some(arg) = args.next() {
+        let Some((flag, takes_value, inline)) = split_flag(arg)? else {
+            return Err(LpmakeParseError::UnexpectedArgument(arg.to_string()));
+        };
+        let value = match (takes_value, inline) {
+            (false, None) => "",
+            (false, Some(value)) => return Err(invalid(flag, value, "the flag takes no value")),
+            (true, Some(value)) => value,
+            (true, None) => args.next().ok_or_else(|| LpmakeParseError::MissingValue(flag.to_string()))?,
+        };
+        match flag {
+            "device-size" => device_size = Some(size_value(flag, value)?),
+            "metadata-size" => metadata_size = Some(size_value(flag, value)?),
+            "metadata-slots" => match value.parse::<u32>() {
+                Ok(0) => return Err(invalid(flag, value, "at least one slot is needed")),
+                Ok(slots) => metadata_slots = slots,
+                Err(e) => return Err(invalid(flag, value, e)),
+            },
+            "block-size" => block_size = Some(size_value(flag, value)?),
+            "alignment" => alignment = Some(size_value(flag, value)?),
+            "super-name" => super_name = Some(value.to_string()),
+            "output" => config.super_meta.path = value.to_string(),
+            "virtual-ab" => config.virtual_ab = Some(true),
+            "device" => {
+                let (name, size) = name_and_size(flag, value)?;
+                config.block_devices.push(BlockDevice { name: name.to_string(), size: ByteSize::new(size), ..Default::default() });
+            }
+            "group" => {
+                let (name, size) = name_and_size(flag, value)?;
+                config.groups.push(Group {
+                    name: name.to_string(),
+                    maximum_size: (size > 0).then(|| ByteSize::new(size)),
+                    ..Default::default()
+                });
+            }
+            "partition" => {
+                let parts: Vec<&str> = value.split(':').collect();
+                let (name, attributes, size, group) = match parts[..] {
+                    [name, attributes, size] => (name, attributes, size, "default"),
+                    [name, attributes, size, group] => (name, attributes, size, group),
+                    _ => return Err(invalid(flag, value, "expected <name>:<attributes>:<size>[:<group>]")),
+                };
+                if name.is_empty() {
+                    return Err(invalid(flag, value, "the partition has no name"));
+                }
+                if attributes != "none" && attributes != "readonly" {
+                    return Err(invalid(flag, value, format!("unknown attributes '{}', expected none or readonly", attributes)));
+                }
+                let size = size_value(flag, size)?;
+                config.partitions.push(Partition {
+                    name: name.to_string(),
+                    group_name: group.to_string(),
+                    size: (size > 0).then(|| ByteSize::new(size)),
+                    ..Default::default()
+                });
+            }
+            "image" => match value.split_once('=') {
+                Some((name, path)) if !name.is_empty() && !path.is_empty() => images.push((name, path)),
+                _ => return Err(invalid(flag, value, "expected <partition>=<path>")),
+            },
+            // "sparse" and "force-full-image" only change how the image is written
+            _ => {}
+        }
+    }
+
+    match (device_size, config.block_devices.is_empty()) {
+        (Some(_), false) => return Err(LpmakeParseError::DeviceConflict),
+        (Some(size), true) => config.block_devices.push(BlockDevice {
+            name: super_name.unwrap_or_else(|| BlockDevice::default().name),
+            size: ByteSize::new(size),
+            ..Default::default()
+        }),
+        (None, true) => return Err(LpmakeParseError::NoDevice),
+        (None, false) => {
+            // The metadata lives on the super device, which has to come first
+            if let Some(name) = super_name {
+                let index = config
+                    .block_devices
+                    .iter()
+                    .position(|d| d.name == name)
+                    .ok_or_else(|| invalid("super-name", &name, "no --device has that name"))?;
+                let device = config.block_devices.remove(index);
+                config.block_devices.insert(0, device);
+            }
+        }
+    }
+    for device in &mut config.block_devices {
+        if let Some(size) = block_size {
+            device.block_size = ByteSize::new(size);
+        }
+        if let Some(size) = alignment {
+            device.alignment = ByteSize::new(size);
+        }
+    }
+    config.super_meta.size = ByteSize::new(metadata_size.ok_or(LpmakeParseError::NoMetadataSize)?);
+    if config.partitions.iter().any(|p| p.group_name == "default") && !config.groups.iter().any(|g| g.name == "default") {
+        config.groups.insert(0, Group { name: "default".to_string(), ..Default::default() });
+    }
+    // Stored only when the default would build a different geometry
+    config.metadata_slots = (metadata_slots != DEFAULT_METADATA_SLOTS).then_some(metadata_slots);
+    for (name, path) in images {
+        let partition = config
+            .partitions
+            .iter_mut()
+            .find(|p| p.name == name)
+            .ok_or_else(|| LpmakeParseError::UnknownImagePartition(name.to_string()))?;
+        partition.path = path.to_string();
+    }
+    Ok(config)
+}
+
+/// Splits a pasted command line into arguments like a POSIX shell: at unquoted
+/// whitespace, with `'...'` taken literally, `\` escapes in `"..."` and outside of
+/// quotes, and `\` line continuations dropped
+pub fn split_command_line(line: &str) -> Result<Vec<String>, LpmakeParseError> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(LpmakeParseError::UnterminatedQuote),
+                    }
+                }
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(LpmakeParseError::UnterminatedQuote),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(LpmakeParseError::UnterminatedQuote),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // A line continuation, `\r\n` leaves the `\n` as whitespace
+                Some('\n' | '\r') | None => {}
+                Some(c) => current.get_or_insert_with(String::new).push(c),
+            },
+            c if c.is_whitespace() => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<PartitionConfig, LpmakeParseError> {
+        config_from_lpmake_args(&split_command_line(line).unwrap())
+    }
+
+    #[test]
+    fn reads_a_documented_layout() {
+        let config = parse(
+            "lpmake --metadata-size 65536 --device super:9126805504 --group main:9122611200 \\\n\
+             --partition system:readonly:2147483648:main --image system=system.img --sparse",
+        )
+        .unwrap();
+        assert_eq!(config.super_meta.size_bytes(), 65536);
+        assert_eq!(config.block_devices[0].name, "super");
+        assert_eq!(config.block_devices[0].size_bytes(), 9126805504);
+        assert_eq!(config.groups.len(), 1);
+        assert_eq!(config.groups[0].maximum_size_bytes(), Some(9122611200));
+        assert_eq!(config.partitions[0].group_name, "main");
+        assert_eq!(config.partitions[0].path, "system.img");
+        // lpmake's two slots, which are the default with one group too
+        assert_eq!(config.metadata_slots, None);
+        assert_eq!(config.metadata_slot_count(), 2);
+    }
+
+    #[test]
+    fn partitions_without_a_group_get_the_default_group() {
+        let config = parse("--metadata-size 65536 --device-size 16777216 --partition system:none:4096 --partition vendor:none:0").unwrap();
+        assert_eq!(config.groups.len(), 1);
+        assert_eq!(config.groups[0].name, "default");
+        assert_eq!(config.groups[0].maximum_size, None);
+        assert!(config.partitions.iter().all(|p| p.group_name == "default"));
+        assert_eq!(config.validate_group_references(), Ok(()));
+
+        let config = parse("--metadata-size 65536 --device-size 16777216 --group default:8388608 --partition system:none:4096").unwrap();
+        assert_eq!(config.groups.len(), 1);
+        assert_eq!(config.groups[0].maximum_size_bytes(), Some(8388608));
+    }
+
+    #[test]
+    fn metadata_slots_are_kept() {
+        let base = "--metadata-size 65536 --device-size 16777216 --group main_a:0 --group main_b:0";
+        let config = parse(&format!("{} --metadata-slots 3", base)).unwrap();
+        assert_eq!(config.metadata_slots, Some(3));
+        assert_eq!(config.metadata_slot_count(), 3);
+        // The default, so nothing is stored
+        let config = parse(&format!("{} -s 2", base)).unwrap();
+        assert_eq!(config.metadata_slots, None);
+        assert_eq!(config.metadata_slot_count(), 2);
+        assert!(matches!(parse(&format!("{} --metadata-slots=0", base)), Err(LpmakeParseError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn rejects_unsupported_input() {
+        let base = "--metadata-size 65536 --device-size 16777216";
+        assert_eq!(parse(&format!("{} --foo", base)), Err(LpmakeParseError::UnsupportedFlag("--foo".to_string())));
+        assert_eq!(parse(&format!("{} extra", base)), Err(LpmakeParseError::UnexpectedArgument("extra".to_string())));
+        assert_eq!(parse("--metadata-size 65536"), Err(LpmakeParseError::NoDevice));
+        assert_eq!(parse("--device-size 16777216 --device super:16777216 --metadata-size 65536"), Err(LpmakeParseError::DeviceConflict));
+        assert_eq!(
+            parse(&format!("{} --image system=system.img", base)),
+            Err(LpmakeParseError::UnknownImagePartition("system".to_string()))
+        );
+    }
+
+    #[test]
+    fn splits_like_a_shell() {
+        assert_eq!(
+            split_command_line("lpmake --image 'a b'=\"c \\\"d\\\"\" e\\ f \\\n-S").unwrap(),
+            ["lpmake", "--image", "a b=c \"d\"", "e f", "-S"]
+        );
+        assert_eq!(split_command_line("--image 'a"), Err(LpmakeParseError::UnterminatedQuote));
+    }
+}
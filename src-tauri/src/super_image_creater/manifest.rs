@@ -0,0 +1,236 @@
+use super::sparse::open_raw_or_sparse;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Zero buffer used to hash gaps and read buffer used by `verify_manifest`
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Digest of one partition image as written into the super image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionDigest {
+    pub name: String,
+    /// `size` from the config, `None` if the partition had none
+    pub declared_size: Option<u64>,
+    /// Byte offset of the partition data in the raw image
+    pub offset: u64,
+    /// Bytes copied from the image (expanded, for sparse input)
+    pub bytes_copied: u64,
+    /// Lowercase hex SHA-256 of the copied bytes
+    pub sha256: String,
+}
+
+/// Digest of a whole super image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageDigest {
+    /// Raw image size, i.e. the block device size
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the raw image. For sparse output this is the hash of
+    /// the image it expands to, not of the sparse file.
+    pub sha256: String,
+}
+
+/// Contents of the `<output>.manifest.json` written next to a built image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub image: ImageDigest,
+    /// Partitions whose image was copied, in offset order
+    pub partitions: Vec<PartitionDigest>,
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Manifest error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Image is {actual} bytes, the manifest expects {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("Data of partition(s) {} does not match the manifest", .0.join(", "))]
+    PartitionMismatch(Vec<String>),
+    #[error("Image does not match the manifest outside the partition data (metadata or free space)")]
+    ImageMismatch,
+}
+
+/// Path of the manifest written for `output`, e.g. `super.img.manifest.json`
+pub fn manifest_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".manifest.json");
+    PathBuf::from(path)
+}
+
+impl BuildManifest {
+    /// Reads a manifest written by a build
+    pub fn read(path: &Path) -> Result<Self, ManifestError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ManifestError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Hashes a raw image while it is written front to back, along with the partitions
+/// in it. Regions that are never written (gaps, free space) are hashed as zeros.
+pub(super) struct ImageHasher {
+    image: Sha256,
+    position: u64,
+    current: Option<(PartitionDigest, Sha256)>,
+    partitions: Vec<PartitionDigest>,
+}
+
+impl ImageHasher {
+    pub(super) fn new() -> Self {
+        ImageHasher { image: Sha256::new(), position: 0, current: None, partitions: Vec::new() }
+    }
+
+    /// Hashes zeros up to `offset`, which must not be behind the data hashed so far
+    fn zeros_until(&mut self, offset: u64) {
+        let zeros = [0u8; HASH_BUFFER_SIZE];
+        while self.position < offset {
+            let len = (offset - self.position).min(HASH_BUFFER_SIZE as u64) as usize;
+            self.image.update(&zeros[..len]);
+            self.position += len as u64;
+        }
+    }
+
+    /// Hashes `data` at the current position, and into the current partition if any
+    pub(super) fn update(&mut self, data: &[u8]) {
+        self.image.update(data);
+        self.position += data.len() as u64;
+        if let Some((digest, hasher)) = &mut self.current {
+            hasher.update(data);
+            digest.bytes_copied += data.len() as u64;
+        }
+    }
+
+    /// Starts a partition whose data follows at `offset`
+    pub(super) fn begin_partition(&mut self, name: &str, declared_size: Option<u64>, offset: u64) {
+        self.zeros_until(offset);
+        let digest = PartitionDigest { name: name.to_string(), declared_size, offset, bytes_copied: 0, sha256: String::new() };
+        self.current = Some((digest, Sha256::new()));
+    }
+
+    pub(super) fn end_partition(&mut self) {
+        if let Some((mut digest, hasher)) = self.current.take() {
+            digest.sha256 = format!("{:x}", hasher.finalize());
+            self.partitions.push(digest);
+        }
+    }
+
+    /// Hashes zeros up to `image_size` and returns the manifest
+    pub(super) fn finish(mut self, image_size: u64) -> BuildManifest {
+        self.end_partition();
+        self.zeros_until(image_size);
+        BuildManifest {
+            image: ImageDigest { size: image_size, sha256: format!("{:x}", self.image.finalize()) },
+            partitions: self.partitions,
+        }
+    }
+}
+
+/// Checks that a super image still matches the manifest it was built with.
+///
+/// The image may be raw or sparse; it is read once, front to back, hashing the whole
+/// image and every partition listed in the manifest. Partitions whose data changed
+/// are reported by name, a change anywhere else as `ImageMismatch`.
+pub fn verify_manifest(manifest: &BuildManifest, super_path: &Path) -> Result<(), ManifestError> {
+    let (mut reader, size) = open_raw_or_sparse(super_path)?;
+    if size != manifest.image.size {
+        return Err(ManifestError::SizeMismatch { expected: manifest.image.size, actual: size });
+    }
+
+    let mut partitions = manifest.partitions.clone();
+    partitions.sort_by_key(|p| p.offset);
+    let mut hasher = ImageHasher::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    let mut copy = |hasher: &mut ImageHasher, until: u64| -> io::Result<()> {
+        while hasher.position < until {
+            let len = (until - hasher.position).min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..len])?;
+            hasher.update(&buf[..len]);
+        }
+        Ok(())
+    };
+    for partition in &partitions {
+        copy(&mut hasher, partition.offset)?;
+        hasher.begin_partition(&partition.name, partition.declared_size, partition.offset);
+        copy(&mut hasher, partition.offset + partition.bytes_copied)?;
+        hasher.end_partition();
+    }
+    copy(&mut hasher, size)?;
+    let actual = hasher.finish(size);
+
+    let mismatched: Vec<String> = partitions
+        .iter()
+        .zip(&actual.partitions)
+        .filter(|(expected, actual)| expected.sha256 != actual.sha256)
+        .map(|(expected, _)| expected.name.clone())
+        .collect();
+    if !mismatched.is_empty() {
+        return Err(ManifestError::PartitionMismatch(mismatched));
+    }
+    if actual.image.sha256 != manifest.image.sha256 {
+        return Err(ManifestError::ImageMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::builder::{OutputFormat, SuperImageBuilder};
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn changed_or_missing_data_is_reported() {
+        let dir = test_dir("manifest");
+        let system = pattern(3 * HASH_BUFFER_SIZE + 17, 0x11);
+        let vendor = pattern(6000, 0x22);
+        let config = config_with_images(&dir, &[("system", &system, 4 << 20), ("vendor", &vendor, 1 << 20)]);
+        let output = dir.join("super.img");
+        let report = SuperImageBuilder::new(config.clone()).output(&output).manifest(true).build_with_cancel(&AtomicBool::new(false), |_| {}).unwrap();
+        assert_eq!(report.manifest.as_deref(), Some(manifest_path(&output).as_path()));
+        let manifest = BuildManifest::read(&manifest_path(&output)).unwrap();
+        assert_eq!(manifest.image.size, 16 << 20);
+        let names: Vec<(&str, Option<u64>, u64)> = manifest.partitions.iter().map(|p| (p.name.as_str(), p.declared_size, p.bytes_copied)).collect();
+        assert_eq!(names, [("system", Some(4 << 20), system.len() as u64), ("vendor", Some(1 << 20), vendor.len() as u64)]);
+        assert_eq!(manifest.partitions[1].sha256, format!("{:x}", Sha256::digest(&vendor)));
+        verify_manifest(&manifest, &output).unwrap();
+
+        let built = fs::read(&output).unwrap();
+        let verify_changed = |change: &dyn Fn(&mut Vec<u8>)| {
+            let mut image = built.clone();
+            change(&mut image);
+            fs::write(&output, &image).unwrap();
+            verify_manifest(&manifest, &output)
+        };
+        let vendor_offset = manifest.partitions[1].offset as usize;
+        let result = verify_changed(&|image| image[vendor_offset + 5999] ^= 1);
+        assert!(matches!(&result, Err(ManifestError::PartitionMismatch(names)) if names == &["vendor"]), "{:?}", result);
+        // A partition whose data is gone, as in an image built without it
+        let system_offset = manifest.partitions[0].offset as usize;
+        let result = verify_changed(&|image| image[system_offset..system_offset + system.len()].fill(0));
+        assert!(matches!(&result, Err(ManifestError::PartitionMismatch(names)) if names == &["system"]), "{:?}", result);
+        // The geometry, outside every partition
+        let result = verify_changed(&|image| image[4096] ^= 1);
+        assert!(matches!(result, Err(ManifestError::ImageMismatch)), "{:?}", result);
+        let result = verify_changed(&|image| image.truncate(8 << 20));
+        assert!(matches!(result, Err(ManifestError::SizeMismatch { expected, actual: 8388608 }) if expected == 16 << 20), "{:?}", result);
+        assert!(verify_changed(&|_| {}).is_ok());
+
+        // A sparse image is checked against the raw image it expands to
+        let sparse = dir.join("super.simg");
+        SuperImageBuilder::new(config).output(&sparse).format(OutputFormat::Sparse { max_blob_size: None }).manifest(true).build_with_cancel(&AtomicBool::new(false), |_| {}).unwrap();
+        assert_eq!(BuildManifest::read(&manifest_path(&sparse)).unwrap(), manifest);
+        verify_manifest(&manifest, &sparse).unwrap();
+        assert!(matches!(BuildManifest::read(&dir.join("none.manifest.json")), Err(ManifestError::Io(_))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,184 @@
+use super::{JsonParseError, PartitionConfig};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// Layout version of the configs this version reads and writes, see `config_version`
+pub const CONFIG_VERSION: u32 = 1;
+
+/// One upgrade `read_partition_config` applied to a config in an older layout
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum Migration {
+    /// A size written as a JSON number, now the decimal string
+    NumericSize { field: String },
+    /// A required text field that was missing, now empty
+    MissingField { field: String },
+    /// A field under its old name, e.g. `partitions[3].group` for `group_name`
+    RenamedField { field: String, new_name: String },
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Migration::NumericSize { field } => write!(f, "'{field}' was a number, converted to a size string"),
+            Migration::MissingField { field } => write!(f, "'{field}' was missing, set to an empty string"),
+            Migration::RenamedField { field, new_name } => write!(f, "'{field}' renamed to '{new_name}'"),
+        }
+    }
+}
+
+/// A config together with the migrations applied to read it, see
+/// `read_partition_config_migrated`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MigratedConfig {
+    pub config: PartitionConfig,
+    /// Empty when the config already had the current layout
+    pub migrations: Vec<Migration>,
+}
+
+/// Upgrades a config without `config_version` (or with version 0) from the older
+/// OPlus layouts: sizes as plain numbers, no `nv_text` / `nv_id`, and `group`
+/// instead of `group_name` in partitions. Returns the migrations applied and marks
+/// the config as the current version when there were any.
+///
+/// Versioned configs are left alone, a version newer than `CONFIG_VERSION` is an
+/// `UnsupportedVersion` error.
+pub(super) fn migrate(root: &mut Value) -> Result<Vec<Migration>, JsonParseError> {
+    let Some(object) = root.as_object_mut() else {
+        return Ok(Vec::new());
+    };
+    match object.get("config_version").and_then(Value::as_u64) {
+        Some(version) if version > CONFIG_VERSION as u64 => {
+            return Err(JsonParseError::UnsupportedVersion { version, supported: CONFIG_VERSION });
+        }
+        Some(version) if version > 0 => return Ok(Vec::new()),
+        _ => {}
+    }
+
+    let mut migrations = Vec::new();
+    for key in ["nv_text", "nv_id"] {
+        if !object.contains_key(key) {
+            object.insert(key.to_string(), Value::String(String::new()));
+            migrations.push(Migration::MissingField { field: key.to_string() });
+        }
+    }
+    if let Some(partitions) = object.get_mut("partitions").and_then(Value::as_array_mut) {
+        for (i, partition) in partitions.iter_mut().enumerate() {
+            let Some(partition) = partition.as_object_mut() else { continue };
+            if !partition.contains_key("group_name")
+                && let Some(group) = partition.remove("group")
+            {
+                partition.insert("group_name".to_string(), group);
+                migrations.push(Migration::RenamedField { field: format!("partitions[{i}].group"), new_name: "group_name".to_string() });
+            }
+        }
+    }
+
+    for (field, pointer) in size_fields(root) {
+        if let Some(value) = root.pointer_mut(&pointer)
+            && let Some(number) = value.as_u64()
+        {
+            *value = Value::String(number.to_string());
+            migrations.push(Migration::NumericSize { field });
+        }
+    }
+
+    if !migrations.is_empty() {
+        root["config_version"] = Value::from(CONFIG_VERSION);
+    }
+    Ok(migrations)
+}
+
+/// (field path, JSON pointer) of every size field of a raw config
+fn size_fields(root: &Value) -> Vec<(String, String)> {
+    let count = |pointer: &str| root.pointer(pointer).and_then(Value::as_array).map_or(0, Vec::len);
+    let mut fields = vec![("super_meta.size".to_string(), "/super_meta/size".to_string())];
+    for i in 0..count("/block_devices") {
+        for key in ["block_size", "alignment", "size"] {
+            fields.push((format!("block_devices[{i}].{key}"), format!("/block_devices/{i}/{key}")));
+        }
+    }
+    for i in 0..count("/groups") {
+        fields.push((format!("groups[{i}].maximum_size"), format!("/groups/{i}/maximum_size")));
+    }
+    for i in 0..count("/partitions") {
+        fields.push((format!("partitions[{i}].size"), format!("/partitions/{i}/size")));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::fixture;
+    use crate::super_image_creater::{read_partition_config_migrated, read_partition_config_migrated_from_str};
+
+    fn field(name: &str) -> String {
+        name.to_string()
+    }
+
+    #[test]
+    fn version_0_configs_are_upgraded() {
+        let migrated = read_partition_config_migrated(fixture("super_def_v0.json")).unwrap();
+        let config = &migrated.config;
+        assert_eq!(config.config_version, Some(CONFIG_VERSION));
+        assert_eq!((config.nv_text.as_str(), config.nv_id.as_str()), ("", ""));
+        assert_eq!(config.super_meta.size_bytes(), 65536);
+        assert_eq!(config.block_devices[0].size_bytes(), 9126805504);
+        assert_eq!(config.groups[0].maximum_size_bytes(), Some(9122611200));
+        assert_eq!(config.partitions[0].group_name, "qti_dynamic_partitions_a");
+        assert_eq!(config.partitions[0].size_bytes(), Some(4194304));
+        assert_eq!(config.partitions[1].group_name, "qti_dynamic_partitions_b");
+        assert_eq!(
+            migrated.migrations,
+            [
+                Migration::MissingField { field: field("nv_text") },
+                Migration::MissingField { field: field("nv_id") },
+                Migration::RenamedField { field: field("partitions[0].group"), new_name: field("group_name") },
+                Migration::RenamedField { field: field("partitions[1].group"), new_name: field("group_name") },
+                Migration::NumericSize { field: field("super_meta.size") },
+                Migration::NumericSize { field: field("block_devices[0].block_size") },
+                Migration::NumericSize { field: field("block_devices[0].alignment") },
+                Migration::NumericSize { field: field("block_devices[0].size") },
+                Migration::NumericSize { field: field("groups[0].maximum_size") },
+                Migration::NumericSize { field: field("partitions[0].size") },
+            ]
+        );
+        assert_eq!(migrated.migrations[2].to_string(), "'partitions[0].group' renamed to 'group_name'");
+
+        // Written back it is a version 1 config, which reads without migrations
+        let written = serde_json::to_string(config).unwrap();
+        let again = read_partition_config_migrated_from_str(&written).unwrap();
+        assert_eq!((&again.config, again.migrations.len()), (config, 0));
+
+        // An explicit version 0 is the same as none
+        let mut root: Value = serde_json::from_str(&std::fs::read_to_string(fixture("super_def_v0.json")).unwrap()).unwrap();
+        root["config_version"] = Value::from(0);
+        let explicit = read_partition_config_migrated_from_str(&root.to_string()).unwrap();
+        assert_eq!(explicit, migrated);
+    }
+
+    #[test]
+    fn version_1_configs_are_read_as_they_are() {
+        let migrated = read_partition_config_migrated(fixture("super_def_v1.json")).unwrap();
+        assert_eq!(migrated.migrations, []);
+        // The same config the upgrade of the version 0 fixture gives
+        assert_eq!(migrated.config, read_partition_config_migrated(fixture("super_def_v0.json")).unwrap().config);
+
+        // Versioned configs are not upgraded, so an old field name stays unknown
+        let mut root: Value = serde_json::from_str(&std::fs::read_to_string(fixture("super_def_v1.json")).unwrap()).unwrap();
+        let partition = root["partitions"][1].as_object_mut().unwrap();
+        let group = partition.remove("group_name").unwrap();
+        partition.insert("group".to_string(), group);
+        assert!(read_partition_config_migrated_from_str(&root.to_string()).is_err());
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut root: Value = serde_json::from_str(&std::fs::read_to_string(fixture("super_def_v1.json")).unwrap()).unwrap();
+        root["config_version"] = Value::from(CONFIG_VERSION + 1);
+        let error = read_partition_config_migrated_from_str(&root.to_string()).unwrap_err();
+        assert!(matches!(error, JsonParseError::UnsupportedVersion { version: 2, supported: CONFIG_VERSION }), "{}", error);
+    }
+}
@@ -0,0 +1,916 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use thiserror::Error;
+
+mod builder;
+mod compressed;
+mod diff;
+mod firehose;
+mod fs_type;
+mod hash;
+mod inputs;
+mod json_path;
+mod link;
+mod lp_metadata;
+mod lpmake;
+mod manifest;
+mod migrate;
+mod normalize;
+mod nv;
+mod nv_info;
+mod rawprogram;
+mod size;
+mod slots;
+mod space;
+mod sparse;
+mod split;
+mod strict;
+#[cfg(test)]
+mod test_support;
+mod unpack;
+mod validate;
+mod variants;
+mod verify;
+pub use builder::{
+    BuildError, BuildJob, BuildPhase, BuildProgress, BuildReport, DeviceUsage, GroupUsage, ImageChunk, OutputFormat,
+    PartitionExtent, PlanOptions, PlannedExtent, PlannedPartition, Segment, SegmentKind, SuperImageBuilder, SuperLayout,
+    ZstdStats, build_super_image, build_super_image_as, build_super_image_to, build_super_image_with_progress,
+    estimate_output_size, layout_path, partial_path, plan_super_image,
+};
+pub use compressed::{Compression, decompress_reader, decompress_super, decompressed_size, detect_compression};
+pub use diff::{ConfigChange, ConfigDiff, diff_configs};
+pub use firehose::{
+    PatchEntry, PatchExprError, PatchValue, ProgramEntry, RawProgram, XmlParseError, evaluate_patch_expr, parse_patch_str,
+    parse_patch_xml, parse_rawprogram, parse_rawprogram_str,
+};
+pub use fs_type::{FsType, detect_fs_type, detect_image_fs};
+pub use hash::{DEFAULT_HASH_PARALLELISM, HashMismatch, sha256_file};
+pub use inputs::{
+    AutoSizeError, InputImage, InputIssue, InputProblem, InputReport, IssueSeverity, ResolvedSize, check_inputs, inspect_inputs,
+    resolve_auto_sizes,
+};
+pub use link::{GroupId, LinkError, LinkedConfig};
+pub use lp_metadata::{LpBlockDevice, LpExtent, LpGeometry, LpGroup, LpMetadata, LpMetadataError, LpPartition};
+pub use lpmake::{LpmakeParseError, config_from_lpmake_args, split_command_line};
+pub use manifest::{BuildManifest, ImageDigest, ManifestError, PartitionDigest, manifest_path, verify_manifest};
+pub use migrate::{CONFIG_VERSION, MigratedConfig, Migration};
+pub use nv::{AppliedNv, NvChoice, NvEntry, NvError, NvImage, NvMapping, read_nv_mapping};
+pub use nv_info::{NvInfo, NvParseError};
+pub use rawprogram::{PhysicalPartition, RawprogramError, rawprogram_entries, write_rawprogram};
+pub use size::{AUTO_SIZE, ByteSize, CowReserve, SizeParseError, parse_optional_size, parse_size};
+pub use slots::{Slot, SlotMode};
+pub use space::{SizeEstimate, SpaceVerdict, available_space};
+pub use sparse::{SparseError, SparseImage, SparseReader, SparseStats, SparseWriter, expand_sparse, open_raw_or_sparse};
+pub use split::{
+    ChunkWriter, FAT32_CHUNK_SIZE, SplitChunk, SplitError, SplitIndex, join_image, split_image, split_index_path,
+};
+pub use unpack::{UnpackError, config_from_super_dump, inspect_super, read_super_metadata, unpack_super, unpack_super_image, unpack_super_image_with_cancel};
+pub use validate::{
+    BlockDeviceError, BudgetViolation, GroupLimit, LimitSource, SuperSizeError, ValidationEntry, ValidationError,
+    ValidationIssue, ValidationReport, ValidationWarning, validate,
+};
+pub use variants::{BlockDeviceOverride, GroupOverride, Variant, VariantError, resolve_variant};
+pub use verify::{VerifyError, verify_super, verify_super_data};
+
+// ======================== 1. Custom Error Type (Optional, improves error handling) ========================
+#[derive(Error, Debug)]
+pub enum JsonParseError {
+    /// From reading a `Read` source, which has no path
+    #[error("File operation error: {0}")]
+    FileError(#[from] std::io::Error),
+    /// Opening, reading or writing the config file at `path` failed
+    #[error("File operation error on '{}': {source}", path.display())]
+    FileErrorAt { path: PathBuf, source: std::io::Error },
+    /// `line` and `column` are 1-based (0 when unknown, e.g. for serialization errors),
+    /// `path` is set when the JSON was read from a file. `field_path` (for data
+    /// errors, like `partitions[14].group_name`) and `snippet` (the JSON around the
+    /// error) are set by `with_source`.
+    #[error("JSON parsing error{}{}: {source}{}", describe_path(path), describe_field(field_path), describe_snippet(snippet))]
+    JsonError {
+        source: serde_json::Error,
+        line: usize,
+        column: usize,
+        path: Option<PathBuf>,
+        field_path: Option<String>,
+        snippet: Option<String>,
+    },
+    #[error("Invalid size in field '{field}'{}: '{value}' ({source})", describe_path(path))]
+    InvalidSize {
+        field: String,
+        value: String,
+        source: SizeParseError,
+        path: Option<PathBuf>,
+    },
+    /// `config_version` is newer than `CONFIG_VERSION`, the config comes from a newer
+    /// version of the toolkit
+    #[error("Config version {version} is not supported (up to {supported}), update the toolkit to read it")]
+    UnsupportedVersion { version: u64, supported: u32 },
+}
+
+// Keeps `?` working on serde_json results; the location is captured here
+impl From<serde_json::Error> for JsonParseError {
+    fn from(source: serde_json::Error) -> Self {
+        JsonParseError::JsonError {
+            line: source.line(),
+            column: source.column(),
+            source,
+            path: None,
+            field_path: None,
+            snippet: None,
+        }
+    }
+}
+
+impl JsonParseError {
+    /// Attaches the file the JSON came from to a `JsonError` or `InvalidSize`, and
+    /// turns a `FileError` into a `FileErrorAt` naming it
+    pub fn with_path<P: AsRef<Path>>(self, file: P) -> Self {
+        let file = file.as_ref().to_path_buf();
+        match self {
+            JsonParseError::FileError(source) => JsonParseError::FileErrorAt { path: file, source },
+            mut e => {
+                if let JsonParseError::JsonError { path, .. } | JsonParseError::InvalidSize { path, .. } = &mut e {
+                    *path = Some(file);
+                }
+                e
+            }
+        }
+    }
+
+    /// Fills in the `field_path` and `snippet` of a `JsonError` from the JSON text it
+    /// was parsed from
+    pub fn with_source(mut self, content: &str) -> Self {
+        if let JsonParseError::JsonError { source, line, column, field_path, snippet, .. } = &mut self
+            && *line > 0
+        {
+            let offset = json_path::offset_of(content, *line, *column);
+            if source.is_data() {
+                *field_path = json_path::field_path_at(content, offset, &source.to_string());
+            }
+            *snippet = json_path::snippet_at(content, offset);
+        }
+        self
+    }
+
+    /// 1-based (line, column) of a JSON syntax or data error, so the UI can jump to it
+    pub fn location(&self) -> Option<(usize, usize)> {
+        match self {
+            JsonParseError::JsonError { line, column, .. } if *line > 0 => Some((*line, *column)),
+            _ => None,
+        }
+    }
+}
+
+// serde_json already appends "at line X column Y" to its message, only the file is added
+fn describe_path(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!(" in '{}'", path.display()),
+        None => String::new(),
+    }
+}
+
+fn describe_field(field_path: &Option<String>) -> String {
+    match field_path {
+        Some(field_path) => format!(" at {field_path}"),
+        None => String::new(),
+    }
+}
+
+fn describe_snippet(snippet: &Option<String>) -> String {
+    match snippet {
+        Some(snippet) => format!(", near `{snippet}`"),
+        None => String::new(),
+    }
+}
+
+/// What Tauri commands send to the frontend for a `JsonParseError`, with the same
+/// `kind` tag as the other error types. `message` is the `Display` text, the rest
+/// what the UI needs to point at the problem.
+#[derive(Serialize)]
+#[serde(tag = "kind")]
+enum JsonParseErrorDto<'a> {
+    FileError {
+        message: String,
+        path: Option<&'a Path>,
+    },
+    JsonError {
+        message: String,
+        /// `syntax`, `data`, `eof` or `io`, as serde_json classifies it
+        category: &'static str,
+        line: usize,
+        column: usize,
+        path: Option<&'a Path>,
+        field_path: Option<&'a str>,
+        snippet: Option<&'a str>,
+    },
+    InvalidSize {
+        message: String,
+        field: &'a str,
+        value: &'a str,
+        path: Option<&'a Path>,
+    },
+    UnsupportedVersion {
+        message: String,
+        version: u64,
+        supported: u32,
+    },
+}
+
+impl Serialize for JsonParseError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let message = self.to_string();
+        let dto = match self {
+            JsonParseError::FileError(_) => JsonParseErrorDto::FileError { message, path: None },
+            JsonParseError::FileErrorAt { path, .. } => JsonParseErrorDto::FileError { message, path: Some(path) },
+            JsonParseError::JsonError { source, line, column, path, field_path, snippet } => JsonParseErrorDto::JsonError {
+                message,
+                category: match source.classify() {
+                    serde_json::error::Category::Io => "io",
+                    serde_json::error::Category::Syntax => "syntax",
+                    serde_json::error::Category::Data => "data",
+                    serde_json::error::Category::Eof => "eof",
+                },
+                line: *line,
+                column: *column,
+                path: path.as_deref(),
+                field_path: field_path.as_deref(),
+                snippet: snippet.as_deref(),
+            },
+            JsonParseError::InvalidSize { field, value, path, .. } => {
+                JsonParseErrorDto::InvalidSize { message, field, value, path: path.as_deref() }
+            }
+            JsonParseError::UnsupportedVersion { version, supported } => {
+                JsonParseErrorDto::UnsupportedVersion { message, version: *version, supported: *supported }
+            }
+        };
+        dto.serialize(serializer)
+    }
+}
+
+// ======================== 2. Define Structs Matching JSON Structure ========================
+/// Top-level struct corresponding to the entire JSON configuration
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")] // Ensure field names match JSON's snake_case convention
+pub struct PartitionConfig {
+    // Optional: layout version, see `CONFIG_VERSION`. Stock configs have none, it is
+    // only written when set (by a migration from an older layout, see `migrate`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_version: Option<u32>,
+    pub super_meta: SuperMeta,
+    pub nv_text: String,
+    pub block_devices: Vec<BlockDevice>,
+    pub groups: Vec<Group>,
+    pub nv_id: String,
+    pub partitions: Vec<Partition>,
+    // Optional flag: sets the Virtual A/B header flag. Missing means set, as every
+    // build did before the flag existed (lpmake was called with `--virtual-ab`), see
+    // `is_virtual_ab`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtual_ab: Option<bool>,
+    // Optional flag: retrofit dynamic partitions, built for the slot of the `_a` /
+    // `_b` block devices with slot-suffixed metadata; only written when true
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retrofit: bool,
+    // Optional: number of metadata slots (lpmake `--metadata-slots`), missing means
+    // `DEFAULT_METADATA_SLOTS`, see `metadata_slot_count`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_slots: Option<u32>,
+    // Optional: COW headroom for every group without its own `cow_reserve`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cow_reserve: Option<CowReserve>,
+    // Optional section: per storage size overrides, see `resolve_variant`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+}
+
+/// Struct for the "super_meta" sub-object in JSON
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SuperMeta {
+    pub path: String,
+    pub size: ByteSize, // JSON uses string-encoded sizes, parsed on load (see `parse_size`)
+}
+
+/// Struct for elements in the "block_devices" array
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct BlockDevice {
+    pub block_size: ByteSize,
+    pub name: String,
+    pub alignment: ByteSize,
+    pub size: ByteSize,
+}
+
+/// Struct for elements in the "groups" array (maximum_size is optional)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Group {
+    pub name: String,
+    // Missing or empty in JSON: None (no limit), omitted again when serialized
+    #[serde(default, deserialize_with = "size::deserialize_optional", skip_serializing_if = "Option::is_none")]
+    pub maximum_size: Option<ByteSize>,
+    // Set when maximum_size was filled in by `resolve_group_limits` rather than the
+    // user, so it is recomputed next time; only written when true
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub maximum_size_computed: bool,
+    // Optional: space the partitions must leave free for OTA snapshots, a size or a
+    // percentage of maximum_size; overrides the config-wide `cow_reserve`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cow_reserve: Option<CowReserve>,
+}
+
+/// Struct for elements in the "partitions" array (path/size are optional)
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Partition {
+    pub is_dynamic: bool,
+    pub name: String,
+    pub group_name: String,
+    // Optional field: empty string if missing. `<payload.bin>#<partition>` takes the
+    // image from an OTA payload, see `open_raw_or_sparse`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    // Optional field: None if missing or empty, "auto" to measure the image file
+    #[serde(default, deserialize_with = "size::deserialize_partition_size", skip_serializing_if = "Option::is_none")]
+    pub size: Option<ByteSize>,
+    // Optional field: SHA-256 (hex) the image file must have, see `verify_hashes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_sha256: Option<String>,
+    // Optional field: filesystem the image must have, see `inspect_inputs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_fs: Option<FsType>,
+    // Optional flag: a size without a path allocates the partition and leaves it
+    // zero-filled instead of failing validation; only written when true
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub zero_fill: bool,
+}
+
+/// The stock `super` block device: 4 KiB blocks, 1 MiB alignment, size still 0
+impl Default for BlockDevice {
+    fn default() -> Self {
+        BlockDevice {
+            block_size: ByteSize::new(4096),
+            name: "super".to_string(),
+            alignment: ByteSize::new(1024 * 1024),
+            size: ByteSize::default(),
+        }
+    }
+}
+
+/// A dynamic partition without name, group, image or size
+impl Default for Partition {
+    fn default() -> Self {
+        Partition {
+            is_dynamic: true,
+            name: String::new(),
+            group_name: String::new(),
+            path: String::new(),
+            size: None,
+            expected_sha256: None,
+            expected_fs: None,
+            zero_fill: false,
+        }
+    }
+}
+
+impl PartitionConfig {
+    /// Blank config for a new super image, to be filled in with `add_block_device`,
+    /// `add_group` and `add_partition` and saved with `write_partition_config`.
+    ///
+    /// `super_path` and `super_size` are `super_meta.path` (the image to build) and
+    /// `super_meta.size` (the room for one metadata copy, 65536 in stock configs).
+    pub fn new(super_path: &str, super_size: u64) -> Self {
+        PartitionConfig {
+            super_meta: SuperMeta { path: super_path.to_string(), size: ByteSize::new(super_size) },
+            ..Default::default()
+        }
+    }
+
+    pub fn add_block_device(&mut self, device: BlockDevice) -> &mut Self {
+        self.block_devices.push(device);
+        self
+    }
+
+    pub fn add_group(&mut self, group: Group) -> &mut Self {
+        self.groups.push(group);
+        self
+    }
+
+    pub fn add_partition(&mut self, partition: Partition) -> &mut Self {
+        self.partitions.push(partition);
+        self
+    }
+}
+
+/// Metadata slots of a config without `metadata_slots`, one per A/B slot as lpmake
+/// builds by default
+pub const DEFAULT_METADATA_SLOTS: u32 = 2;
+
+// ======================== 3. Parsed Size Accessors ========================
+impl PartitionConfig {
+    /// Number of LP metadata slots the super image is built with.
+    ///
+    /// The lpmake `--metadata-slots` equivalent: `metadata_slots` if set, otherwise
+    /// `DEFAULT_METADATA_SLOTS`, whatever the groups are.
+    pub fn metadata_slot_count(&self) -> u32 {
+        self.metadata_slots.unwrap_or(DEFAULT_METADATA_SLOTS)
+    }
+
+    /// Whether the metadata gets the Virtual A/B header flag: `virtual_ab` if set,
+    /// otherwise on, except for retrofit configs (retrofit devices launched before
+    /// Virtual A/B existed)
+    pub fn is_virtual_ab(&self) -> bool {
+        self.virtual_ab.unwrap_or(!self.retrofit)
+    }
+}
+
+/// Bucket of `PartitionConfig::partitions_by_group` for partitions whose
+/// `group_name` matches no group
+pub const ORPHAN_GROUP: &str = "orphans";
+
+impl PartitionConfig {
+    /// Partitions bucketed by `group_name`, for display nested under their group.
+    ///
+    /// Every group gets a bucket, empty ones too, in the order of `groups`, with
+    /// partitions in config order. Partitions of no known group are collected in a
+    /// last `ORPHAN_GROUP` bucket, which is left out when there are none.
+    pub fn partitions_by_group(&self) -> Vec<(&str, Vec<&Partition>)> {
+        let mut buckets: Vec<(&str, Vec<&Partition>)> = self.groups.iter().map(|g| (g.name.as_str(), Vec::new())).collect();
+        let mut orphans = Vec::new();
+        for partition in &self.partitions {
+            match buckets.iter_mut().find(|(name, _)| *name == partition.group_name) {
+                Some((_, partitions)) => partitions.push(partition),
+                None => orphans.push(partition),
+            }
+        }
+        if !orphans.is_empty() {
+            buckets.push((ORPHAN_GROUP, orphans));
+        }
+        buckets
+    }
+
+    /// Partitions whose image the build copies (see `Partition::has_data`), in
+    /// config order
+    pub fn data_partitions(&self) -> impl Iterator<Item = &Partition> {
+        self.partitions.iter().filter(|p| p.has_data())
+    }
+
+    /// The `data_partitions` whose image is not on disk. A `payload.bin#system` path
+    /// counts as there when the payload is, whether it holds the partition is up to
+    /// `inspect_inputs`.
+    pub fn missing_image_files(&self) -> Vec<&Partition> {
+        self.data_partitions()
+            .filter(|p| {
+                let path = p.path.trim();
+                !Path::new(path).exists()
+                    && !crate::payload::split_payload_path(path).is_some_and(|(payload, _)| Path::new(payload).is_file())
+            })
+            .collect()
+    }
+}
+
+impl SuperMeta {
+    /// Value of `size` in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.size.as_u64()
+    }
+}
+
+impl BlockDevice {
+    /// Value of `block_size` in bytes
+    pub fn block_size_bytes(&self) -> u64 {
+        self.block_size.as_u64()
+    }
+
+    /// Value of `alignment` in bytes
+    pub fn alignment_bytes(&self) -> u64 {
+        self.alignment.as_u64()
+    }
+
+    /// Value of `size` in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.size.as_u64()
+    }
+}
+
+impl Group {
+    /// Value of `maximum_size` in bytes, `None` when the field is empty (no limit)
+    pub fn maximum_size_bytes(&self) -> Option<u64> {
+        self.maximum_size.as_ref().map(ByteSize::as_u64)
+    }
+}
+
+impl Partition {
+    /// Value of `size` in bytes, `None` when the field is empty. An unresolved
+    /// `"auto"` size is 0, see `resolve_auto_sizes`.
+    pub fn size_bytes(&self) -> Option<u64> {
+        self.size.as_ref().map(ByteSize::as_u64)
+    }
+
+    /// Whether `size` is `"auto"` and still has to be measured from the image
+    pub fn has_auto_size(&self) -> bool {
+        self.size.as_ref().is_some_and(ByteSize::is_auto)
+    }
+
+    /// Whether this is a placeholder like the stock `my_company` or `odm_dlkm`: no
+    /// `path` and no (or a zero) size. It gets a metadata entry without extents.
+    pub fn is_placeholder(&self) -> bool {
+        self.path.trim().is_empty() && !self.has_auto_size() && self.size_bytes().unwrap_or(0) == 0
+    }
+
+    /// Whether the build copies data into the partition: it is dynamic and has a
+    /// `path`. Others are only reserved (zero-filled) in the super image.
+    pub fn has_data(&self) -> bool {
+        self.is_dynamic && !self.path.trim().is_empty()
+    }
+}
+
+impl PartitionConfig {
+    /// Makes relative image paths (`super_meta.path` and every partition `path`)
+    /// relative to `base_dir`, usually the directory of the config file, instead of
+    /// the working directory.
+    ///
+    /// Absolute paths are kept as they are, and so are Windows drive and UNC paths
+    /// (`C:\...`, `\\server\...`) on every platform. Empty paths stay empty. In
+    /// relative paths `\` separates components on every platform as well, so configs
+    /// written on Windows resolve elsewhere too.
+    pub fn resolve_paths(&mut self, base_dir: &Path) {
+        self.super_meta.path = resolve_path(&self.super_meta.path, base_dir);
+        for partition in &mut self.partitions {
+            partition.path = resolve_path(&partition.path, base_dir);
+        }
+    }
+}
+
+fn resolve_path(path: &str, base_dir: &Path) -> String {
+    let trimmed = path.trim();
+    if trimmed.is_empty() || is_absolute_path(trimmed) {
+        return path.to_string();
+    }
+    base_dir.join(trimmed.replace('\\', "/")).to_string_lossy().to_string()
+}
+
+/// Absolute on this platform, or rooted in Windows terms (drive, UNC or `\`)
+fn is_absolute_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/');
+    Path::new(path).is_absolute() || path.starts_with(['/', '\\']) || has_drive
+}
+
+// ======================== 4. Core Function: Read JSON and Parse to Struct ========================
+/// Reads a JSON file from the specified path and parses it into a PartitionConfig struct
+///
+/// Configs in an older OPlus layout are upgraded while they are read, see
+/// `read_partition_config_migrated` for which upgrades were applied.
+/// 
+/// # Arguments
+/// * `path` - Path to the JSON configuration file (e.g., "partition_config.json")
+/// 
+/// # Returns
+/// * `Ok(PartitionConfig)` - Successfully parsed configuration
+/// * `Err(JsonParseError)` - Failed to open file, parse JSON or parse one of the size
+///   fields, or the config is from a newer layout version
+pub fn read_partition_config<P: AsRef<Path>>(path: P) -> Result<PartitionConfig, JsonParseError> {
+    // 1. Open the JSON file
+    let file = fs::File::open(&path).map_err(|e| JsonParseError::from(e).with_path(&path))?;
+
+    // 2. Parse it like any other JSON source, naming the file in JSON errors
+    parse_partition_config(file).map_err(|e| e.with_path(&path))
+}
+
+/// Async variant of `read_partition_config` for Tauri commands, reading the file with
+/// `tokio::fs` so the runtime is not blocked.
+///
+/// Cancellation-safe: dropping the future abandons the read, nothing is written.
+pub async fn read_partition_config_async<P: AsRef<Path>>(path: P) -> Result<PartitionConfig, JsonParseError> {
+    let content = tokio::fs::read_to_string(&path).await.map_err(|e| JsonParseError::from(e).with_path(&path))?;
+    parse_partition_config_str(&content).map_err(|e| e.with_path(&path))
+}
+
+/// Reads a JSON configuration from any `Read` source (in-memory buffer, archive entry,
+/// IPC payload, or the bytes of a dropped file as `&[u8]`) and parses it into a
+/// PartitionConfig struct, with the same upgrades and error details as
+/// `read_partition_config`
+///
+/// The whole input is read before parsing, so the size fields can be looked up again
+/// when one of them is invalid. Input that is not UTF-8 is a `FileError`.
+pub fn parse_partition_config<R: Read>(mut reader: R) -> Result<PartitionConfig, JsonParseError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    parse_partition_config_str(&content)
+}
+
+/// Parses a JSON configuration held in a string into a PartitionConfig struct
+pub fn parse_partition_config_str(content: &str) -> Result<PartitionConfig, JsonParseError> {
+    read_partition_config_migrated_from_str(content).map(|migrated| migrated.config)
+}
+
+/// `content` without a leading UTF-8 byte order mark, which Windows Notepad writes
+/// and serde_json rejects
+pub(super) fn strip_bom(content: &str) -> &str {
+    content.strip_prefix('\u{feff}').unwrap_or(content)
+}
+
+/// Same as `read_partition_config`, also returning the upgrades that were applied to
+/// a config in an older layout (see `Migration`), e.g. to tell the user before the
+/// config is saved in the current layout
+pub fn read_partition_config_migrated<P: AsRef<Path>>(path: P) -> Result<MigratedConfig, JsonParseError> {
+    let content = fs::read_to_string(&path).map_err(|e| JsonParseError::from(e).with_path(&path))?;
+    read_partition_config_migrated_from_str(&content).map_err(|e| e.with_path(&path))
+}
+
+/// String variant of `read_partition_config_migrated`
+pub fn read_partition_config_migrated_from_str(content: &str) -> Result<MigratedConfig, JsonParseError> {
+    let content = strip_bom(content);
+    if let Some((root, migrations)) = migrated_value(content)? {
+        // Parsed from the upgraded value, so errors have no location in the file
+        let config = PartitionConfig::deserialize(&root)
+            .map_err(|e| find_invalid_size(&root).unwrap_or_else(|| JsonParseError::from(e)))?;
+        return Ok(MigratedConfig { config, migrations });
+    }
+    // Parse JSON into PartitionConfig struct (serde_json auto-maps fields)
+    let config = match serde_json::from_str(content) {
+        Ok(config) => config,
+        // A bad size only surfaces as a generic serde message, so look up which field it was
+        Err(e) => {
+            let invalid_size = serde_json::from_str(content).ok().and_then(|root| find_invalid_size(&root));
+            return Err(invalid_size.unwrap_or_else(|| JsonParseError::from(e).with_source(content)));
+        }
+    };
+    Ok(MigratedConfig { config, migrations: Vec::new() })
+}
+
+/// The raw config upgraded to the current layout, `None` when it already has it (or
+/// is not valid JSON at all, which the normal parse reports with its location)
+fn migrated_value(content: &str) -> Result<Option<(Value, Vec<Migration>)>, JsonParseError> {
+    let Ok(mut root) = serde_json::from_str::<Value>(content) else {
+        return Ok(None);
+    };
+    let migrations = migrate::migrate(&mut root)?;
+    Ok((!migrations.is_empty()).then_some((root, migrations)))
+}
+
+/// Same as `read_partition_config`, but keys that are not part of the config format
+/// are errors instead of being ignored, e.g. a typo like `maximum_szie` or a config
+/// meant for another tool.
+///
+/// The error is a `JsonError` naming the key and where it is ("unknown field `foo`,
+/// expected `path` or `size` at line 3 column 9"). `read_partition_config` stays
+/// lenient, so configs from newer versions with extra fields still load there.
+/// Older layouts are checked after they are upgraded, so their old field names pass.
+pub fn read_partition_config_strict<P: AsRef<Path>>(path: P) -> Result<PartitionConfig, JsonParseError> {
+    let content = fs::read_to_string(&path).map_err(|e| JsonParseError::from(e).with_path(&path))?;
+    read_partition_config_strict_from_str(&content).map_err(|e| e.with_path(&path))
+}
+
+/// Strict variant of `parse_partition_config_str`, see `read_partition_config_strict`
+pub fn read_partition_config_strict_from_str(content: &str) -> Result<PartitionConfig, JsonParseError> {
+    let content = strip_bom(content);
+    let config = parse_partition_config_str(content)?;
+    match migrated_value(content)? {
+        Some((root, _)) => strict::check_known_fields_in(&root)?,
+        None => strict::check_known_fields(content).map_err(|e| JsonParseError::from(e).with_source(content))?,
+    }
+    Ok(config)
+}
+
+/// Walks the size fields of a raw JSON config and reports the first one that fails to parse
+fn find_invalid_size(root: &Value) -> Option<JsonParseError> {
+    let entries = |key: &str| root[key].as_array().map(|v| v.iter().enumerate().collect::<Vec<_>>()).unwrap_or_default();
+
+    // (field path, JSON value, may be empty)
+    let mut fields: Vec<(String, &Value, bool)> = Vec::new();
+    fields.push(("super_meta.size".to_string(), &root["super_meta"]["size"], false));
+    for (i, device) in entries("block_devices") {
+        for key in ["block_size", "alignment", "size"] {
+            fields.push((format!("block_devices[{}].{}", i, key), &device[key], false));
+        }
+    }
+    for (i, group) in entries("groups") {
+        fields.push((format!("groups[{}].maximum_size", i), &group["maximum_size"], true));
+    }
+    // Percentages are checked by the deserializer, only sizes are reported here
+    let mut reserves = vec![("cow_reserve".to_string(), &root["cow_reserve"])];
+    reserves.extend(entries("groups").into_iter().map(|(i, group)| (format!("groups[{}].cow_reserve", i), &group["cow_reserve"])));
+    for (field, value) in reserves {
+        if value.as_str().is_some_and(|s| !s.trim().ends_with('%')) {
+            fields.push((field, value, false));
+        }
+    }
+    for (i, partition) in entries("partitions") {
+        if partition["size"].as_str().is_some_and(|s| s.trim().eq_ignore_ascii_case(AUTO_SIZE)) {
+            continue;
+        }
+        fields.push((format!("partitions[{}].size", i), &partition["size"], true));
+    }
+    for (i, variant) in entries("variants") {
+        fields.push((format!("variants[{}].super_meta_size", i), &variant["super_meta_size"], false));
+        let overrides = |key: &str| variant[key].as_array().map(|v| v.iter().enumerate().collect::<Vec<_>>()).unwrap_or_default();
+        for (j, device) in overrides("block_devices") {
+            fields.push((format!("variants[{}].block_devices[{}].size", i, j), &device["size"], false));
+        }
+        for (j, group) in overrides("groups") {
+            fields.push((format!("variants[{}].groups[{}].maximum_size", i, j), &group["maximum_size"], false));
+        }
+    }
+
+    for (field, value, optional) in fields {
+        let Some(text) = value.as_str() else { continue };
+        let result = if optional {
+            parse_optional_size(text).map(|_| ())
+        } else {
+            parse_size(text).map(|_| ())
+        };
+        if let Err(source) = result {
+            return Some(JsonParseError::InvalidSize { field, value: text.to_string(), source, path: None });
+        }
+    }
+    None
+}
+
+/// Serializes a PartitionConfig and writes it to the specified path as JSON
+///
+/// Field names and ordering follow the stock OPlus `super_def.*.json` files, sizes
+/// keep the spelling they were read with, and the optional fields (`maximum_size`,
+/// `path`, `size`) are omitted when empty. The output is pretty-printed so it can
+/// be diffed against the original file.
+///
+/// # Arguments
+/// * `path` - Destination of the JSON configuration file (overwritten if it exists)
+/// * `config` - Configuration to write
+pub fn write_partition_config<P: AsRef<Path>>(path: P, config: &PartitionConfig) -> Result<(), JsonParseError> {
+    let json = serde_json::to_string_pretty(config)?;
+    fs::write(&path, json).map_err(|e| JsonParseError::from(e).with_path(&path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{SAMPLE, test_dir};
+
+    #[test]
+    fn write_partition_config_round_trips_an_edited_config() {
+        let dir = test_dir("write-config");
+        let source = dir.join("super_def.json");
+        fs::write(&source, SAMPLE).unwrap();
+        let mut config = read_partition_config(&source).unwrap();
+        config.add_partition(Partition {
+            name: "vendor_a".into(),
+            group_name: "qti_dynamic_partitions_a".into(),
+            path: "IMAGES/vendor.img".into(),
+            size: Some(ByteSize::new(1 << 20)),
+            ..Default::default()
+        });
+
+        let output = dir.join("out.json");
+        write_partition_config(&output, &config).unwrap();
+        let json = fs::read_to_string(&output).unwrap();
+        assert!(json.contains("\"maximum_size\": \"8.25GiB\""));
+        assert!(json.contains("\"size\": \"4096k\""));
+        assert_eq!(read_partition_config(&output).unwrap(), config);
+    }
+
+    #[test]
+    fn stock_config_round_trips_without_empty_optional_fields() {
+        let dir = test_dir("stock-config");
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        let output = dir.join("super_def.json");
+        write_partition_config(&output, &config).unwrap();
+        let json = fs::read_to_string(&output).unwrap();
+
+        assert_eq!(read_partition_config(&output).unwrap(), config);
+        assert!(!json.contains("\"\""), "empty optional field written:\n{}", json);
+        assert_eq!(json.matches("\"maximum_size\"").count(), 1);
+        assert_eq!(json.matches("\"path\"").count(), 2);
+        // Same top-level order as the stock files, one field per line
+        let keys: Vec<&str> = json.lines().filter(|line| line.starts_with("  \"")).map(|line| line.trim().split('"').nth(1).unwrap()).collect();
+        assert_eq!(keys, ["super_meta", "nv_text", "block_devices", "groups", "nv_id", "partitions"]);
+    }
+
+    #[test]
+    fn configs_parse_from_readers_and_strings() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        assert_eq!(parse_partition_config(SAMPLE.as_bytes()).unwrap(), config);
+        assert_eq!(parse_partition_config(std::io::Cursor::new(SAMPLE.to_string().into_bytes())).unwrap(), config);
+        // A byte order mark from Notepad is skipped
+        assert_eq!(parse_partition_config_str(&format!("\u{feff}{}", SAMPLE)).unwrap(), config);
+
+        let error = parse_partition_config(&[0xff, 0xfe, b'{'][..]).unwrap_err();
+        assert!(matches!(error, JsonParseError::FileError(_)), "{:?}", error);
+        let error = parse_partition_config(SAMPLE.replace("4096k", "12XB").as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("partitions[0].size"), "{}", error);
+        let error = parse_partition_config_str("{\"super_meta\": ").unwrap_err();
+        assert!(error.location().is_some(), "{:?}", error);
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_config_directory() {
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        let paths = ["IMAGES/system.img", "IMAGES\\vendor.img", "./sub/./odm.img", "", "C:\\fw\\product.img", "\\\\server\\share\\my_stock.img", "/abs/system_ext.img"];
+        config.partitions = paths.iter().map(|path| Partition { path: path.to_string(), ..config.partitions[0].clone() }).collect();
+        let base_dir = Path::new("firmware").join("META");
+        config.resolve_paths(&base_dir);
+
+        let resolved: Vec<&str> = config.partitions.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(Path::new(&config.super_meta.path), base_dir.join("IMAGES").join("super.img"));
+        // Unix and Windows separators in relative paths
+        assert_eq!(Path::new(resolved[0]), base_dir.join("IMAGES").join("system.img"));
+        assert_eq!(Path::new(resolved[1]), base_dir.join("IMAGES").join("vendor.img"));
+        assert_eq!(Path::new(resolved[2]), base_dir.join("sub").join("odm.img"));
+        // Empty and absolute paths, of either platform, are kept
+        assert_eq!(resolved[3..], paths[3..]);
+    }
+
+    #[test]
+    fn a_constructed_config_validates_and_serializes() {
+        let mut config = PartitionConfig::new("out/super.img", 65536);
+        config
+            .add_block_device(BlockDevice { size: ByteSize::new(64 << 20), ..Default::default() })
+            .add_group(Group { name: "main".into(), maximum_size: Some(ByteSize::new(32 << 20)), ..Default::default() })
+            .add_partition(Partition { name: "system".into(), group_name: "main".into(), path: "system.img".into(), size: Some(ByteSize::new(1 << 20)), ..Default::default() });
+        assert_eq!(validate(&config), Ok(()));
+        assert!(PartitionConfig::default().partitions.is_empty());
+
+        let path = test_dir("new-config").join("super_def.json");
+        write_partition_config(&path, &config).unwrap();
+        assert_eq!(read_partition_config_strict(&path).unwrap(), config);
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["super_meta"]["path"], "out/super.img");
+        assert_eq!(json["block_devices"][0]["name"], "super");
+        assert_eq!(json["block_devices"][0]["alignment"], "1048576");
+        assert_eq!(json["partitions"][0]["is_dynamic"], true);
+    }
+
+    /// Names of every bucket of `partitions_by_group` and of the partitions in it
+    fn group_names(config: &PartitionConfig) -> Vec<(&str, Vec<&str>)> {
+        config.partitions_by_group().into_iter().map(|(group, partitions)| (group, partitions.iter().map(|p| p.name.as_str()).collect())).collect()
+    }
+
+    #[test]
+    fn partitions_of_unknown_groups_are_orphans() {
+        let mut config = PartitionConfig::new("super.img", 65536);
+        config
+            .add_group(Group { name: "main_a".into(), ..Default::default() })
+            .add_group(Group { name: "main_b".into(), ..Default::default() })
+            .add_partition(Partition { name: "system_a".into(), group_name: "main_a".into(), ..Default::default() })
+            .add_partition(Partition { name: "odm".into(), group_name: "gone".into(), ..Default::default() })
+            .add_partition(Partition { name: "vendor_a".into(), group_name: "main_a".into(), ..Default::default() });
+        assert_eq!(group_names(&config), [("main_a", vec!["system_a", "vendor_a"]), ("main_b", vec![]), (ORPHAN_GROUP, vec!["odm"])]);
+
+        config.partitions.remove(1);
+        assert_eq!(group_names(&config), [("main_a", vec!["system_a", "vendor_a"]), ("main_b", vec![])]);
+    }
+
+    #[test]
+    fn empty_groups_keep_their_bucket() {
+        let config = parse_partition_config_str(SAMPLE).unwrap();
+        assert_eq!(group_names(&config), [("qti_dynamic_partitions_a", vec!["system_a"]), ("qti_dynamic_partitions_b", vec!["system_b", "odm_b"])]);
+
+        let mut config = PartitionConfig::new("super.img", 65536);
+        config.add_group(Group { name: "main".into(), ..Default::default() });
+        assert_eq!(group_names(&config), [("main", vec![])]);
+        assert!(PartitionConfig::default().partitions_by_group().is_empty());
+    }
+
+    #[test]
+    fn data_partitions_and_missing_images_of_a_mixed_config() {
+        let dir = test_dir("data-partitions");
+        fs::write(dir.join("system.img"), b"system").unwrap();
+        fs::write(dir.join("payload.bin"), b"CrAU").unwrap();
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.partitions[0].path = dir.join("system.img").to_str().unwrap().into();
+        config.partitions[1].path = "  ".into();
+        config.partitions[2].path = dir.join("odm.img").to_str().unwrap().into();
+        let partition = |name: &str, path: String, is_dynamic| Partition { name: name.into(), group_name: "qti_dynamic_partitions_a".into(), path, is_dynamic, ..Default::default() };
+        config.add_partition(partition("vendor_a", format!("{}#vendor", dir.join("payload.bin").display()), true));
+        config.add_partition(partition("product_a", format!("{}#product", dir.join("other.bin").display()), true));
+        config.add_partition(partition("boot_a", dir.join("boot.img").to_str().unwrap().into(), false));
+
+        let names = |partitions: Vec<&Partition>| partitions.into_iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(config.data_partitions().collect()), ["system_a", "odm_b", "vendor_a", "product_a"]);
+        assert_eq!(names(config.missing_image_files()), ["odm_b", "product_a"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_errors_name_the_file() {
+        let dir = test_dir("file-errors");
+        let missing = dir.join("super_def.missing.json");
+        let error = read_partition_config(&missing).unwrap_err();
+        assert!(matches!(&error, JsonParseError::FileErrorAt { path, .. } if *path == missing), "{:?}", error);
+        assert!(error.to_string().contains(&*missing.to_string_lossy()), "{}", error);
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["kind"], "FileError");
+        assert_eq!(json["path"], missing.to_str().unwrap());
+
+        let unwritable = dir.join("no-such-dir").join("super_def.json");
+        let error = write_partition_config(&unwritable, &parse_partition_config_str(SAMPLE).unwrap()).unwrap_err();
+        assert!(error.to_string().contains(&*unwritable.to_string_lossy()), "{}", error);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,146 @@
+use super::{ByteSize, CowReserve, PartitionConfig};
+
+/// `size` as plain decimal bytes, `"auto"` stays as it is
+fn canonical(size: &mut ByteSize) {
+    if !size.is_auto() {
+        *size = ByteSize::new(size.as_u64());
+    }
+}
+
+fn canonical_reserve(reserve: &mut Option<CowReserve>) {
+    if let Some(CowReserve::Size(size)) = reserve {
+        canonical(size);
+    }
+}
+
+fn trim(text: &mut String) {
+    *text = text.trim().to_string();
+}
+
+impl PartitionConfig {
+    /// Rewrites the config into one canonical form, so two configs that mean the
+    /// same serialize to the same JSON and `diff` and version control only show
+    /// real changes.
+    ///
+    /// Sizes are written as decimal bytes (`"0x1000"` and `"4KiB"` become `"4096"`),
+    /// names and paths are trimmed, `expected_sha256` is lowercased (it is compared
+    /// ignoring case), and block devices, groups, partitions and
+    /// variants are sorted by name. The first block device stays first, as it holds
+    /// the metadata, and so does a `default` group, which liblp has as group 0.
+    /// Partitions are laid out in config order, so a sorted config builds an image
+    /// with the same partitions at other offsets.
+    pub fn normalize(&mut self) {
+        trim(&mut self.super_meta.path);
+        canonical(&mut self.super_meta.size);
+        trim(&mut self.nv_text);
+        trim(&mut self.nv_id);
+        canonical_reserve(&mut self.cow_reserve);
+
+        for device in &mut self.block_devices {
+            trim(&mut device.name);
+            canonical(&mut device.block_size);
+            canonical(&mut device.alignment);
+            canonical(&mut device.size);
+        }
+        if let Some((_, rest)) = self.block_devices.split_first_mut() {
+            rest.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
+        for group in &mut self.groups {
+            trim(&mut group.name);
+            if let Some(size) = &mut group.maximum_size {
+                canonical(size);
+            }
+            canonical_reserve(&mut group.cow_reserve);
+        }
+        self.groups.sort_by(|a, b| (a.name != "default", &a.name).cmp(&(b.name != "default", &b.name)));
+
+        for partition in &mut self.partitions {
+            trim(&mut partition.name);
+            trim(&mut partition.group_name);
+            trim(&mut partition.path);
+            if let Some(size) = &mut partition.size {
+                canonical(size);
+            }
+            if let Some(sha256) = &mut partition.expected_sha256 {
+                *sha256 = sha256.trim().to_ascii_lowercase();
+            }
+        }
+        self.partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for variant in &mut self.variants {
+            trim(&mut variant.name);
+            if let Some(size) = &mut variant.super_meta_size {
+                canonical(size);
+            }
+            for device in &mut variant.block_devices {
+                trim(&mut device.name);
+                canonical(&mut device.size);
+            }
+            variant.block_devices.sort_by(|a, b| a.name.cmp(&b.name));
+            for group in &mut variant.groups {
+                trim(&mut group.name);
+                canonical(&mut group.maximum_size);
+            }
+            variant.groups.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        self.variants.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::super_image_creater::parse_partition_config_str;
+
+    /// The same config twice, with other size spellings, order, whitespace and case
+    const HAND_EDITED: &str = r#"{"super_meta": {"path": " IMAGES/super.img", "size": "0x10000"}, "nv_text": "Global", "nv_id": " 00011010 ",
+        "block_devices": [{"block_size": "4KiB", "name": "super", "alignment": "0x100000", "size": "64M"},
+                          {"block_size": 4096, "name": "aux", "alignment": 1048576, "size": "1M"}],
+        "groups": [{"name": "grp_b", "cow_reserve": "1m"}, {"name": "grp_a", "maximum_size": "0x2000000", "cow_reserve": "10%"}, {"name": "default"}],
+        "partitions": [
+            {"is_dynamic": true, "name": "vendor_a ", "group_name": "grp_a", "path": " IMAGES/vendor.img", "size": "0x1000", "expected_sha256": "ABCD"},
+            {"is_dynamic": true, "name": "system_a", "group_name": "grp_a", "size": "auto", "path": "IMAGES/system.img"}
+        ]}"#;
+    const EXPORTED: &str = r#"{
+      "super_meta": {"path": "IMAGES/super.img", "size": 65536},
+      "nv_text": "Global",
+      "nv_id": "00011010",
+      "block_devices": [
+        {"block_size": "4096", "name": "super", "alignment": "1MiB", "size": "67108864"},
+        {"block_size": "4k", "name": "aux", "alignment": "1M", "size": 1048576}
+      ],
+      "groups": [
+        {"name": "default"},
+        {"name": "grp_a", "maximum_size": "32M", "cow_reserve": "10%"},
+        {"name": "grp_b", "cow_reserve": "1048576"}
+      ],
+      "partitions": [
+        {"is_dynamic": true, "name": "system_a", "group_name": "grp_a", "size": "AUTO", "path": "IMAGES/system.img"},
+        {"is_dynamic": true, "name": "vendor_a", "group_name": "grp_a ", "path": "IMAGES/vendor.img", "size": "4096", "expected_sha256": "abcd"}
+      ]
+    }"#;
+
+    #[test]
+    fn differently_formatted_configs_normalize_to_the_same_json() {
+        let mut hand_edited = parse_partition_config_str(HAND_EDITED).unwrap();
+        let mut exported = parse_partition_config_str(EXPORTED).unwrap();
+        assert_ne!(serde_json::to_string(&hand_edited).unwrap(), serde_json::to_string(&exported).unwrap());
+        hand_edited.normalize();
+        exported.normalize();
+        let json = serde_json::to_string_pretty(&hand_edited).unwrap();
+        assert_eq!(json, serde_json::to_string_pretty(&exported).unwrap());
+
+        // `super` keeps holding the metadata and `default` stays group 0
+        let names: Vec<&str> = hand_edited.block_devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["super", "aux"]);
+        let groups: Vec<&str> = hand_edited.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(groups, ["default", "grp_a", "grp_b"]);
+        assert_eq!(hand_edited.block_devices[0].size.as_str(), "67108864");
+        assert_eq!(hand_edited.partitions[0].size.as_ref().unwrap().as_str(), "auto");
+        assert_eq!(hand_edited.partitions[1].expected_sha256.as_deref(), Some("abcd"));
+
+        let mut again = hand_edited.clone();
+        again.normalize();
+        assert_eq!(serde_json::to_string_pretty(&again).unwrap(), json);
+    }
+}
@@ -0,0 +1,232 @@
+use super::{JsonParseError, PartitionConfig, resolve_path, strip_bom};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// The firmware's NV mapping: which region-specific images (`my_carrier`,
+/// `my_region`...) belong to each NV ID, e.g.
+///
+/// ```json
+/// {"nv_list": [{"nv_id": "00011010", "nv_text": "Global",
+///   "images": [{"partition": "my_carrier", "path": "IMAGES/my_carrier.00011010.img"}]}]}
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct NvMapping {
+    pub nv_list: Vec<NvEntry>,
+}
+
+/// One NV ID of an `NvMapping`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct NvEntry {
+    pub nv_id: String,
+    /// Human readable region or carrier, like `nv_text` in the config
+    #[serde(default)]
+    pub nv_text: String,
+    #[serde(default)]
+    pub images: Vec<NvImage>,
+}
+
+/// Image of one partition for an NV ID. `partition` has no slot suffix, it selects
+/// the image of the slot that has one (`my_carrier_a` with a size) as well.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct NvImage {
+    pub partition: String,
+    pub path: String,
+}
+
+/// An NV ID with its description, as listed in `NvError`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NvChoice {
+    pub nv_id: String,
+    pub nv_text: String,
+}
+
+impl fmt::Display for NvChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.nv_text.is_empty() {
+            true => write!(f, "{}", self.nv_id),
+            false => write!(f, "{} ({})", self.nv_id, self.nv_text),
+        }
+    }
+}
+
+/// A config `nv_id` the mapping cannot serve, with the IDs it can
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum NvError {
+    #[error("Config has no nv_id, pick one of: {}", describe_choices(available))]
+    NoNvId { available: Vec<NvChoice> },
+    #[error("nv_id '{nv_id}' is not in the firmware's NV mapping, pick one of: {}", describe_choices(available))]
+    UnknownNvId { nv_id: String, available: Vec<NvChoice> },
+}
+
+fn describe_choices(choices: &[NvChoice]) -> String {
+    if choices.is_empty() {
+        return "none (the mapping is empty)".to_string();
+    }
+    choices.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// NV variant a build used, see `BuildReport::nv`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AppliedNv {
+    pub nv_id: String,
+    pub nv_text: String,
+    /// Partition (with slot suffix, as in the config) and the image it got, in
+    /// config order
+    pub images: Vec<NvImage>,
+}
+
+/// Reads an NV mapping file. Relative image paths in it are resolved against the
+/// directory of the file.
+pub fn read_nv_mapping<P: AsRef<Path>>(path: P) -> Result<NvMapping, JsonParseError> {
+    let content = fs::read_to_string(&path).map_err(|e| JsonParseError::from(e).with_path(&path))?;
+    let content = strip_bom(&content);
+    let mut mapping: NvMapping = serde_json::from_str(content).map_err(|e| JsonParseError::from(e).with_source(content).with_path(&path))?;
+    let base_dir = path.as_ref().parent().unwrap_or(Path::new(""));
+    for image in mapping.nv_list.iter_mut().flat_map(|entry| entry.images.iter_mut()) {
+        image.path = resolve_path(&image.path, base_dir);
+    }
+    Ok(mapping)
+}
+
+impl NvMapping {
+    /// Every NV ID of the mapping (trimmed, as `entry` compares them) with its
+    /// description, in mapping order
+    pub fn choices(&self) -> Vec<NvChoice> {
+        self.nv_list.iter().map(|e| NvChoice { nv_id: e.nv_id.trim().to_string(), nv_text: e.nv_text.clone() }).collect()
+    }
+
+    /// The entry of `nv_id`. Surrounding whitespace is ignored, hand edited configs
+    /// sometimes have it.
+    pub fn entry(&self, nv_id: &str) -> Result<&NvEntry, NvError> {
+        let nv_id = nv_id.trim();
+        if nv_id.is_empty() {
+            return Err(NvError::NoNvId { available: self.choices() });
+        }
+        self.nv_list
+            .iter()
+            .find(|e| e.nv_id.trim() == nv_id)
+            .ok_or_else(|| NvError::UnknownNvId { nv_id: nv_id.to_string(), available: self.choices() })
+    }
+
+    /// Points the region-specific partitions of `config` at the images of its
+    /// `nv_id`, fills in an empty `nv_text` and returns what was applied.
+    ///
+    /// A mapping image goes to the partition of that exact name, or else to every
+    /// slot of it (`<partition>_a`...) that has a size, so the empty inactive slot of
+    /// stock configs is left alone. Mapping images for partitions the config does not
+    /// have are skipped.
+    pub fn apply(&self, config: &mut PartitionConfig) -> Result<AppliedNv, NvError> {
+        let entry = self.entry(&config.nv_id)?;
+        let mut images = Vec::new();
+        for partition in &mut config.partitions {
+            let matches = |image: &&NvImage| {
+                partition.name == image.partition
+                    || (partition.size.is_some()
+                        && ["_a", "_b"].iter().any(|suffix| partition.name.strip_suffix(suffix) == Some(image.partition.as_str())))
+            };
+            if let Some(image) = entry.images.iter().find(matches) {
+                partition.path = image.path.clone();
+                images.push(NvImage { partition: partition.name.clone(), path: image.path.clone() });
+            }
+        }
+        if config.nv_text.trim().is_empty() {
+            config.nv_text = entry.nv_text.clone();
+        }
+        Ok(AppliedNv { nv_id: entry.nv_id.clone(), nv_text: entry.nv_text.clone(), images })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::builder::{BuildError, SuperImageBuilder};
+    use crate::super_image_creater::parse_partition_config_str;
+    use crate::super_image_creater::test_support::{SAMPLE, config_with_images, pattern, test_dir};
+    use std::sync::atomic::AtomicBool;
+
+    const MAPPING: &str = r#"{"nv_list": [
+      {"nv_id": "00011010", "nv_text": "Global", "images": [
+        {"partition": "system", "path": "IMAGES/system.global.img"},
+        {"partition": "odm_b", "path": "IMAGES/odm.global.img"},
+        {"partition": "my_carrier", "path": "IMAGES/my_carrier.global.img"}]},
+      {"nv_id": " 00011011 ", "nv_text": "India"}
+    ]}"#;
+
+    #[test]
+    fn images_go_to_the_named_partition_or_its_sized_slots() {
+        let dir = test_dir("nv-read");
+        let path = dir.join("nv_mapping.json");
+        fs::write(&path, format!("\u{feff}{}", MAPPING)).unwrap();
+        let mapping = read_nv_mapping(&path).unwrap();
+        let global = dir.join("IMAGES/system.global.img");
+        assert_eq!(mapping.nv_list[0].images[0].path, global.to_str().unwrap());
+
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.nv_text.clear();
+        let applied = mapping.apply(&mut config).unwrap();
+        assert_eq!((applied.nv_id.as_str(), applied.nv_text.as_str()), ("00011010", "Global"));
+        let partitions: Vec<&str> = applied.images.iter().map(|i| i.partition.as_str()).collect();
+        // system_b has no size, and there is no my_carrier
+        assert_eq!(partitions, ["system_a", "odm_b"]);
+        assert_eq!(config.partitions[0].path, global.to_str().unwrap());
+        assert_eq!(config.partitions[1].path, "");
+        assert_eq!(config.nv_text, "Global");
+
+        // A set nv_text is kept, the ID is matched without surrounding whitespace
+        config.nv_id = "00011011\n".into();
+        config.nv_text = "India (hand edited)".into();
+        let applied = mapping.apply(&mut config).unwrap();
+        assert!(applied.images.is_empty());
+        assert_eq!((applied.nv_text.as_str(), config.nv_text.as_str()), ("India", "India (hand edited)"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unknown_or_missing_nv_ids_list_the_choices() {
+        let mapping: NvMapping = serde_json::from_str(MAPPING).unwrap();
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.nv_id = "00011099".into();
+        let error = mapping.apply(&mut config).unwrap_err();
+        assert_eq!(error.to_string(), "nv_id '00011099' is not in the firmware's NV mapping, pick one of: 00011010 (Global), 00011011 (India)");
+        config.nv_id = " ".into();
+        assert!(matches!(mapping.apply(&mut config), Err(NvError::NoNvId { available }) if available.len() == 2));
+        assert_eq!(NvMapping::default().entry("1").unwrap_err().to_string(), "nv_id '1' is not in the firmware's NV mapping, pick one of: none (the mapping is empty)");
+    }
+
+    #[test]
+    fn a_build_packs_the_images_of_the_nv_id() {
+        let dir = test_dir("nv-build");
+        let stock = pattern(5000, 1);
+        let global = pattern(7000, 2);
+        let mut config = config_with_images(&dir, &[("my_region", &stock, 1 << 20)]);
+        config.nv_id = "00011010".into();
+        fs::write(dir.join("my_region.global.img"), &global).unwrap();
+        let mapping = NvMapping {
+            nv_list: vec![NvEntry {
+                nv_id: "00011010".into(),
+                nv_text: "Global".into(),
+                images: vec![NvImage { partition: "my_region".into(), path: dir.join("my_region.global.img").to_str().unwrap().into() }],
+            }],
+        };
+        let output = dir.join("super.img");
+        let builder = SuperImageBuilder::new(config.clone()).output(&output).nv_mapping(mapping.clone());
+        let report = builder.build_with_cancel(&AtomicBool::new(false), |_| {}).unwrap();
+        assert_eq!(report.nv.as_ref().map(|nv| nv.images.len()), Some(1));
+        let image = fs::read(&output).unwrap();
+        let offset = report.partitions[0].offset as usize;
+        assert!(image[offset..offset + global.len()] == global[..]);
+        assert_eq!(report.partitions[0].image_bytes, global.len() as u64);
+
+        config.nv_id = "00011011".into();
+        let result = SuperImageBuilder::new(config).output(&output).nv_mapping(mapping).build_with_cancel(&AtomicBool::new(false), |_| {});
+        assert!(matches!(result, Err(BuildError::Nv(NvError::UnknownNvId { .. }))), "{:?}", result.map(|_| ()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
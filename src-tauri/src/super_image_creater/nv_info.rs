@@ -0,0 +1,114 @@
+use super::PartitionConfig;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Longest `nv_id` seen on OPlus firmware, e.g. `"00011010"`
+const NV_ID_DIGITS: usize = 8;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum NvParseError {
+    #[error("nv_id is empty")]
+    Empty,
+    #[error("nv_id '{0}' is not a number")]
+    NotNumeric(String),
+    #[error("nv_id '{0}' has more than {NV_ID_DIGITS} digits")]
+    TooLong(String),
+}
+
+/// The NV (region / carrier) fields of a config, split into what they encode.
+///
+/// `nv_id` is the numeric code of the NV variant the firmware was built for, with
+/// leading zeros (`"00011010"`), and `nv_text` its human readable name (`"Global"`,
+/// `"EU"`). The raw strings are always kept, for configs of devices whose NV fields
+/// look different.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NvInfo {
+    /// `nv_id` as written in the config
+    pub nv_id: String,
+    /// `nv_text` as written in the config
+    pub nv_text: String,
+    /// `nv_id` as a number, `None` when it is not in the known format
+    pub id: Option<u32>,
+    /// `nv_text` without surrounding whitespace, `None` when it is empty
+    pub label: Option<String>,
+}
+
+impl NvInfo {
+    /// Splits `nv_id` (up to 8 decimal digits) and `nv_text` into their fields
+    pub fn parse(nv_id: &str, nv_text: &str) -> Result<NvInfo, NvParseError> {
+        let digits = nv_id.trim();
+        if digits.is_empty() {
+            return Err(NvParseError::Empty);
+        }
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(NvParseError::NotNumeric(nv_id.to_string()));
+        }
+        if digits.len() > NV_ID_DIGITS {
+            return Err(NvParseError::TooLong(nv_id.to_string()));
+        }
+        let mut info = NvInfo::raw(nv_id, nv_text);
+        // At most 8 digits always fit
+        info.id = digits.parse().ok();
+        Ok(info)
+    }
+
+    /// The fields as written, with only the label split out
+    fn raw(nv_id: &str, nv_text: &str) -> NvInfo {
+        let label = nv_text.trim();
+        NvInfo {
+            nv_id: nv_id.to_string(),
+            nv_text: nv_text.to_string(),
+            id: None,
+            label: (!label.is_empty()).then(|| label.to_string()),
+        }
+    }
+
+    /// Whether `nv_id` was in the known format
+    pub fn is_recognized(&self) -> bool {
+        self.id.is_some()
+    }
+}
+
+impl PartitionConfig {
+    /// `nv_id` and `nv_text` parsed with `NvInfo::parse`. An `nv_id` in another
+    /// format is no error, the result then only has the raw strings (and the label),
+    /// so configs of unknown devices still load.
+    pub fn nv_info(&self) -> NvInfo {
+        NvInfo::parse(&self.nv_id, &self.nv_text).unwrap_or_else(|_| NvInfo::raw(&self.nv_id, &self.nv_text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::parse_partition_config_str;
+    use crate::super_image_creater::test_support::SAMPLE;
+
+    #[test]
+    fn stock_nv_ids_parse() {
+        let info = NvInfo::parse("00011010", " Global ").unwrap();
+        assert_eq!(info.id, Some(11010));
+        assert_eq!(info.label.as_deref(), Some("Global"));
+        assert_eq!(info.nv_id, "00011010");
+        assert!(info.is_recognized());
+
+        let info = NvInfo::parse("10010111", "").unwrap();
+        assert_eq!((info.id, info.label), (Some(10010111), None));
+        assert_eq!(parse_partition_config_str(SAMPLE).unwrap().nv_info().id, Some(11010));
+    }
+
+    #[test]
+    fn unknown_nv_ids_keep_the_raw_strings() {
+        assert_eq!(NvInfo::parse(" ", "EU"), Err(NvParseError::Empty));
+        assert_eq!(NvInfo::parse("EU-01", "EU"), Err(NvParseError::NotNumeric("EU-01".into())));
+        assert_eq!(NvInfo::parse("123456789", ""), Err(NvParseError::TooLong("123456789".into())));
+
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.nv_id = "EU-01".into();
+        config.nv_text = "Europe".into();
+        let info = config.nv_info();
+        assert!(!info.is_recognized());
+        assert_eq!(info.nv_id, "EU-01");
+        assert_eq!(info.label.as_deref(), Some("Europe"));
+    }
+}
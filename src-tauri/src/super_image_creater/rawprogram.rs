@@ -0,0 +1,129 @@
+use super::PartitionConfig;
+use super::sparse::SparseImage;
+use crate::xml_file_util::{DataRoot, Program};
+use quick_xml::se::to_string;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+/// Where a partition lives on the device, e.g. from the GPT read by `read_gpt`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalPartition {
+    /// LUN, written as `physical_partition_number`
+    pub lun: u8,
+    pub start_sector: u64,
+    /// Partition size in sectors
+    pub num_sectors: u64,
+    /// Sector size of the LUN (4096 on UFS)
+    pub sector_size: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum RawprogramError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("No block device defined")]
+    NoBlockDevice,
+    #[error("No physical partition info for '{0}'")]
+    MissingPhysical(String),
+    #[error("Image '{filename}' is {image_size} bytes, larger than partition '{label}' ({partition_size} bytes)")]
+    TooLarge { label: String, filename: String, image_size: u64, partition_size: u64 },
+    #[error("Transfer size limit {0} is smaller than a sector")]
+    ChunkTooSmall(u64),
+    #[error("XML serialization error: {0}")]
+    Xml(String),
+}
+
+/// Creates the `<program>` entries that flash a built super image, and every
+/// non-dynamic partition of the config that has an image, in EDL mode.
+///
+/// The super entry writes `super_meta.path` to the physical partition named like the
+/// first block device. `physical` maps partition labels to their location on the
+/// device. Filenames are written without their directory, as QFIL and fh_loader
+/// look them up next to the XML (or in `--search_path`).
+///
+/// With `max_transfer_bytes`, raw images larger than that are split into several
+/// `<program>` lines over consecutive sectors, each with a `file_sector_offset`
+/// into the same file, for loaders that cap the size of a single transfer. Sparse
+/// images are never split (fh_loader unpacks them itself) and get `sparse="true"`.
+/// An image that does not exist yet (e.g. super before building) is taken to be a
+/// raw image filling the whole partition.
+pub fn rawprogram_entries(
+    config: &PartitionConfig,
+    physical: &HashMap<String, PhysicalPartition>,
+    max_transfer_bytes: Option<u64>,
+) -> Result<Vec<Program>, RawprogramError> {
+    let device = config.block_devices.first().ok_or(RawprogramError::NoBlockDevice)?;
+    let mut images = vec![(device.name.as_str(), config.super_meta.path.as_str())];
+    for partition in config.partitions.iter().filter(|p| !p.is_dynamic && !p.path.trim().is_empty()) {
+        images.push((partition.name.as_str(), partition.path.trim()));
+    }
+
+    let mut programs = Vec::new();
+    for (label, path) in images {
+        let location = physical.get(label).ok_or_else(|| RawprogramError::MissingPhysical(label.to_string()))?;
+        let partition_size = location.num_sectors * location.sector_size;
+        let (image_size, sparse) = match fs::metadata(path) {
+            Ok(metadata) => (metadata.len(), SparseImage::is_sparse(path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (partition_size, false),
+            Err(e) => return Err(e.into()),
+        };
+        let filename = Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if !sparse && image_size > partition_size {
+            return Err(RawprogramError::TooLarge { label: label.to_string(), filename, image_size, partition_size });
+        }
+
+        let chunk_sectors = match max_transfer_bytes {
+            Some(max) if !sparse && image_size > max => {
+                let sectors = max / location.sector_size;
+                if sectors == 0 {
+                    return Err(RawprogramError::ChunkTooSmall(max));
+                }
+                Some(sectors)
+            }
+            _ => None,
+        };
+        let Some(chunk_sectors) = chunk_sectors else {
+            programs.push(program(label, &filename, location, 0, location.num_sectors, sparse));
+            continue;
+        };
+        let image_sectors = image_size.div_ceil(location.sector_size);
+        let mut offset = 0;
+        while offset < image_sectors {
+            let sectors = chunk_sectors.min(image_sectors - offset);
+            programs.push(program(label, &filename, location, offset, sectors, false));
+            offset += sectors;
+        }
+    }
+    Ok(programs)
+}
+
+/// Writes `programs` as a rawprogram XML file
+pub fn write_rawprogram<P: AsRef<Path>>(path: P, programs: Vec<Program>) -> Result<(), RawprogramError> {
+    let root = DataRoot { programs, read_tags: Vec::new() };
+    let xml = to_string(&root).map_err(|e| RawprogramError::Xml(e.to_string()))?;
+    fs::write(path, format!("<?xml version=\"1.0\" ?>\n{}\n", xml))?;
+    Ok(())
+}
+
+/// `<program>` for `sectors` sectors of `filename`, starting `file_offset` sectors
+/// into both the file and the partition
+fn program(label: &str, filename: &str, location: &PhysicalPartition, file_offset: u64, sectors: u64, sparse: bool) -> Program {
+    let start_sector = location.start_sector + file_offset;
+    Program {
+        start_sector: start_sector.to_string(),
+        size_in_kb: (sectors * location.sector_size) as f64 / 1024.0,
+        physical_partition_number: location.lun,
+        part_of_single_image: false,
+        file_sector_offset: file_offset,
+        num_partition_sectors: sectors,
+        readback_verify: false,
+        filename: filename.to_string(),
+        sparse,
+        start_byte_hex: format!("0x{:x}", start_sector * location.sector_size),
+        sector_size_in_bytes: location.sector_size,
+        label: label.to_string(),
+    }
+}
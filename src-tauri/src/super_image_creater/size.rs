@@ -0,0 +1,407 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SizeParseError {
+    #[error("Size string is empty")]
+    Empty,
+    #[error("Invalid size '{0}'")]
+    InvalidNumber(String),
+    #[error("Unknown size suffix '{suffix}' in '{value}'")]
+    UnknownSuffix { value: String, suffix: String },
+    #[error("Size '{0}' does not fit in 64 bits")]
+    Overflow(String),
+    #[error("Size '{0}' is not a whole number of bytes")]
+    Fractional(String),
+    #[error("Invalid percentage '{0}', expected 0% to 100%")]
+    InvalidPercent(String),
+}
+
+/// Spelling of a partition size that is measured from its image file
+pub const AUTO_SIZE: &str = "auto";
+
+/// A byte count parsed from a config size field.
+///
+/// The original spelling is kept next to the parsed value so that a config can
+/// be written back exactly as it was read (`"0x220000000"` stays hex, `"8GiB"`
+/// keeps its suffix). Equality only compares the byte value. A size written as a
+/// JSON number reads like the same digits as a string, and is written back as one.
+#[derive(Debug, Clone)]
+pub struct ByteSize {
+    bytes: u64,
+    raw: String,
+}
+
+impl ByteSize {
+    /// Creates a size from a byte count, spelled as plain decimal bytes
+    pub fn new(bytes: u64) -> Self {
+        Self { bytes, raw: bytes.to_string() }
+    }
+
+    /// The `"auto"` partition size, 0 bytes until it is resolved from the image file
+    pub fn auto() -> Self {
+        Self { bytes: 0, raw: AUTO_SIZE.to_string() }
+    }
+
+    /// Whether this is the `"auto"` partition size
+    pub fn is_auto(&self) -> bool {
+        self.raw == AUTO_SIZE
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The size exactly as it was written in the config
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+}
+
+/// Zero bytes
+impl Default for ByteSize {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl PartialEq for ByteSize {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes && self.is_auto() == other.is_auto()
+    }
+}
+
+impl Eq for ByteSize {}
+
+impl FromStr for ByteSize {
+    type Err = SizeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self { bytes: parse_size(s)?, raw: s.to_string() })
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+/// Space a group keeps free for the COW snapshots of Virtual A/B updates, see
+/// `Group.cow_reserve`. Written as a size (`"512M"`) or as a percentage of the
+/// group's `maximum_size` (`"10%"`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum CowReserve {
+    Size(ByteSize),
+    Percent(f64),
+}
+
+impl CowReserve {
+    /// Bytes reserved in a group of `group_size` bytes
+    pub fn bytes_of(&self, group_size: u64) -> u64 {
+        match self {
+            CowReserve::Size(size) => size.as_u64(),
+            CowReserve::Percent(percent) => (group_size as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for CowReserve {
+    type Err = SizeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(number) = s.trim().strip_suffix('%') else {
+            return s.parse::<ByteSize>().map(CowReserve::Size);
+        };
+        match number.trim().parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(CowReserve::Percent(percent)),
+            _ => Err(SizeParseError::InvalidPercent(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for CowReserve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CowReserve::Size(size) => f.write_str(size.as_str()),
+            CowReserve::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CowReserve {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_size_text(deserializer)?.parse::<CowReserve>().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for CowReserve {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Text of a size field, which configs from other lpmake wrappers also write as a
+/// JSON number (`128` reads like `"128"`)
+struct SizeTextVisitor;
+
+impl<'de> Visitor<'de> for SizeTextVisitor {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a size string such as \"4096\", \"0x1000\" or \"4KiB\", or a byte count")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<String, E> {
+        u64::try_from(v).map(|v| v.to_string()).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+}
+
+fn deserialize_size_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    deserializer.deserialize_any(SizeTextVisitor)
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_size_text(deserializer)?.parse::<ByteSize>().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.raw)
+    }
+}
+
+/// Deserializer for optional size fields: an empty string (or a missing field,
+/// together with `#[serde(default)]`) becomes `None`. Numbers are accepted like
+/// for `ByteSize`.
+pub fn deserialize_optional<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ByteSize>, D::Error> {
+    let raw = deserialize_size_text(deserializer)?;
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    raw.parse::<ByteSize>().map(Some).map_err(de::Error::custom)
+}
+
+/// Deserializer for `Partition.size`: like `deserialize_optional`, but also accepts
+/// `"auto"` (case-insensitive), see `ByteSize::auto`
+pub fn deserialize_partition_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ByteSize>, D::Error> {
+    let raw = deserialize_size_text(deserializer)?;
+    if raw.trim().eq_ignore_ascii_case(AUTO_SIZE) {
+        return Ok(Some(ByteSize::auto()));
+    }
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    raw.parse::<ByteSize>().map(Some).map_err(de::Error::custom)
+}
+
+/// Parses a human-readable size string into a byte count.
+///
+/// Accepted forms:
+/// * plain decimal bytes: `"9126805504"`
+/// * hexadecimal bytes: `"0x200000"`
+/// * decimal with a unit suffix: `"128MB"`, `"4GiB"`, `"64 K"`, `"4096k"`
+/// * a fractional value with a suffix, as long as it is a whole number of
+///   bytes: `"8.5GiB"`, `"1.5K"`
+///
+/// Suffixes are case-insensitive. Binary (power-of-2) units are `K`/`KiB`,
+/// `M`/`MiB`, `G`/`GiB` and `T`/`TiB`, because super image math (block sizes,
+/// alignment, lpmake arguments) is always done in powers of two. The SI forms
+/// `KB`, `MB`, `GB` and `TB` are power-of-10 units, so `"128MB"` is
+/// 128 000 000 bytes while `"128MiB"` and `"128M"` are 134 217 728 bytes.
+/// `B` is accepted as an explicit "bytes" suffix.
+///
+/// An empty (or whitespace-only) string is rejected with `SizeParseError::Empty`;
+/// use `parse_optional_size` for fields that may be left empty.
+pub fn parse_size(value: &str) -> Result<u64, SizeParseError> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Err(SizeParseError::Empty);
+    }
+
+    if let Some(hex) = trimmed.strip_prefix("0x").or_else(|| trimmed.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).map_err(|_| SizeParseError::InvalidNumber(value.to_string()));
+    }
+
+    // Split into the numeric part and the unit suffix
+    let split_at = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split_at);
+    let (digits, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if digits.is_empty() || fraction.contains('.') || (number.contains('.') && fraction.is_empty()) {
+        return Err(SizeParseError::InvalidNumber(value.to_string()));
+    }
+
+    let multiplier = suffix_multiplier(suffix.trim()).ok_or_else(|| SizeParseError::UnknownSuffix {
+        value: value.to_string(),
+        suffix: suffix.trim().to_string(),
+    })?;
+    let overflow = || SizeParseError::Overflow(value.to_string());
+
+    let whole = digits
+        .parse::<u64>()
+        .map_err(|_| overflow())?
+        .checked_mul(multiplier)
+        .ok_or_else(overflow)?;
+    if fraction.is_empty() {
+        return Ok(whole);
+    }
+
+    // Fractional part, computed exactly: fraction * multiplier / 10^len
+    let scale = 10u128.checked_pow(fraction.len() as u32).ok_or_else(overflow)?;
+    let numerator = fraction
+        .parse::<u128>()
+        .map_err(|_| overflow())?
+        .checked_mul(multiplier as u128)
+        .ok_or_else(overflow)?;
+    if numerator % scale != 0 {
+        return Err(SizeParseError::Fractional(value.to_string()));
+    }
+    let part = u64::try_from(numerator / scale).map_err(|_| overflow())?;
+    whole.checked_add(part).ok_or_else(overflow)
+}
+
+/// Same as `parse_size`, but maps an empty string to `None`.
+///
+/// This is used for the fields that are `#[serde(default)]` in the config
+/// (`Group.maximum_size`, `Partition.size`).
+pub fn parse_optional_size(value: &str) -> Result<Option<u64>, SizeParseError> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    parse_size(value).map(Some)
+}
+
+fn suffix_multiplier(suffix: &str) -> Option<u64> {
+    const KIB: u64 = 1024;
+    const KB: u64 = 1000;
+    let multiplier = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => KIB,
+        "m" | "mib" => KIB.pow(2),
+        "g" | "gib" => KIB.pow(3),
+        "t" | "tib" => KIB.pow(4),
+        "kb" => KB,
+        "mb" => KB.pow(2),
+        "gb" => KB.pow(3),
+        "tb" => KB.pow(4),
+        _ => return None,
+    };
+    Some(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::{parse_partition_config_str, read_partition_config_strict_from_str};
+
+    #[test]
+    fn size_strings_parse_to_bytes() {
+        let ok = [
+            ("4096", 4096),
+            (" 4096 ", 4096),
+            ("0x220000000", 0x2_2000_0000),
+            ("0X1f", 31),
+            ("512b", 512),
+            ("4096k", 4 << 20),
+            ("128M", 128 << 20),
+            ("128MiB", 128 << 20),
+            ("128mib", 128 << 20),
+            ("128MB", 128_000_000),
+            ("128 MB", 128_000_000),
+            ("8.25GiB", 33 << 28),
+            ("1.5k", 1536),
+            ("0.5MB", 500_000),
+            ("2TiB", 2 << 40),
+            ("3TB", 3_000_000_000_000),
+            ("18446744073709551615", u64::MAX),
+            ("18446744073709551.615KB", u64::MAX),
+        ];
+        for (value, bytes) in ok {
+            assert_eq!(parse_size(value), Ok(bytes), "{}", value);
+        }
+
+        let err = |value: &str| value.to_string();
+        let bad = [
+            ("", SizeParseError::Empty),
+            ("  ", SizeParseError::Empty),
+            ("12XB", SizeParseError::UnknownSuffix { value: err("12XB"), suffix: "XB".into() }),
+            ("4 GiBs", SizeParseError::UnknownSuffix { value: err("4 GiBs"), suffix: "GiBs".into() }),
+            ("-1", SizeParseError::InvalidNumber(err("-1"))),
+            ("0x", SizeParseError::InvalidNumber(err("0x"))),
+            ("0x12g", SizeParseError::InvalidNumber(err("0x12g"))),
+            ("1.2.3M", SizeParseError::InvalidNumber(err("1.2.3M"))),
+            ("1.", SizeParseError::InvalidNumber(err("1."))),
+            (".5M", SizeParseError::InvalidNumber(err(".5M"))),
+            ("1.5", SizeParseError::Fractional(err("1.5"))),
+            ("0.3k", SizeParseError::Fractional(err("0.3k"))),
+            ("18446744073709551616", SizeParseError::Overflow(err("18446744073709551616"))),
+            ("16777216TiB", SizeParseError::Overflow(err("16777216TiB"))),
+            ("18446744073709551.616KB", SizeParseError::Overflow(err("18446744073709551.616KB"))),
+            ("0x10000000000000000", SizeParseError::InvalidNumber(err("0x10000000000000000"))),
+        ];
+        for (value, error) in bad {
+            assert_eq!(parse_size(value), Err(error), "{}", value);
+        }
+    }
+
+    /// A config with some sizes as strings and the others as JSON numbers
+    const MIXED: &str = r#"{
+        "super_meta": {"path": "super.img", "size": 65536},
+        "nv_text": "", "nv_id": "",
+        "block_devices": [{"block_size": "4096", "name": "super", "alignment": 1048576, "size": "0x220000000"}],
+        "groups": [{"name": "a", "maximum_size": 128}, {"name": "b", "maximum_size": "128"}],
+        "partitions": [
+            {"is_dynamic": true, "name": "x", "group_name": "a", "size": 128},
+            {"is_dynamic": true, "name": "y", "group_name": "b", "size": "128"}
+        ]
+    }"#;
+
+    #[test]
+    fn string_and_number_sizes_parse_to_the_same_size() {
+        let config = parse_partition_config_str(MIXED).unwrap();
+        assert_eq!(config.groups[0].maximum_size, config.groups[1].maximum_size);
+        assert_eq!(config.groups[0].maximum_size.as_ref().unwrap().as_str(), "128");
+        assert_eq!(config.partitions[0].size, config.partitions[1].size);
+        assert_eq!(config.partitions[0].size.as_ref().unwrap().as_u64(), 128);
+        assert_eq!(config.block_devices[0].block_size.as_u64(), 4096);
+        assert_eq!(config.block_devices[0].alignment.as_u64(), 1 << 20);
+        assert_eq!(config.super_meta.size.as_u64(), 65536);
+        assert_eq!(read_partition_config_strict_from_str(MIXED).unwrap(), config);
+
+        // Numbers are written back as strings
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""alignment":"1048576""#), "{}", json);
+        assert!(!json.contains(r#""size":128"#), "{}", json);
+    }
+
+    #[test]
+    fn negative_fractional_and_non_numeric_sizes_are_rejected() {
+        for bad in [r#""size": -1"#, r#""size": 1.5"#, r#""size": true"#] {
+            let config = MIXED.replace(r#""size": 128"#, bad);
+            assert_ne!(config, MIXED);
+            let error = parse_partition_config_str(&config).unwrap_err();
+            assert!(error.to_string().contains("a size string"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use super::{Group, Partition, PartitionConfig};
+use std::collections::HashSet;
+
+/// One of the two slots of an A/B device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Slot {
+    #[default]
+    A,
+    B,
+}
+
+impl Slot {
+    /// `_a` or `_b`
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Slot::A => "_a",
+            Slot::B => "_b",
+        }
+    }
+
+    fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// How partition and group names of a config map to the built image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlotMode {
+    /// Names are used as written, with `metadata_slot_count()` metadata slots. This
+    /// is what stock configs that already list `*_a` / `*_b` names need.
+    #[default]
+    Single,
+    /// The config lists un-suffixed names, and every group and partition is written
+    /// twice, as `<name>_a` and `<name>_b`, with two metadata slots. Only the `active`
+    /// slot's partitions get their size and image, the other slot's are left empty.
+    AB { active: Slot },
+}
+
+impl SlotMode {
+    /// Suffix of the slot the partition data is written to, `None` in `Single` mode
+    pub fn active_suffix(self) -> Option<&'static str> {
+        match self {
+            SlotMode::Single => None,
+            SlotMode::AB { active } => Some(active.suffix()),
+        }
+    }
+
+    /// Number of LP metadata slots the image is built with
+    pub(super) fn metadata_slot_count(self, config: &PartitionConfig) -> u32 {
+        match self {
+            SlotMode::Single => config.metadata_slot_count(),
+            SlotMode::AB { .. } => 2,
+        }
+    }
+
+    /// Returns the config with the slot suffixes applied, or the first group or
+    /// partition name that already ends in `_a` / `_b` in `AB` mode.
+    ///
+    /// Groups come out as all `_a` groups followed by all `_b` groups, in config
+    /// order, and partitions likewise. Non-dynamic partitions are duplicated too.
+    pub(super) fn apply(self, config: &PartitionConfig) -> Result<PartitionConfig, String> {
+        let SlotMode::AB { active } = self else {
+            return Ok(config.clone());
+        };
+        let names = config.groups.iter().map(|g| &g.name).chain(config.partitions.iter().map(|p| &p.name));
+        if let Some(name) = names.into_iter().find(|n| n.ends_with("_a") || n.ends_with("_b")) {
+            return Err(name.clone());
+        }
+
+        let mut expanded = config.clone();
+        expanded.groups.clear();
+        expanded.partitions.clear();
+        for slot in [Slot::A, Slot::B] {
+            for group in &config.groups {
+                expanded.groups.push(Group { name: format!("{}{}", group.name, slot.suffix()), ..group.clone() });
+            }
+        }
+        for slot in [Slot::A, Slot::B] {
+            for partition in &config.partitions {
+                let mut slotted = Partition {
+                    name: format!("{}{}", partition.name, slot.suffix()),
+                    group_name: format!("{}{}", partition.group_name, slot.suffix()),
+                    ..partition.clone()
+                };
+                if slot == active.other() {
+                    slotted.path = String::new();
+                    slotted.size = None;
+                    slotted.expected_sha256 = None;
+                }
+                expanded.partitions.push(slotted);
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// Splits `name` into its base and a slot suffix of the form `_<lowercase letter>`
+fn split_suffix(name: &str) -> Option<(&str, &str)> {
+    let (base, letter) = name.rsplit_once('_')?;
+    let is_letter = letter.len() == 1 && letter.bytes().all(|b| b.is_ascii_lowercase());
+    (is_letter && !base.is_empty()).then(|| (base, &name[base.len()..]))
+}
+
+impl PartitionConfig {
+    /// Slot suffix of a partition, if it is slotted.
+    ///
+    /// A name ending in `_<letter>` alone is not enough, since plain partitions can
+    /// end like that too. The partition counts as slotted only if its group ends in
+    /// the same suffix (`system_a` in `qti_dynamic_partitions_a`), or if the config
+    /// also has the same base name with another suffix (`system_a` and `system_b`).
+    fn partition_slot_suffix<'a>(&self, partition: &'a Partition) -> Option<&'a str> {
+        let (base, suffix) = split_suffix(&partition.name)?;
+        let group_matches = split_suffix(&partition.group_name).is_some_and(|(_, g)| g == suffix);
+        let has_sibling = self
+            .partitions
+            .iter()
+            .filter_map(|p| split_suffix(&p.name))
+            .any(|(other_base, other_suffix)| other_base == base && other_suffix != suffix);
+        (group_matches || has_sibling).then_some(suffix)
+    }
+
+    /// Slot suffixes (`_a`, `_b`...) used by slotted partitions, sorted. Empty for a
+    /// config without slotted partitions. See `retrofit_slot` for how slotted
+    /// partitions are told apart from names that merely end in `_a`.
+    pub fn slot_suffixes(&self) -> Vec<String> {
+        let mut suffixes: Vec<String> = self
+            .partitions
+            .iter()
+            .filter_map(|p| self.partition_slot_suffix(p))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        suffixes.sort();
+        suffixes
+    }
+
+    /// Slot suffix (`_a` or `_b`) of the first block device, the slot a `retrofit`
+    /// config is built for. `None` if it has neither suffix, or there is no device.
+    pub fn retrofit_suffix(&self) -> Option<&'static str> {
+        let device = self.block_devices.first()?;
+        [Slot::A, Slot::B].into_iter().map(Slot::suffix).find(|suffix| device.name.ends_with(suffix))
+    }
+
+    /// Adds the `suffix` slot (`"_b"` or `"b"`) for every slotted partition that does
+    /// not have it yet.
+    ///
+    /// A partition is slotted if its name ends in `_<letter>` and either its group
+    /// ends in the same suffix or another slot of the same partition exists; a lone
+    /// `foo_a` in an un-suffixed group is left alone, like non-slotted partitions
+    /// (`userdata`...). Each copy goes into the group with the matching suffix
+    /// (created from the original group if missing, same `maximum_size`), or into the
+    /// same group if that one is not slotted. Copies get no `path` and no `size`,
+    /// like the inactive slot in stock configs, so the config still validates.
+    pub fn retrofit_slot(&mut self, suffix: &str) {
+        let suffix = if suffix.starts_with('_') { suffix.to_string() } else { format!("_{}", suffix) };
+        let existing: HashSet<String> = self.partitions.iter().map(|p| p.name.clone()).collect();
+
+        let mut added = Vec::new();
+        for partition in &self.partitions {
+            let Some(own_suffix) = self.partition_slot_suffix(partition) else { continue };
+            if own_suffix == suffix {
+                continue;
+            }
+            let base = &partition.name[..partition.name.len() - own_suffix.len()];
+            let name = format!("{}{}", base, suffix);
+            if existing.contains(&name) || added.iter().any(|p: &Partition| p.name == name) {
+                continue;
+            }
+            let group_name = match split_suffix(&partition.group_name) {
+                Some((group_base, group_suffix)) if group_suffix == own_suffix => format!("{}{}", group_base, suffix),
+                _ => partition.group_name.clone(),
+            };
+            if !self.groups.iter().any(|g| g.name == group_name)
+                && let Some(group) = self.groups.iter().find(|g| g.name == partition.group_name)
+            {
+                self.groups.push(Group { name: group_name.clone(), ..group.clone() });
+            }
+            added.push(Partition { name, group_name, path: String::new(), size: None, ..partition.clone() });
+        }
+        self.partitions.extend(added);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+    use crate::super_image_creater::{BuildError, SuperImageBuilder, read_super_metadata};
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn ab_mode_writes_both_slots_with_two_metadata_slots() {
+        let dir = test_dir("ab-slots");
+        let system = pattern(8192, 1);
+        let mut config = config_with_images(&dir, &[("system", &system, 1 << 20), ("vendor", &pattern(4096, 2), 4096)]);
+        let output = dir.join("super.img");
+        let builder = SuperImageBuilder::new(config.clone()).output(&output).slot_mode(SlotMode::AB { active: Slot::B });
+        let report = builder.build_with_cancel(&AtomicBool::new(false), |_| {}).unwrap();
+        assert_eq!(report.slot_suffix.as_deref(), Some("_b"));
+
+        let (geometry, metadata) = read_super_metadata(&output).unwrap();
+        assert_eq!(geometry.metadata_slot_count, 2);
+        let groups: Vec<&str> = metadata.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(groups, ["default", "main_a", "main_b"]);
+        let partitions: Vec<(&str, &str, u32)> = metadata
+            .partitions
+            .iter()
+            .map(|p| (p.name.as_str(), groups[p.group_index as usize], p.num_extents))
+            .collect();
+        // Only the active slot gets extents
+        assert_eq!(
+            partitions,
+            [("system_a", "main_a", 0), ("vendor_a", "main_a", 0), ("system_b", "main_b", 1), ("vendor_b", "main_b", 1)]
+        );
+        let system_b = &metadata.extents[metadata.partitions[2].first_extent_index as usize];
+        assert_eq!(system_b.num_sectors * 512, 1 << 20);
+        let image = std::fs::read(&output).unwrap();
+        let start = system_b.target_data as usize * 512;
+        assert!(image[start..start + system.len()] == system[..]);
+
+        // Names already ending in a slot suffix are refused, a single slot keeps them
+        config.partitions[0].name = "system_a".into();
+        let error = SuperImageBuilder::new(config.clone()).output(&output).slot_mode(SlotMode::AB { active: Slot::A }).build().unwrap_err();
+        assert!(matches!(&error, BuildError::AlreadySlotted(name) if name == "system_a"), "{}", error);
+        assert_eq!(SlotMode::Single.apply(&config).unwrap(), config);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How the free space at the output compares with a `SizeEstimate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpaceVerdict {
+    /// At least `output_size` bytes are free
+    Enough,
+    /// Less than `output_size` but at least `minimum_size` bytes are free. A raw image
+    /// then only fits on a filesystem that keeps its free space as holes (not FAT32 or
+    /// exFAT), sparse and zstd output only if it comes out below the bound.
+    Tight,
+    /// Less than `minimum_size` bytes are free, the build cannot finish
+    Insufficient,
+    /// The free space could not be determined
+    Unknown,
+}
+
+/// Size of a build's output and whether it fits where it goes, see
+/// `SuperImageBuilder::estimate`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeEstimate {
+    /// Size of the raw image, the block device size
+    pub image_size: u64,
+    /// Metadata and partition data (block aligned) the build writes, what a raw image
+    /// takes on a filesystem with sparse files
+    pub data_size: u64,
+    /// Size of the output: exact for raw output, an upper bound for sparse and zstd
+    pub output_size: u64,
+    pub exact: bool,
+    /// Bytes the output takes at the least: `data_size` for raw output, for sparse
+    /// only the header and geometry block (blocks of one value become Fill chunks),
+    /// and 0 for zstd, which all-zero data shrinks to almost nothing
+    pub minimum_size: u64,
+    /// Directory the output and its partial file are written to
+    pub output_dir: PathBuf,
+    /// Bytes free for this user there, `None` if unknown
+    pub available: Option<u64>,
+    pub verdict: SpaceVerdict,
+}
+
+impl SizeEstimate {
+    /// Estimate for an output at `output`, with the free space of its directory
+    pub(super) fn at(output: &Path, image_size: u64, data_size: u64, output_size: u64, exact: bool, minimum_size: u64) -> Self {
+        let output_dir = match output.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let available = available_space(&output_dir).ok();
+        let verdict = match available {
+            None => SpaceVerdict::Unknown,
+            Some(free) if free >= output_size => SpaceVerdict::Enough,
+            Some(free) if free >= minimum_size => SpaceVerdict::Tight,
+            Some(_) => SpaceVerdict::Insufficient,
+        };
+        SizeEstimate { image_size, data_size, output_size, exact, minimum_size, output_dir, available, verdict }
+    }
+}
+
+/// Bytes available to this user on the filesystem holding `path`, or its nearest
+/// existing ancestor if `path` does not exist yet
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    #[cfg(test)]
+    if let Some(free) = tests::PRETEND_AVAILABLE.get() {
+        return Ok(free);
+    }
+    let mut dir = path;
+    while !dir.exists() {
+        dir = match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    filesystem_available(dir)
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn filesystem_available(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a zeroed statvfs for the call to fill in
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The field types differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn filesystem_available(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, available: *mut u64, total: *mut u64, free: *mut u64) -> i32;
+    }
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: `wide` is NUL-terminated, the totals we do not need may be null
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn filesystem_available(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is not known on this platform"))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::super_image_creater::builder::{BuildError, OutputFormat, PlanOptions, SuperImageBuilder, estimate_output_size, partial_path};
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicBool;
+
+    thread_local! {
+        /// Free space `available_space` reports on this thread instead of asking the filesystem
+        pub(in crate::super_image_creater) static PRETEND_AVAILABLE: Cell<Option<u64>> = const { Cell::new(None) };
+    }
+
+    /// `len` bytes that neither zstd nor the sparse writer can shrink
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn estimates_do_not_undercount_the_output() {
+        let dir = test_dir("estimate");
+        let config = config_with_images(
+            &dir,
+            &[("system", &noise(3 << 20 | 123, 7), 4 << 20), ("vendor", &pattern(10000, 3), 1 << 20), ("odm", &vec![0; 8192], 1 << 20)],
+        );
+        // Shrinks to next to nothing as a sparse image
+        std::fs::create_dir(dir.join("zeros")).unwrap();
+        let zeros = config_with_images(&dir.join("zeros"), &[("system", &vec![0; 3 << 20], 4 << 20)]);
+        let cases = [(&config, OutputFormat::Raw), (&config, OutputFormat::Sparse { max_blob_size: Some(1 << 20) }), (&config, OutputFormat::Zstd { level: 1 })];
+        for (config, format) in cases.into_iter().chain([(&zeros, OutputFormat::Sparse { max_blob_size: None }), (&zeros, OutputFormat::Zstd { level: 0 })]) {
+            let dir = Path::new(&config.super_meta.path).parent().unwrap();
+            let output = dir.join("super.out");
+            let options = PlanOptions { output: Some(output.clone()), format, ..Default::default() };
+            let estimate = estimate_output_size(config, &options).unwrap();
+            let builder = SuperImageBuilder::new((*config).clone()).output(&output).format(format);
+            assert_eq!(builder.estimate().unwrap(), estimate);
+            builder.build_with_cancel(&AtomicBool::new(false), |_| {}).unwrap();
+            let written = std::fs::metadata(&output).unwrap().len();
+
+            assert_eq!(estimate.image_size, 16 << 20);
+            assert_eq!(estimate.exact, format == OutputFormat::Raw);
+            if estimate.exact {
+                assert_eq!(estimate.output_size, written);
+            }
+            assert!(estimate.output_size >= written, "{:?}: {} < {}", format, estimate.output_size, written);
+            assert!(estimate.minimum_size <= written, "{:?}: {} > {}", format, estimate.minimum_size, written);
+            let image_bytes: u64 = config.partitions.iter().map(|p| std::fs::metadata(&p.path).unwrap().len()).sum();
+            assert!(estimate.data_size > image_bytes);
+            assert_eq!(estimate.output_dir, dir);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn too_little_free_space_refuses_the_build() {
+        let dir = test_dir("free-space");
+        let config = config_with_images(&dir, &[("system", &pattern(1 << 20, 9), 2 << 20)]);
+        let output = dir.join("super.img");
+        let builder = SuperImageBuilder::new(config).output(&output);
+        let build = |builder: &SuperImageBuilder| builder.build_with_cancel(&AtomicBool::new(false), |_| {});
+        let minimum = builder.estimate().unwrap().minimum_size;
+
+        PRETEND_AVAILABLE.set(Some(minimum - 1));
+        let estimate = builder.estimate().unwrap();
+        assert_eq!((estimate.available, estimate.verdict), (Some(minimum - 1), SpaceVerdict::Insufficient));
+        let result = build(&builder);
+        assert!(
+            matches!(&result, Err(BuildError::InsufficientSpace { dir: d, required, available }) if *d == dir && *required == minimum && *available == minimum - 1),
+            "{:?}",
+            result.map(|_| ())
+        );
+        assert!(!output.exists() && !partial_path(&output).exists());
+        // Not checked when turned off
+        build(&builder.clone().check_free_space(false)).unwrap();
+
+        // A raw image between its data and its full size still fits as a sparse file
+        PRETEND_AVAILABLE.set(Some(minimum));
+        assert_eq!(builder.estimate().unwrap().verdict, SpaceVerdict::Tight);
+        build(&builder).unwrap();
+        PRETEND_AVAILABLE.set(Some(16 << 20));
+        assert_eq!(builder.estimate().unwrap().verdict, SpaceVerdict::Enough);
+        PRETEND_AVAILABLE.set(None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,632 @@
+// Android sparse image format, as defined by AOSP libsparse
+// (system/core/libsparse/sparse_format.h).
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::Crc;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use thiserror::Error;
+
+use super::compressed::{Compression, decompress_reader, decompressed_size, detect_compression, read_head};
+use crate::payload::{self, PayloadError};
+
+pub const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+
+const SPARSE_MAJOR_VERSION: u16 = 1;
+const SPARSE_MINOR_VERSION: u16 = 0;
+pub(super) const SPARSE_HEADER_SIZE: u16 = 28;
+pub(super) const CHUNK_HEADER_SIZE: u16 = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+/// Raw chunk size limit when none is given, which bounds memory use while merging blocks
+const DEFAULT_MAX_RAW_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// Block and chunk counts of a finished sparse image, as stored in its header
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SparseStats {
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub total_chunks: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum SparseError {
+    #[error("File operation error: {0}")]
+    Io(io::Error),
+    /// Bad magic, unsupported version, a malformed chunk header or a truncated file
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Sparse image expanded to {actual} bytes, but its header declares {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    /// A CRC32 chunk disagrees with the data before it, `offset` is where it sits in
+    /// the expanded image
+    #[error("CRC32 chunk at offset {offset} expects {stored:#010x}, the data before it has {computed:#010x}")]
+    CrcMismatch { offset: u64, stored: u32, computed: u32 },
+    #[error("Sparse header declares {declared} blocks, but the chunks hold {found}")]
+    BlockCountMismatch { declared: u32, found: u64 },
+    /// The image ends after `found` chunks
+    #[error("Sparse header declares {declared} chunks, but the image has {found}")]
+    ChunkCountMismatch { declared: u32, found: u32 },
+    #[error("Data follows the {declared} chunks the sparse header declares")]
+    TrailingData { declared: u32 },
+}
+
+// SparseReader reports format problems as InvalidData and truncation as
+// UnexpectedEof, neither of which is an IO failure. Checks with their own variant
+// travel inside the io::Error.
+impl From<io::Error> for SparseError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData if e.get_ref().is_some_and(|inner| inner.is::<SparseError>()) => {
+                *e.into_inner().and_then(|inner| inner.downcast::<SparseError>().ok()).expect("checked above")
+            }
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => SparseError::Invalid(e.to_string()),
+            _ => SparseError::Io(e),
+        }
+    }
+}
+
+/// Detection of Android sparse images on disk
+pub struct SparseImage;
+
+impl SparseImage {
+    /// Whether the file starts with the sparse magic `0xED26FF3A`. Files that cannot
+    /// be read are reported as not sparse.
+    pub fn is_sparse<P: AsRef<Path>>(path: P) -> bool {
+        let mut magic = [0u8; 4];
+        File::open(path).and_then(|mut f| f.read_exact(&mut magic)).is_ok()
+            && u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC
+    }
+}
+
+/// Decodes a sparse image (Raw, Fill, Don't-Care and CRC32 chunks) from `reader` into
+/// a raw stream on `writer`, and returns the number of bytes written.
+///
+/// Don't-Care chunks are written as zeros and CRC32 chunks are checked against the
+/// data written before them (see `SparseReader`). The result always equals
+/// `blk_sz * total_blks` from the header, and the image must hold exactly
+/// `total_chunks` chunks; anything else is an error.
+pub fn expand_sparse<R: Read, W: Write>(reader: R, mut writer: W) -> Result<u64, SparseError> {
+    let mut sparse = SparseReader::new(reader)?;
+    let expected = sparse.expanded_size();
+    let actual = io::copy(&mut sparse, &mut writer)?;
+    if actual != expected {
+        return Err(SparseError::SizeMismatch { expected, actual });
+    }
+    if read_head(&mut sparse.inner, &mut [0u8; 1])? > 0 {
+        return Err(SparseError::TrailingData { declared: sparse.total_chunks });
+    }
+    writer.flush()?;
+    Ok(actual)
+}
+
+enum Pending {
+    None,
+    Raw(Vec<u8>),
+    Fill { value: u32, blocks: u32 },
+    DontCare(u32),
+}
+
+/// Streams an image into the sparse format, one block at a time.
+///
+/// Consecutive blocks are merged into a single chunk: identical 32-bit patterns
+/// (including all zeros) become Fill chunks, everything else Raw chunks, and skipped
+/// blocks Don't-Care chunks. The header is written as a placeholder and patched
+/// with the final counts by `finish`.
+pub struct SparseWriter<W: Write + Seek> {
+    out: W,
+    block_size: u32,
+    max_raw_chunk: u64,
+    total_blocks: u32,
+    total_chunks: u32,
+    pending: Pending,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// # Arguments
+    /// * `out` - Destination, positioned at the start of the sparse file
+    /// * `block_size` - Block size in bytes, a multiple of 4
+    /// * `max_raw_chunk` - Largest data size of a single Raw chunk
+    ///   (rounded down to whole blocks), `None` for the 64 MiB default
+    pub fn new(mut out: W, block_size: u32, max_raw_chunk: Option<u64>) -> io::Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sparse block size must be a multiple of 4"));
+        }
+        out.write_all(&[0u8; SPARSE_HEADER_SIZE as usize])?;
+        let max_raw_chunk = max_raw_chunk.unwrap_or(DEFAULT_MAX_RAW_CHUNK).max(block_size as u64);
+        Ok(Self {
+            out,
+            block_size,
+            max_raw_chunk: max_raw_chunk - max_raw_chunk % block_size as u64,
+            total_blocks: 0,
+            total_chunks: 0,
+            pending: Pending::None,
+        })
+    }
+
+    /// Adds image data; `data` must be a whole number of blocks
+    pub fn write_blocks(&mut self, data: &[u8]) -> io::Result<()> {
+        if !data.len().is_multiple_of(self.block_size as usize) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sparse data is not a whole number of blocks"));
+        }
+        for block in data.chunks(self.block_size as usize) {
+            let value = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+            let uniform = block.chunks(4).all(|word| word == value.to_le_bytes());
+            match (&mut self.pending, uniform) {
+                (Pending::Fill { value: pending_value, blocks }, true) if *pending_value == value => *blocks += 1,
+                (Pending::Raw(buf), false) if (buf.len() + block.len()) as u64 <= self.max_raw_chunk => {
+                    buf.extend_from_slice(block)
+                }
+                (_, true) => {
+                    self.flush_pending()?;
+                    self.pending = Pending::Fill { value, blocks: 1 };
+                }
+                (_, false) => {
+                    self.flush_pending()?;
+                    self.pending = Pending::Raw(block.to_vec());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `blocks` blocks whose content does not matter (left untouched when flashed,
+    /// zeros when expanded with simg2img)
+    pub fn skip_blocks(&mut self, blocks: u32) -> io::Result<()> {
+        if blocks == 0 {
+            return Ok(());
+        }
+        if let Pending::DontCare(pending) = &mut self.pending {
+            *pending += blocks;
+            return Ok(());
+        }
+        self.flush_pending()?;
+        self.pending = Pending::DontCare(blocks);
+        Ok(())
+    }
+
+    /// Number of blocks added so far
+    pub fn blocks_written(&self) -> u32 {
+        self.total_blocks
+            + match &self.pending {
+                Pending::None => 0,
+                Pending::Raw(buf) => (buf.len() / self.block_size as usize) as u32,
+                Pending::Fill { blocks, .. } | Pending::DontCare(blocks) => *blocks,
+            }
+    }
+
+    /// Writes the last chunk and the final header, and returns the header counts
+    pub fn finish(mut self) -> io::Result<SparseStats> {
+        self.flush_pending()?;
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_u32::<LittleEndian>(SPARSE_HEADER_MAGIC)?;
+        self.out.write_u16::<LittleEndian>(SPARSE_MAJOR_VERSION)?;
+        self.out.write_u16::<LittleEndian>(SPARSE_MINOR_VERSION)?;
+        self.out.write_u16::<LittleEndian>(SPARSE_HEADER_SIZE)?;
+        self.out.write_u16::<LittleEndian>(CHUNK_HEADER_SIZE)?;
+        self.out.write_u32::<LittleEndian>(self.block_size)?;
+        self.out.write_u32::<LittleEndian>(self.total_blocks)?;
+        self.out.write_u32::<LittleEndian>(self.total_chunks)?;
+        self.out.write_u32::<LittleEndian>(0)?; // image_checksum, unused by AOSP tools
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(SparseStats { block_size: self.block_size, total_blocks: self.total_blocks, total_chunks: self.total_chunks })
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => {}
+            Pending::Raw(buf) => {
+                let blocks = (buf.len() / self.block_size as usize) as u32;
+                self.write_chunk_header(CHUNK_TYPE_RAW, blocks, buf.len() as u32)?;
+                self.out.write_all(&buf)?;
+            }
+            Pending::Fill { value, blocks } => {
+                self.write_chunk_header(CHUNK_TYPE_FILL, blocks, 4)?;
+                self.out.write_u32::<LittleEndian>(value)?;
+            }
+            Pending::DontCare(blocks) => self.write_chunk_header(CHUNK_TYPE_DONT_CARE, blocks, 0)?,
+        }
+        Ok(())
+    }
+
+    fn write_chunk_header(&mut self, chunk_type: u16, blocks: u32, data_size: u32) -> io::Result<()> {
+        self.out.write_u16::<LittleEndian>(chunk_type)?;
+        self.out.write_u16::<LittleEndian>(0)?;
+        self.out.write_u32::<LittleEndian>(blocks)?;
+        self.out.write_u32::<LittleEndian>(CHUNK_HEADER_SIZE as u32 + data_size)?;
+        self.total_blocks += blocks;
+        self.total_chunks += 1;
+        Ok(())
+    }
+}
+
+enum Chunk {
+    Done,
+    Raw(u64),
+    Fill { value: [u8; 4], remaining: u64 },
+    Zero(u64),
+}
+
+/// Expands a sparse image on the fly, reading like the raw image it describes.
+///
+/// Fill chunks repeat their pattern and Don't-Care chunks read as zeros, so the
+/// output is what `simg2img` would produce. A CRC32 chunk holds the CRC of all
+/// output before it (Don't-Care blocks as zeros, like libsparse), a mismatch fails
+/// the read. So does a chunk past `total_blks`, or an image that ends before
+/// `total_chunks` chunks or before `total_blks` blocks. The errors are `InvalidData`
+/// carrying the `SparseError`, which `SparseError::from` recovers.
+pub struct SparseReader<R: Read> {
+    inner: R,
+    block_size: u32,
+    total_blocks: u32,
+    total_chunks: u32,
+    chunk_header_size: u16,
+    chunks_left: u32,
+    chunk: Chunk,
+    /// Blocks of the chunks read so far
+    blocks_seen: u64,
+    /// Running CRC of the output
+    crc: Crc,
+    /// Bytes of output so far
+    position: u64,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid sparse image: {}", message))
+}
+
+fn check_failed(error: SparseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Reads the sparse header; `inner` must be positioned at the magic
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        if inner.read_u32::<LittleEndian>()? != SPARSE_HEADER_MAGIC {
+            return Err(invalid_data("bad magic"));
+        }
+        if inner.read_u16::<LittleEndian>()? != SPARSE_MAJOR_VERSION {
+            return Err(invalid_data("unsupported major version"));
+        }
+        let _minor_version = inner.read_u16::<LittleEndian>()?;
+        let file_header_size = inner.read_u16::<LittleEndian>()?;
+        let chunk_header_size = inner.read_u16::<LittleEndian>()?;
+        let block_size = inner.read_u32::<LittleEndian>()?;
+        let total_blocks = inner.read_u32::<LittleEndian>()?;
+        let total_chunks = inner.read_u32::<LittleEndian>()?;
+        let _image_checksum = inner.read_u32::<LittleEndian>()?;
+        if file_header_size < SPARSE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+            return Err(invalid_data("header too small"));
+        }
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid_data("bad block size"));
+        }
+        skip(&mut inner, (file_header_size - SPARSE_HEADER_SIZE) as u64)?;
+        Ok(Self {
+            inner,
+            block_size,
+            total_blocks,
+            total_chunks,
+            chunk_header_size,
+            chunks_left: total_chunks,
+            chunk: Chunk::Done,
+            blocks_seen: 0,
+            crc: Crc::new(),
+            position: 0,
+        })
+    }
+
+    /// Size of the expanded image, `blk_sz * total_blks`
+    pub fn expanded_size(&self) -> u64 {
+        self.block_size as u64 * self.total_blocks as u64
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        while self.chunks_left > 0 {
+            let mut chunk_type = [0u8; 2];
+            match read_head(&mut self.inner, &mut chunk_type)? {
+                0 => {
+                    let found = self.total_chunks - self.chunks_left;
+                    return Err(check_failed(SparseError::ChunkCountMismatch { declared: self.total_chunks, found }));
+                }
+                1 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated sparse image")),
+                _ => {}
+            }
+            self.chunks_left -= 1;
+            let chunk_type = u16::from_le_bytes(chunk_type);
+            let _reserved = self.inner.read_u16::<LittleEndian>()?;
+            let blocks = self.inner.read_u32::<LittleEndian>()? as u64;
+            let total_size = self.inner.read_u32::<LittleEndian>()? as u64;
+            skip(&mut self.inner, (self.chunk_header_size - CHUNK_HEADER_SIZE) as u64)?;
+            let len = blocks * self.block_size as u64;
+            let data_size = total_size.saturating_sub(self.chunk_header_size as u64);
+            self.chunk = match chunk_type {
+                CHUNK_TYPE_RAW if data_size == len => Chunk::Raw(len),
+                CHUNK_TYPE_FILL if data_size == 4 => {
+                    let mut value = [0u8; 4];
+                    self.inner.read_exact(&mut value)?;
+                    Chunk::Fill { value, remaining: len }
+                }
+                CHUNK_TYPE_DONT_CARE if data_size == 0 => Chunk::Zero(len),
+                CHUNK_TYPE_CRC32 if data_size == 4 => {
+                    let stored = self.inner.read_u32::<LittleEndian>()?;
+                    let computed = self.crc.sum();
+                    if stored != computed {
+                        return Err(check_failed(SparseError::CrcMismatch { offset: self.position, stored, computed }));
+                    }
+                    continue;
+                }
+                _ => return Err(invalid_data("bad chunk header")),
+            };
+            self.blocks_seen += blocks;
+            if self.blocks_seen > self.total_blocks as u64 {
+                let found = self.blocks_seen;
+                return Err(check_failed(SparseError::BlockCountMismatch { declared: self.total_blocks, found }));
+            }
+            if len > 0 {
+                return Ok(());
+            }
+        }
+        if self.blocks_seen != self.total_blocks as u64 {
+            let found = self.blocks_seen;
+            return Err(check_failed(SparseError::BlockCountMismatch { declared: self.total_blocks, found }));
+        }
+        self.chunk = Chunk::Done;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.read_expanded(buf)?;
+        self.crc.update(&buf[..read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> SparseReader<R> {
+    fn read_expanded(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let exhausted = match self.chunk {
+            Chunk::Done => true,
+            Chunk::Raw(remaining) | Chunk::Fill { remaining, .. } | Chunk::Zero(remaining) => remaining == 0,
+        };
+        if exhausted {
+            self.next_chunk()?;
+        }
+        match &mut self.chunk {
+            Chunk::Done => Ok(0),
+            Chunk::Raw(remaining) => {
+                let len = (*remaining).min(buf.len() as u64) as usize;
+                let read = self.inner.read(&mut buf[..len])?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated sparse image"));
+                }
+                *remaining -= read as u64;
+                Ok(read)
+            }
+            Chunk::Fill { value, remaining } => {
+                let len = (*remaining).min(buf.len() as u64) as usize;
+                // Fill chunks are a whole number of blocks, so the pattern phase
+                // follows from the bytes still remaining
+                let phase = ((4 - *remaining % 4) % 4) as usize;
+                for (i, byte) in buf[..len].iter_mut().enumerate() {
+                    *byte = value[(phase + i) % 4];
+                }
+                *remaining -= len as u64;
+                Ok(len)
+            }
+            Chunk::Zero(remaining) => {
+                let len = (*remaining).min(buf.len() as u64) as usize;
+                buf[..len].fill(0);
+                *remaining -= len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated sparse image"));
+    }
+    Ok(())
+}
+
+/// Opens an image that may be raw or sparse, and returns a reader of its raw contents
+/// together with the raw size (the expanded size for sparse images).
+///
+/// gzip, xz and zstd compressed images (`system.img.xz`, `super.img.zst`) are
+/// decompressed while they are read, and the size is the decompressed one; a
+/// compressed sparse image is both decompressed and expanded.
+///
+/// A path like `payload.bin#system` that is not a file itself reads partition
+/// `system` from the OTA payload `payload.bin`, decoded and hash-checked while it is
+/// read (see `payload::PartitionReader`), so configs can use images still inside
+/// an OTA.
+pub fn open_raw_or_sparse(path: &Path) -> io::Result<(Box<dyn Read>, u64)> {
+    if !path.exists()
+        && let Some((payload, partition)) = path.to_str().and_then(payload::split_payload_path)
+        && Path::new(payload).is_file()
+    {
+        let reader = payload::Payload::open(payload).and_then(|p| p.reader(partition)).map_err(|e| match e {
+            PayloadError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        let size = reader.size();
+        return Ok((Box::new(reader), size));
+    }
+    if detect_compression(path)? != Compression::None {
+        let mut reader = decompress_reader(path)?;
+        let mut magic = [0u8; 4];
+        let filled = read_head(&mut reader, &mut magic)?;
+        let reader = io::Cursor::new(magic[..filled].to_vec()).chain(reader);
+        if filled == magic.len() && u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC {
+            let reader = SparseReader::new(BufReader::new(reader))?;
+            let size = reader.expanded_size();
+            return Ok((Box::new(reader), size));
+        }
+        return Ok((Box::new(reader), decompressed_size(path)?));
+    }
+    let mut image = File::open(path)?;
+    let file_size = image.metadata()?.len();
+
+    let mut magic = [0u8; 4];
+    let is_sparse = file_size >= 4 && {
+        image.read_exact(&mut magic)?;
+        image.seek(SeekFrom::Start(0))?;
+        u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC
+    };
+    if is_sparse {
+        let reader = SparseReader::new(BufReader::new(image))?;
+        let size = reader.expanded_size();
+        Ok((Box::new(reader), size))
+    } else {
+        Ok((Box::new(BufReader::new(image)), file_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{config_with_images, fixture, lines, pattern, test_dir};
+    use crate::super_image_creater::{BuildError, ByteSize, OutputFormat, build_super_image, build_super_image_as};
+    use std::io::Cursor;
+
+    /// Header counts and the (type, blocks) of every chunk of a sparse image
+    fn chunk_table(image: &[u8]) -> (SparseStats, Vec<(u16, u32)>) {
+        let mut reader = Cursor::new(image);
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), SPARSE_HEADER_MAGIC);
+        reader.set_position(12);
+        let block_size = reader.read_u32::<LittleEndian>().unwrap();
+        let total_blocks = reader.read_u32::<LittleEndian>().unwrap();
+        let total_chunks = reader.read_u32::<LittleEndian>().unwrap();
+        reader.set_position(SPARSE_HEADER_SIZE as u64);
+        let mut chunks = Vec::new();
+        while (reader.position() as usize) < image.len() {
+            let chunk_type = reader.read_u16::<LittleEndian>().unwrap();
+            let _reserved = reader.read_u16::<LittleEndian>().unwrap();
+            let blocks = reader.read_u32::<LittleEndian>().unwrap();
+            let total_size = reader.read_u32::<LittleEndian>().unwrap();
+            reader.set_position(reader.position() + (total_size - CHUNK_HEADER_SIZE as u32) as u64);
+            chunks.push((chunk_type, blocks));
+        }
+        assert_eq!(reader.position() as usize, image.len());
+        (SparseStats { block_size, total_blocks, total_chunks }, chunks)
+    }
+
+    #[test]
+    fn writer_counts_every_block_and_chunk() {
+        let raw = pattern(4096 * 5, 7);
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = SparseWriter::new(&mut out, 4096, Some(8192)).unwrap();
+        writer.write_blocks(&raw).unwrap(); // three Raw chunks of at most 2 blocks
+        writer.write_blocks(&[0u8; 4096 * 3]).unwrap(); // one Fill chunk
+        writer.skip_blocks(4).unwrap();
+        writer.skip_blocks(6).unwrap(); // merged into one Don't-Care chunk
+        writer.write_blocks(&[0x11u8; 4096]).unwrap(); // a Fill chunk with another value
+        assert_eq!(writer.blocks_written(), 19);
+        let stats = writer.finish().unwrap();
+        assert_eq!(stats, SparseStats { block_size: 4096, total_blocks: 19, total_chunks: 6 });
+
+        let (header, chunks) = chunk_table(out.get_ref());
+        assert_eq!(header, stats);
+        let raw_chunk = (CHUNK_TYPE_RAW, 2);
+        assert_eq!(chunks, [raw_chunk, raw_chunk, (CHUNK_TYPE_RAW, 1), (CHUNK_TYPE_FILL, 3), (CHUNK_TYPE_DONT_CARE, 10), (CHUNK_TYPE_FILL, 1)]);
+    }
+
+    #[test]
+    fn sparse_build_has_the_counts_of_its_chunks_and_expands_to_the_raw_build() {
+        let dir = test_dir("sparse-build");
+        let mut system = pattern(3 << 20, 3);
+        system[8192..5 * 4096].fill(0);
+        let config = config_with_images(&dir, &[("system", &system, 4 << 20), ("vendor", &pattern(5000, 4), 1 << 20)]);
+        let raw_path = dir.join("raw.img");
+        let sparse_path = dir.join("sparse.img");
+        build_super_image(&config, &raw_path).unwrap();
+        let report = build_super_image_as(&config, &sparse_path, OutputFormat::Sparse { max_blob_size: Some(1 << 20) }).unwrap();
+
+        let sparse = std::fs::read(&sparse_path).unwrap();
+        let (stats, chunks) = chunk_table(&sparse);
+        assert_eq!(report.sparse.as_ref(), Some(&stats));
+        assert_eq!(stats.total_blocks as u64 * stats.block_size as u64, 16 << 20);
+        assert_eq!(stats.total_chunks as usize, chunks.len());
+        assert_eq!(chunks.iter().map(|&(_, blocks)| blocks as u64).sum::<u64>(), stats.total_blocks as u64);
+        assert!(chunks.iter().any(|&(chunk_type, _)| chunk_type == CHUNK_TYPE_DONT_CARE));
+        assert!(chunks.iter().filter(|&&(chunk_type, _)| chunk_type == CHUNK_TYPE_RAW).all(|&(_, blocks)| blocks <= 256));
+        assert!(sparse.len() < 5 << 20, "sparse image is {} bytes", sparse.len());
+
+        let mut expanded = Vec::new();
+        assert_eq!(expand_sparse(&sparse[..], &mut expanded).unwrap(), 16 << 20);
+        assert!(expanded == std::fs::read(&raw_path).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// What `testdata/lines.simg` expands to: a Raw chunk of 4 blocks, a Fill chunk of
+    /// 2, a Don't-Care chunk of 2 and a Raw chunk of 1 block, followed by a CRC32 chunk
+    fn lines_simg_expanded() -> Vec<u8> {
+        let lines = lines();
+        let mut expanded = lines[..4 * 4096].to_vec();
+        expanded.extend(b"000\n".repeat(2 * 4096 / 4));
+        expanded.extend([0u8; 2 * 4096]);
+        expanded.extend(&lines[4 * 4096..5 * 4096]);
+        expanded
+    }
+
+    #[test]
+    fn fixture_sparse_file_expands_and_is_built_into_super() {
+        let path = fixture("lines.simg");
+        let expanded = lines_simg_expanded();
+        assert!(SparseImage::is_sparse(&path));
+        assert!(!SparseImage::is_sparse(fixture("lines.img.gz")));
+        let mut out = Vec::new();
+        assert_eq!(expand_sparse(File::open(&path).unwrap(), &mut out).unwrap(), expanded.len() as u64);
+        assert!(out == expanded);
+        let (mut reader, size) = open_raw_or_sparse(&path).unwrap();
+        assert_eq!(size, expanded.len() as u64);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert!(out == expanded);
+
+        let dir = test_dir("sparse-fixture");
+        let mut config = config_with_images(&dir, &[("vendor", &pattern(100, 5), 4096)]);
+        config.partitions[0].path = path.to_str().unwrap().into();
+        config.partitions[0].size = Some(ByteSize::new(8 * 4096));
+        let output = dir.join("super.img");
+        let error = build_super_image(&config, &output).unwrap_err();
+        assert!(matches!(error, BuildError::ImageTooLarge { image_size: 36864, size: 32768, .. }), "{}", error);
+
+        config.partitions[0].size = Some(ByteSize::new(1 << 20));
+        let report = build_super_image(&config, &output).unwrap();
+        assert_eq!(report.partitions[0].image_bytes, expanded.len() as u64);
+        let image = std::fs::read(&output).unwrap();
+        let offset = report.partitions[0].offset as usize;
+        assert!(image[offset..offset + expanded.len()] == expanded[..]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn damaged_fixture_sparse_file_is_rejected() {
+        let sparse = std::fs::read(fixture("lines.simg")).unwrap();
+        let error = expand_sparse(&sparse[..sparse.len() - 100], io::sink()).unwrap_err();
+        assert!(matches!(error, SparseError::Io(_) | SparseError::Invalid(_)), "{}", error);
+        let mut bad_crc = sparse.clone();
+        *bad_crc.last_mut().unwrap() ^= 1;
+        let error = expand_sparse(&bad_crc[..], io::sink()).unwrap_err();
+        assert!(matches!(error, SparseError::CrcMismatch { offset: 36864, .. }), "{}", error);
+        let mut bad_magic = sparse;
+        bad_magic[0] = 0;
+        assert!(matches!(expand_sparse(&bad_magic[..], io::sink()), Err(SparseError::Invalid(_))));
+    }
+}
@@ -0,0 +1,394 @@
+use super::builder::partial_path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Largest MiB-aligned chunk that fits in a FAT32 file (at most 4 GiB - 1 byte)
+pub const FAT32_CHUNK_SIZE: u64 = (4 << 30) - (1 << 20);
+
+/// Read buffer used to hash and join chunks
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Zeros hashed for the regions of a chunk that are never written
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// One chunk file of a split image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitChunk {
+    /// File name next to the index, `<image>.<n>`
+    pub file: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the chunk
+    pub sha256: String,
+}
+
+/// Contents of the `<image>.chunks.json` index written next to the chunks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitIndex {
+    /// File name of the image the chunks join into
+    pub image: String,
+    pub image_size: u64,
+    /// Size of every chunk but the last, which holds the rest
+    pub chunk_size: u64,
+    /// In image order
+    pub chunks: Vec<SplitChunk>,
+}
+
+#[derive(Error, Debug)]
+pub enum SplitError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Chunk index error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Chunk size must be at least one byte")]
+    ZeroChunkSize,
+    #[error("Chunk {index} is '{file}', expected '{expected}'; the index lists the chunks out of order")]
+    OutOfOrder { index: usize, file: String, expected: String },
+    #[error("Chunk '{0}' is missing")]
+    MissingChunk(String),
+    #[error("Chunk '{file}' is {actual} bytes, the index expects {expected}")]
+    SizeMismatch { file: String, expected: u64, actual: u64 },
+    #[error("Chunk '{0}' does not match its SHA-256 in the index")]
+    HashMismatch(String),
+    #[error("Chunks add up to {actual} bytes, the index expects an image of {expected}")]
+    ImageSizeMismatch { expected: u64, actual: u64 },
+}
+
+/// Path of the index written for the chunks of `image` in `dir`, e.g.
+/// `super.img.chunks.json`
+pub fn split_index_path(dir: &Path, image: &str) -> PathBuf {
+    dir.join(format!("{}.chunks.json", image))
+}
+
+fn chunk_name(image: &str, index: usize) -> String {
+    format!("{}.{}", image, index)
+}
+
+impl SplitIndex {
+    pub fn read(path: &Path) -> Result<Self, SplitError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), SplitError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Chunk file of a `ChunkWriter`, hashed up to `hashed` while it is written front
+/// to back
+struct OpenChunk {
+    file: File,
+    path: PathBuf,
+    sha256: Sha256,
+    hashed: u64,
+    /// Written behind `hashed` (a sparse header patched at the end), so the hash is
+    /// taken from the file once it is complete
+    rehash: bool,
+}
+
+impl OpenChunk {
+    /// Hashes zeros up to `offset`, what the file reads back as over a hole
+    fn hash_zeros(&mut self, offset: u64) {
+        while self.hashed < offset {
+            let n = (offset - self.hashed).min(ZEROS.len() as u64) as usize;
+            self.sha256.update(&ZEROS[..n]);
+            self.hashed += n as u64;
+        }
+    }
+}
+
+/// Writes an image as `<image>.0`, `<image>.1`, ... of `chunk_size` bytes each in
+/// `dir`, as the image is written instead of in a second pass.
+///
+/// Seeks are allowed anywhere. Regions seeked over stay holes on disk and are
+/// hashed as zeros; a chunk written behind the part already hashed is hashed again
+/// from disk by `finish`. Chunks go to their `partial_path` until `finish` renames
+/// them and writes the index, and are removed if the writer is dropped before.
+pub struct ChunkWriter {
+    dir: PathBuf,
+    image: String,
+    chunk_size: u64,
+    position: u64,
+    /// Image size so far, the end of the furthest write
+    end: u64,
+    chunks: Vec<OpenChunk>,
+    finished: bool,
+}
+
+impl ChunkWriter {
+    /// Writer for the chunks of the image named `image` (a file name, `super.img`)
+    pub fn new(dir: &Path, image: &str, chunk_size: u64) -> Result<Self, SplitError> {
+        if chunk_size == 0 {
+            return Err(SplitError::ZeroChunkSize);
+        }
+        fs::create_dir_all(dir)?;
+        Ok(ChunkWriter {
+            dir: dir.to_path_buf(),
+            image: image.to_string(),
+            chunk_size,
+            position: 0,
+            end: 0,
+            chunks: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Opens the chunks up to `index`
+    fn chunk(&mut self, index: usize) -> io::Result<&mut OpenChunk> {
+        while self.chunks.len() <= index {
+            let path = partial_path(&self.dir.join(chunk_name(&self.image, self.chunks.len())));
+            let file = OpenOptions::new().create(true).read(true).write(true).truncate(true).open(&path)?;
+            self.chunks.push(OpenChunk { file, path, sha256: Sha256::new(), hashed: 0, rehash: false });
+        }
+        Ok(&mut self.chunks[index])
+    }
+
+    /// Sizes and hashes the chunks, renames them to their final names and writes the
+    /// index. An image of 0 bytes has no chunks.
+    pub fn finish(mut self) -> Result<SplitIndex, SplitError> {
+        let count = self.end.div_ceil(self.chunk_size) as usize;
+        if count > 0 {
+            self.chunk(count - 1)?;
+        }
+        let mut entries = Vec::with_capacity(count);
+        for (i, chunk) in self.chunks.iter_mut().enumerate() {
+            let size = (self.end - i as u64 * self.chunk_size).min(self.chunk_size);
+            chunk.file.set_len(size)?;
+            let sha256 = if chunk.rehash {
+                chunk.file.seek(SeekFrom::Start(0))?;
+                let mut sha256 = Sha256::new();
+                let mut buf = vec![0u8; BUFFER_SIZE];
+                loop {
+                    let n = chunk.file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    sha256.update(&buf[..n]);
+                }
+                sha256
+            } else {
+                chunk.hash_zeros(size);
+                std::mem::take(&mut chunk.sha256)
+            };
+            chunk.file.sync_all()?;
+            entries.push(SplitChunk { file: chunk_name(&self.image, i), size, sha256: format!("{:x}", sha256.finalize()) });
+        }
+        for (chunk, entry) in self.chunks.iter().zip(&entries) {
+            fs::rename(&chunk.path, self.dir.join(&entry.file))?;
+        }
+        self.finished = true;
+        let index = SplitIndex { image: self.image.clone(), image_size: self.end, chunk_size: self.chunk_size, chunks: entries };
+        index.write(&split_index_path(&self.dir, &self.image))?;
+        Ok(index)
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_size = self.chunk_size;
+        let offset = self.position % chunk_size;
+        let n = buf.len().min((chunk_size - offset) as usize);
+        let chunk = self.chunk((self.position / chunk_size) as usize)?;
+        chunk.file.seek(SeekFrom::Start(offset))?;
+        chunk.file.write_all(&buf[..n])?;
+        if offset < chunk.hashed {
+            chunk.rehash = true;
+        } else if !chunk.rehash {
+            chunk.hash_zeros(offset);
+            chunk.sha256.update(&buf[..n]);
+            chunk.hashed += n as u64;
+        }
+        self.position += n as u64;
+        self.end = self.end.max(self.position);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChunkWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.end.checked_add_signed(delta),
+        };
+        self.position = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the image"))?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            for chunk in &self.chunks {
+                let _ = fs::remove_file(&chunk.path);
+            }
+        }
+    }
+}
+
+/// Splits the image at `input` into chunks of `chunk_size` bytes in `out_dir`,
+/// named after the input file, and writes their index (see `split_index_path`)
+pub fn split_image(input: &Path, chunk_size: u64, out_dir: &Path) -> Result<SplitIndex, SplitError> {
+    let image = input
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "input is not a file"))?
+        .to_string_lossy()
+        .into_owned();
+    let mut writer = ChunkWriter::new(out_dir, &image, chunk_size)?;
+    io::copy(&mut File::open(input)?, &mut writer)?;
+    writer.finish()
+}
+
+/// Joins the chunks listed in the index at `index` (which sit next to it) into
+/// `out`, returns the image size.
+///
+/// The chunks must be listed in order under their `<image>.<n>` names, and every
+/// chunk must exist with the size and SHA-256 the index records; otherwise nothing
+/// is written to `out`. The image is written to `partial_path(out)` and renamed once
+/// complete, so a chunk that turns out to be corrupt never leaves a partial image
+/// under `out`.
+pub fn join_image(index: &Path, out: &Path) -> Result<u64, SplitError> {
+    let split = SplitIndex::read(index)?;
+    let dir = index.parent().unwrap_or(Path::new("."));
+    let mut total = 0u64;
+    for (i, chunk) in split.chunks.iter().enumerate() {
+        let expected = chunk_name(&split.image, i);
+        if chunk.file != expected {
+            return Err(SplitError::OutOfOrder { index: i, file: chunk.file.clone(), expected });
+        }
+        let actual = match fs::metadata(dir.join(&chunk.file)) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(SplitError::MissingChunk(chunk.file.clone())),
+            Err(e) => return Err(e.into()),
+        };
+        if actual != chunk.size {
+            return Err(SplitError::SizeMismatch { file: chunk.file.clone(), expected: chunk.size, actual });
+        }
+        total += chunk.size;
+    }
+    if total != split.image_size {
+        return Err(SplitError::ImageSizeMismatch { expected: split.image_size, actual: total });
+    }
+
+    let partial = partial_path(out);
+    let result = (|| -> Result<(), SplitError> {
+        let mut file = File::create(&partial)?;
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        for chunk in &split.chunks {
+            let mut input = File::open(dir.join(&chunk.file))?;
+            let mut sha256 = Sha256::new();
+            loop {
+                let n = input.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                sha256.update(&buf[..n]);
+                file.write_all(&buf[..n])?;
+            }
+            if format!("{:x}", sha256.finalize()) != chunk.sha256 {
+                return Err(SplitError::HashMismatch(chunk.file.clone()));
+            }
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&partial, out)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result.map(|_| split.image_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{pattern, test_dir};
+
+    /// Splits `len` bytes of a pattern into chunks of `chunk_size`, returns the image
+    fn split(dir: &Path, len: usize, chunk_size: u64) -> (Vec<u8>, SplitIndex) {
+        let data = pattern(len, 0x3c);
+        let input = dir.join("super.img");
+        fs::write(&input, &data).unwrap();
+        let index = split_image(&input, chunk_size, &dir.join("chunks")).unwrap();
+        (data, index)
+    }
+
+    #[test]
+    fn split_chunks_join_back_into_the_image() {
+        let dir = test_dir("split-join");
+        // Neither the image nor the chunks are a multiple of any block size
+        let (data, index) = split(&dir, 3 * 777_777 + 4321, 777_777);
+        let sizes: Vec<u64> = index.chunks.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, [777_777, 777_777, 777_777, 4321]);
+        assert_eq!(index.chunks[3].file, "super.img.3");
+        assert_eq!(SplitIndex::read(&split_index_path(&dir.join("chunks"), "super.img")).unwrap(), index);
+        let chunk = fs::read(dir.join("chunks/super.img.1")).unwrap();
+        assert!(chunk == data[777_777..2 * 777_777]);
+        assert_eq!(index.chunks[1].sha256, format!("{:x}", Sha256::digest(&chunk)));
+
+        let joined = dir.join("joined.img");
+        assert_eq!(join_image(&split_index_path(&dir.join("chunks"), "super.img"), &joined).unwrap(), data.len() as u64);
+        assert!(fs::read(&joined).unwrap() == data);
+
+        // A chunk size of the whole image or more gives one chunk
+        let (_, index) = split(&dir, 5000, 5000);
+        assert_eq!(index.chunks.len(), 1);
+        assert!(matches!(split_image(&dir.join("super.img"), 0, &dir), Err(SplitError::ZeroChunkSize)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn missing_short_or_changed_chunks_are_rejected() {
+        let dir = test_dir("split-broken");
+        let (_, index) = split(&dir, 10_000, 4000);
+        let chunks = dir.join("chunks");
+        let index_path = split_index_path(&chunks, "super.img");
+        let joined = dir.join("joined.img");
+        let original = fs::read(chunks.join("super.img.1")).unwrap();
+
+        fs::write(chunks.join("super.img.1"), &original[..3999]).unwrap();
+        let error = join_image(&index_path, &joined).unwrap_err();
+        assert!(matches!(&error, SplitError::SizeMismatch { file, expected: 4000, actual: 3999 } if file == "super.img.1"), "{}", error);
+
+        let mut changed = original.clone();
+        changed[100] ^= 1;
+        fs::write(chunks.join("super.img.1"), &changed).unwrap();
+        let error = join_image(&index_path, &joined).unwrap_err();
+        assert!(matches!(&error, SplitError::HashMismatch(file) if file == "super.img.1"), "{}", error);
+        // Neither the image nor its partial file is left behind
+        assert!(!joined.exists() && !partial_path(&joined).exists());
+
+        fs::remove_file(chunks.join("super.img.1")).unwrap();
+        let error = join_image(&index_path, &joined).unwrap_err();
+        assert!(matches!(&error, SplitError::MissingChunk(file) if file == "super.img.1"), "{}", error);
+
+        fs::write(chunks.join("super.img.1"), &original).unwrap();
+        let mut reordered = index.clone();
+        reordered.chunks.swap(0, 1);
+        reordered.write(&index_path).unwrap();
+        let error = join_image(&index_path, &joined).unwrap_err();
+        assert!(matches!(&error, SplitError::OutOfOrder { index: 0, .. }), "{}", error);
+
+        let mut truncated = index.clone();
+        truncated.chunks.pop();
+        truncated.write(&index_path).unwrap();
+        let error = join_image(&index_path, &joined).unwrap_err();
+        assert!(matches!(error, SplitError::ImageSizeMismatch { expected: 10_000, actual: 8000 }), "{}", error);
+
+        index.write(&index_path).unwrap();
+        assert_eq!(join_image(&index_path, &joined).unwrap(), 10_000);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
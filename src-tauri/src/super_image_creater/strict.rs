@@ -0,0 +1,133 @@
+//! Field lists of the config structs with `deny_unknown_fields`, for
+//! `read_partition_config_strict`. Keep them in sync with the structs in `mod.rs`.
+//!
+//! Values are not looked at (the lenient parse already checked them), so every
+//! field is optional and ignored here; only keys outside these lists fail.
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictPartitionConfig {
+    config_version: Option<IgnoredAny>,
+    super_meta: Option<StrictSuperMeta>,
+    nv_text: Option<IgnoredAny>,
+    block_devices: Option<Vec<StrictBlockDevice>>,
+    groups: Option<Vec<StrictGroup>>,
+    nv_id: Option<IgnoredAny>,
+    partitions: Option<Vec<StrictPartition>>,
+    virtual_ab: Option<IgnoredAny>,
+    retrofit: Option<IgnoredAny>,
+    metadata_slots: Option<IgnoredAny>,
+    cow_reserve: Option<IgnoredAny>,
+    variants: Option<Vec<StrictVariant>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictSuperMeta {
+    path: Option<IgnoredAny>,
+    size: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictBlockDevice {
+    block_size: Option<IgnoredAny>,
+    name: Option<IgnoredAny>,
+    alignment: Option<IgnoredAny>,
+    size: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictGroup {
+    name: Option<IgnoredAny>,
+    maximum_size: Option<IgnoredAny>,
+    maximum_size_computed: Option<IgnoredAny>,
+    cow_reserve: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictPartition {
+    is_dynamic: Option<IgnoredAny>,
+    name: Option<IgnoredAny>,
+    group_name: Option<IgnoredAny>,
+    path: Option<IgnoredAny>,
+    size: Option<IgnoredAny>,
+    expected_sha256: Option<IgnoredAny>,
+    expected_fs: Option<IgnoredAny>,
+    zero_fill: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictVariant {
+    name: Option<IgnoredAny>,
+    super_meta_size: Option<IgnoredAny>,
+    block_devices: Option<Vec<StrictBlockDeviceOverride>>,
+    groups: Option<Vec<StrictGroupOverride>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictBlockDeviceOverride {
+    name: Option<IgnoredAny>,
+    size: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictGroupOverride {
+    name: Option<IgnoredAny>,
+    maximum_size: Option<IgnoredAny>,
+}
+
+/// Fails on the first key that is not a field of the config structs, with serde's
+/// "unknown field `foo`, expected ..." message and its location
+pub(super) fn check_known_fields(content: &str) -> Result<(), serde_json::Error> {
+    serde_json::from_str::<StrictPartitionConfig>(content).map(|_| ())
+}
+
+/// `check_known_fields` of a config already parsed into a value, e.g. one upgraded
+/// from an older layout. The errors have no location.
+pub(super) fn check_known_fields_in(root: &serde_json::Value) -> Result<(), serde_json::Error> {
+    StrictPartitionConfig::deserialize(root).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::super_image_creater::test_support::{SAMPLE, test_dir};
+    use crate::super_image_creater::{parse_partition_config_str, read_partition_config_strict, read_partition_config_strict_from_str};
+
+    #[test]
+    fn stray_keys_fail_only_the_strict_parse() {
+        assert!(read_partition_config_strict_from_str(SAMPLE).is_ok());
+
+        let in_super_meta = SAMPLE.replace(r#""size": "65536"}"#, r#""size": "65536", "foo": 1}"#);
+        assert!(parse_partition_config_str(&in_super_meta).is_ok());
+        let error = read_partition_config_strict_from_str(&in_super_meta).unwrap_err();
+        assert!(error.to_string().contains("unknown field `foo`"), "{}", error);
+        assert_eq!(error.location().map(|(line, _)| line), Some(2));
+
+        let at_top_level = SAMPLE.replace(r#""nv_text": "Global","#, r#""nv_text": "Global", "foo": "x","#);
+        assert!(parse_partition_config_str(&at_top_level).is_ok());
+        let path = test_dir("strict").join("super_def.json");
+        std::fs::write(&path, &at_top_level).unwrap();
+        let error = read_partition_config_strict(&path).unwrap_err();
+        assert!(error.to_string().contains("unknown field `foo`") && error.to_string().contains("super_def.json"), "{}", error);
+        assert_eq!(error.location().map(|(line, _)| line), Some(3));
+
+        let in_partition = SAMPLE.replace(r#""size": "4096k""#, r#""sizee": "4096k""#);
+        assert!(read_partition_config_strict_from_str(&in_partition).unwrap_err().to_string().contains("`sizee`"));
+    }
+}
@@ -0,0 +1,81 @@
+//! Scratch directories, images and configs shared by the tests of this module
+use super::fs_type::FsType;
+use super::{BlockDevice, ByteSize, Group, Partition, PartitionConfig};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A config in the layout of the stock OPlus `super_def.*.json` files: an A/B pair
+/// of groups, sizes in several spellings, and partitions without path or size
+pub(crate) const SAMPLE: &str = r#"{
+  "super_meta": {"path": "IMAGES/super.img", "size": "65536"},
+  "nv_text": "Global",
+  "block_devices": [{"block_size": "4096", "name": "super", "alignment": "1048576", "size": "0x220000000"}],
+  "groups": [{"name": "qti_dynamic_partitions_a", "maximum_size": "8.25GiB"}, {"name": "qti_dynamic_partitions_b"}],
+  "nv_id": "00011010",
+  "partitions": [
+    {"is_dynamic": true, "name": "system_a", "group_name": "qti_dynamic_partitions_a", "path": "IMAGES/system.img", "size": "4096k"},
+    {"is_dynamic": true, "name": "system_b", "group_name": "qti_dynamic_partitions_b"},
+    {"is_dynamic": true, "name": "odm_b", "group_name": "qti_dynamic_partitions_b", "size": ""}
+  ]
+}"#;
+
+/// A file from `testdata`
+pub(crate) fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("src/super_image_creater/testdata").join(name)
+}
+
+/// What `testdata/lines.img.gz` and `testdata/lines.img.xz` decompress to: 80000
+/// bytes of numbered text lines. `lines.simg` holds parts of it, see the sparse tests
+pub(crate) fn lines() -> Vec<u8> {
+    (0..8000).flat_map(|i| format!("{:05} {:03}\n", i, i * 37 % 1000).into_bytes()).collect()
+}
+
+/// The first 4 KiB of an image of `fs_type`: zeros but for its superblock magic,
+/// at 1 KiB in (ext4 `s_magic` at 0x38 into the superblock) or at 0 for squashfs
+pub(crate) fn fs_head(fs_type: FsType) -> Vec<u8> {
+    let (offset, magic): (usize, &[u8]) = match fs_type {
+        FsType::Ext4 => (1024 + 0x38, &[0x53, 0xef]),
+        FsType::Erofs => (1024, &[0xe2, 0xe1, 0xf5, 0xe0]),
+        FsType::F2fs => (1024, &[0x10, 0x20, 0xf5, 0xf2]),
+        FsType::Squashfs => (0, b"hsqs"),
+        FsType::Unknown => (0, &[]),
+    };
+    let mut head = vec![0u8; 4096];
+    head[offset..offset + magic.len()].copy_from_slice(magic);
+    head
+}
+
+/// An empty directory under the system temp dir, unique to the test `name` and
+/// this test run
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("super-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `len` bytes of a repeating pattern, different for every `seed`
+pub(crate) fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A 16 MiB super device with group `main` (no limit) holding one partition per
+/// entry of `images`: name, image contents written to `<dir>/<name>.img`, and size
+pub(crate) fn config_with_images(dir: &Path, images: &[(&str, &[u8], u64)]) -> PartitionConfig {
+    let mut config = PartitionConfig::new(dir.join("super.img").to_str().unwrap(), 65536);
+    config
+        .add_block_device(BlockDevice { size: ByteSize::new(16 << 20), ..Default::default() })
+        .add_group(Group { name: "main".into(), ..Default::default() });
+    for &(name, data, size) in images {
+        let path = dir.join(format!("{}.img", name));
+        fs::write(&path, data).unwrap();
+        config.add_partition(Partition {
+            name: name.into(),
+            group_name: "main".into(),
+            path: path.to_str().unwrap().into(),
+            size: Some(ByteSize::new(size)),
+            ..Default::default()
+        });
+    }
+    config
+}
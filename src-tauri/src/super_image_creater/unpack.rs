@@ -0,0 +1,381 @@
+use super::lp_metadata::*;
+use super::sparse::SPARSE_HEADER_MAGIC;
+use super::builder::SuperLayout;
+use super::{BlockDevice, ByteSize, DEFAULT_METADATA_SLOTS, Group, Partition, PartitionConfig, SuperMeta};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+
+/// Copy buffer used when extracting extents
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum UnpackError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("No valid LP geometry found: {0}")]
+    Geometry(LpMetadataError),
+    #[error("No valid LP metadata slot found: {0}")]
+    Metadata(LpMetadataError),
+    #[error("Image is an Android sparse image, convert it to a raw image first")]
+    SparseImage,
+    #[error("Partition '{partition}' has an extent on block device {source_index}, only the super image itself can be read")]
+    ExternalBlockDevice { partition: String, source_index: u32 },
+    #[error("Partition '{partition}' has an unsupported extent type {target_type}")]
+    UnsupportedExtent { partition: String, target_type: u32 },
+    #[error("Unpack cancelled by user")]
+    Cancelled,
+}
+
+/// Reads the LP metadata of a raw super image, without extracting anything.
+///
+/// The primary geometry is used unless it is corrupted, in which case the backup is
+/// tried. LP metadata has no generation counter, so slots are tried in order (slot 0
+/// first, the primary copy before the backup) and the first copy that passes its
+/// checksums is returned.
+///
+/// The file may also be a dump of just the start of the super partition (the first
+/// few hundred KiB, covering the geometry and the first metadata slot), or such a
+/// dump with the 4 KiB reserved area cut off so it starts at the geometry. Copies that
+/// lie past the end of the file are skipped.
+pub fn read_super_metadata(super_path: &Path) -> Result<(LpGeometry, LpMetadata), UnpackError> {
+    let mut file = File::open(super_path)?;
+    let file_size = file.metadata()?.len();
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    let magic = u32::from_le_bytes(magic);
+    if magic == SPARSE_HEADER_MAGIC {
+        return Err(UnpackError::SparseImage);
+    }
+    // Offset of the super partition start relative to the file start
+    let base = if magic == LP_METADATA_GEOMETRY_MAGIC { LP_PARTITION_RESERVED_BYTES } else { 0 };
+    let mut read_copy = |offset: u64, len: u64| -> io::Result<Option<Vec<u8>>> {
+        let Some(offset) = offset.checked_sub(base) else { return Ok(None) };
+        if offset >= file_size {
+            return Ok(None);
+        }
+        // A dump may end right after the tables, before `metadata_max_size`
+        read_at(&mut file, offset, len.min(file_size - offset) as usize).map(Some)
+    };
+
+    let mut geometry = Err(LpMetadataError::BadMagic("geometry"));
+    for offset in [LP_PARTITION_RESERVED_BYTES, LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE] {
+        let Some(block) = read_copy(offset, LP_METADATA_GEOMETRY_SIZE)? else { continue };
+        geometry = LpGeometry::from_bytes(&block);
+        if geometry.is_ok() {
+            break;
+        }
+    }
+    let geometry = geometry.map_err(UnpackError::Geometry)?;
+
+    let max_size = geometry.metadata_max_size as u64;
+    let slot_count = geometry.metadata_slot_count as u64;
+    let mut last_error = LpMetadataError::Malformed("metadata slot count");
+    for slot in 0..slot_count {
+        for offset in [primary_metadata_offset(max_size, slot), backup_metadata_offset(max_size, slot_count, slot)] {
+            let Some(blob) = read_copy(offset, max_size)? else { continue };
+            match LpMetadata::from_bytes(&blob) {
+                Ok(metadata) => return Ok((geometry, metadata)),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+    Err(UnpackError::Metadata(last_error))
+}
+
+/// Reads the layout of an existing raw super image (or a dump of its metadata area)
+/// from its LP metadata, in the format `SuperImageBuilder::layout_file` writes for
+/// the images it builds. Names are as stored, without the slot suffix in a retrofit
+/// image; there are no resolved sizes or slot suffix to report.
+pub fn inspect_super<P: AsRef<Path>>(image: P) -> Result<SuperLayout, UnpackError> {
+    let (geometry, metadata) = read_super_metadata(image.as_ref())?;
+    Ok(SuperLayout::from_metadata(&geometry, &metadata))
+}
+
+/// Builds a config template from the LP metadata of a super image or a dump of its
+/// metadata area, without extracting anything.
+///
+/// Block devices, groups (with their `maximum_size`) and partitions (names, groups
+/// and sizes) come from the metadata; every `path` is left empty for the user to
+/// fill in, and so is `super_meta.path`. `nv_id` and `nv_text` are not stored in the
+/// image and are left empty as well.
+pub fn config_from_super_dump(super_or_metadata: &Path) -> Result<PartitionConfig, UnpackError> {
+    let (geometry, metadata) = read_super_metadata(super_or_metadata)?;
+    Ok(config_from_metadata(&geometry, &metadata, String::new()))
+}
+
+/// Extracts every logical partition of a raw super image into `out_dir` and returns
+/// a config describing it, which `build_super_image` can turn back into an equivalent
+/// image.
+///
+/// Each partition with data is written to `<out_dir>/<name>.img`, its extents
+/// concatenated in order (zero extents become zeros). Partitions without extents get
+/// no file and are listed without `path` or `size`, like the unused slot's partitions
+/// in stock configs. The implicit `default` group is only listed if a partition uses
+/// it. `nv_id` and `nv_text` are not stored in the image and are left empty.
+///
+/// # Arguments
+/// * `super_path` - Raw (non-sparse) super image, e.g. dumped from a device
+/// * `out_dir` - Destination directory for the partition images (created if missing)
+pub fn unpack_super_image(super_path: &Path, out_dir: &Path) -> Result<PartitionConfig, UnpackError> {
+    unpack_super_image_with_cancel(super_path, out_dir, &AtomicBool::new(false))
+}
+
+/// `unpack_super_image` for any path-like arguments.
+///
+/// The LP header does not record which slot is active: the geometry only gives the
+/// slot count, and the builder (like lpmake) writes identical metadata to every
+/// slot. The metadata is therefore taken from the first slot whose primary or
+/// backup copy is intact, see `read_super_metadata`.
+pub fn unpack_super<P: AsRef<Path>>(image: P, out_dir: P) -> Result<PartitionConfig, UnpackError> {
+    unpack_super_image(image.as_ref(), out_dir.as_ref())
+}
+
+/// Same as `unpack_super_image`, stopping when `cancel` is set.
+///
+/// `cancel` is checked before every copied buffer. On cancellation the partially
+/// written partition image is removed (images extracted before it are kept) and
+/// `UnpackError::Cancelled` is returned.
+pub fn unpack_super_image_with_cancel(super_path: &Path, out_dir: &Path, cancel: &AtomicBool) -> Result<PartitionConfig, UnpackError> {
+    let (geometry, metadata) = read_super_metadata(super_path)?;
+    fs::create_dir_all(out_dir)?;
+    let mut file = File::open(super_path)?;
+
+    let mut config = config_from_metadata(&geometry, &metadata, super_path.to_string_lossy().to_string());
+    for (lp_partition, partition) in metadata.partitions.iter().zip(&mut config.partitions) {
+        if partition.is_placeholder() {
+            continue;
+        }
+        let image_path = out_dir.join(format!("{}.img", lp_partition.name));
+        let extents = metadata.partition_extents(lp_partition);
+        if let Err(e) = extract_partition(&mut file, lp_partition, extents, &image_path, cancel) {
+            if let UnpackError::Cancelled = e {
+                let _ = fs::remove_file(&image_path);
+            }
+            return Err(e);
+        }
+        partition.path = image_path.to_string_lossy().to_string();
+    }
+    Ok(config)
+}
+
+/// Config described by LP metadata, with partition sizes but no image paths.
+/// Partitions without extents get no size, placeholders as in a stock config.
+fn config_from_metadata(geometry: &LpGeometry, metadata: &LpMetadata, super_path: String) -> PartitionConfig {
+    let partitions = metadata
+        .partitions
+        .iter()
+        .map(|lp_partition| {
+            let size: u64 = metadata.partition_extents(lp_partition).iter().map(|e| e.num_sectors * LP_SECTOR_SIZE).sum();
+            Partition {
+                is_dynamic: true,
+                name: lp_partition.name.clone(),
+                group_name: metadata.groups[lp_partition.group_index as usize].name.clone(),
+                path: String::new(),
+                size: (size > 0).then(|| ByteSize::new(size)),
+                expected_sha256: None,
+                expected_fs: None,
+                zero_fill: false,
+            }
+        })
+        .collect();
+
+    let groups: Vec<Group> = metadata
+        .groups
+        .iter()
+        .enumerate()
+        .filter(|(i, group)| {
+            *i != 0 || group.name != "default" || metadata.partitions.iter().any(|p| p.group_index == 0)
+        })
+        .map(|(_, group)| Group {
+            name: group.name.clone(),
+            maximum_size: (group.maximum_size > 0).then(|| ByteSize::new(group.maximum_size)),
+            maximum_size_computed: false,
+            cow_reserve: None,
+        })
+        .collect();
+
+    let block_devices = metadata
+        .block_devices
+        .iter()
+        .map(|device| BlockDevice {
+            block_size: ByteSize::new(geometry.logical_block_size as u64),
+            name: device.partition_name.clone(),
+            alignment: ByteSize::new(device.alignment as u64),
+            size: ByteSize::new(device.size),
+        })
+        .collect();
+
+    let slot_count = geometry.metadata_slot_count;
+    // Only when the default would not rebuild the same geometry
+    let metadata_slots = (slot_count != DEFAULT_METADATA_SLOTS).then_some(slot_count);
+    PartitionConfig {
+        config_version: None,
+        super_meta: SuperMeta { path: super_path, size: ByteSize::new(geometry.metadata_max_size as u64) },
+        nv_text: String::new(),
+        block_devices,
+        groups,
+        nv_id: String::new(),
+        partitions,
+        // Only written when it differs from the default, so stock dumps stay unchanged
+        virtual_ab: (metadata.header_flags & LP_HEADER_FLAG_VIRTUAL_AB_DEVICE == 0).then_some(false),
+        retrofit: metadata.block_devices.iter().any(|d| d.flags & LP_BLOCK_DEVICE_SLOT_SUFFIXED != 0),
+        metadata_slots,
+        cow_reserve: None,
+        variants: Vec::new(),
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn extract_partition(
+    file: &mut File,
+    partition: &LpPartition,
+    extents: &[LpExtent],
+    image_path: &Path,
+    cancel: &AtomicBool,
+) -> Result<(), UnpackError> {
+    let mut out = File::create(image_path)?;
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    for extent in extents {
+        let len = extent.num_sectors * LP_SECTOR_SIZE;
+        match extent.target_type {
+            LP_TARGET_TYPE_LINEAR => {
+                if extent.target_source != 0 {
+                    return Err(UnpackError::ExternalBlockDevice {
+                        partition: partition.name.clone(),
+                        source_index: extent.target_source,
+                    });
+                }
+                file.seek(SeekFrom::Start(extent.target_data * LP_SECTOR_SIZE))?;
+                let mut remaining = len;
+                while remaining > 0 {
+                    if cancel.load(Ordering::SeqCst) {
+                        return Err(UnpackError::Cancelled);
+                    }
+                    let chunk = remaining.min(buf.len() as u64) as usize;
+                    file.read_exact(&mut buf[..chunk]).map_err(|e| match e.kind() {
+                        io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "extent past the end of the image"),
+                        _ => e,
+                    })?;
+                    out.write_all(&buf[..chunk])?;
+                    remaining -= chunk as u64;
+                }
+            }
+            LP_TARGET_TYPE_ZERO => {
+                // Grows the file without writing, the gap reads back as zeros
+                let end = out.stream_position()? + len;
+                out.set_len(end)?;
+                out.seek(SeekFrom::Start(end))?;
+            }
+            target_type => {
+                return Err(UnpackError::UnsupportedExtent { partition: partition.name.clone(), target_type });
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::build_super_image;
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+
+    #[test]
+    fn unpacked_images_and_config_rebuild_the_same_super() {
+        let dir = test_dir("unpack-round-trip");
+        let system = pattern(3 * 8192, 1);
+        let mut config = config_with_images(&dir, &[("system", &system, system.len() as u64), ("vendor", &pattern(100, 2), 4096)]);
+        config.groups[0].maximum_size = Some(ByteSize::new(8 << 20));
+        config.add_group(Group { name: "main_b".into(), ..Default::default() });
+        config.add_partition(Partition { name: "system_b".into(), group_name: "main_b".into(), ..Default::default() });
+        let output = dir.join("super.img");
+        build_super_image(&config, &output).unwrap();
+
+        let unpacked = unpack_super(&output, &dir.join("out")).unwrap();
+        assert_eq!(unpacked.block_devices, config.block_devices);
+        assert_eq!(unpacked.groups, config.groups);
+        assert_eq!(unpacked.super_meta.size, config.super_meta.size);
+        let names: Vec<&str> = unpacked.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["system", "vendor", "system_b"]);
+        for (partition, original) in unpacked.partitions.iter().zip(&config.partitions) {
+            assert_eq!(partition.size_bytes(), original.size_bytes(), "{}", partition.name);
+        }
+        assert!(fs::read(&unpacked.partitions[0].path).unwrap() == system);
+        assert!(unpacked.partitions[2].is_placeholder());
+
+        let mut rebuilt_config = unpacked.clone();
+        rebuilt_config.super_meta.path = dir.join("rebuilt.img").to_string_lossy().to_string();
+        let rebuilt = dir.join("rebuilt.img");
+        build_super_image(&rebuilt_config, &rebuilt).unwrap();
+        assert!(fs::read(&rebuilt).unwrap() == fs::read(&output).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_config_from_a_metadata_dump_rebuilds_the_same_layout() {
+        let dir = test_dir("super-dump");
+        let mut config = config_with_images(&dir, &[("system", &pattern(70000, 4), 3 << 20), ("vendor", &pattern(9000, 5), 1 << 20)]);
+        config.groups[0].maximum_size = Some(ByteSize::new(6 << 20));
+        config.add_group(Group { name: "main_b".into(), ..Default::default() });
+        config.add_partition(Partition { name: "system_b".into(), group_name: "main_b".into(), ..Default::default() });
+        let output = dir.join("super.img");
+        let report = build_super_image(&config, &output).unwrap();
+        // Everything before the first partition: geometry and metadata, as dumped from a device
+        let image = fs::read(&output).unwrap();
+        let dump = dir.join("super_metadata.bin");
+        fs::write(&dump, &image[..report.partitions[0].offset as usize]).unwrap();
+
+        for source in [&output, &dump] {
+            let template = config_from_super_dump(source).unwrap();
+            assert_eq!(template.super_meta.path, "");
+            assert!(template.partitions.iter().all(|p| p.path.is_empty()));
+            assert_eq!((template.nv_id.as_str(), template.nv_text.as_str()), ("", ""));
+            assert_eq!(template.block_devices, config.block_devices);
+            assert_eq!(template.groups, config.groups);
+            let sizes: Vec<(&str, Option<u64>)> = template.partitions.iter().map(|p| (p.name.as_str(), p.size_bytes())).collect();
+            assert_eq!(sizes, [("system", Some(3 << 20)), ("vendor", Some(1 << 20)), ("system_b", None)]);
+
+            // With the images filled in, the template builds the image it came from
+            let mut rebuilt_config = template;
+            for (partition, original) in rebuilt_config.partitions.iter_mut().zip(&config.partitions) {
+                partition.path = original.path.clone();
+            }
+            let rebuilt = dir.join("rebuilt.img");
+            build_super_image(&rebuilt_config, &rebuilt).unwrap();
+            assert_eq!(read_super_metadata(&rebuilt).unwrap(), read_super_metadata(&output).unwrap());
+            assert!(fs::read(&rebuilt).unwrap() == image);
+        }
+        assert!(config_from_super_dump(&dir.join("system.img")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_corrupt_primary_slot_falls_back_to_the_backup() {
+        let dir = test_dir("unpack-backup");
+        let config = config_with_images(&dir, &[("system", &pattern(8192, 3), 1 << 20)]);
+        let output = dir.join("super.img");
+        build_super_image(&config, &output).unwrap();
+        let (_, intact) = read_super_metadata(&output).unwrap();
+
+        let metadata_size = config.super_meta.size_bytes();
+        let mut image = fs::read(&output).unwrap();
+        // Break the primary copies of both the geometry and slot 0
+        image[primary_metadata_offset(metadata_size, 0) as usize + 20] ^= 0xff;
+        image[LP_PARTITION_RESERVED_BYTES as usize + 50] ^= 1;
+        fs::write(&output, &image).unwrap();
+        let (geometry, metadata) = read_super_metadata(&output).unwrap();
+        assert_eq!(geometry.metadata_max_size as u64, metadata_size);
+        assert_eq!(metadata, intact);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
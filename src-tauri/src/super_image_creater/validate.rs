@@ -0,0 +1,746 @@
+use super::lp_metadata::{LP_SECTOR_SIZE, align_up, first_usable_offset, metadata_region_end};
+use super::inputs::IssueSeverity;
+use super::nv::{NvError, NvMapping};
+use super::slots::Slot;
+use super::{BlockDevice, ByteSize, Group, PartitionConfig};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use thiserror::Error;
+
+/// A single problem found by `validate`.
+///
+/// Serialized with a `kind` tag so the frontend can render each problem type
+/// differently, e.g. `{"kind": "UnknownGroup", "partition": "odm_a", "group": "main"}`.
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum ValidationError {
+    #[error("No block device defined")]
+    NoBlockDevice,
+    #[error("Partition '{partition}' references unknown group '{group}'")]
+    UnknownGroup { partition: String, group: String },
+    #[error("Duplicate partition name '{name}'")]
+    DuplicatePartition { name: String },
+    #[error("Duplicate group name '{name}'")]
+    DuplicateGroup { name: String },
+    #[error("Dynamic partition '{partition}' has a size of {size} bytes but no image path (set zero_fill to leave it empty)")]
+    MissingImagePath { partition: String, size: u64 },
+    #[error("Partition '{partition}' has a size of {size} bytes, which is not a multiple of the 512 byte sector size (nearest: {nearest})")]
+    PartitionSizeNotSectorAligned { partition: String, size: u64, nearest: u64 },
+    #[error("Partitions in group '{group}' need {used} bytes, but its maximum_size is {maximum_size}")]
+    GroupOverflow { group: String, maximum_size: u64, used: u64 },
+    #[error("Group '{group}' leaves {free} bytes free, but its cow_reserve keeps {reserved} bytes for OTA snapshots")]
+    CowReserveShort { group: String, reserved: u64, free: u64 },
+    #[error("Group '{group}' has a maximum_size of {maximum_size} bytes, but only {available} bytes are usable")]
+    GroupTooLarge { group: String, maximum_size: u64, available: u64 },
+    #[error("Partitions need {required} bytes, but only {available} bytes are usable after metadata and alignment")]
+    SuperOverflow { required: u64, available: u64 },
+    #[error(
+        "Block device '{device}' has no _a/_b slot suffix, but the config is retrofit: retrofit metadata lives on the partitions of one slot, name the block devices after them (e.g. system_a)"
+    )]
+    RetrofitUnsuffixedDevice { device: String },
+    #[error("Block device '{device}' is in slot {suffix}, but the first block device puts the retrofit config in slot {expected}")]
+    RetrofitMixedSlots { device: String, suffix: String, expected: String },
+    #[error("Partition '{partition}' is in slot {suffix}, but a retrofit image for slot {expected} only holds that slot's partitions")]
+    RetrofitOtherSlot { partition: String, suffix: String, expected: String },
+    #[error("virtual_ab and retrofit are both set, but Virtual A/B devices launch with a real super partition")]
+    RetrofitVirtualAb,
+}
+
+/// A block device whose geometry would produce a broken super layout
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum BlockDeviceError {
+    #[error("Block device '{device}' has a block_size of {block_size}, which is not a power of two (nearest: {nearest})")]
+    BlockSizeNotPowerOfTwo { device: String, block_size: u64, nearest: u64 },
+    #[error("Block device '{device}' has a block_size of {block_size}, below the 512 byte sector size (nearest: {nearest})")]
+    BlockSizeTooSmall { device: String, block_size: u64, nearest: u64 },
+    #[error("Block device '{device}' has an alignment of {alignment}, which is not a power of two (nearest: {nearest})")]
+    AlignmentNotPowerOfTwo { device: String, alignment: u64, nearest: u64 },
+    #[error("Block device '{device}' has an alignment of {alignment}, which is not a multiple of its block_size {block_size} (nearest: {nearest})")]
+    AlignmentNotBlockMultiple { device: String, alignment: u64, block_size: u64, nearest: u64 },
+    #[error("Block device '{device}' has a size of {size}, which is not a multiple of its block_size {block_size} (nearest: {nearest})")]
+    SizeNotBlockMultiple { device: String, size: u64, block_size: u64, nearest: u64 },
+}
+
+/// A `super_meta.size` that does not fit the block devices.
+///
+/// `super_meta.size` is the size of one LP metadata slot (lpmake `--metadata-size`),
+/// not the size of the super partition, which is the sum of the block device sizes.
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum SuperSizeError {
+    #[error("No block device defined")]
+    NoBlockDevice,
+    #[error("super_meta.size {size} is not a multiple of the {sector_size} byte sector size")]
+    NotSectorAligned { size: u64, sector_size: u64 },
+    #[error(
+        "super_meta.size {size} needs a {metadata_region} byte metadata region, but block device '{device}' is only {device_size} bytes ({difference} bytes short; super_meta.size is the metadata size, not the super size)"
+    )]
+    ExceedsDevice { size: u64, metadata_region: u64, device: String, device_size: u64, difference: u64 },
+    #[error(
+        "super_meta.size {size} leaves no room for partitions: the metadata region ends at {metadata_region} bytes, the block devices total {total_size} bytes"
+    )]
+    NoPartitionSpace { size: u64, metadata_region: u64, total_size: u64 },
+}
+
+/// Config that builds, but probably not the way the user intended
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum ValidationWarning {
+    #[error("Group '{group}' has no maximum_size and is unlimited")]
+    UnlimitedGroup { group: String },
+    #[error("Partition '{partition}' has an image '{path}' but no size, the image is not included")]
+    ImageIgnored { partition: String, path: String },
+}
+
+/// A finding of `PartitionConfig::validate`, by the check that produced it.
+///
+/// Serialized as `{"category": "block_device", "detail": {"kind": ...}}`, where
+/// `detail` is the tagged error or warning.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "category", content = "detail", rename_all = "snake_case")]
+pub enum ValidationIssue {
+    /// References, budgets and space, see the free `validate` function
+    Config(ValidationError),
+    BlockDevice(BlockDeviceError),
+    SuperSize(SuperSizeError),
+    /// `nv_id` against the firmware's NV mapping, see `validate_with_nv`
+    Nv(NvError),
+    Warning(ValidationWarning),
+}
+
+/// One entry of a `ValidationReport`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidationEntry {
+    pub severity: IssueSeverity,
+    /// Human readable description of `issue`
+    pub message: String,
+    #[serde(flatten)]
+    pub issue: ValidationIssue,
+}
+
+/// Everything `PartitionConfig::validate` found, errors first, then warnings.
+/// Within each severity, entries are ordered by category (in `ValidationIssue`
+/// order) and then by their position in the config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub entries: Vec<ValidationEntry>,
+}
+
+impl ValidationReport {
+    fn push(&mut self, severity: IssueSeverity, issue: ValidationIssue) {
+        let message = match &issue {
+            ValidationIssue::Config(e) => e.to_string(),
+            ValidationIssue::BlockDevice(e) => e.to_string(),
+            ValidationIssue::SuperSize(e) => e.to_string(),
+            ValidationIssue::Nv(e) => e.to_string(),
+            ValidationIssue::Warning(w) => w.to_string(),
+        };
+        self.entries.push(ValidationEntry { severity, message, issue });
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationEntry> {
+        self.entries.iter().filter(|e| e.severity == IssueSeverity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationEntry> {
+        self.entries.iter().filter(|e| e.severity == IssueSeverity::Warning)
+    }
+
+    /// Whether the config would fail to build
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Config has {} error(s) and {} warning(s)", self.errors().count(), self.warnings().count())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+/// Where the `maximum_size` of a group came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitSource {
+    /// Written in the config by the user
+    Specified,
+    /// Filled in by `resolve_group_limits`
+    Computed,
+    /// The `default` group, which liblp always treats as unlimited
+    Unlimited,
+}
+
+/// Resolved `maximum_size` of one group, as reported by `resolve_group_limits`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupLimit {
+    pub group: String,
+    /// 0 for an unlimited group
+    pub maximum_size: u64,
+    pub source: LimitSource,
+}
+
+/// A group whose partitions add up to more than its `maximum_size`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetViolation {
+    pub group: String,
+    pub maximum_size: u64,
+    pub total: u64,
+}
+
+impl PartitionConfig {
+    /// Checks that, for every group with a `maximum_size`, the sizes of the partitions
+    /// in that group fit into it.
+    ///
+    /// Groups without a `maximum_size` are unbounded and skipped. Partitions without
+    /// a size count as 0 bytes.
+    pub fn validate_group_budgets(&self) -> Result<(), Vec<BudgetViolation>> {
+        let mut violations = Vec::new();
+        for group in &self.groups {
+            let Some(maximum_size) = group.maximum_size_bytes() else { continue };
+            let total: u64 = self
+                .partitions
+                .iter()
+                .filter(|p| p.group_name == group.name)
+                .map(|p| p.size_bytes().unwrap_or(0))
+                .sum();
+            if total > maximum_size {
+                violations.push(BudgetViolation { group: group.name.clone(), maximum_size, total });
+            }
+        }
+        if violations.is_empty() { Ok(()) } else { Err(violations) }
+    }
+
+    /// Runs every config check (`validate`, `validate_block_devices` and
+    /// `validate_super_size`) and collects their findings in one report, together
+    /// with warnings about unlimited groups and images that would be ignored.
+    ///
+    /// Returns `Err` if anything was found, warnings included; use
+    /// `ValidationReport::has_errors` to decide whether the config can be built.
+    pub fn validate(&self) -> Result<(), ValidationReport> {
+        let mut report = ValidationReport::default();
+        if let Err(errors) = validate(self) {
+            for error in errors {
+                report.push(IssueSeverity::Error, ValidationIssue::Config(error));
+            }
+        }
+        if let Err(errors) = self.validate_block_devices() {
+            for error in errors {
+                report.push(IssueSeverity::Error, ValidationIssue::BlockDevice(error));
+            }
+        }
+        match self.validate_super_size() {
+            // Already reported by `validate`
+            Ok(()) | Err(SuperSizeError::NoBlockDevice) => {}
+            Err(error) => report.push(IssueSeverity::Error, ValidationIssue::SuperSize(error)),
+        }
+
+        for group in self.groups.iter().filter(|g| g.maximum_size.is_none() && g.name != "default") {
+            let warning = ValidationWarning::UnlimitedGroup { group: group.name.clone() };
+            report.push(IssueSeverity::Warning, ValidationIssue::Warning(warning));
+        }
+        for partition in &self.partitions {
+            if partition.size.is_none() && partition.has_data() {
+                let warning = ValidationWarning::ImageIgnored { partition: partition.name.clone(), path: partition.path.clone() };
+                report.push(IssueSeverity::Warning, ValidationIssue::Warning(warning));
+            }
+        }
+
+        // Stable, so entries keep config order within a severity and category
+        report.entries.sort_by_key(|e| (e.severity, category_order(&e.issue)));
+        if report.entries.is_empty() { Ok(()) } else { Err(report) }
+    }
+
+    /// Same as `validate`, for the config as built with the images of its `nv_id`
+    /// from `mapping` (see `NvMapping::apply`). An `nv_id` the mapping does not
+    /// have is an error listing the ones it does.
+    pub fn validate_with_nv(&self, mapping: &NvMapping) -> Result<(), ValidationReport> {
+        let mut config = self.clone();
+        let nv_error = mapping.apply(&mut config).err();
+        let mut report = config.validate().err().unwrap_or_default();
+        if let Some(error) = nv_error {
+            report.push(IssueSeverity::Error, ValidationIssue::Nv(error));
+            report.entries.sort_by_key(|e| (e.severity, category_order(&e.issue)));
+        }
+        if report.entries.is_empty() { Ok(()) } else { Err(report) }
+    }
+
+    /// Fills in the `maximum_size` of groups that have none with the space their
+    /// partitions take, plus `headroom_percent` percent, and returns the limit of
+    /// every group in `groups` order.
+    ///
+    /// Partition sizes are counted rounded up to the block size, like the builder
+    /// lays them out, and the result is rounded up to the block size as well.
+    /// Computed limits are marked with `maximum_size_computed`, so they are written
+    /// back to JSON as such and recomputed by the next call. A group named `default`
+    /// without a limit is left unlimited, as liblp always treats it. A group with no
+    /// sized partitions gets a limit of 0, which LP metadata also reads as unlimited.
+    ///
+    /// Resolve `"auto"` partition sizes (`resolve_auto_sizes`) first, they count as 0
+    /// bytes until then.
+    pub fn resolve_group_limits(&mut self, headroom_percent: u32) -> Vec<GroupLimit> {
+        let block_size = self.block_devices.first().map(|d| d.block_size_bytes()).unwrap_or(LP_SECTOR_SIZE);
+        let mut limits = Vec::new();
+        for group in &mut self.groups {
+            let source = match (&group.maximum_size, group.maximum_size_computed) {
+                (Some(_), false) => LimitSource::Specified,
+                _ if group.name == "default" => LimitSource::Unlimited,
+                _ => LimitSource::Computed,
+            };
+            if source == LimitSource::Computed {
+                let used: u64 = self
+                    .partitions
+                    .iter()
+                    .filter(|p| p.group_name == group.name)
+                    .map(|p| align_up(p.size_bytes().unwrap_or(0), block_size))
+                    .sum();
+                let limit = align_up(used + used * headroom_percent as u64 / 100, block_size);
+                group.maximum_size = Some(ByteSize::new(limit));
+                group.maximum_size_computed = true;
+            }
+            limits.push(GroupLimit {
+                group: group.name.clone(),
+                maximum_size: group.maximum_size_bytes().unwrap_or(0),
+                source,
+            });
+        }
+        limits
+    }
+
+    /// Checks that every partition's `group_name` names a group from `groups`, and
+    /// that partition and group names are unique (lpmake rejects the image otherwise).
+    ///
+    /// Unlike `validate`, this needs no block device and does no size math, so it can
+    /// be applied on its own. Each returned string describes one problem and names
+    /// the offending partition or group.
+    pub fn validate_group_references(&self) -> Result<(), Vec<String>> {
+        let errors: Vec<String> = reference_errors(self).iter().map(ToString::to_string).collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+impl PartitionConfig {
+    /// Checks `super_meta.size` against the block devices.
+    ///
+    /// The metadata region at the start of the first block device (4 KiB reserved,
+    /// two geometry copies, then a primary and a backup copy of every slot, rounded
+    /// up to the device alignment) must fit into that device and leave room for
+    /// partition data. A `super_meta.size` that is really a super partition size, as
+    /// in configs edited by hand, fails here with the difference in bytes.
+    pub fn validate_super_size(&self) -> Result<(), SuperSizeError> {
+        let Some(first_device) = self.block_devices.first() else {
+            return Err(SuperSizeError::NoBlockDevice);
+        };
+        let size = self.super_meta.size_bytes();
+        if size == 0 || !size.is_multiple_of(LP_SECTOR_SIZE) {
+            return Err(SuperSizeError::NotSectorAligned { size, sector_size: LP_SECTOR_SIZE });
+        }
+
+        let slot_count = self.metadata_slot_count() as u64;
+        let device_size = first_device.size_bytes();
+        let metadata_region = metadata_region_end(size, slot_count);
+        if metadata_region > device_size {
+            return Err(SuperSizeError::ExceedsDevice {
+                size,
+                metadata_region,
+                device: first_device.name.clone(),
+                device_size,
+                difference: metadata_region - device_size,
+            });
+        }
+        if usable_space(self, slot_count as u32) == 0 {
+            let total_size = self.block_devices.iter().map(|d| d.size_bytes()).sum();
+            return Err(SuperSizeError::NoPartitionSpace { size, metadata_region, total_size });
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice {
+    /// Checks that `block_size` and `alignment` are powers of two (0 is not) of at least
+    /// 512 bytes, that `alignment` is a multiple of `block_size` and that `size` is a
+    /// whole number of blocks.
+    ///
+    /// Stops at the first problem, in that order. Each error carries the nearest valid
+    /// value; for `size` that is the next whole block down, so it still fits the device.
+    pub fn validate(&self) -> Result<(), BlockDeviceError> {
+        let block_size = self.block_size_bytes();
+        let alignment = self.alignment_bytes();
+        let size = self.size_bytes();
+        let device = || self.name.clone();
+        if !block_size.is_power_of_two() {
+            return Err(BlockDeviceError::BlockSizeNotPowerOfTwo { device: device(), block_size, nearest: nearest_power_of_two(block_size) });
+        }
+        if block_size < LP_SECTOR_SIZE {
+            return Err(BlockDeviceError::BlockSizeTooSmall { device: device(), block_size, nearest: LP_SECTOR_SIZE });
+        }
+        if !alignment.is_power_of_two() {
+            return Err(BlockDeviceError::AlignmentNotPowerOfTwo { device: device(), alignment, nearest: nearest_power_of_two(alignment) });
+        }
+        if !alignment.is_multiple_of(block_size) {
+            // Both are powers of two here, so the smaller alignment divides block_size
+            return Err(BlockDeviceError::AlignmentNotBlockMultiple { device: device(), alignment, block_size, nearest: block_size });
+        }
+        if !size.is_multiple_of(block_size) {
+            return Err(BlockDeviceError::SizeNotBlockMultiple { device: device(), size, block_size, nearest: size / block_size * block_size });
+        }
+        Ok(())
+    }
+}
+
+/// The power of two closest to `value`, the lower one on a tie, and at least 512
+fn nearest_power_of_two(value: u64) -> u64 {
+    if value <= LP_SECTOR_SIZE {
+        return LP_SECTOR_SIZE;
+    }
+    let upper = value.checked_next_power_of_two().unwrap_or(1 << 63);
+    let lower = if upper == value { value } else { upper / 2 };
+    if value - lower <= upper.saturating_sub(value) { lower } else { upper }
+}
+
+impl PartitionConfig {
+    /// Bytes `group` keeps free for OTA snapshots: its `cow_reserve`, else the
+    /// config-wide one, 0 without either. A percentage is of the group's
+    /// `maximum_size`, or of `usable` (the room for partitions) for a group without one.
+    pub fn cow_reserve_bytes(&self, group: &Group, usable: u64) -> u64 {
+        match group.cow_reserve.as_ref().or(self.cow_reserve.as_ref()) {
+            Some(reserve) => reserve.bytes_of(group.maximum_size_bytes().unwrap_or(usable)),
+            None => 0,
+        }
+    }
+
+    /// Runs `BlockDevice::validate` on every block device and collects the failures
+    pub fn validate_block_devices(&self) -> Result<(), Vec<BlockDeviceError>> {
+        let errors: Vec<BlockDeviceError> = self.block_devices.iter().filter_map(|d| d.validate().err()).collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+/// Checks that a parsed config can actually produce a flashable super image.
+///
+/// All problems are collected instead of stopping at the first one, so the UI can
+/// show everything that needs fixing in one pass. Space checks account for the LP
+/// metadata region at the start of the first block device (`super_meta.size` is the
+/// metadata size, with `metadata_slot_count()` slots) and for every partition start
+/// being aligned to the block device `alignment`. `retrofit` configs also need
+/// block devices of one slot (`system_a`) and no partitions of the other one.
+pub fn validate(config: &PartitionConfig) -> Result<(), Vec<ValidationError>> {
+    validate_with_slot_count(config, config.metadata_slot_count())
+}
+
+/// Same as `validate`, reserving metadata space for `slot_count` slots
+pub(crate) fn validate_with_slot_count(config: &PartitionConfig, slot_count: u32) -> Result<(), Vec<ValidationError>> {
+    let mut errors = reference_errors(config);
+    if config.retrofit {
+        errors.extend(retrofit_errors(config));
+    }
+
+    for partition in &config.partitions {
+        let size = partition.size_bytes().unwrap_or(0);
+        // Placeholders (no path, no size) are fine, they take no space
+        if partition.is_dynamic && size > 0 && partition.path.trim().is_empty() && !partition.zero_fill {
+            errors.push(ValidationError::MissingImagePath { partition: partition.name.clone(), size });
+        }
+        if partition.is_dynamic && !size.is_multiple_of(LP_SECTOR_SIZE) {
+            // Rounded up, the image has to fit in the partition
+            let nearest = align_up(size, LP_SECTOR_SIZE);
+            errors.push(ValidationError::PartitionSizeNotSectorAligned { partition: partition.name.clone(), size, nearest });
+        }
+    }
+
+    let Some(first_device) = config.block_devices.first() else {
+        errors.push(ValidationError::NoBlockDevice);
+        return Err(errors);
+    };
+    let alignment = first_device.alignment_bytes();
+
+    if let Err(violations) = config.validate_group_budgets() {
+        errors.extend(violations.into_iter().map(|v| ValidationError::GroupOverflow {
+            group: v.group,
+            maximum_size: v.maximum_size,
+            used: v.total,
+        }));
+    }
+
+    let available = usable_space(config, slot_count);
+    for group in &config.groups {
+        if let Some(maximum_size) = group.maximum_size_bytes()
+            && maximum_size > available
+        {
+            errors.push(ValidationError::GroupTooLarge { group: group.name.clone(), maximum_size, available });
+        }
+    }
+
+    let required: u64 = config
+        .partitions
+        .iter()
+        .map(|p| align_up(p.size_bytes().unwrap_or(0), alignment))
+        .sum();
+    if required > available {
+        errors.push(ValidationError::SuperOverflow { required, available });
+    }
+
+    for group in &config.groups {
+        let reserved = config.cow_reserve_bytes(group, available);
+        if reserved == 0 {
+            continue;
+        }
+        // A group without a limit has what the partitions leave of the super partition
+        let free = match group.maximum_size_bytes() {
+            Some(maximum_size) => {
+                let used: u64 = config.partitions.iter().filter(|p| p.group_name == group.name).map(|p| p.size_bytes().unwrap_or(0)).sum();
+                maximum_size.saturating_sub(used)
+            }
+            None => available.saturating_sub(required),
+        };
+        if free < reserved {
+            errors.push(ValidationError::CowReserveShort { group: group.name.clone(), reserved, free });
+        }
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Duplicate group names, duplicate partition names and partitions pointing at unknown groups
+fn reference_errors(config: &PartitionConfig) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    let mut group_names = HashSet::new();
+    for group in &config.groups {
+        if !group_names.insert(group.name.as_str()) {
+            errors.push(ValidationError::DuplicateGroup { name: group.name.clone() });
+        }
+    }
+
+    let mut partition_names = HashSet::new();
+    for partition in &config.partitions {
+        if !partition_names.insert(partition.name.as_str()) {
+            errors.push(ValidationError::DuplicatePartition { name: partition.name.clone() });
+        }
+        if !group_names.contains(partition.group_name.as_str()) {
+            errors.push(ValidationError::UnknownGroup {
+                partition: partition.name.clone(),
+                group: partition.group_name.clone(),
+            });
+        }
+    }
+    errors
+}
+
+/// Block devices without a matching `_a` / `_b` suffix and partitions of the other
+/// slot in a `retrofit` config
+fn retrofit_errors(config: &PartitionConfig) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if config.virtual_ab == Some(true) {
+        errors.push(ValidationError::RetrofitVirtualAb);
+    }
+    let Some(expected) = config.retrofit_suffix() else {
+        // Without a block device `NoBlockDevice` is reported instead
+        if let Some(device) = config.block_devices.first() {
+            errors.push(ValidationError::RetrofitUnsuffixedDevice { device: device.name.clone() });
+        }
+        return errors;
+    };
+    let other = if expected == Slot::A.suffix() { Slot::B.suffix() } else { Slot::A.suffix() };
+    for device in &config.block_devices[1..] {
+        if device.name.ends_with(other) {
+            errors.push(ValidationError::RetrofitMixedSlots {
+                device: device.name.clone(),
+                suffix: other.to_string(),
+                expected: expected.to_string(),
+            });
+        } else if !device.name.ends_with(expected) {
+            errors.push(ValidationError::RetrofitUnsuffixedDevice { device: device.name.clone() });
+        }
+    }
+    for partition in config.partitions.iter().filter(|p| p.name.ends_with(other)) {
+        errors.push(ValidationError::RetrofitOtherSlot {
+            partition: partition.name.clone(),
+            suffix: other.to_string(),
+            expected: expected.to_string(),
+        });
+    }
+    errors
+}
+
+/// Bytes available for partition data across all block devices: the first device
+/// loses its metadata region (rounded up to its alignment), the others are fully usable
+pub(crate) fn usable_space(config: &PartitionConfig, slot_count: u32) -> u64 {
+    let metadata_size = config.super_meta.size_bytes();
+    let slot_count = slot_count as u64;
+    config
+        .block_devices
+        .iter()
+        .enumerate()
+        .map(|(i, device)| {
+            let reserved = if i == 0 {
+                first_usable_offset(metadata_size, slot_count, device.alignment_bytes())
+            } else {
+                0
+            };
+            device.size_bytes().saturating_sub(reserved)
+        })
+        .sum()
+}
+
+fn category_order(issue: &ValidationIssue) -> u8 {
+    match issue {
+        ValidationIssue::Config(_) => 0,
+        ValidationIssue::BlockDevice(_) => 1,
+        ValidationIssue::SuperSize(_) => 2,
+        ValidationIssue::Nv(_) => 3,
+        ValidationIssue::Warning(_) => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::SAMPLE;
+    use crate::super_image_creater::{Partition, parse_partition_config_str};
+
+    fn sized(name: &str, group: &str, size: u64) -> Partition {
+        Partition { name: name.into(), group_name: group.into(), size: Some(ByteSize::new(size)), ..Default::default() }
+    }
+
+    #[test]
+    fn only_groups_over_their_maximum_size_break_the_budget() {
+        let mut config = PartitionConfig::new("super.img", 65536);
+        config
+            .add_group(Group { name: "over".into(), maximum_size: Some(ByteSize::new(1 << 20)), ..Default::default() })
+            .add_group(Group { name: "exact".into(), maximum_size: Some(ByteSize::new(3 << 20)), ..Default::default() })
+            .add_group(Group { name: "unbounded".into(), ..Default::default() })
+            .add_partition(sized("system", "over", 1 << 20))
+            .add_partition(sized("vendor", "over", 1))
+            .add_partition(sized("product", "exact", 1 << 20))
+            .add_partition(sized("odm", "exact", 2 << 20))
+            .add_partition(sized("my_stock", "unbounded", 1 << 40))
+            .add_partition(Partition { name: "placeholder".into(), group_name: "exact".into(), ..Default::default() });
+
+        let violations = config.validate_group_budgets().unwrap_err();
+        assert_eq!(violations, [BudgetViolation { group: "over".into(), maximum_size: 1 << 20, total: (1 << 20) + 1 }]);
+
+        config.partitions.retain(|p| p.name != "vendor");
+        assert_eq!(config.validate_group_budgets(), Ok(()));
+    }
+
+    #[test]
+    fn dangling_group_references_and_duplicate_names_are_reported() {
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        assert_eq!(validate(&config), Ok(()));
+        assert_eq!(config.validate_group_references(), Ok(()));
+
+        config.partitions[1].group_name = "qti_dynamic_partitions_c".into();
+        config.partitions[2].name = "system_a".into();
+        let errors = validate(&config).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                ValidationError::UnknownGroup { partition: "system_b".into(), group: "qti_dynamic_partitions_c".into() },
+                ValidationError::DuplicatePartition { name: "system_a".into() },
+            ]
+        );
+        let messages = config.validate_group_references().unwrap_err();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("system_b") && messages[0].contains("qti_dynamic_partitions_c"), "{}", messages[0]);
+        assert!(messages[1].contains("system_a"), "{}", messages[1]);
+    }
+
+    fn device(block_size: u64, alignment: u64, size: u64) -> BlockDevice {
+        BlockDevice { block_size: ByteSize::new(block_size), alignment: ByteSize::new(alignment), size: ByteSize::new(size), ..Default::default() }
+    }
+
+    #[test]
+    fn block_size_and_alignment_must_be_powers_of_two() {
+        let device_name = || "super".to_string();
+        assert_eq!(device(0, 4096, 0).validate(), Err(BlockDeviceError::BlockSizeNotPowerOfTwo { device: device_name(), block_size: 0, nearest: 512 }));
+        assert_eq!(device(1, 4096, 0).validate(), Err(BlockDeviceError::BlockSizeTooSmall { device: device_name(), block_size: 1, nearest: 512 }));
+        assert_eq!(device(3, 4096, 0).validate(), Err(BlockDeviceError::BlockSizeNotPowerOfTwo { device: device_name(), block_size: 3, nearest: 512 }));
+        assert_eq!(device(4096, 4096, 1 << 30).validate(), Ok(()));
+
+        assert_eq!(device(4096, 0, 0).validate(), Err(BlockDeviceError::AlignmentNotPowerOfTwo { device: device_name(), alignment: 0, nearest: 512 }));
+        assert_eq!(device(4096, 1, 0).validate(), Err(BlockDeviceError::AlignmentNotBlockMultiple { device: device_name(), alignment: 1, block_size: 4096, nearest: 4096 }));
+        assert_eq!(device(4096, 3, 0).validate(), Err(BlockDeviceError::AlignmentNotPowerOfTwo { device: device_name(), alignment: 3, nearest: 512 }));
+        assert_eq!(device(4096, 6000, 0).validate(), Err(BlockDeviceError::AlignmentNotPowerOfTwo { device: device_name(), alignment: 6000, nearest: 4096 }));
+        assert_eq!(device(4096, 1 << 20, 0).validate(), Ok(()));
+    }
+
+    #[test]
+    fn device_size_must_be_whole_blocks() {
+        let error = device(4096, 1 << 20, (1 << 30) + 100).validate().unwrap_err();
+        assert_eq!(error, BlockDeviceError::SizeNotBlockMultiple { device: "super".into(), size: (1 << 30) + 100, block_size: 4096, nearest: 1 << 30 });
+
+        let mut config = PartitionConfig::new("super.img", 65536);
+        config.add_block_device(device(4096, 1 << 20, 1 << 30)).add_block_device(BlockDevice { name: "system_b".into(), ..device(3, 4096, 0) });
+        let errors = config.validate_block_devices().unwrap_err();
+        assert_eq!(errors, [BlockDeviceError::BlockSizeNotPowerOfTwo { device: "system_b".into(), block_size: 3, nearest: 512 }]);
+    }
+
+    #[test]
+    fn super_meta_size_exactly_fitting_and_too_large_for_the_device() {
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        assert_eq!(config.validate_super_size(), Ok(()));
+
+        // Reserved bytes, two geometries and 2 x 2 metadata copies fill the device exactly
+        let metadata_region = 4096 * 3 + 65536 * 4;
+        config.block_devices[0].alignment = ByteSize::new(4096);
+        config.block_devices[0].size = ByteSize::new(metadata_region);
+        assert_eq!(
+            config.validate_super_size(),
+            Err(SuperSizeError::NoPartitionSpace { size: 65536, metadata_region, total_size: metadata_region })
+        );
+        config.block_devices[0].size = ByteSize::new(metadata_region + 4096);
+        assert_eq!(config.validate_super_size(), Ok(()));
+
+        // The super partition size copied into super_meta.size
+        config.super_meta.size = ByteSize::new(0x220000000);
+        let region = 4096 * 3 + 0x220000000 * 4;
+        assert_eq!(
+            config.validate_super_size(),
+            Err(SuperSizeError::ExceedsDevice {
+                size: 0x220000000,
+                metadata_region: region,
+                device: "super".into(),
+                device_size: metadata_region + 4096,
+                difference: region - metadata_region - 4096,
+            })
+        );
+        config.super_meta.size = ByteSize::new(1000);
+        assert_eq!(config.validate_super_size(), Err(SuperSizeError::NotSectorAligned { size: 1000, sector_size: 512 }));
+        config.block_devices.clear();
+        assert_eq!(config.validate_super_size(), Err(SuperSizeError::NoBlockDevice));
+    }
+
+    #[test]
+    fn report_orders_errors_before_warnings_then_by_category() {
+        let mut config = parse_partition_config_str(SAMPLE).unwrap();
+        config.partitions[1].path = "IMAGES/system_b.img".into();
+        config.partitions[1].group_name = "qti_dynamic_partitions_c".into();
+        config.block_devices[0].alignment = ByteSize::new(3 << 20);
+        config.super_meta.size = ByteSize::new(1000);
+        let report = config.validate().unwrap_err();
+
+        let kinds: Vec<(IssueSeverity, String)> = report
+            .entries
+            .iter()
+            .map(|e| (e.severity, serde_json::to_value(&e.issue).unwrap()["detail"]["kind"].as_str().unwrap().to_string()))
+            .collect();
+        let expected = [
+            (IssueSeverity::Error, "UnknownGroup"),
+            (IssueSeverity::Error, "AlignmentNotPowerOfTwo"),
+            (IssueSeverity::Error, "NotSectorAligned"),
+            (IssueSeverity::Warning, "UnlimitedGroup"),
+            (IssueSeverity::Warning, "ImageIgnored"),
+        ];
+        assert_eq!(kinds, expected.map(|(severity, kind)| (severity, kind.to_string())));
+        assert!(report.has_errors());
+        assert_eq!(report.to_string(), "Config has 3 error(s) and 2 warning(s)");
+        // Same JSON on every run, for UI snapshot tests
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::to_string(&config.validate().unwrap_err()).unwrap(), json);
+        assert!(json.starts_with(r#"{"entries":[{"severity":"error","message":"Partition 'system_b' references unknown group"#), "{}", json);
+    }
+}
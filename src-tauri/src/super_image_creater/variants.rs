@@ -0,0 +1,154 @@
+use super::{ByteSize, PartitionConfig};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Sizes of one storage variant of a device (e.g. the 256 GB model), replacing
+/// those of the base config in `resolve_variant`. Everything not listed is shared.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Variant {
+    pub name: String,
+    /// Replaces `super_meta.size`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub super_meta_size: Option<ByteSize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub block_devices: Vec<BlockDeviceOverride>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<GroupOverride>,
+}
+
+/// New size of the block device called `name`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct BlockDeviceOverride {
+    pub name: String,
+    pub size: ByteSize,
+}
+
+/// New `maximum_size` of the group called `name`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GroupOverride {
+    pub name: String,
+    pub maximum_size: ByteSize,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum VariantError {
+    #[error("No variant '{name}' in the config (available: {})", describe_names(available))]
+    UnknownVariant { name: String, available: Vec<String> },
+    #[error("Variant '{variant}' resizes block device '{device}', which is not in the config")]
+    UnknownBlockDevice { variant: String, device: String },
+    #[error("Variant '{variant}' sets the limit of group '{group}', which is not in the config")]
+    UnknownGroup { variant: String, group: String },
+}
+
+fn describe_names(names: &[String]) -> String {
+    if names.is_empty() { "none".to_string() } else { names.join(", ") }
+}
+
+impl PartitionConfig {
+    /// Names of the `variants` section, in config order
+    pub fn variant_names(&self) -> Vec<String> {
+        self.variants.iter().map(|v| v.name.clone()).collect()
+    }
+}
+
+/// The concrete config of variant `name`: `config` with the sizes of the matching
+/// entry of `variants` applied and no `variants` section left.
+///
+/// `variants` is usually `config.variants`, but may come from a separate file.
+/// Overriding a group limit makes it user-set again (`maximum_size_computed` is
+/// cleared). The result is checked like any config once it is built or validated.
+pub fn resolve_variant(config: &PartitionConfig, variants: &[Variant], name: &str) -> Result<PartitionConfig, VariantError> {
+    let variant = variants.iter().find(|v| v.name == name).ok_or_else(|| VariantError::UnknownVariant {
+        name: name.to_string(),
+        available: variants.iter().map(|v| v.name.clone()).collect(),
+    })?;
+
+    let mut resolved = config.clone();
+    resolved.variants.clear();
+    if let Some(size) = &variant.super_meta_size {
+        resolved.super_meta.size = size.clone();
+    }
+    for device_override in &variant.block_devices {
+        let device = resolved.block_devices.iter_mut().find(|d| d.name == device_override.name).ok_or_else(|| {
+            VariantError::UnknownBlockDevice { variant: variant.name.clone(), device: device_override.name.clone() }
+        })?;
+        device.size = device_override.size.clone();
+    }
+    for group_override in &variant.groups {
+        let group = resolved.groups.iter_mut().find(|g| g.name == group_override.name).ok_or_else(|| {
+            VariantError::UnknownGroup { variant: variant.name.clone(), group: group_override.name.clone() }
+        })?;
+        group.maximum_size = Some(group_override.maximum_size.clone());
+        group.maximum_size_computed = false;
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::parse_partition_config_str;
+    use crate::super_image_creater::test_support::SAMPLE;
+
+    /// `SAMPLE` with a 256 GB and a 512 GB variant
+    fn config_with_variants() -> PartitionConfig {
+        let mut json: serde_json::Value = serde_json::from_str(SAMPLE).unwrap();
+        json["groups"][1]["maximum_size"] = "1GiB".into();
+        json["groups"][1]["maximum_size_computed"] = true.into();
+        json["variants"] = serde_json::json!([
+            {"name": "256GB", "block_devices": [{"name": "super", "size": "8GiB"}], "groups": [{"name": "qti_dynamic_partitions_b", "maximum_size": "3.5GiB"}]},
+            {"name": "512GB", "super_meta_size": "0x20000", "block_devices": [{"name": "super", "size": "16GiB"}]}
+        ]);
+        parse_partition_config_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn a_variant_replaces_only_the_sizes_it_lists() {
+        let config = config_with_variants();
+        assert_eq!(config.variant_names(), ["256GB", "512GB"]);
+
+        let small = resolve_variant(&config, &config.variants, "256GB").unwrap();
+        assert!(small.variants.is_empty());
+        assert_eq!(small.block_devices[0].size.as_u64(), 8 << 30);
+        assert_eq!(small.groups[1].maximum_size.as_ref().map(ByteSize::as_u64), Some(7 << 29));
+        assert!(!small.groups[1].maximum_size_computed);
+        // Shared with the base config
+        assert_eq!(small.super_meta, config.super_meta);
+        assert_eq!(small.groups[0], config.groups[0]);
+        assert_eq!(small.partitions, config.partitions);
+
+        let large = resolve_variant(&config, &config.variants, "512GB").unwrap();
+        assert_eq!(large.super_meta.size.as_u64(), 0x20000);
+        assert_eq!(large.block_devices[0].size.as_u64(), 16 << 30);
+        assert_eq!(large.groups, config.groups);
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        let config = config_with_variants();
+        assert_eq!(
+            resolve_variant(&config, &config.variants, "1TB"),
+            Err(VariantError::UnknownVariant { name: "1TB".into(), available: vec!["256GB".into(), "512GB".into()] })
+        );
+        assert_eq!(
+            resolve_variant(&config, &[], "1TB").unwrap_err().to_string(),
+            "No variant '1TB' in the config (available: none)"
+        );
+
+        let mut variants = config.variants.clone();
+        variants[0].block_devices[0].name = "userdata".into();
+        assert_eq!(
+            resolve_variant(&config, &variants, "256GB"),
+            Err(VariantError::UnknownBlockDevice { variant: "256GB".into(), device: "userdata".into() })
+        );
+        variants[0].block_devices.clear();
+        variants[0].groups[0].name = "qti_dynamic_partitions_c".into();
+        assert_eq!(
+            resolve_variant(&config, &variants, "256GB"),
+            Err(VariantError::UnknownGroup { variant: "256GB".into(), group: "qti_dynamic_partitions_c".into() })
+        );
+    }
+}
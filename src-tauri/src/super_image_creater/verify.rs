@@ -0,0 +1,258 @@
+use super::PartitionConfig;
+use super::builder::{BuildError, expected_image};
+use super::lp_metadata::*;
+use super::sparse::open_raw_or_sparse;
+use std::io::{self, Read};
+use std::path::Path;
+use thiserror::Error;
+
+/// Read buffer used to compare partition data with its source image
+const COMPARE_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("File operation error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot lay out the config to compare with: {0}")]
+    Build(#[from] BuildError),
+    #[error("Image is {actual} bytes, but the block device is {expected} bytes")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("{copy} is unreadable: {source}")]
+    Unreadable { copy: String, source: LpMetadataError },
+    #[error("Geometry {field} is {actual}, the config expects {expected}")]
+    GeometryMismatch { field: &'static str, expected: u64, actual: u64 },
+    #[error("{copy} differs from the primary metadata of slot 0")]
+    CopyMismatch { copy: String },
+    #[error("Metadata does not match the config: {0}")]
+    MetadataMismatch(String),
+    #[error("Data of partition '{partition}' differs from its image '{image}' at byte {offset} of the partition")]
+    DataMismatch { partition: String, image: String, offset: u64 },
+}
+
+/// Checks that the super image at `image` is what `config` builds, in a
+/// `SlotMode::Single` build.
+///
+/// The image may be raw, sparse or compressed. It must be the size of the block
+/// device (`super_meta.size` is the size of one metadata slot, not of the image),
+/// both geometry copies and the primary and backup metadata of every slot must read
+/// back with valid checksums and agree, and the block devices, groups, partitions
+/// and their extents must be those the builder lays out for `config`. The first
+/// mismatch is returned.
+pub fn verify_super<P: AsRef<Path>>(image: P, config: &PartitionConfig) -> Result<(), VerifyError> {
+    verify(image.as_ref(), config, false)
+}
+
+/// Same as `verify_super`, also comparing the data of every copied partition with
+/// its source image, byte for byte. Reads the whole image once, front to back.
+pub fn verify_super_data<P: AsRef<Path>>(image: P, config: &PartitionConfig) -> Result<(), VerifyError> {
+    verify(image.as_ref(), config, true)
+}
+
+fn verify(image: &Path, config: &PartitionConfig, data: bool) -> Result<(), VerifyError> {
+    let expected = expected_image(config)?;
+    let (mut reader, size) = open_raw_or_sparse(image)?;
+    if size != expected.device_size {
+        return Err(VerifyError::SizeMismatch { expected: expected.device_size, actual: size });
+    }
+    let unreadable = |copy: &str, source| VerifyError::Unreadable { copy: copy.to_string(), source };
+    let expected_geometry = LpGeometry::from_bytes(&expected.geometry).map_err(|e| unreadable("Built geometry", e))?;
+    let expected_metadata = LpMetadata::from_bytes(&expected.metadata).map_err(|e| unreadable("Built metadata", e))?;
+
+    let max_size = expected_geometry.metadata_max_size as u64;
+    let region_end = metadata_region_end(max_size, expected.slot_count);
+    let mut region = vec![0u8; region_end as usize];
+    reader.read_exact(&mut region)?;
+
+    for (i, name) in ["Primary geometry", "Backup geometry"].into_iter().enumerate() {
+        let offset = (LP_PARTITION_RESERVED_BYTES + LP_METADATA_GEOMETRY_SIZE * i as u64) as usize;
+        let geometry = LpGeometry::from_bytes(&region[offset..offset + LP_METADATA_GEOMETRY_SIZE as usize])
+            .map_err(|e| unreadable(name, e))?;
+        let fields = [
+            ("metadata_max_size", expected_geometry.metadata_max_size, geometry.metadata_max_size),
+            ("metadata_slot_count", expected_geometry.metadata_slot_count, geometry.metadata_slot_count),
+            ("logical_block_size", expected_geometry.logical_block_size, geometry.logical_block_size),
+        ];
+        if let Some(&(field, expected, actual)) = fields.iter().find(|(_, expected, actual)| expected != actual) {
+            return Err(VerifyError::GeometryMismatch { field, expected: expected as u64, actual: actual as u64 });
+        }
+    }
+
+    let mut first = None;
+    for slot in 0..expected.slot_count {
+        let copies = [
+            (format!("Primary metadata of slot {}", slot), primary_metadata_offset(max_size, slot)),
+            (format!("Backup metadata of slot {}", slot), backup_metadata_offset(max_size, expected.slot_count, slot)),
+        ];
+        for (copy, offset) in copies {
+            let blob = &region[offset as usize..(offset + max_size) as usize];
+            let metadata = LpMetadata::from_bytes(blob).map_err(|e| unreadable(&copy, e))?;
+            match &first {
+                None => first = Some(metadata),
+                Some(first) if *first != metadata => return Err(VerifyError::CopyMismatch { copy }),
+                Some(_) => {}
+            }
+        }
+    }
+    if let Some(difference) = first.as_ref().and_then(|actual| metadata_difference(&expected_metadata, actual)) {
+        return Err(VerifyError::MetadataMismatch(difference));
+    }
+
+    if data {
+        let mut position = region_end;
+        let mut buf = vec![0u8; COMPARE_BUFFER_SIZE];
+        let mut source_buf = vec![0u8; COMPARE_BUFFER_SIZE];
+        for (name, path, offset) in &expected.images {
+            io::copy(&mut (&mut reader).take(offset - position), &mut io::sink())?;
+            let (mut source, source_size) = open_raw_or_sparse(Path::new(path))?;
+            let mut done = 0u64;
+            while done < source_size {
+                let len = (source_size - done).min(buf.len() as u64) as usize;
+                reader.read_exact(&mut buf[..len])?;
+                source.read_exact(&mut source_buf[..len])?;
+                if let Some(i) = buf[..len].iter().zip(&source_buf[..len]).position(|(a, b)| a != b) {
+                    return Err(VerifyError::DataMismatch { partition: name.clone(), image: path.clone(), offset: done + i as u64 });
+                }
+                done += len as u64;
+            }
+            position = offset + source_size;
+        }
+    }
+    Ok(())
+}
+
+/// The first difference of the tables in `actual` from those in `expected`, described
+/// by the names of the entries
+fn metadata_difference(expected: &LpMetadata, actual: &LpMetadata) -> Option<String> {
+    if expected.minor_version != actual.minor_version {
+        return Some(format!("version is 10.{}, expected 10.{}", actual.minor_version, expected.minor_version));
+    }
+    if expected.header_flags != actual.header_flags {
+        return Some(format!("header flags are 0x{:x}, expected 0x{:x}", actual.header_flags, expected.header_flags));
+    }
+
+    if expected.block_devices.len() != actual.block_devices.len() {
+        return Some(format!("{} block device(s), expected {}", actual.block_devices.len(), expected.block_devices.len()));
+    }
+    for (e, a) in expected.block_devices.iter().zip(&actual.block_devices) {
+        if e != a {
+            return Some(format!("block device '{}' is {:?}, expected {:?}", e.partition_name, a, e));
+        }
+    }
+
+    let names: Vec<&str> = expected.groups.iter().map(|g| g.name.as_str()).collect();
+    let actual_names: Vec<&str> = actual.groups.iter().map(|g| g.name.as_str()).collect();
+    if names != actual_names {
+        return Some(format!("groups are {:?}, expected {:?}", actual_names, names));
+    }
+    for (e, a) in expected.groups.iter().zip(&actual.groups) {
+        if e.maximum_size != a.maximum_size {
+            return Some(format!("group '{}' has a maximum_size of {}, expected {}", e.name, a.maximum_size, e.maximum_size));
+        }
+        if e.flags != a.flags {
+            return Some(format!("group '{}' has flags 0x{:x}, expected 0x{:x}", e.name, a.flags, e.flags));
+        }
+    }
+
+    let names: Vec<&str> = expected.partitions.iter().map(|p| p.name.as_str()).collect();
+    let actual_names: Vec<&str> = actual.partitions.iter().map(|p| p.name.as_str()).collect();
+    if names != actual_names {
+        return Some(format!("partitions are {:?}, expected {:?}", actual_names, names));
+    }
+    let extents = |metadata: &'_ LpMetadata, partition: &LpPartition| -> Vec<LpExtent> {
+        let start = partition.first_extent_index as usize;
+        metadata.extents.get(start..start + partition.num_extents as usize).unwrap_or_default().to_vec()
+    };
+    for (e, a) in expected.partitions.iter().zip(&actual.partitions) {
+        let group = |metadata: &'_ LpMetadata, p: &LpPartition| metadata.groups.get(p.group_index as usize).map(|g| g.name.clone());
+        if group(expected, e) != group(actual, a) {
+            return Some(format!("partition '{}' is in group {:?}, expected {:?}", e.name, group(actual, a), group(expected, e)));
+        }
+        if e.attributes != a.attributes {
+            return Some(format!("partition '{}' has attributes 0x{:x}, expected 0x{:x}", e.name, a.attributes, e.attributes));
+        }
+        let (expected_extents, actual_extents) = (extents(expected, e), extents(actual, a));
+        let size = |extents: &[LpExtent]| extents.iter().map(|x| x.num_sectors * LP_SECTOR_SIZE).sum::<u64>();
+        if size(&expected_extents) != size(&actual_extents) {
+            return Some(format!(
+                "partition '{}' is {} bytes, expected {}",
+                e.name,
+                size(&actual_extents),
+                size(&expected_extents)
+            ));
+        }
+        if expected_extents.len() != actual_extents.len() {
+            return Some(format!("partition '{}' has {} extent(s), expected {}", e.name, actual_extents.len(), expected_extents.len()));
+        }
+        for (i, (x, y)) in expected_extents.iter().zip(&actual_extents).enumerate() {
+            if x.target_data != y.target_data {
+                return Some(format!(
+                    "extent {} of partition '{}' is at offset {}, expected {}",
+                    i,
+                    e.name,
+                    y.target_data * LP_SECTOR_SIZE,
+                    x.target_data * LP_SECTOR_SIZE
+                ));
+            }
+            if x != y {
+                return Some(format!("extent {} of partition '{}' is {:?}, expected {:?}", i, e.name, y, x));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::super_image_creater::test_support::{config_with_images, pattern, test_dir};
+    use crate::super_image_creater::{BuildReport, ByteSize, build_super_image};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// An image built from its config, with 4096 bytes in `system` and 100 in `vendor`
+    fn built(name: &str) -> (PathBuf, PartitionConfig, BuildReport) {
+        let dir = test_dir(name);
+        let config = config_with_images(&dir, &[("system", &pattern(4096, 1), 1 << 20), ("vendor", &pattern(100, 2), 4096)]);
+        let output = dir.join("super.img");
+        let report = build_super_image(&config, &output).unwrap();
+        (output, config, report)
+    }
+
+    #[test]
+    fn a_fresh_build_verifies() {
+        let (image, config, _) = built("verify-ok");
+        verify_super(&image, &config).unwrap();
+        verify_super_data(&image, &config).unwrap();
+    }
+
+    #[test]
+    fn one_changed_metadata_byte_fails() {
+        let (image, config, _) = built("verify-metadata");
+        let mut data = fs::read(&image).unwrap();
+        // In the tables of the primary metadata of slot 0, past the header
+        let offset = primary_metadata_offset(config.super_meta.size_bytes(), 0) + 300;
+        data[offset as usize] ^= 1;
+        fs::write(&image, &data).unwrap();
+        let e = verify_super(&image, &config).unwrap_err();
+        assert!(matches!(&e, VerifyError::Unreadable { copy, .. } if copy == "Primary metadata of slot 0"), "{}", e);
+    }
+
+    #[test]
+    fn a_changed_config_fails() {
+        let (image, mut config, _) = built("verify-config");
+        config.partitions[1].size = Some(ByteSize::new(8192));
+        let e = verify_super(&image, &config).unwrap_err();
+        assert!(matches!(e, VerifyError::MetadataMismatch(_)), "{}", e);
+    }
+
+    #[test]
+    fn one_changed_data_byte_only_fails_the_data_check() {
+        let (image, config, report) = built("verify-data");
+        let mut data = fs::read(&image).unwrap();
+        data[report.partitions[0].offset as usize + 100] ^= 1;
+        fs::write(&image, &data).unwrap();
+        verify_super(&image, &config).unwrap();
+        let e = verify_super_data(&image, &config).unwrap_err();
+        assert!(matches!(&e, VerifyError::DataMismatch { partition, offset: 100, .. } if partition == "system"), "{}", e);
+    }
+}
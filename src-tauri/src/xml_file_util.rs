@@ -67,6 +67,28 @@ pub struct ReadTag {
     pub sparse: bool,
 }
 
+// Define struct for the <patch> node of patch*.xml (matches all attributes)
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(rename = "patch")]
+pub struct Patch {
+    #[serde(rename = "@SECTOR_SIZE_IN_BYTES")]
+    pub sector_size_in_bytes: u64,
+    #[serde(rename = "@byte_offset")]
+    pub byte_offset: u64,
+    #[serde(rename = "@filename")]
+    pub filename: String,
+    #[serde(rename = "@physical_partition_number")]
+    pub physical_partition_number: u8,
+    #[serde(rename = "@size_in_bytes")]
+    pub size_in_bytes: u64,
+    #[serde(rename = "@start_sector")]
+    pub start_sector: String,
+    #[serde(rename = "@value")]
+    pub value: String,
+    #[serde(rename = "@what")]
+    pub what: String,
+}
+
 fn serialize_size_in_kb<S>(value: &f64, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
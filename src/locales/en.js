@@ -18,6 +18,7 @@ export default {
 		start: 'Start Sector',
 		num: 'Sector Num',
 		imgPath: 'Image Path',
+		fsType: 'Filesystem',
 	},
 	reboot: {
 		title: 'Reboot to',
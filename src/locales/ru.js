@@ -18,6 +18,7 @@ export default {
 		start: 'Начальный сектор',
 		num: 'Кол-во секторов',
 		imgPath: 'Путь к Image',
+		fsType: 'Файловая система',
 	},
 	reboot: {
 		title: 'Перезагрузка в',
@@ -18,6 +18,7 @@
 		start: '起始扇区',
 		num: '扇区数',
 		imgPath: '镜像文件',
+		fsType: '文件系统',
 	},
 	reboot: {
 		title: '重启至',
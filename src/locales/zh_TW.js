@@ -18,6 +18,7 @@
 		start: '起始扇區',
 		num: '扇區數',
 		imgPath: '映像檔路徑',
+		fsType: '檔案系統',
 	},
 	reboot: {
 		title: '重啟至',